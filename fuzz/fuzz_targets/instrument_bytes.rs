@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use vv_profiler::pipeline::{instrument_bytes, Error, InstrumentOptions};
+
+// Run with `cargo fuzz run instrument_bytes` from the repository root.
+fuzz_target!(|module: wasm_smith::Module| {
+    let wasm = module.to_bytes();
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    // Parse errors are expected (wasm-smith generates proposals walrus doesn't
+    // support); a panic, a failed self-check or an internal error is a bug
+    match instrument_bytes(&wasm, &options) {
+        Err(e @ Error::SelfCheck(_)) | Err(e @ Error::Internal(_)) => {
+            panic!("instrument_bytes failed on a valid module: {}", e)
+        }
+        _ => (),
+    }
+});
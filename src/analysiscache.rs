@@ -0,0 +1,97 @@
+use crate::importclasses::CostClass;
+use crate::manifest::fingerprint;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use walrus::*;
+use wasmparser::{Parser, Payload};
+
+pub const ANALYSIS_CACHE_VERSION: u32 = 2;
+
+/*
+ * On-disk cache of the per-function fastcall scan (see
+ * fastcalls::compute_slowcalls), so a run on a slightly changed module only
+ * scans the functions that changed. A scan result depends on the function's
+ * body, its own index, and what the rest of the module looks like to it (the
+ * types, the table entries, the imports and entry points), so entries are
+ * keyed by all three: anything else changing just misses. Results are
+ * stored with function indices, which stay put as long as no function is
+ * added in front. Entries are never evicted, delete the file to start over.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AnalysisCache {
+    pub version: u32,
+    pub entries: HashMap<String, CachedScan>,
+    // Fingerprint of each local function body of the module at hand, in order
+    #[serde(skip)]
+    bodies: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedScan {
+    pub is_fastcall: bool,
+    // The worst class of the imports it calls directly
+    pub class: CostClass,
+    // Indices of the functions it may call, directly or through the table
+    pub deps: Vec<u32>,
+}
+
+impl AnalysisCache {
+    // The cache at `path` (empty if there's none yet or it's stale), ready to look up the functions of `wasm`
+    pub fn load(path: &str, wasm: &[u8]) -> AnalysisCache {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(text) => match serde_json::from_str::<AnalysisCache>(&text) {
+                Ok(cache) if cache.version == ANALYSIS_CACHE_VERSION => cache.entries,
+                _ => {
+                    println!(
+                        "{} is from another version, starting a new analysis cache",
+                        path
+                    );
+                    HashMap::new()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => panic!("can't read {}: {}", path, e),
+        };
+        AnalysisCache {
+            version: ANALYSIS_CACHE_VERSION,
+            entries,
+            bodies: body_fingerprints(wasm),
+        }
+    }
+
+    pub fn write(&self, path: &str) {
+        let json = serde_json::to_string(self).unwrap();
+        std::fs::write(path, json).unwrap();
+    }
+
+    // The key of the `nth` local function (function index `index`) under `context`
+    pub fn key(&self, context: &str, index: u32, nth: usize) -> Option<String> {
+        let body = self.bodies.get(nth)?;
+        Some(format!("{}:{}:{}", context, index, body))
+    }
+}
+
+// Empty if the module doesn't parse, so nothing is found in the cache
+fn body_fingerprints(wasm: &[u8]) -> Vec<String> {
+    let mut bodies = vec![];
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload {
+            Ok(Payload::CodeSectionEntry(body)) => {
+                bodies.push(fingerprint(&wasm[body.range().start..body.range().end]))
+            }
+            Ok(_) => (),
+            Err(_) => return vec![],
+        }
+    }
+    bodies
+}
+
+// Each function's index in the module, imports first
+pub fn function_indices(module: &Module) -> HashMap<FunctionId, u32> {
+    module
+        .funcs
+        .iter()
+        .enumerate()
+        .map(|(idx, func)| (func.id(), idx as u32))
+        .collect()
+}
@@ -0,0 +1,227 @@
+use std::collections::HashSet;
+use walrus::*;
+use wasmparser::{
+    BinaryReader, BinaryReaderError, ConstExpr, Element, ElementItems, ElementKind,
+    ElementSectionReader, Operator, Parser, Payload, RefType, TypeRef,
+};
+
+// Name prefixes of wasm-bindgen's glue: its exported allocator and externref
+// heap helpers, and the JS functions it imports
+pub const SHIM_PREFIXES: &[&str] = &["__wbindgen_", "__externref_", "__wbg_"];
+
+pub fn is_shim(name: &str) -> bool {
+    SHIM_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+}
+
+/*
+ * The wasm-bindgen glue functions, by name or export name. They are only
+ * ever called from JS, so like tiny functions their callsites are left
+ * uninstrumented and they get no slowcall stub.
+ */
+pub fn shim_functions(module: &Module) -> HashSet<FunctionId> {
+    let mut shims: HashSet<FunctionId> = module
+        .funcs
+        .iter()
+        .filter(|func| matches!(func.kind, FunctionKind::Local(_)))
+        .filter(|func| func.name.as_deref().is_some_and(is_shim))
+        .map(|func| func.id())
+        .collect();
+    shims.extend(
+        module
+            .exports
+            .iter()
+            .filter(|export| is_shim(&export.name))
+            .filter_map(|export| match export.item {
+                ExportItem::Function(f_id) => Some(f_id),
+                _ => None,
+            })
+            .filter(|f_id| matches!(module.funcs.get(*f_id).kind, FunctionKind::Local(_))),
+    );
+    shims
+}
+
+fn leb128(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+// The offset of a segment placed with a lone i32.const
+fn const_offset(expr: &ConstExpr) -> Option<u32> {
+    let mut ops = expr.get_operators_reader();
+    match (ops.read().ok()?, ops.read().ok()?) {
+        (Operator::I32Const { value }, Operator::End) => Some(value as u32),
+        _ => None,
+    }
+}
+
+// Why funcref_segments_only gave up
+enum Failure {
+    // Left for walrus to report
+    Parse,
+    Unsupported(String),
+}
+
+impl From<BinaryReaderError> for Failure {
+    fn from(_: BinaryReaderError) -> Failure {
+        Failure::Parse
+    }
+}
+
+fn only_nulls(items: &ElementItems) -> Result<bool, Failure> {
+    let exprs = match items {
+        ElementItems::Expressions(_, exprs) => exprs.clone(),
+        ElementItems::Functions(_) => return Ok(false),
+    };
+    for expr in exprs {
+        let mut ops = expr?.get_operators_reader();
+        if !matches!(ops.read()?, Operator::RefNull { .. }) || !matches!(ops.read()?, Operator::End)
+        {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+struct Tables {
+    imported: u32,
+    // Initial size of each table the module defines
+    sizes: Vec<u64>,
+    // Segments some table.init copies from
+    initialized_from: HashSet<u32>,
+}
+
+// Why segment `idx` can't be left out, None if all it does is write nulls over nulls
+fn keep_reason(tables: &Tables, idx: u32, elem: &Element) -> Result<Option<String>, Failure> {
+    let (table, offset) = match &elem.kind {
+        ElementKind::Active {
+            table_index,
+            offset_expr,
+        } => (table_index.unwrap_or(0), offset_expr),
+        _ => return Ok(Some("it isn't active".to_string())),
+    };
+    let reason = if tables.initialized_from.contains(&idx) {
+        "a table.init copies from it"
+    } else if table < tables.imported {
+        "it writes into an imported table"
+    } else if !only_nulls(&elem.items)? {
+        "it holds values other than ref.null"
+    } else {
+        let count = match &elem.items {
+            ElementItems::Functions(funcs) => funcs.count(),
+            ElementItems::Expressions(_, exprs) => exprs.count(),
+        };
+        let size = tables.sizes.get((table - tables.imported) as usize);
+        match (const_offset(offset), size) {
+            (Some(offset), Some(size)) if offset as u64 + count as u64 <= *size => return Ok(None),
+            _ => "it may not fit in the table",
+        }
+    };
+    Ok(Some(reason.to_string()))
+}
+
+fn rewrite(wasm: &[u8]) -> Result<Option<Vec<u8>>, Failure> {
+    let mut tables = Tables {
+        imported: 0,
+        sizes: vec![],
+        initialized_from: HashSet::new(),
+    };
+    let mut found = false;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    if let TypeRef::Table(_) = import?.ty {
+                        tables.imported += 1;
+                    }
+                }
+            }
+            Payload::TableSection(reader) => {
+                for table in reader {
+                    tables.sizes.push(table?.ty.initial);
+                }
+            }
+            Payload::ElementSection(reader) => {
+                for elem in reader {
+                    if let ElementItems::Expressions(ty, _) = elem?.items {
+                        found |= ty == RefType::EXTERNREF;
+                    }
+                }
+            }
+            Payload::CodeSectionEntry(body) => {
+                let mut reader = body.get_operators_reader()?;
+                while !reader.eof() {
+                    if let Operator::TableInit { elem_index, .. } = reader.read()? {
+                        tables.initialized_from.insert(elem_index);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    if !found {
+        return Ok(None);
+    }
+
+    // Copy the sections over, rewriting the element section
+    let mut out = wasm[..8].to_vec();
+    let mut reader = BinaryReader::new(wasm, 0);
+    reader.read_bytes(8)?;
+    while !reader.eof() {
+        let start = reader.original_position();
+        let id = reader.read_u8()?;
+        let size = reader.read_var_u32()? as usize;
+        let content = reader.original_position();
+        reader.read_bytes(size)?;
+        if id != 9 {
+            out.extend_from_slice(&wasm[start..content + size]);
+            continue;
+        }
+        let section =
+            ElementSectionReader::new(BinaryReader::new(&wasm[content..content + size], content))?;
+        let mut segments = vec![];
+        leb128(&mut segments, section.count());
+        for (idx, elem) in section.into_iter().enumerate() {
+            let elem = elem?;
+            match &elem.items {
+                ElementItems::Expressions(ty, _) if *ty == RefType::EXTERNREF => {
+                    if let Some(reason) = keep_reason(&tables, idx as u32, &elem)? {
+                        return Err(Failure::Unsupported(format!(
+                            "element segment {} is an externref segment walrus can't represent, and {}",
+                            idx, reason
+                        )));
+                    }
+                    // An empty declarative funcref segment, so later segments keep their index
+                    segments.extend_from_slice(&[0x03, 0x00, 0x00]);
+                }
+                _ => segments.extend_from_slice(&wasm[elem.range.clone()]),
+            }
+        }
+        out.push(id);
+        leb128(&mut out, segments.len() as u32);
+        out.extend(segments);
+    }
+    Ok(Some(out))
+}
+
+/*
+ * walrus only parses funcref element segments, but modules with an externref
+ * table (wasm-bindgen's heap of JS values, for one) may initialize it with a
+ * segment of ref.nulls. That writes what a new table already holds, so such
+ * a segment is swapped for an empty one and we go on; other externref
+ * segments can't be processed. Returns the rewritten module, or None if
+ * there was nothing to rewrite. Parse errors are left for walrus to report.
+ */
+pub fn funcref_segments_only(wasm: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    match rewrite(wasm) {
+        Ok(rewritten) => Ok(rewritten),
+        Err(Failure::Parse) => Ok(None),
+        Err(Failure::Unsupported(msg)) => Err(msg),
+    }
+}
@@ -0,0 +1,72 @@
+use crate::counters::CounterPolicy;
+use walrus::ir::*;
+use walrus::*;
+
+// Collects the instruction sequences of a function in a deterministic order
+// (in-order dfs starting from the entry block)
+struct SeqScan {
+    seqs: Vec<InstrSeqId>,
+}
+
+impl<'instr> Visitor<'instr> for SeqScan {
+    fn start_instr_seq(&mut self, instr_seq: &'instr InstrSeq) {
+        self.seqs.push(instr_seq.id());
+    }
+}
+
+/*
+ * Number every instruction sequence (function body, block, loop, if/else arm)
+ * in the given functions. The index of each entry is the block id used in the
+ * profile, so this must be computed on the *original* module in both the
+ * instrumentation and optimization runs.
+ */
+pub fn enumerate_blocks(module: &Module, funcs: &[FunctionId]) -> Vec<(FunctionId, InstrSeqId)> {
+    let mut blocks = vec![];
+    for f_id in funcs {
+        match &module.funcs.get(*f_id).kind {
+            FunctionKind::Local(func) => {
+                let mut scan = SeqScan { seqs: vec![] };
+                walrus::ir::dfs_in_order(&mut scan, func, func.entry_block());
+                for seq in scan.seqs {
+                    blocks.push((*f_id, seq));
+                }
+            }
+            _ => (),
+        }
+    }
+    blocks
+}
+
+/*
+ * Insert a counter at the start of each block. Each counter is a separate
+ * global (exported as {prefix}profiling_block_{idx}) that is incremented every time
+ * the block is entered. With `entry_only`, just the function entry blocks get
+ * one (--coarse), keeping the ids they would have with every block counted.
+ */
+pub fn instrument_blocks(
+    module: &mut Module,
+    blocks: &[(FunctionId, InstrSeqId)],
+    export_prefix: &str,
+    entry_only: bool,
+    policy: &CounterPolicy,
+) -> Vec<GlobalId> {
+    let mut counters = vec![];
+    for (idx, (f_id, seq)) in blocks.iter().enumerate() {
+        if entry_only && module.funcs.get(*f_id).kind.unwrap_local().entry_block() != *seq {
+            continue;
+        }
+        let counter = policy.add_counter(module);
+        let func = module.funcs.get_mut(*f_id).kind.unwrap_local_mut();
+        let mut body = func.builder_mut().instr_seq(*seq);
+        for instr in policy.increment(counter).into_iter().rev() {
+            body.instr_at(0, instr);
+        }
+        module.exports.add(
+            &format!("{}profiling_block_{}", export_prefix, idx),
+            counter,
+        );
+        counters.push(counter);
+    }
+    println!("Instrumented {} blocks with counters", counters.len());
+    counters
+}
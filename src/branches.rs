@@ -0,0 +1,164 @@
+use crate::counters::CounterPolicy;
+use std::collections::HashMap;
+use walrus::ir::*;
+use walrus::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BranchKind {
+    IfElse,
+    BrIf,
+}
+
+// A conditional branch in the original module: (function, sequence, position)
+#[derive(Clone, Copy, Debug)]
+pub struct Branch {
+    pub func: FunctionId,
+    pub seq: InstrSeqId,
+    pub pos: usize,
+    pub kind: BranchKind,
+}
+
+fn scan_seq(func: &LocalFunction, f_id: FunctionId, seq: InstrSeqId, branches: &mut Vec<Branch>) {
+    for (pos, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+        match instr {
+            Instr::IfElse(if_else) => {
+                branches.push(Branch {
+                    func: f_id,
+                    seq,
+                    pos,
+                    kind: BranchKind::IfElse,
+                });
+                scan_seq(func, f_id, if_else.consequent, branches);
+                scan_seq(func, f_id, if_else.alternative, branches);
+            }
+            Instr::BrIf(_) => branches.push(Branch {
+                func: f_id,
+                seq,
+                pos,
+                kind: BranchKind::BrIf,
+            }),
+            Instr::Block(b) => scan_seq(func, f_id, b.seq, branches),
+            Instr::Loop(l) => scan_seq(func, f_id, l.seq, branches),
+            _ => (),
+        }
+    }
+}
+
+/*
+ * Number every conditional branch (if/else + br_if) in the given functions.
+ * Like block ids, branch ids must be computed on the original module.
+ */
+pub fn enumerate_branches(module: &Module, funcs: &[FunctionId]) -> Vec<Branch> {
+    let mut branches = vec![];
+    for f_id in funcs {
+        match &module.funcs.get(*f_id).kind {
+            FunctionKind::Local(func) => scan_seq(func, *f_id, func.entry_block(), &mut branches),
+            _ => (),
+        }
+    }
+    branches
+}
+
+fn increment(seq: &mut InstrSeqBuilder, pos: usize, counter: GlobalId, policy: &CounterPolicy) {
+    for instr in policy.increment(counter).into_iter().rev() {
+        seq.instr_at(pos, instr);
+    }
+}
+
+/*
+ * Add a (taken, not taken) counter pair to each conditional branch.
+ *
+ * VectorVisor runs many instances in lockstep, so the host sums these counters
+ * across lanes. A branch that goes both ways often is a branch where lanes are
+ * likely to disagree (diverge).
+ *
+ * - if/else: increment at the start of each arm
+ * - br_if: stash the condition in a scratch local, count, then branch as before
+ *
+ * Must run before any other pass changes instruction positions.
+ */
+pub fn instrument_branches(
+    module: &mut Module,
+    branches: &[Branch],
+    export_prefix: &str,
+    policy: &CounterPolicy,
+) -> Vec<(GlobalId, GlobalId)> {
+    let mut counters = vec![];
+    let mut scratch: HashMap<FunctionId, LocalId> = HashMap::new();
+    for (idx, _) in branches.iter().enumerate() {
+        let taken = policy.add_counter(module);
+        let not_taken = policy.add_counter(module);
+        module.exports.add(
+            &format!("{}profiling_branch_{}_taken", export_prefix, idx),
+            taken,
+        );
+        module.exports.add(
+            &format!("{}profiling_branch_{}_not_taken", export_prefix, idx),
+            not_taken,
+        );
+        counters.push((taken, not_taken));
+    }
+
+    // Look up the if/else arms before we start shifting instructions around
+    let mut arms = vec![];
+    for (idx, branch) in branches.iter().enumerate() {
+        if branch.kind == BranchKind::IfElse {
+            let func = module.funcs.get(branch.func).kind.unwrap_local();
+            match &func.block(branch.seq).instrs[branch.pos].0 {
+                Instr::IfElse(if_else) => arms.push((idx, if_else.consequent, if_else.alternative)),
+                _ => panic!("branch {} is not an if/else", idx),
+            }
+        }
+    }
+
+    // Insert from the back of each sequence so earlier positions stay valid
+    let mut order: Vec<usize> = (0..branches.len())
+        .filter(|idx| branches[*idx].kind == BranchKind::BrIf)
+        .collect();
+    order.sort_by(|a, b| branches[*b].pos.cmp(&branches[*a].pos));
+    for idx in order {
+        let branch = branches[idx];
+        let (taken, not_taken) = counters[idx];
+        let tmp = *scratch
+            .entry(branch.func)
+            .or_insert_with(|| module.locals.add(ValType::I32));
+        let builder = module
+            .funcs
+            .get_mut(branch.func)
+            .kind
+            .unwrap_local_mut()
+            .builder_mut();
+        let mut then = builder.dangling_instr_seq(None);
+        increment(&mut then, 0, taken, policy);
+        let then_id = then.id();
+        let mut else_ = builder.dangling_instr_seq(None);
+        increment(&mut else_, 0, not_taken, policy);
+        let else_id = else_.id();
+
+        let mut seq = builder.instr_seq(branch.seq);
+        seq.instr_at(branch.pos, LocalGet { local: tmp });
+        seq.instr_at(
+            branch.pos,
+            IfElse {
+                consequent: then_id,
+                alternative: else_id,
+            },
+        );
+        seq.instr_at(branch.pos, LocalGet { local: tmp });
+        seq.instr_at(branch.pos, LocalSet { local: tmp });
+    }
+
+    for (idx, consequent, alternative) in arms {
+        let (taken, not_taken) = counters[idx];
+        let builder = module
+            .funcs
+            .get_mut(branches[idx].func)
+            .kind
+            .unwrap_local_mut()
+            .builder_mut();
+        increment(&mut builder.instr_seq(consequent), 0, taken, policy);
+        increment(&mut builder.instr_seq(alternative), 0, not_taken, policy);
+    }
+    println!("Instrumented {} conditional branches", branches.len());
+    counters
+}
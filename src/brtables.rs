@@ -0,0 +1,229 @@
+use crate::counters::CounterPolicy;
+use std::collections::HashMap;
+use walrus::ir::*;
+use walrus::*;
+
+// A br_table in the original module: (function, sequence, position), with its arm count
+#[derive(Clone, Copy, Debug)]
+pub struct BrTableSite {
+    pub func: FunctionId,
+    pub seq: InstrSeqId,
+    pub pos: usize,
+    // The targets plus the default, which takes every index past the end
+    pub arms: usize,
+}
+
+fn scan_seq(
+    func: &LocalFunction,
+    f_id: FunctionId,
+    seq: InstrSeqId,
+    tables: &mut Vec<BrTableSite>,
+) {
+    for (pos, (instr, _)) in func.block(seq).instrs.iter().enumerate() {
+        match instr {
+            Instr::BrTable(br_table) => tables.push(BrTableSite {
+                func: f_id,
+                seq,
+                pos,
+                arms: br_table.blocks.len() + 1,
+            }),
+            Instr::Block(b) => scan_seq(func, f_id, b.seq, tables),
+            Instr::Loop(l) => scan_seq(func, f_id, l.seq, tables),
+            Instr::IfElse(if_else) => {
+                scan_seq(func, f_id, if_else.consequent, tables);
+                scan_seq(func, f_id, if_else.alternative, tables);
+            }
+            _ => (),
+        }
+    }
+}
+
+// Number every br_table in the given functions, which must be those of the original module
+pub fn enumerate_br_tables(module: &Module, funcs: &[FunctionId]) -> Vec<BrTableSite> {
+    let mut tables = vec![];
+    for f_id in funcs {
+        if let FunctionKind::Local(func) = &module.funcs.get(*f_id).kind {
+            scan_seq(func, *f_id, func.entry_block(), &mut tables);
+        }
+    }
+    tables
+}
+
+/*
+ * One block per arm, nested in arm order, around a br_table on the index
+ * whose arm n targets the nth block. Leaving the block of arm n bumps its
+ * counter and skips the rest.
+ */
+fn arm_blocks(
+    seq: &mut InstrSeqBuilder,
+    counters: &[GlobalId],
+    index: LocalId,
+    done: InstrSeqId,
+    targets: &mut Vec<InstrSeqId>,
+    policy: &CounterPolicy,
+) {
+    let arm = targets.len();
+    seq.block(None, |block| {
+        targets.push(block.id());
+        if arm + 1 == counters.len() {
+            let (default, arms) = targets.split_last().unwrap();
+            block
+                .local_get(index)
+                .br_table(arms.to_vec().into_boxed_slice(), *default);
+        } else {
+            arm_blocks(block, counters, index, done, targets, policy);
+        }
+    });
+    for instr in policy.increment(counters[arm]) {
+        seq.instr(instr);
+    }
+    seq.br(done);
+}
+
+/*
+ * Count how often each arm of `tables` is taken: the index is passed to
+ * br_table_stub_{id}, which bumps profiling_br_table_{id}_{arm}, the last
+ * arm being the default. Switch-heavy code (interpreters) dispatches through
+ * a few large br_tables, whose hot arms VectorVisor or a later pass could
+ * test first. The positions are those enumerate_br_tables found, so this
+ * runs right after it, before any other pass inserts instructions.
+ */
+pub fn instrument_br_tables(
+    module: &mut Module,
+    tables: &[(usize, BrTableSite)],
+    export_prefix: &str,
+    policy: &CounterPolicy,
+) {
+    let mut stubs = vec![];
+    for (id, table) in tables {
+        let counters: Vec<GlobalId> = (0..table.arms)
+            .map(|arm| {
+                let counter = policy.add_counter(module);
+                module.exports.add(
+                    &format!("{}profiling_br_table_{}_{}", export_prefix, id, arm),
+                    counter,
+                );
+                counter
+            })
+            .collect();
+        let index = module.locals.add(ValType::I32);
+        let mut stub = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+        stub.name(format!("br_table_stub_{}", id));
+        stub.func_body().block(None, |done| {
+            let done_id = done.id();
+            arm_blocks(done, &counters, index, done_id, &mut vec![], policy);
+        });
+        stubs.push(stub.finish(vec![index], &mut module.funcs));
+    }
+
+    // Insert from the back of each sequence so earlier positions stay valid
+    let mut order: Vec<usize> = (0..tables.len()).collect();
+    order.sort_by(|a, b| tables[*b].1.pos.cmp(&tables[*a].1.pos));
+    let mut scratch: HashMap<FunctionId, LocalId> = HashMap::new();
+    for idx in order {
+        let table = tables[idx].1;
+        let tmp = *scratch
+            .entry(table.func)
+            .or_insert_with(|| module.locals.add(ValType::I32));
+        let func = module.funcs.get_mut(table.func).kind.unwrap_local_mut();
+        let instrs = &mut func.block_mut(table.seq).instrs;
+        let loc = instrs[table.pos].1;
+        let count = vec![
+            Instr::LocalTee(LocalTee { local: tmp }),
+            Instr::Call(Call { func: stubs[idx] }),
+            Instr::LocalGet(LocalGet { local: tmp }),
+        ];
+        instrs.splice(
+            table.pos..table.pos,
+            count.into_iter().map(|instr| (instr, loc)),
+        );
+    }
+    println!("Counting the arms of {} br_tables", tables.len());
+}
+
+// An arm is only peeled if it takes at least this share of the table's dispatches
+pub const PEEL_MIN_SHARE: f64 = 0.25;
+
+/*
+ * Peel up to `max_arms` of the hottest arms of each profiled br_table (the
+ * profile's arm counts, see instrument_br_tables) into br_ifs ahead of it,
+ * so an interpreter's dispatch loop tests its common opcodes first instead
+ * of going through the full table. Arm k becomes `index == k`, the default
+ * `index >= arms - 1` (unsigned). Rarely taken arms aren't worth a test on
+ * every dispatch (PEEL_MIN_SHARE), and tables whose arm count doesn't match
+ * the profile are left alone. The positions are those enumerate_br_tables
+ * found, so this runs right after it. Returns how many arms were peeled.
+ */
+pub fn peel_br_tables(
+    module: &mut Module,
+    tables: &[BrTableSite],
+    counts: &HashMap<usize, Vec<i32>>,
+    max_arms: usize,
+) -> usize {
+    let mut order: Vec<usize> = (0..tables.len()).collect();
+    order.sort_by(|a, b| tables[*b].pos.cmp(&tables[*a].pos));
+    let mut scratch: HashMap<FunctionId, LocalId> = HashMap::new();
+    let mut peeled = 0;
+    for idx in order {
+        let table = tables[idx];
+        let arms = match counts.get(&idx) {
+            Some(arms) if arms.len() == table.arms => arms,
+            Some(_) => {
+                println!(
+                    "warning: br_table {} has {} arms, not the profile's, leaving it alone",
+                    idx, table.arms
+                );
+                continue;
+            }
+            None => continue,
+        };
+        let total: i64 = arms.iter().map(|count| *count as i64).sum();
+        let mut hot: Vec<(usize, i32)> = arms
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, count)| *count > 0 && *count as f64 >= total as f64 * PEEL_MIN_SHARE)
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(max_arms);
+        if hot.is_empty() {
+            continue;
+        }
+
+        let tmp = *scratch
+            .entry(table.func)
+            .or_insert_with(|| module.locals.add(ValType::I32));
+        let func = module.funcs.get_mut(table.func).kind.unwrap_local_mut();
+        let instrs = &mut func.block_mut(table.seq).instrs;
+        let (targets, default) = match &instrs[table.pos].0 {
+            Instr::BrTable(br_table) => (br_table.blocks.clone(), br_table.default),
+            _ => unreachable!("br_table positions are taken before any pass inserts instructions"),
+        };
+        let loc = instrs[table.pos].1;
+        // [values index] => [values index], having branched away on the hot arms
+        let mut tests = vec![Instr::LocalSet(LocalSet { local: tmp })];
+        for (arm, _) in &hot {
+            let (op, block) = if *arm + 1 == table.arms {
+                (BinaryOp::I32GeU, default)
+            } else {
+                (BinaryOp::I32Eq, targets[*arm])
+            };
+            tests.extend(vec![
+                Instr::LocalGet(LocalGet { local: tmp }),
+                Instr::Const(Const {
+                    value: Value::I32(*arm as i32),
+                }),
+                Instr::Binop(Binop { op }),
+                Instr::BrIf(BrIf { block }),
+            ]);
+        }
+        tests.push(Instr::LocalGet(LocalGet { local: tmp }));
+        instrs.splice(
+            table.pos..table.pos,
+            tests.into_iter().map(|instr| (instr, loc)),
+        );
+        peeled += hot.len();
+    }
+    println!("Peeled {} hot br_table arms", peeled);
+    peeled
+}
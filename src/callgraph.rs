@@ -0,0 +1,172 @@
+use std::collections::{HashMap, HashSet};
+use walrus::FunctionId;
+
+// A set of node indices 0..len, a bit each
+#[derive(Debug, Clone, PartialEq)]
+pub struct BitSet {
+    words: Vec<u64>,
+    len: usize,
+}
+
+impl BitSet {
+    pub fn new(len: usize) -> BitSet {
+        BitSet {
+            words: vec![0; (len + 63) / 64],
+            len,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|word| *word == 0)
+    }
+
+    // Whether `idx` wasn't in the set yet
+    pub fn insert(&mut self, idx: usize) -> bool {
+        let (word, bit) = (idx / 64, 1u64 << (idx % 64));
+        let added = self.words[word] & bit == 0;
+        self.words[word] |= bit;
+        added
+    }
+
+    pub fn contains(&self, idx: usize) -> bool {
+        idx < self.len && self.words[idx / 64] & (1u64 << (idx % 64)) != 0
+    }
+
+    pub fn count(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).filter(move |idx| self.contains(*idx))
+    }
+}
+
+/*
+ * Functions as dense indices with their edges as adjacency lists, for the
+ * analyses that walk calls over the whole module: a pass over it is a walk
+ * of a few vectors instead of hash lookups per edge. Edges to functions
+ * that aren't nodes (say, the imports an analysis leaves out) aren't kept,
+ * the nodes that had any are marked `open`.
+ */
+#[derive(Debug, Clone)]
+pub struct CallGraph {
+    pub funcs: Vec<FunctionId>,
+    pub index: HashMap<FunctionId, usize>,
+    pub succs: Vec<Vec<u32>>,
+    pub open: BitSet,
+}
+
+impl CallGraph {
+    pub fn new(nodes: &[(FunctionId, &HashSet<FunctionId>)]) -> CallGraph {
+        let funcs: Vec<FunctionId> = nodes.iter().map(|(func, _)| *func).collect();
+        let index: HashMap<FunctionId, usize> = funcs
+            .iter()
+            .enumerate()
+            .map(|(idx, func)| (*func, idx))
+            .collect();
+        let mut open = BitSet::new(funcs.len());
+        let succs = nodes
+            .iter()
+            .enumerate()
+            .map(|(node, (_, callees))| {
+                let mut succs: Vec<u32> = callees
+                    .iter()
+                    .filter_map(|callee| match index.get(callee) {
+                        Some(idx) => Some(*idx as u32),
+                        None => {
+                            open.insert(node);
+                            None
+                        }
+                    })
+                    .collect();
+                succs.sort_unstable();
+                succs
+            })
+            .collect();
+        CallGraph {
+            funcs,
+            index,
+            succs,
+            open,
+        }
+    }
+
+    pub fn preds(&self) -> Vec<Vec<u32>> {
+        let mut preds = vec![vec![]; self.funcs.len()];
+        for (node, succs) in self.succs.iter().enumerate() {
+            for succ in succs {
+                preds[*succ as usize].push(node as u32);
+            }
+        }
+        preds
+    }
+
+    // Every node reachable from `roots`, the roots included
+    pub fn reachable(&self, roots: &BitSet) -> BitSet {
+        let mut seen = roots.clone();
+        let mut to_visit: Vec<usize> = roots.iter().collect();
+        while let Some(node) = to_visit.pop() {
+            for succ in &self.succs[node] {
+                if seen.insert(*succ as usize) {
+                    to_visit.push(*succ as usize);
+                }
+            }
+        }
+        seen
+    }
+
+    /*
+     * Raise `values` (one per node) until each is at least every successor's:
+     * the worst a node can reach. A worklist of the raised nodes' preds, so a
+     * node is revisited once each time a successor is raised.
+     */
+    pub fn propagate_max<T: Ord + Copy>(&self, values: &mut [T]) {
+        let preds = self.preds();
+        let mut to_visit: Vec<usize> = (0..self.funcs.len()).collect();
+        while let Some(node) = to_visit.pop() {
+            for pred in &preds[node] {
+                let pred = *pred as usize;
+                if values[pred] < values[node] {
+                    values[pred] = values[node];
+                    to_visit.push(pred);
+                }
+            }
+        }
+    }
+
+    /*
+     * The nodes of `eligible` that only ever lead to other such nodes: the
+     * least fixed point of "eligible, not open, and every successor is in
+     * the set", so a cycle is never in it. Worklist with a count of the
+     * successors still outstanding per node, linear in the edges.
+     */
+    pub fn settled(&self, eligible: &BitSet) -> BitSet {
+        let preds = self.preds();
+        let mut outstanding: Vec<usize> = self.succs.iter().map(|succs| succs.len()).collect();
+        let mut settled = BitSet::new(self.funcs.len());
+        let mut ready: Vec<usize> = eligible
+            .iter()
+            .filter(|node| outstanding[*node] == 0 && !self.open.contains(*node))
+            .collect();
+        while let Some(node) = ready.pop() {
+            if !settled.insert(node) {
+                continue;
+            }
+            for pred in &preds[node] {
+                let pred = *pred as usize;
+                outstanding[pred] -= 1;
+                if outstanding[pred] == 0 && eligible.contains(pred) && !self.open.contains(pred) {
+                    ready.push(pred);
+                }
+            }
+        }
+        settled
+    }
+}
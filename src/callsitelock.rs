@@ -0,0 +1,120 @@
+use crate::callsites::{callsite_keys, enumerate_callsites};
+use crate::Profile;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use walrus::{Module, TableId};
+
+pub const LOCK_VERSION: u32 = 1;
+
+/*
+ * callsites.lock: callsite ids that survive rebuilds. A callsite's id is its
+ * position in the module, so a rebuild that adds or moves a call_indirect
+ * renumbers every callsite after it and profiles merged across builds mix up
+ * callsites. The lock hands each callsite key an id the first time it's seen
+ * and keeps it: instrumented binaries export their slots under the locked
+ * ids, and optimizing maps them back to positions in the module at hand.
+ * Ids of callsites that went away aren't handed out again.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CallsiteLock {
+    pub version: u32,
+    pub next_id: usize,
+    pub ids: BTreeMap<String, usize>,
+}
+
+impl Default for CallsiteLock {
+    fn default() -> CallsiteLock {
+        CallsiteLock {
+            version: LOCK_VERSION,
+            next_id: 0,
+            ids: BTreeMap::new(),
+        }
+    }
+}
+
+impl CallsiteLock {
+    // A lock file that doesn't exist yet is an empty lock
+    pub fn load(path: &str) -> CallsiteLock {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return CallsiteLock::default(),
+            Err(e) => panic!("can't read {}: {}", path, e),
+        };
+        let lock: CallsiteLock = serde_json::from_str(&text).unwrap();
+        if lock.version != LOCK_VERSION {
+            panic!(
+                "{} is a version {} lock file, expected version {}",
+                path, lock.version, LOCK_VERSION
+            );
+        }
+        lock
+    }
+
+    pub fn write(&self, path: &str) {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, json).unwrap();
+    }
+
+    // The id of each key, allocating ids for keys the lock hasn't seen
+    pub fn allocate(&mut self, keys: &[String]) -> Vec<usize> {
+        keys.iter()
+            .map(|key| {
+                if let Some(id) = self.ids.get(key) {
+                    return *id;
+                }
+                let id = self.next_id;
+                self.ids.insert(key.clone(), id);
+                self.next_id += 1;
+                id
+            })
+            .collect()
+    }
+
+    /*
+     * Rekey a profile collected under locked ids by position in the module
+     * whose callsites are `keys`. Callsites the module no longer has are
+     * dropped, and ones the lock doesn't know yet have no data.
+     */
+    pub fn localize(&self, keys: &[String], profile: Profile) -> Profile {
+        let positions: HashMap<usize, usize> = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(position, key)| Some((*self.ids.get(key)?, position)))
+            .collect();
+        let dropped = profile
+            .map
+            .keys()
+            .filter(|id| !positions.contains_key(id))
+            .count();
+        if dropped > 0 {
+            println!(
+                "{} profiled callsites aren't in the module any more, ignoring them",
+                dropped
+            );
+        }
+        Profile {
+            map: profile
+                .map
+                .into_iter()
+                .filter_map(|(id, slots)| Some((*positions.get(&id)?, slots)))
+                .collect(),
+            target_counts: profile
+                .target_counts
+                .into_iter()
+                .filter_map(|(id, counts)| Some((*positions.get(&id)?, counts)))
+                .collect(),
+            ..profile
+        }
+    }
+}
+
+// The keys a module's callsites are locked under, qualified with the module's name in a module set
+pub fn lock_keys(module: &Module, table: Option<TableId>, name: Option<&str>) -> Vec<String> {
+    callsite_keys(module, &enumerate_callsites(module, table))
+        .into_iter()
+        .map(|key| match name {
+            Some(name) => format!("{}:{}", name, key),
+            None => key,
+        })
+        .collect()
+}
@@ -0,0 +1,378 @@
+use crate::schema::MapValue;
+use crate::tableimage::TableImage;
+use crate::typecompat::func_matches;
+use std::collections::{HashMap, HashSet};
+use walrus::ir::*;
+use walrus::*;
+
+// An indirect callsite in the original module
+#[derive(Clone, Copy, Debug)]
+pub struct Callsite {
+    pub func: FunctionId,
+    pub ty: TypeId,
+    // The table the call_indirect goes through
+    pub table: TableId,
+    // Offset of the call_indirect in the original binary
+    pub loc: InstrLocId,
+}
+
+/*
+ * Number every call_indirect in the module, in the same order that the
+ * instrumentation pass assigns callsite ids (profiling_global_{id}_*).
+ * Only valid on the original (uninstrumented) module. The call_indirects
+ * with a constant table index that the pipeline turns into direct calls
+ * before numbering (see constfold) don't count, so `table` must be the one
+ * the pipeline resolves targets in (profilemap::function_table).
+ */
+pub fn enumerate_callsites(module: &Module, table: Option<TableId>) -> Vec<Callsite> {
+    let folded = crate::constfold::foldable_calls(module, table);
+    let mut callsites = all_callsites(module);
+    callsites.retain(|callsite| !folded.contains_key(&(callsite.func, callsite.loc)));
+    callsites
+}
+
+// Every call_indirect in the module, constant table index or not
+fn all_callsites(module: &Module) -> Vec<Callsite> {
+    let mut callsites = vec![];
+    for (id, func) in module.funcs.iter_local() {
+        let mut seqs_to_process: Vec<InstrSeqId> = vec![func.entry_block()];
+        while let Some(current_seq) = seqs_to_process.pop() {
+            for (instr, loc) in &func.block(current_seq).instrs {
+                match instr {
+                    Instr::CallIndirect(call) => callsites.push(Callsite {
+                        func: id,
+                        ty: call.ty,
+                        table: call.table,
+                        loc: *loc,
+                    }),
+                    Instr::Block(b) => seqs_to_process.push(b.seq),
+                    Instr::Loop(l) => seqs_to_process.push(l.seq),
+                    Instr::IfElse(if_else) => {
+                        seqs_to_process.push(if_else.consequent);
+                        seqs_to_process.push(if_else.alternative);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    callsites
+}
+
+// Stable key for a callsite: "{function name}#{nth call_indirect in the function}"
+pub fn callsite_key(func_name: &str, nth: usize) -> String {
+    format!("{}#{}", func_name, nth)
+}
+
+// The key of each callsite returned by enumerate_callsites
+pub fn callsite_keys(module: &Module, callsites: &[Callsite]) -> Vec<String> {
+    let mut nth: HashMap<FunctionId, usize> = HashMap::new();
+    callsites
+        .iter()
+        .map(|c| {
+            let n = nth.entry(c.func).or_insert(0);
+            *n += 1;
+            callsite_key(&crate::report::func_name(module, c.func), *n - 1)
+        })
+        .collect()
+}
+
+/*
+ * The functions that run as the module's entry point: the exported WASI
+ * `_start` and the start section function. The latter runs during
+ * instantiation, after globals are initialized, so its callsites record into
+ * the profiling globals like any other.
+ */
+pub fn entry_functions(module: &Module) -> HashSet<FunctionId> {
+    let mut funcs: HashSet<FunctionId> = module
+        .exports
+        .iter()
+        .filter(|export| export.name == "_start")
+        .filter_map(|export| match export.item {
+            ExportItem::Function(f_id) => Some(f_id),
+            _ => None,
+        })
+        .collect();
+    funcs.extend(module.start);
+    funcs
+}
+
+// Whether any instruction can change what's in `table` at runtime
+fn table_is_mutated(module: &Module, table: TableId) -> bool {
+    module.funcs.iter_local().any(|(_, func)| {
+        crate::selfcheck::instrs(func)
+            .iter()
+            .any(|instr| match instr {
+                Instr::TableSet(t) => t.table == table,
+                Instr::TableGrow(t) => t.table == table,
+                Instr::TableFill(t) => t.table == table,
+                Instr::TableInit(t) => t.table == table,
+                Instr::TableCopy(t) => t.dst == table,
+                _ => false,
+            })
+    })
+}
+
+// Whether the host (or, under dynamic linking, another module) can reach `table` through an export
+pub fn table_is_exported(module: &Module, table: TableId) -> bool {
+    module
+        .exports
+        .iter()
+        .any(|export| matches!(export.item, ExportItem::Table(t) if t == table))
+}
+
+// Whether `table` can hold something other than its element segments at runtime
+pub fn table_is_mutable(module: &Module, table: TableId) -> bool {
+    module.tables.get(table).import.is_some()
+        || table_is_exported(module, table)
+        || table_is_mutated(module, table)
+}
+
+/*
+ * Whether every index of `table` always holds the same function: the table
+ * is private, never written, and only has segments at constant offsets.
+ */
+pub fn table_is_fixed(module: &Module, table: TableId) -> bool {
+    !table_is_mutable(module, table)
+        && module.tables.get(table).elem_segments.iter().all(|elem| {
+            crate::profilemap::segment_offset(module, &module.elements.get(*elem).kind).is_some()
+        })
+}
+
+/*
+ * Callsites through `table` that can only ever reach one function: the
+ * table is private to the module (neither imported nor exported), nothing
+ * writes to it at runtime, all of its segments sit at constant offsets, and
+ * exactly one of its entries has the callsite's type. Returns callsite ==> (table index,
+ * function). Calls to any other index trap, which is all that calling the
+ * function directly changes (see unguarded_callsites).
+ */
+pub fn static_targets(
+    module: &Module,
+    table: Option<TableId>,
+    callsites: &[Callsite],
+) -> HashMap<usize, (i64, FunctionId)> {
+    let table = match table {
+        Some(table) => table,
+        None => return HashMap::new(),
+    };
+    if table_is_mutable(module, table) {
+        return HashMap::new();
+    }
+
+    let image = TableImage::build(module, table);
+    if image.unplaced > 0 {
+        return HashMap::new();
+    }
+    let entries: Vec<(i64, FunctionId)> = image.entries.into_iter().collect();
+
+    let mut targets = HashMap::new();
+    for (idx, callsite) in callsites.iter().enumerate() {
+        if callsite.table != table {
+            continue;
+        }
+        let mut matching = entries
+            .iter()
+            .filter(|(_, func)| func_matches(module, *func, callsite.ty));
+        if let (Some(only), None) = (matching.next(), matching.next()) {
+            targets.insert(idx, *only);
+        }
+    }
+    targets
+}
+
+/*
+ * Order callsites by how much instrumenting them is likely to be worth, for
+ * --max-callsites: first the callsites in functions with the most static
+ * callers (direct calls plus table entries), then those in the largest
+ * functions, then by id. Only looks at the module itself, so every round of
+ * a chunked instrumentation comes up with the same order.
+ */
+pub fn prioritize_callsites(module: &Module, callsites: &[Callsite]) -> Vec<usize> {
+    let mut fan_in: HashMap<FunctionId, usize> = HashMap::new();
+    let mut size: HashMap<FunctionId, usize> = HashMap::new();
+    for (id, func) in module.funcs.iter_local() {
+        let instrs = crate::selfcheck::instrs(func);
+        for instr in &instrs {
+            if let Instr::Call(call) = instr {
+                *fan_in.entry(call.func).or_insert(0) += 1;
+            }
+        }
+        size.insert(id, instrs.len());
+    }
+    for elem in module.elements.iter() {
+        for func in elem.members.iter().flatten() {
+            *fan_in.entry(*func).or_insert(0) += 1;
+        }
+    }
+
+    let mut order: Vec<usize> = (0..callsites.len()).collect();
+    order.sort_by_key(|idx| {
+        let func = callsites[*idx].func;
+        (
+            std::cmp::Reverse(fan_in.get(&func).cloned().unwrap_or(0)),
+            std::cmp::Reverse(size.get(&func).cloned().unwrap_or(0)),
+            *idx,
+        )
+    });
+    order
+}
+
+// The instruction sequences nested directly in `instr`
+fn nested_seqs(instr: &Instr) -> Vec<InstrSeqId> {
+    match instr {
+        Instr::Block(b) => vec![b.seq],
+        Instr::Loop(l) => vec![l.seq],
+        Instr::IfElse(if_else) => vec![if_else.consequent, if_else.alternative],
+        _ => vec![],
+    }
+}
+
+// `seqs` and every sequence nested in them
+fn all_seqs(func: &LocalFunction, mut seqs: Vec<InstrSeqId>) -> Vec<InstrSeqId> {
+    let mut all = vec![];
+    while let Some(seq) = seqs.pop() {
+        for (instr, _) in &func.block(seq).instrs {
+            seqs.extend(nested_seqs(instr));
+        }
+        all.push(seq);
+    }
+    all
+}
+
+fn local_of(instr: &Instr) -> Option<LocalId> {
+    match instr {
+        Instr::LocalGet(get) => Some(get.local),
+        Instr::LocalSet(set) => Some(set.local),
+        Instr::LocalTee(tee) => Some(tee.local),
+        _ => None,
+    }
+}
+
+// The i32 `instr` pushes if it's a constant: an i32.const, or a read of an immutable global holding one
+fn i32_const(module: &Module, instr: &Instr) -> Option<i32> {
+    match instr {
+        Instr::Const(Const {
+            value: Value::I32(x),
+        }) => Some(*x),
+        Instr::GlobalGet(get) => match &module.globals.get(get.global) {
+            Global {
+                mutable: false,
+                kind: GlobalKind::Local(InitExpr::Value(Value::I32(x))),
+                ..
+            } => Some(*x),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/*
+ * Locals that hold the same i32 whenever they are read: the entry block
+ * sets them to a constant before anything else in the function touches
+ * them (nothing can branch back above an instruction of the entry block),
+ * and every other write stores that same constant.
+ */
+fn constant_locals(module: &Module, func: &LocalFunction) -> HashMap<LocalId, i32> {
+    let mut constants = HashMap::new();
+    let mut seen: HashSet<LocalId> = HashSet::new();
+    let mut prev = None;
+    for (instr, _) in &func.block(func.entry_block()).instrs {
+        if let (Instr::LocalSet(set), Some(x)) = (instr, prev) {
+            if !seen.contains(&set.local) {
+                constants.insert(set.local, x);
+            }
+        }
+        seen.extend(local_of(instr));
+        for seq in all_seqs(func, nested_seqs(instr)) {
+            seen.extend(
+                func.block(seq)
+                    .instrs
+                    .iter()
+                    .filter_map(|(i, _)| local_of(i)),
+            );
+        }
+        prev = i32_const(module, instr);
+    }
+    for seq in all_seqs(func, vec![func.entry_block()]) {
+        let mut prev = None;
+        for (instr, _) in &func.block(seq).instrs {
+            let written = match instr {
+                Instr::LocalSet(set) => Some(set.local),
+                Instr::LocalTee(tee) => Some(tee.local),
+                _ => None,
+            };
+            if let Some(local) = written {
+                if constants.get(&local).map_or(false, |x| prev != Some(*x)) {
+                    constants.remove(&local);
+                }
+            }
+            prev = i32_const(module, instr);
+        }
+    }
+    constants
+}
+
+/*
+ * Callsites whose table index is a constant: an i32.const, an immutable
+ * global holding one, or a local that always holds one (see
+ * constant_locals), right before the call_indirect. Returns each of them
+ * with its table index.
+ */
+pub fn constant_selectors(module: &Module) -> Vec<(Callsite, i64)> {
+    let mut selectors = vec![];
+    for (id, func) in module.funcs.iter_local() {
+        let locals = constant_locals(module, func);
+        let mut seqs_to_process: Vec<InstrSeqId> = vec![func.entry_block()];
+        while let Some(current_seq) = seqs_to_process.pop() {
+            let mut prev = None;
+            for (instr, loc) in &func.block(current_seq).instrs {
+                if let (Instr::CallIndirect(call), Some(x)) = (instr, prev) {
+                    let callsite = Callsite {
+                        func: id,
+                        ty: call.ty,
+                        table: call.table,
+                        loc: *loc,
+                    };
+                    // Table indices are unsigned
+                    selectors.push((callsite, x as u32 as i64));
+                }
+                seqs_to_process.extend(nested_seqs(instr));
+                prev = match instr {
+                    Instr::LocalGet(get) => locals.get(&get.local).cloned(),
+                    _ => i32_const(module, instr),
+                };
+            }
+        }
+    }
+    selectors
+}
+
+/*
+ * Devirtualized callsites that can do without the guard and call their
+ * target directly: the table is fixed (see table_is_fixed) and the target
+ * is the only table entry with the callsite's type (static_targets). A call
+ * through any other index traps in the call_indirect, so only a program
+ * that was going to trap anyway behaves differently. Callsites with a
+ * constant table index never get this far, see constfold.
+ */
+pub fn unguarded_callsites(
+    module: &Module,
+    table: Option<TableId>,
+    static_targets: &HashMap<usize, (i64, FunctionId)>,
+    devirtualized: &HashMap<usize, MapValue>,
+) -> HashSet<usize> {
+    if !table.map_or(false, |table| table_is_fixed(module, table)) {
+        return HashSet::new();
+    }
+    devirtualized
+        .iter()
+        .filter(|(idx, val)| match &val.f_id {
+            Some(targets) if targets.len() == 1 => static_targets
+                .get(idx)
+                .map_or(false, |(_, func)| *func == targets[0]),
+            _ => false,
+        })
+        .map(|(idx, _)| *idx)
+        .collect()
+}
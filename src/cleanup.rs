@@ -0,0 +1,29 @@
+use crate::reachability::{reachable_functions, ALL_ROOTS};
+use std::collections::HashSet;
+use walrus::*;
+
+/*
+ * Optimize mode's last pass: delete the functions this run added (guard
+ * stubs, specialized clones, split off cold blocks) that nothing ends up
+ * calling, e.g. the stub of a retained callsite, or of a callsite the
+ * profile mentions but the module doesn't have. Functions that were in the
+ * input always stay, as do exports, the start function and table entries,
+ * and an added function is live as soon as a live function refers to it
+ * (see reachability::call_graph). Returns how many functions were deleted.
+ */
+pub fn remove_unused_functions(module: &mut Module, input: &HashSet<FunctionId>) -> usize {
+    let live = reachable_functions(module, ALL_ROOTS, input);
+    let unused: Vec<FunctionId> = module
+        .funcs
+        .iter()
+        .map(|func| func.id())
+        .filter(|id| !live.contains(id))
+        .collect();
+    for id in &unused {
+        module.funcs.delete(*id);
+    }
+    if !unused.is_empty() {
+        println!("Removed {} unused generated functions", unused.len());
+    }
+    unused.len()
+}
@@ -0,0 +1,341 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use walrus::ir::*;
+use walrus::*;
+
+// Don't bother outlining regions smaller than this (the call itself costs a few instrs)
+const MIN_OUTLINE_SIZE: usize = 8;
+
+// Everything we need to know about a candidate region before outlining it
+struct Region {
+    seqs: HashSet<InstrSeqId>,
+    branch_targets: Vec<InstrSeqId>,
+    size: usize,
+    has_return: bool,
+}
+
+fn scan_region(func: &LocalFunction, seq: InstrSeqId, region: &mut Region) {
+    region.seqs.insert(seq);
+    for (instr, _) in &func.block(seq).instrs {
+        region.size += 1;
+        match instr {
+            Instr::Block(b) => scan_region(func, b.seq, region),
+            Instr::Loop(l) => scan_region(func, l.seq, region),
+            Instr::IfElse(if_else) => {
+                scan_region(func, if_else.consequent, region);
+                scan_region(func, if_else.alternative, region);
+            }
+            Instr::Br(br) => region.branch_targets.push(br.block),
+            Instr::BrIf(br) => region.branch_targets.push(br.block),
+            Instr::BrTable(br) => {
+                region.branch_targets.extend(br.blocks.iter());
+                region.branch_targets.push(br.default);
+            }
+            Instr::Return(_) => region.has_return = true,
+            _ => (),
+        }
+    }
+}
+
+/*
+ * A cold region can be outlined if control never falls out of it:
+ * 1) It ends in `unreachable` (panics, error paths)
+ * 2) It never branches to a block outside of itself (or to itself)
+ * 3) It never returns from the enclosing function
+ * 4) It doesn't take stack parameters (MVP block type)
+ *
+ * Since control never leaves the region, no locals written inside of it are
+ * live afterwards, so we only have to thread live-in locals through as params.
+ */
+fn can_outline(func: &LocalFunction, seq: InstrSeqId) -> bool {
+    let block = func.block(seq);
+    match block.ty {
+        InstrSeqType::Simple(_) => (),
+        _ => return false,
+    }
+    match block.instrs.last() {
+        Some((Instr::Unreachable(_), _)) => (),
+        _ => return false,
+    }
+    let mut region = Region {
+        seqs: HashSet::new(),
+        branch_targets: vec![],
+        size: 0,
+        has_return: false,
+    };
+    scan_region(func, seq, &mut region);
+    if region.has_return || region.size < MIN_OUTLINE_SIZE {
+        return false;
+    }
+    region
+        .branch_targets
+        .iter()
+        .all(|target| *target != seq && region.seqs.contains(target))
+}
+
+// Collect every local referenced in the region, in order of first use
+pub fn region_locals(func: &LocalFunction, seq: InstrSeqId, locals: &mut Vec<LocalId>) {
+    for (instr, _) in &func.block(seq).instrs {
+        let local = match instr {
+            Instr::LocalGet(l) => Some(l.local),
+            Instr::LocalSet(l) => Some(l.local),
+            Instr::LocalTee(l) => Some(l.local),
+            Instr::Block(b) => {
+                region_locals(func, b.seq, locals);
+                None
+            }
+            Instr::Loop(l) => {
+                region_locals(func, l.seq, locals);
+                None
+            }
+            Instr::IfElse(if_else) => {
+                region_locals(func, if_else.consequent, locals);
+                region_locals(func, if_else.alternative, locals);
+                None
+            }
+            _ => None,
+        };
+        match local {
+            Some(l) if !locals.contains(&l) => locals.push(l),
+            _ => (),
+        }
+    }
+}
+
+/*
+ * Live-in analysis for the region: a local is live-in unless it is
+ * unconditionally written (at the top level of the region) before any read.
+ * Reads nested inside of blocks/loops/ifs that come before the write still
+ * count as uses, so this is a conservative over-approximation.
+ */
+fn live_in_locals(func: &LocalFunction, seq: InstrSeqId) -> Vec<LocalId> {
+    let mut defined: HashSet<LocalId> = HashSet::new();
+    let mut live_in: Vec<LocalId> = vec![];
+    for (instr, _) in &func.block(seq).instrs {
+        let mut used = vec![];
+        match instr {
+            Instr::LocalGet(l) => used.push(l.local),
+            Instr::LocalSet(l) => {
+                defined.insert(l.local);
+            }
+            Instr::LocalTee(l) => {
+                defined.insert(l.local);
+            }
+            // Everything read inside of a nested sequence is a potential use
+            Instr::Block(b) => nested_reads(func, b.seq, &mut used),
+            Instr::Loop(l) => nested_reads(func, l.seq, &mut used),
+            Instr::IfElse(if_else) => {
+                nested_reads(func, if_else.consequent, &mut used);
+                nested_reads(func, if_else.alternative, &mut used);
+            }
+            _ => (),
+        }
+        for l in used {
+            if !defined.contains(&l) && !live_in.contains(&l) {
+                live_in.push(l);
+            }
+        }
+    }
+    live_in
+}
+
+fn nested_reads(func: &LocalFunction, seq: InstrSeqId, reads: &mut Vec<LocalId>) {
+    for (instr, _) in &func.block(seq).instrs {
+        match instr {
+            Instr::LocalGet(l) => reads.push(l.local),
+            Instr::Block(b) => nested_reads(func, b.seq, reads),
+            Instr::Loop(l) => nested_reads(func, l.seq, reads),
+            Instr::IfElse(if_else) => {
+                nested_reads(func, if_else.consequent, reads);
+                nested_reads(func, if_else.alternative, reads);
+            }
+            _ => (),
+        }
+    }
+}
+
+// Deep copy a sequence from `func` into the builder, remapping locals + labels
+pub fn copy_seq(
+    func: &LocalFunction,
+    from: InstrSeqId,
+    builder: &mut FunctionBuilder,
+    to: InstrSeqId,
+    seq_map: &mut HashMap<InstrSeqId, InstrSeqId>,
+    local_map: &HashMap<LocalId, LocalId>,
+) {
+    seq_map.insert(from, to);
+    for (instr, _) in &func.block(from).instrs {
+        let new_instr = match instr {
+            Instr::Block(b) => {
+                let new_seq = builder.dangling_instr_seq(func.block(b.seq).ty).id();
+                copy_seq(func, b.seq, builder, new_seq, seq_map, local_map);
+                Instr::Block(Block { seq: new_seq })
+            }
+            Instr::Loop(l) => {
+                let new_seq = builder.dangling_instr_seq(func.block(l.seq).ty).id();
+                copy_seq(func, l.seq, builder, new_seq, seq_map, local_map);
+                Instr::Loop(Loop { seq: new_seq })
+            }
+            Instr::IfElse(if_else) => {
+                let consequent = builder
+                    .dangling_instr_seq(func.block(if_else.consequent).ty)
+                    .id();
+                copy_seq(
+                    func,
+                    if_else.consequent,
+                    builder,
+                    consequent,
+                    seq_map,
+                    local_map,
+                );
+                let alternative = builder
+                    .dangling_instr_seq(func.block(if_else.alternative).ty)
+                    .id();
+                copy_seq(
+                    func,
+                    if_else.alternative,
+                    builder,
+                    alternative,
+                    seq_map,
+                    local_map,
+                );
+                Instr::IfElse(IfElse {
+                    consequent,
+                    alternative,
+                })
+            }
+            Instr::Br(br) => Instr::Br(Br {
+                block: seq_map[&br.block],
+            }),
+            Instr::BrIf(br) => Instr::BrIf(BrIf {
+                block: seq_map[&br.block],
+            }),
+            Instr::BrTable(br) => Instr::BrTable(BrTable {
+                blocks: br.blocks.iter().map(|b| seq_map[b]).collect(),
+                default: seq_map[&br.default],
+            }),
+            Instr::LocalGet(l) => Instr::LocalGet(LocalGet {
+                local: local_map[&l.local],
+            }),
+            Instr::LocalSet(l) => Instr::LocalSet(LocalSet {
+                local: local_map[&l.local],
+            }),
+            Instr::LocalTee(l) => Instr::LocalTee(LocalTee {
+                local: local_map[&l.local],
+            }),
+            _ => instr.clone(),
+        };
+        builder.instr_seq(to).instr(new_instr);
+    }
+}
+
+// Move the contents of `seq` into a new function, returning the new id + live-in args
+fn outline(
+    module: &mut Module,
+    f_id: FunctionId,
+    seq: InstrSeqId,
+    ctr: usize,
+) -> (FunctionId, Vec<LocalId>) {
+    let func = module.funcs.get(f_id).kind.unwrap_local();
+    let live_in = live_in_locals(func, seq);
+    let mut all_locals = vec![];
+    region_locals(func, seq, &mut all_locals);
+
+    let params: Vec<ValType> = live_in.iter().map(|l| module.locals.get(*l).ty()).collect();
+    let mut builder = FunctionBuilder::new(&mut module.types, &params, &[]);
+    builder.name(format!("cold_outline_{}", ctr));
+
+    // Live-in locals become params, everything else gets a fresh local
+    let mut local_map = HashMap::new();
+    let mut args = vec![];
+    for l in &all_locals {
+        let new_local = module.locals.add(module.locals.get(*l).ty());
+        local_map.insert(*l, new_local);
+    }
+    for l in &live_in {
+        args.push(local_map[l]);
+    }
+
+    let mut seq_map = HashMap::new();
+    let body = builder.func_body_id();
+    copy_seq(func, seq, &mut builder, body, &mut seq_map, &local_map);
+
+    (builder.finish(args, &mut module.funcs), live_in)
+}
+
+/*
+ * Outline cold blocks of hot functions into separate functions, using the
+ * block counters from a profiling run. A function is hot if its entry block
+ * was executed at least `hot_threshold` times, and a block is cold if its
+ * counter is exactly 0. Blocks without profile data are left alone.
+ */
+pub fn split_cold_blocks(
+    module: &mut Module,
+    blocks: &[(FunctionId, InstrSeqId)],
+    counts: &HashMap<usize, i32>,
+    hot_threshold: i32,
+) -> usize {
+    let mut block_counts: HashMap<(FunctionId, InstrSeqId), i32> = HashMap::new();
+    for (idx, block) in blocks.iter().enumerate() {
+        if let Some(count) = counts.get(&idx) {
+            block_counts.insert(*block, *count);
+        }
+    }
+
+    let mut funcs: Vec<FunctionId> = blocks.iter().map(|(f, _)| *f).collect();
+    funcs.dedup();
+
+    let mut outlined = 0;
+    for f_id in funcs {
+        let entry = module.funcs.get(f_id).kind.unwrap_local().entry_block();
+        match block_counts.get(&(f_id, entry)) {
+            Some(count) if *count >= hot_threshold => (),
+            _ => continue,
+        }
+
+        // Walk top-down so we outline the largest cold region first
+        let mut to_outline = vec![];
+        let mut seqs_to_process = vec![entry];
+        let func = module.funcs.get(f_id).kind.unwrap_local();
+        while let Some(current_seq) = seqs_to_process.pop() {
+            if current_seq != entry
+                && block_counts.get(&(f_id, current_seq)) == Some(&0)
+                && can_outline(func, current_seq)
+            {
+                to_outline.push(current_seq);
+                continue;
+            }
+            for (instr, _) in &func.block(current_seq).instrs {
+                match instr {
+                    Instr::Block(b) => seqs_to_process.push(b.seq),
+                    Instr::Loop(l) => seqs_to_process.push(l.seq),
+                    Instr::IfElse(if_else) => {
+                        seqs_to_process.push(if_else.consequent);
+                        seqs_to_process.push(if_else.alternative);
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        for seq in to_outline {
+            let (new_id, live_in) = outline(module, f_id, seq, outlined);
+            // Replace the cold region with a call to the outlined function
+            let func = module.funcs.get_mut(f_id).kind.unwrap_local_mut();
+            let mut instrs = vec![];
+            for l in live_in {
+                instrs.push((
+                    Instr::LocalGet(LocalGet { local: l }),
+                    InstrLocId::default(),
+                ));
+            }
+            instrs.push((Instr::Call(Call { func: new_id }), InstrLocId::default()));
+            instrs.push((Instr::Unreachable(Unreachable {}), InstrLocId::default()));
+            func.block_mut(seq).instrs = instrs;
+            outlined += 1;
+        }
+    }
+
+    println!("Outlined {} cold blocks", outlined);
+    outlined
+}
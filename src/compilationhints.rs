@@ -0,0 +1,136 @@
+use crate::costs::entry_counts;
+use crate::indexmap::emitted_indices;
+use crate::manifest::leb128;
+use crate::Profile;
+use std::collections::HashMap;
+use walrus::*;
+use wasmparser::BinaryReader;
+
+// The compilation-hints proposal's per-function priority section
+pub const COMPILATION_PRIORITY_SECTION: &str = "metadata.code.compilation_priority";
+// Lower is sooner; PRIORITY_NEVER as the optimization priority means don't optimize
+const PRIORITY_FIRST: u32 = 0;
+const PRIORITY_DEFAULT: u32 = 1;
+const PRIORITY_NEVER: u32 = 127;
+
+// How the profile saw a function run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Hotness {
+    // Called at least --hot-threshold times: compile eagerly, optimize right away
+    Hot,
+    // Called, but less often: compile on first call, tier up as usual
+    Lazy,
+    // Never called: compile on first call, and don't bother optimizing
+    Cold,
+}
+
+impl Hotness {
+    // (compilation priority, optimization priority) as the hint's payload carries them
+    fn priorities(self) -> (u32, Option<u32>) {
+        match self {
+            Hotness::Hot => (PRIORITY_FIRST, Some(PRIORITY_FIRST)),
+            Hotness::Lazy => (PRIORITY_DEFAULT, None),
+            Hotness::Cold => (PRIORITY_NEVER, Some(PRIORITY_NEVER)),
+        }
+    }
+}
+
+/*
+ * The hotness of each of `module`'s local functions by its entry count,
+ * for the compilation hints. Needs a profile collected with
+ * --block-counters; functions the profile has no count for get no hint.
+ */
+pub fn function_hotness(
+    module: &Module,
+    profile: &Profile,
+    hot_threshold: i32,
+) -> HashMap<FunctionId, Hotness> {
+    if profile.blocks.is_empty() {
+        println!("no block counts in the profile (collect it with --block-counters), emitting no compilation hints");
+    }
+    entry_counts(module, profile)
+        .into_iter()
+        .map(|(func, calls)| {
+            let hotness = match calls {
+                0 => Hotness::Cold,
+                calls if calls >= hot_threshold => Hotness::Hot,
+                _ => Hotness::Lazy,
+            };
+            (func, hotness)
+        })
+        .collect()
+}
+
+/*
+ * The COMPILATION_PRIORITY_SECTION for `module` as it is about to be
+ * emitted. Like the other code metadata sections it lists functions by
+ * index, in increasing order, each with one hint at byte offset 0 (the
+ * function as a whole) whose payload is the compilation priority and,
+ * optionally, the optimization priority.
+ */
+pub fn compilation_hints_section(
+    module: &Module,
+    hotness: &HashMap<FunctionId, Hotness>,
+) -> Vec<u8> {
+    let indices = emitted_indices(module);
+    let mut funcs: Vec<(u32, Hotness)> = hotness
+        .iter()
+        .filter_map(|(func, hotness)| Some((*indices.get(func)?, *hotness)))
+        .collect();
+    funcs.sort_by_key(|(idx, _)| *idx);
+
+    let mut contents = vec![];
+    leb128(&mut contents, COMPILATION_PRIORITY_SECTION.len() as u32);
+    contents.extend_from_slice(COMPILATION_PRIORITY_SECTION.as_bytes());
+    leb128(&mut contents, funcs.len() as u32);
+    for (idx, hotness) in &funcs {
+        let (compilation, optimization) = hotness.priorities();
+        let mut payload = vec![];
+        leb128(&mut payload, compilation);
+        if let Some(optimization) = optimization {
+            leb128(&mut payload, optimization);
+        }
+        leb128(&mut contents, *idx);
+        // One hint, at offset 0
+        leb128(&mut contents, 1);
+        leb128(&mut contents, 0);
+        leb128(&mut contents, payload.len() as u32);
+        contents.extend(payload);
+    }
+    let count = |hot: Hotness| funcs.iter().filter(|(_, h)| *h == hot).count();
+    println!(
+        "Compilation hints: {} hot, {} lazy and {} cold functions",
+        count(Hotness::Hot),
+        count(Hotness::Lazy),
+        count(Hotness::Cold)
+    );
+
+    let mut section = vec![0];
+    leb128(&mut section, contents.len() as u32);
+    section.extend(contents);
+    section
+}
+
+/*
+ * Splice a custom section into an emitted binary just before its code
+ * section: engines read code metadata while compiling, so they only act on
+ * the sections that come first, and walrus emits custom sections last.
+ * Without a code section it goes at the end.
+ */
+pub fn insert_before_code(wasm: &mut Vec<u8>, section: &[u8]) {
+    let mut reader = BinaryReader::new(&wasm[..], 0);
+    // Magic and version
+    reader.read_bytes(8).unwrap();
+    let mut at = wasm.len();
+    while !reader.eof() {
+        let start = reader.original_position();
+        let id = reader.read_u8().unwrap();
+        if id == 10 {
+            at = start;
+            break;
+        }
+        let size = reader.read_var_u32().unwrap();
+        reader.read_bytes(size as usize).unwrap();
+    }
+    wasm.splice(at..at, section.iter().cloned());
+}
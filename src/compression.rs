@@ -0,0 +1,76 @@
+use std::io::Read;
+use std::io::Write;
+
+// Optional compression wrapped around an encoded profile file
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Gzip(u32),
+    Zstd(i32),
+}
+
+pub const COMPRESSIONS: &[&str] = &["none", "gzip", "zstd"];
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+impl Compression {
+    pub fn from_args(name: &str, level: Option<&str>) -> Compression {
+        let level = level.map(|l| {
+            l.parse::<i32>()
+                .expect("--compress-level must be an integer")
+        });
+        match name {
+            "none" => Compression::None,
+            "gzip" => {
+                let level = level.unwrap_or(6);
+                if !(0..=9).contains(&level) {
+                    panic!("gzip compression level must be in 0..=9, got {}", level);
+                }
+                Compression::Gzip(level as u32)
+            }
+            "zstd" => {
+                let level = level.unwrap_or(3);
+                if !(1..=22).contains(&level) {
+                    panic!("zstd compression level must be in 1..=22, got {}", level);
+                }
+                Compression::Zstd(level)
+            }
+            _ => panic!("unknown compression: {}", name),
+        }
+    }
+}
+
+pub fn compress(buf: Vec<u8>, compression: Compression) -> Vec<u8> {
+    match compression {
+        Compression::None => buf,
+        Compression::Gzip(level) => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(vec![], flate2::Compression::new(level));
+            encoder.write_all(&buf).unwrap();
+            encoder.finish().unwrap()
+        }
+        Compression::Zstd(level) => zstd::stream::encode_all(&buf[..], level).unwrap(),
+    }
+}
+
+/*
+ * Profiles are decompressed based on their leading magic bytes, so readers
+ * never need to be told how a file was written. None of our encodings can
+ * start with these bytes: msgpack/cbor profiles start with a map header and
+ * the text formats with printable characters. A truncated or corrupt
+ * stream is an error, not a panic: the aggregator gets these off the network.
+ */
+pub fn decompress(buf: Vec<u8>) -> Result<Vec<u8>, String> {
+    if buf.starts_with(GZIP_MAGIC) {
+        let mut out = vec![];
+        flate2::read::GzDecoder::new(&buf[..])
+            .read_to_end(&mut out)
+            .map_err(|e| format!("corrupt gzip stream: {}", e))?;
+        Ok(out)
+    } else if buf.starts_with(ZSTD_MAGIC) {
+        zstd::stream::decode_all(&buf[..]).map_err(|e| format!("corrupt zstd stream: {}", e))
+    } else {
+        Ok(buf)
+    }
+}
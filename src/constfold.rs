@@ -0,0 +1,94 @@
+use crate::callsites::{constant_selectors, table_is_fixed};
+use crate::profilemap::resolve_in_table;
+use crate::typecompat::func_matches;
+use std::collections::BTreeMap;
+use walrus::ir::*;
+use walrus::*;
+
+/*
+ * The call_indirects whose table index is known statically (see
+ * callsites::constant_selectors), by function and location, with the function they
+ * always call. Only calls through `table`, and only when it is fixed, so
+ * the index always reaches the same function; an index that would trap (nothing there, or another
+ * type) doesn't count.
+ */
+pub fn foldable_calls(
+    module: &Module,
+    table: Option<TableId>,
+) -> BTreeMap<(FunctionId, InstrLocId), FunctionId> {
+    // InstrLocId has no Hash
+    let mut targets = BTreeMap::new();
+    let table = match table {
+        Some(table) if table_is_fixed(module, table) => table,
+        _ => return targets,
+    };
+    // An index into another table says nothing about what's in this one
+    for (callsite, slot) in constant_selectors(module)
+        .into_iter()
+        .filter(|(callsite, _)| callsite.table == table)
+    {
+        if let Some(func) = resolve_in_table(module, table, slot) {
+            if func_matches(module, func, callsite.ty) {
+                targets.insert((callsite.func, callsite.loc), func);
+            }
+        }
+    }
+    targets
+}
+
+/*
+ * Turn the foldable_calls into direct calls, dropping the table index. Runs
+ * before anything else in both modes, so the folded callsites never get a
+ * callsite id and never show up in the profile (enumerate_callsites skips
+ * them as well, for the tools that look at the original module). Returns
+ * the folded callsites.
+ */
+pub fn fold_constant_selectors(
+    module: &mut Module,
+    table: Option<TableId>,
+) -> BTreeMap<(FunctionId, InstrLocId), FunctionId> {
+    let targets = foldable_calls(module, table);
+    if targets.is_empty() {
+        return targets;
+    }
+    fold_calls(module, &targets);
+    println!(
+        "Folded {} call_indirects with a constant table index into direct calls",
+        targets.len()
+    );
+    targets
+}
+
+// Rewrite `targets` (see foldable_calls) into direct calls
+pub fn fold_calls(module: &mut Module, targets: &BTreeMap<(FunctionId, InstrLocId), FunctionId>) {
+    for (id, func) in module.funcs.iter_local_mut() {
+        let mut points: Vec<(InstrSeqId, usize, FunctionId)> = vec![];
+        let mut seqs_to_process: Vec<InstrSeqId> = vec![func.entry_block()];
+        while let Some(current_seq) = seqs_to_process.pop() {
+            for (pos, (instr, loc)) in func.block(current_seq).instrs.iter().enumerate() {
+                match instr {
+                    Instr::CallIndirect(_) => {
+                        if let Some(target) = targets.get(&(id, *loc)) {
+                            points.push((current_seq, pos, *target));
+                        }
+                    }
+                    Instr::Block(b) => seqs_to_process.push(b.seq),
+                    Instr::Loop(l) => seqs_to_process.push(l.seq),
+                    Instr::IfElse(if_else) => {
+                        seqs_to_process.push(if_else.consequent);
+                        seqs_to_process.push(if_else.alternative);
+                    }
+                    _ => (),
+                }
+            }
+        }
+        // Back to front, so each drop goes in after the later points of its sequence are done
+        for (seq, pos, target) in points.into_iter().rev() {
+            let instrs = &mut func.block_mut(seq).instrs;
+            // Both keep the call_indirect's offset, for symbolizing
+            let loc = instrs[pos].1;
+            instrs[pos] = (Call { func: target }.into(), loc);
+            instrs.insert(pos, (Drop {}.into(), loc));
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use crate::manifest::{ContextTreeLayout, Manifest};
+use crate::report::func_name;
+use crate::selfcheck::INSTRUMENT_STUB_PREFIX;
+use crate::symbolize::display_key;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use walrus::ir::*;
+use walrus::*;
+
+pub const CONTEXT_TREE_VERSION: u32 = 1;
+// version, capacity, nodes used, entries that found the table full
+const HEADER_BYTES: u32 = 16;
+const NODES_OFFSET: u32 = 8;
+const OVERFLOW_OFFSET: u32 = 12;
+// parent node, function, callsite (-1 for a direct call), calls
+const NODE_BYTES: u32 = 16;
+pub const CONTEXT_ENTER_STUB_NAME: &str = "context_enter_stub";
+
+/*
+ * Find or add the child of the current context for (function, callsite)
+ * and make it current. Nodes live in an open-addressed hash table, node n
+ * at byte n * 16 (the header takes node 0, the root), so a node's id is
+ * its slot + 1 and an empty slot is one whose call count is still 0.
+ */
+fn add_enter_stub(
+    module: &mut Module,
+    memory: MemoryId,
+    capacity: u32,
+    context: GlobalId,
+    pending: GlobalId,
+) -> FunctionId {
+    let label = module.locals.add(ValType::I32);
+    let callsite = module.locals.add(ValType::I32);
+    let slot = module.locals.add(ValType::I32);
+    let addr = module.locals.add(ValType::I32);
+    let probes = module.locals.add(ValType::I32);
+    let count = module.locals.add(ValType::I32);
+    let mask = (capacity - 1) as i32;
+    let load = LoadKind::I32 { atomic: false };
+    let store = StoreKind::I32 { atomic: false };
+    let arg = |offset| MemArg { align: 4, offset };
+
+    let mut stub = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    stub.name(CONTEXT_ENTER_STUB_NAME.to_string());
+    let mut body = stub.func_body();
+    body.global_get(pending)
+        .local_set(callsite)
+        .i32_const(-1)
+        .global_set(pending);
+    // slot = (h ^ (h >> 15)) & mask, h mixing the whole key
+    body.global_get(context)
+        .i32_const(0x9E3779B1u32 as i32)
+        .binop(BinaryOp::I32Mul)
+        .local_get(label)
+        .i32_const(0x85EBCA6Bu32 as i32)
+        .binop(BinaryOp::I32Mul)
+        .binop(BinaryOp::I32Add)
+        .local_get(callsite)
+        .i32_const(0xC2B2AE35u32 as i32)
+        .binop(BinaryOp::I32Mul)
+        .binop(BinaryOp::I32Add)
+        .local_tee(slot)
+        .local_get(slot)
+        .i32_const(15)
+        .binop(BinaryOp::I32ShrU)
+        .binop(BinaryOp::I32Xor)
+        .i32_const(mask)
+        .binop(BinaryOp::I32And)
+        .local_set(slot)
+        .i32_const(capacity as i32)
+        .local_set(probes);
+    body.block(None, |done| {
+        let done_id = done.id();
+        done.loop_(None, |probe| {
+            let probe_id = probe.id();
+            probe
+                .local_get(slot)
+                .i32_const(1)
+                .binop(BinaryOp::I32Add)
+                .i32_const(4)
+                .binop(BinaryOp::I32Shl)
+                .local_tee(addr)
+                .load(memory, load, arg(12))
+                .local_tee(count)
+                .unop(UnaryOp::I32Eqz)
+                .if_else(
+                    None,
+                    |claim| {
+                        claim
+                            .local_get(addr)
+                            .global_get(context)
+                            .store(memory, store, arg(0))
+                            .local_get(addr)
+                            .local_get(label)
+                            .store(memory, store, arg(4))
+                            .local_get(addr)
+                            .local_get(callsite)
+                            .store(memory, store, arg(8))
+                            .local_get(addr)
+                            .i32_const(1)
+                            .store(memory, store, arg(12))
+                            .i32_const(0)
+                            .i32_const(0)
+                            .load(memory, load, arg(NODES_OFFSET))
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Add)
+                            .store(memory, store, arg(NODES_OFFSET))
+                            .local_get(slot)
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Add)
+                            .global_set(context)
+                            .br(done_id);
+                    },
+                    |_| {},
+                );
+            probe
+                .local_get(addr)
+                .load(memory, load, arg(0))
+                .global_get(context)
+                .binop(BinaryOp::I32Eq)
+                .local_get(addr)
+                .load(memory, load, arg(4))
+                .local_get(label)
+                .binop(BinaryOp::I32Eq)
+                .binop(BinaryOp::I32And)
+                .local_get(addr)
+                .load(memory, load, arg(8))
+                .local_get(callsite)
+                .binop(BinaryOp::I32Eq)
+                .binop(BinaryOp::I32And)
+                .if_else(
+                    None,
+                    |found| {
+                        // calls += 1, stopping at u32::MAX so the slot never looks empty
+                        found
+                            .local_get(addr)
+                            .local_get(count)
+                            .local_get(count)
+                            .i32_const(-1)
+                            .binop(BinaryOp::I32Ne)
+                            .binop(BinaryOp::I32Add)
+                            .store(memory, store, arg(12))
+                            .local_get(slot)
+                            .i32_const(1)
+                            .binop(BinaryOp::I32Add)
+                            .global_set(context)
+                            .br(done_id);
+                    },
+                    |_| {},
+                );
+            probe
+                .local_get(slot)
+                .i32_const(1)
+                .binop(BinaryOp::I32Add)
+                .i32_const(mask)
+                .binop(BinaryOp::I32And)
+                .local_set(slot)
+                .local_get(probes)
+                .i32_const(1)
+                .binop(BinaryOp::I32Sub)
+                .local_tee(probes)
+                .br_if(probe_id);
+        });
+        // The table is full: stay in the caller's context
+        done.i32_const(0)
+            .i32_const(0)
+            .load(memory, load, arg(OVERFLOW_OFFSET))
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .store(memory, store, arg(OVERFLOW_OFFSET));
+    });
+    stub.finish(vec![label], &mut module.funcs)
+}
+
+// Every instruction sequence of `func` reachable from `seq`
+fn seqs(func: &LocalFunction, seq: InstrSeqId, out: &mut Vec<InstrSeqId>) {
+    out.push(seq);
+    for (instr, _) in &func.block(seq).instrs {
+        match instr {
+            Instr::Block(b) => seqs(func, b.seq, out),
+            Instr::Loop(l) => seqs(func, l.seq, out),
+            Instr::IfElse(if_else) => {
+                seqs(func, if_else.consequent, out);
+                seqs(func, if_else.alternative, out);
+            }
+            _ => (),
+        }
+    }
+}
+
+/*
+ * Wrap the body of `func` in a block that restores the caller's context on
+ * the way out: `return` and branches to the function's own label become
+ * branches to the end of the wrapper, after which the saved context is put
+ * back.
+ */
+fn wrap_body(
+    module: &mut Module,
+    func: FunctionId,
+    label: u32,
+    context: GlobalId,
+    enter: FunctionId,
+) {
+    let saved = module.locals.add(ValType::I32);
+    let results = module
+        .types
+        .get(module.funcs.get(func).ty())
+        .results()
+        .to_vec();
+    let ty = InstrSeqType::new(&mut module.types, &[], &results);
+    let local = module.funcs.get_mut(func).kind.unwrap_local_mut();
+    let entry = local.entry_block();
+    let wrapper = local.builder_mut().dangling_instr_seq(ty).id();
+    let body = std::mem::take(&mut local.block_mut(entry).instrs);
+    local.block_mut(wrapper).instrs = body;
+
+    let mut all = vec![];
+    seqs(local, wrapper, &mut all);
+    let retarget = |block: &mut InstrSeqId| {
+        if *block == entry {
+            *block = wrapper;
+        }
+    };
+    for seq in all {
+        for (instr, _) in local.block_mut(seq).instrs.iter_mut() {
+            match instr {
+                Instr::Return(_) => *instr = Instr::Br(Br { block: wrapper }),
+                Instr::Br(br) => retarget(&mut br.block),
+                Instr::BrIf(br_if) => retarget(&mut br_if.block),
+                Instr::BrTable(br_table) => {
+                    br_table.blocks.iter_mut().for_each(retarget);
+                    retarget(&mut br_table.default);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    let hooks = vec![
+        Instr::GlobalGet(GlobalGet { global: context }),
+        Instr::LocalSet(LocalSet { local: saved }),
+        Instr::Const(Const {
+            value: Value::I32(label as i32),
+        }),
+        Instr::Call(Call { func: enter }),
+        Instr::Block(Block { seq: wrapper }),
+        Instr::LocalGet(LocalGet { local: saved }),
+        Instr::GlobalSet(GlobalSet { global: context }),
+    ];
+    local.block_mut(entry).instrs = hooks
+        .into_iter()
+        .map(|instr| (instr, InstrLocId::default()))
+        .collect();
+}
+
+/*
+ * `--context-tree`: build a calling-context tree in a dedicated memory
+ * ({prefix}profiling_context_tree), so profiles can tell apart the calls a
+ * function makes on behalf of different callers, for context-sensitive
+ * devirtualization and flame graphs. All little-endian 32-bit words:
+ *
+ *   version, capacity, nodes used, entries that found the table full,
+ *   then `capacity` nodes of (parent node, function, callsite, calls).
+ *
+ * On entry, each of `funcs` (but those in `skip`) moves the current context
+ * to the child node for (itself, the indirect callsite that called it, or
+ * -1), adding the node on first use, and puts the caller's context back on
+ * exit. The indirect call stubs tell the callee which callsite it is, so
+ * the children of a node under one callsite are the targets that callsite
+ * reached in that context. Functions are numbered by their position in
+ * `funcs` and named in the manifest. Recursion adds a node per level, so
+ * deep recursion can fill the table, after which calls stay in the
+ * caller's context. Runs after every pass keyed by instruction position.
+ */
+pub fn instrument_context_tree(
+    module: &mut Module,
+    funcs: &[FunctionId],
+    skip: &HashSet<FunctionId>,
+    capacity: u32,
+    export_prefix: &str,
+) -> ContextTreeLayout {
+    assert!(
+        capacity.is_power_of_two(),
+        "context tree capacity must be a power of two"
+    );
+    let bytes = HEADER_BYTES as u64 + capacity as u64 * NODE_BYTES as u64;
+    let pages = ((bytes + 65535) / 65536) as u32;
+    let memory = module.memories.add_local(false, pages, Some(pages));
+    let header = [CONTEXT_TREE_VERSION, capacity, 0, 0];
+    module.data.add(
+        DataKind::Active(ActiveData {
+            memory,
+            location: ActiveDataLocation::Absolute(0),
+        }),
+        header.iter().flat_map(|word| word.to_le_bytes()).collect(),
+    );
+    let layout = ContextTreeLayout {
+        memory_export: format!("{}profiling_context_tree", export_prefix),
+        version: CONTEXT_TREE_VERSION,
+        capacity,
+        functions: funcs.iter().map(|func| func_name(module, *func)).collect(),
+    };
+    module.exports.add(&layout.memory_export, memory);
+
+    let context = module
+        .globals
+        .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+    let pending = module
+        .globals
+        .add_local(ValType::I32, true, InitExpr::Value(Value::I32(-1)));
+    let enter = add_enter_stub(module, memory, capacity, context, pending);
+
+    let mut hooked = 0;
+    for (label, func) in funcs.iter().enumerate() {
+        if !skip.contains(func) {
+            wrap_body(module, *func, label as u32, context, enter);
+            hooked += 1;
+        }
+    }
+
+    // The stubs name the callsite for the callee's entry, and clear it in
+    // case the callee has no entry hook (an import, a skipped function)
+    let stubs: Vec<FunctionId> = module
+        .funcs
+        .iter()
+        .filter(|func| {
+            func.name
+                .as_deref()
+                .map_or(false, |name| name.starts_with(INSTRUMENT_STUB_PREFIX))
+        })
+        .map(|func| func.id())
+        .collect();
+    for stub in stubs {
+        let func = module.funcs.get_mut(stub).kind.unwrap_local_mut();
+        let callsite = func.args[func.args.len() - 1];
+        let entry = func.entry_block();
+        let instrs = &mut func.block_mut(entry).instrs;
+        let name = vec![
+            Instr::LocalGet(LocalGet { local: callsite }),
+            Instr::GlobalSet(GlobalSet { global: pending }),
+        ];
+        instrs.splice(0..0, name.into_iter().map(|i| (i, InstrLocId::default())));
+        let clear = vec![
+            Instr::Const(Const {
+                value: Value::I32(-1),
+            }),
+            Instr::GlobalSet(GlobalSet { global: pending }),
+        ];
+        instrs.extend(clear.into_iter().map(|i| (i, InstrLocId::default())));
+    }
+    println!(
+        "Building a calling-context tree of up to {} nodes over {} functions",
+        capacity, hooked
+    );
+    layout
+}
+
+// A node of a dumped context tree; the root (id 0) isn't listed
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContextNode {
+    pub id: usize,
+    pub parent: usize,
+    // Index into ContextTreeLayout::functions
+    pub func: usize,
+    // The indirect callsite the function was entered from, None for a direct call
+    pub callsite: Option<usize>,
+    pub calls: u32,
+}
+
+fn word(buf: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes(buf[idx * 4..idx * 4 + 4].try_into().unwrap())
+}
+
+/*
+ * The nodes in a dump of the profiling_context_tree memory, plus how many
+ * function entries found the table full (and were counted in their
+ * caller's context instead).
+ */
+pub fn decode_context_tree(buf: &[u8], layout: &ContextTreeLayout) -> (Vec<ContextNode>, u32) {
+    assert_eq!(
+        word(buf, 0),
+        CONTEXT_TREE_VERSION,
+        "unknown context tree version"
+    );
+    let capacity = word(buf, 1) as usize;
+    assert_eq!(
+        capacity, layout.capacity as usize,
+        "dump doesn't match the manifest"
+    );
+    let nodes = (1..=capacity)
+        .filter_map(|id| {
+            let base = id * (NODE_BYTES as usize / 4);
+            let calls = word(buf, base + 3);
+            if calls == 0 {
+                return None;
+            }
+            let callsite = word(buf, base + 2) as i32;
+            Some(ContextNode {
+                id,
+                parent: word(buf, base) as usize,
+                func: word(buf, base + 1) as usize,
+                callsite: if callsite < 0 {
+                    None
+                } else {
+                    Some(callsite as usize)
+                },
+                calls,
+            })
+        })
+        .collect();
+    (nodes, word(buf, 3))
+}
+
+/*
+ * Summarize the tree and list the indirect callsites that are polymorphic
+ * overall but call a single target in each calling context (per node of
+ * the function making the call): those are the ones context-sensitive
+ * devirtualization would win back.
+ */
+pub fn context_report(
+    nodes: &[ContextNode],
+    overflowed: u32,
+    manifest: &Manifest,
+    layout: &ContextTreeLayout,
+    demangle: bool,
+) {
+    println!(
+        "== Calling-context tree: {} of {} nodes ==",
+        nodes.len(),
+        layout.capacity
+    );
+    if overflowed > 0 {
+        println!(
+            "warning: {} calls found the table full, rebuild with a larger --context-nodes",
+            overflowed
+        );
+    }
+    // callsite ==> calling context ==> targets
+    let mut targets: BTreeMap<usize, BTreeMap<usize, BTreeSet<usize>>> = BTreeMap::new();
+    for node in nodes {
+        if let Some(callsite) = node.callsite {
+            targets
+                .entry(callsite)
+                .or_default()
+                .entry(node.parent)
+                .or_default()
+                .insert(node.func);
+        }
+    }
+    let names: HashMap<usize, String> = manifest
+        .callsites
+        .iter()
+        .map(|c| (c.id, display_key(&c.key, demangle)))
+        .collect();
+    println!("== Callsites monomorphic per calling context ==");
+    println!(
+        "{:>8} {:>8} {:>9}  {}",
+        "callsite", "targets", "contexts", "key"
+    );
+    for (callsite, contexts) in &targets {
+        let all: BTreeSet<&usize> = contexts.values().flatten().collect();
+        if all.len() > 1 && contexts.values().all(|targets| targets.len() == 1) {
+            println!(
+                "{:>8} {:>8} {:>9}  {}",
+                callsite,
+                all.len(),
+                contexts.len(),
+                names
+                    .get(callsite)
+                    .cloned()
+                    .unwrap_or_else(|| format!("#{}", callsite))
+            );
+        }
+    }
+}
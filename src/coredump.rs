@@ -0,0 +1,213 @@
+use crate::formats::decode_globals;
+use crate::merge::merge_slots;
+use crate::slotmemory::decode_slot_memory;
+use crate::strip::export_prefix;
+use crate::Profile;
+use std::collections::HashMap;
+use walrus::*;
+use wasmparser::{BinaryReader, ConstExpr, DataKind, Operator, Parser, Payload, TypeRef};
+
+// What the dump holds of one instance: its globals' values and its memories' contents
+#[derive(Debug, Default)]
+struct InstanceState {
+    globals: Vec<Option<i64>>,
+    memories: Vec<Vec<u8>>,
+}
+
+// The value of an i32.const / i64.const initializer (None for floats, refs, ...)
+fn const_value(expr: &ConstExpr) -> Option<i64> {
+    let mut ops = expr.get_operators_reader();
+    match (ops.read().ok()?, ops.read().ok()?) {
+        (Operator::I32Const { value }, Operator::End) => Some(value as i64),
+        (Operator::I64Const { value }, Operator::End) => Some(value),
+        _ => None,
+    }
+}
+
+fn u32_vec(reader: &mut BinaryReader) -> Result<Vec<usize>, String> {
+    let count = reader.read_var_u32().map_err(|e| e.to_string())?;
+    (0..count)
+        .map(|_| {
+            reader
+                .read_var_u32()
+                .map(|idx| idx as usize)
+                .map_err(|e| e.to_string())
+        })
+        .collect()
+}
+
+/*
+ * The instances of a core dump in the tool-conventions format (wasmtime
+ * --coredump-on-trap): a wasm module whose global section holds every
+ * global's value at the time of the dump as its initializer, and whose
+ * active data segments hold the memories' contents. Its `coreinstances`
+ * section lists, for each instance, which of those globals and memories are
+ * the instance's, in the instance's own index order. A dump without one is
+ * read as a single instance owning everything, which is also how a
+ * snapshot (a module pre-initialized by Wizer) lays out its state.
+ */
+fn read_instances(dump: &[u8]) -> Result<Vec<InstanceState>, String> {
+    let mut globals: Vec<Option<i64>> = vec![];
+    let mut memories: Vec<Vec<u8>> = vec![];
+    // instance ==> (memories, globals), as indices into the above
+    let mut instances: Option<Vec<(Vec<usize>, Vec<usize>)>> = None;
+    for payload in Parser::new(0).parse_all(dump) {
+        match payload.map_err(|e| e.to_string())? {
+            // Imports come first in the index spaces, with no value of their own
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    match import.map_err(|e| e.to_string())?.ty {
+                        TypeRef::Global(_) => globals.push(None),
+                        TypeRef::Memory(_) => memories.push(vec![]),
+                        _ => (),
+                    }
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for memory in reader {
+                    let pages = memory.map_err(|e| e.to_string())?.initial;
+                    memories.push(vec![0; pages as usize * 65536]);
+                }
+            }
+            Payload::GlobalSection(reader) => {
+                for global in reader {
+                    globals.push(const_value(&global.map_err(|e| e.to_string())?.init_expr));
+                }
+            }
+            Payload::DataSection(reader) => {
+                for data in reader {
+                    let data = data.map_err(|e| e.to_string())?;
+                    if let DataKind::Active {
+                        memory_index,
+                        offset_expr,
+                    } = data.kind
+                    {
+                        let offset = const_value(&offset_expr)
+                            .ok_or("a data segment has a non-constant offset")?
+                            as usize;
+                        let memory = memories
+                            .get_mut(memory_index as usize)
+                            .ok_or("a data segment is for a memory the dump doesn't have")?;
+                        if memory.len() < offset + data.data.len() {
+                            memory.resize(offset + data.data.len(), 0);
+                        }
+                        memory[offset..offset + data.data.len()].copy_from_slice(data.data);
+                    }
+                }
+            }
+            Payload::CustomSection(reader) if reader.name() == "coreinstances" => {
+                let mut section = BinaryReader::new(reader.data(), 0);
+                let count = section.read_var_u32().map_err(|e| e.to_string())?;
+                let mut list = vec![];
+                for _ in 0..count {
+                    if section.read_u8().map_err(|e| e.to_string())? != 0 {
+                        return Err("unsupported coreinstances entry".to_string());
+                    }
+                    // The module it instantiates, which we don't need
+                    section.read_var_u32().map_err(|e| e.to_string())?;
+                    let memories = u32_vec(&mut section)?;
+                    let globals = u32_vec(&mut section)?;
+                    list.push((memories, globals));
+                }
+                instances = Some(list);
+            }
+            _ => (),
+        }
+    }
+    let instances = instances
+        .unwrap_or_else(|| vec![((0..memories.len()).collect(), (0..globals.len()).collect())]);
+    instances
+        .into_iter()
+        .map(|(memory_ids, global_ids)| {
+            Ok(InstanceState {
+                globals: global_ids
+                    .iter()
+                    .map(|idx| globals.get(*idx).cloned().flatten())
+                    .collect(),
+                memories: memory_ids
+                    .iter()
+                    .map(|idx| memories.get(*idx).cloned().unwrap_or_default())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+/*
+ * Recover the profile from a core dump of `module`, the instrumented binary,
+ * so a profiling run that trapped still yields its counters. Each instance
+ * of `module` in the dump (those with as many globals as it has) is read
+ * like one lane of a `globals` dump, through the names `module` exports its
+ * globals under; with --slot-memory the slots come from the dumped
+ * profiling_slots memory instead.
+ */
+pub fn profile_from_core_dump(module: &Module, dump: &[u8]) -> Result<Profile, String> {
+    let prefix = export_prefix(module);
+    let global_count = module.globals.iter().count();
+    // On a freshly parsed module ids follow the index space, imports first
+    let exported_globals: Vec<(String, usize)> = module
+        .exports
+        .iter()
+        .filter_map(|export| match export.item {
+            ExportItem::Global(global) => Some((export.name.clone(), global.index())),
+            _ => None,
+        })
+        .collect();
+    let slot_memory = module.exports.iter().find_map(|export| match export.item {
+        ExportItem::Memory(memory) if export.name == format!("{}profiling_slots", prefix) => {
+            Some(memory.index())
+        }
+        _ => None,
+    });
+
+    let instances: Vec<InstanceState> = read_instances(dump)?
+        .into_iter()
+        .filter(|instance| instance.globals.len() == global_count)
+        .collect();
+    if instances.is_empty() {
+        return Err(format!(
+            "no instance with the module's {} globals, is it a dump or snapshot of this binary?",
+            global_count
+        ));
+    }
+
+    let mut text = String::new();
+    for instance in &instances {
+        for (name, idx) in &exported_globals {
+            if let Some(value) = instance.globals[*idx] {
+                text.push_str(&format!("{}={}\n", name, value));
+            }
+        }
+    }
+    let mut profile = decode_globals(&text, &prefix);
+    if let Some(memory) = slot_memory {
+        let mut map: HashMap<usize, Vec<i64>> = HashMap::new();
+        for instance in &instances {
+            let image = instance
+                .memories
+                .get(memory)
+                .ok_or("the profiling_slots memory is missing")?;
+            for (idx, slots) in decode_slot_memory(image) {
+                let merged = map.entry(idx).or_default();
+                *merged = merge_slots(merged, &slots);
+            }
+        }
+        profile.map = map;
+    }
+    println!(
+        "Read the profiling state of {} instance(s)",
+        instances.len()
+    );
+    Ok(profile)
+}
+
+/*
+ * Recover the warm-up profile baked into a snapshot of `module`: Wizer runs
+ * the instrumented binary's initialization and writes the resulting globals
+ * and memory back as initializers and data segments, keeping the index
+ * spaces. So a module pre-initialized after a warm-up run doubles as the
+ * profile for optimizing the original binary.
+ */
+pub fn profile_from_snapshot(module: &Module, snapshot: &[u8]) -> Result<Profile, String> {
+    profile_from_core_dump(module, snapshot)
+}
@@ -0,0 +1,196 @@
+use crate::blockcounters::enumerate_blocks;
+use crate::dwarf::{annotate, SourceMap};
+use crate::report::func_name;
+use crate::symbolize::display_name;
+use crate::Profile;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use walrus::ir::*;
+use walrus::*;
+
+// Static cost summary of a single function
+#[derive(Debug)]
+pub struct FunctionCost {
+    pub func: FunctionId,
+    pub instrs: usize,
+    pub max_loop_depth: usize,
+    pub locals: usize,
+    pub reg_pressure: usize,
+    // Number of invocations observed in the profile (entry block counter)
+    pub calls: Option<i32>,
+}
+
+struct CostScan {
+    instrs: usize,
+    max_loop_depth: usize,
+    // local ==> (first use, last use) in instruction order
+    ranges: HashMap<LocalId, (usize, usize)>,
+}
+
+impl CostScan {
+    fn use_local(&mut self, local: LocalId) {
+        let pos = self.instrs;
+        let range = self.ranges.entry(local).or_insert((pos, pos));
+        range.1 = pos;
+    }
+
+    fn scan(&mut self, func: &LocalFunction, seq: InstrSeqId, loop_depth: usize) {
+        for (instr, _) in &func.block(seq).instrs {
+            self.instrs += 1;
+            match instr {
+                Instr::LocalGet(l) => self.use_local(l.local),
+                Instr::LocalSet(l) => self.use_local(l.local),
+                Instr::LocalTee(l) => self.use_local(l.local),
+                Instr::Block(b) => self.scan(func, b.seq, loop_depth),
+                Instr::Loop(l) => {
+                    let start = self.instrs;
+                    let depth = loop_depth + 1;
+                    if depth > self.max_loop_depth {
+                        self.max_loop_depth = depth;
+                    }
+                    // Remember which locals were already live before the loop
+                    let mut before: HashSet<LocalId> = HashSet::new();
+                    before.extend(self.ranges.keys());
+                    self.scan(func, l.seq, depth);
+                    let end = self.instrs;
+                    // Any local used inside of a loop is live across the back edge
+                    for (local, range) in self.ranges.iter_mut() {
+                        if range.1 >= start {
+                            if before.contains(local) {
+                                range.1 = end;
+                            } else {
+                                range.0 = start;
+                                range.1 = end;
+                            }
+                        }
+                    }
+                }
+                Instr::IfElse(if_else) => {
+                    self.scan(func, if_else.consequent, loop_depth);
+                    self.scan(func, if_else.alternative, loop_depth);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    // Max number of overlapping live ranges
+    fn pressure(&self) -> usize {
+        let mut events = vec![];
+        for (start, end) in self.ranges.values() {
+            events.push((*start, 1));
+            events.push((*end + 1, -1));
+        }
+        events.sort();
+        let mut live: i64 = 0;
+        let mut max: i64 = 0;
+        for (_, delta) in events {
+            live += delta;
+            if live > max {
+                max = live;
+            }
+        }
+        max as usize
+    }
+}
+
+// Entry block counters give us the dynamic call counts
+pub fn entry_counts(module: &Module, profile: &Profile) -> HashMap<FunctionId, i32> {
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let mut entry_counts: HashMap<FunctionId, i32> = HashMap::new();
+    let mut seen = HashSet::new();
+    for (idx, (f_id, _)) in enumerate_blocks(module, &original_funcs).iter().enumerate() {
+        // The first block of each function is its entry block
+        if seen.insert(*f_id) {
+            if let Some(count) = profile.blocks.get(&idx) {
+                entry_counts.insert(*f_id, *count);
+            }
+        }
+    }
+    entry_counts
+}
+
+/*
+ * Names of the functions a --coarse profile saw called at least
+ * `hot_threshold` times, for instrumenting just their callsites in the
+ * second (fine) profiling run.
+ */
+pub fn hot_functions(module: &Module, profile: &Profile, hot_threshold: i32) -> HashSet<String> {
+    entry_counts(module, profile)
+        .into_iter()
+        .filter(|(_, count)| *count >= hot_threshold)
+        .map(|(id, _)| func_name(module, id))
+        .collect()
+}
+
+pub fn compute_costs(module: &Module, profile: &Option<Profile>) -> Vec<FunctionCost> {
+    let entry_counts = match profile {
+        Some(profile) => entry_counts(module, profile),
+        None => HashMap::new(),
+    };
+
+    let mut costs = vec![];
+    for (id, func) in module.funcs.iter_local() {
+        let mut scan = CostScan {
+            instrs: 0,
+            max_loop_depth: 0,
+            ranges: HashMap::new(),
+        };
+        scan.scan(func, func.entry_block(), 0);
+        let mut locals: HashSet<LocalId> = scan.ranges.keys().cloned().collect();
+        locals.extend(func.args.iter());
+        costs.push(FunctionCost {
+            func: id,
+            instrs: scan.instrs,
+            max_loop_depth: scan.max_loop_depth,
+            locals: locals.len(),
+            reg_pressure: scan.pressure(),
+            calls: entry_counts.get(&id).cloned(),
+        });
+    }
+    costs
+}
+
+/*
+ * Print the per-function cost table, hottest functions first when we have
+ * call counts, otherwise the largest functions first.
+ */
+pub fn print_costs(
+    module: &Module,
+    costs: &mut Vec<FunctionCost>,
+    top: usize,
+    demangle: bool,
+    sources: Option<&SourceMap>,
+) {
+    costs.sort_by(|a, b| {
+        b.calls
+            .unwrap_or(0)
+            .cmp(&a.calls.unwrap_or(0))
+            .then(b.instrs.cmp(&a.instrs))
+    });
+    println!("== Per-function static costs ==");
+    println!(
+        "{:>10} {:>8} {:>6} {:>7} {:>9}  {}",
+        "calls", "instrs", "loops", "locals", "pressure", "function"
+    );
+    for cost in costs.iter().take(top) {
+        let calls = match cost.calls {
+            Some(calls) => calls.to_string(),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:>10} {:>8} {:>6} {:>7} {:>9}  {}",
+            calls,
+            cost.instrs,
+            cost.max_loop_depth,
+            cost.locals,
+            cost.reg_pressure,
+            annotate(
+                display_name(module, cost.func, demangle),
+                sources.and_then(
+                    |s| s.locate_function(module.funcs.get(cost.func).kind.unwrap_local())
+                )
+            )
+        );
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+use walrus::ir::*;
+use walrus::*;
+
+// Slot values (profiling_global_{id}_{n}) that aren't table indices
+pub const EMPTY_SLOT: i32 = -1;
+pub const OVERFLOW_SLOT: i32 = -2;
+
+/*
+ * Table indices are unsigned: call_indirect reads its operand as a u32, so
+ * in a table with more than i32::MAX entries the slot globals hold indices
+ * that look negative. Profiles keep slots as i64, with table indices as the
+ * unsigned value and EMPTY_SLOT / OVERFLOW_SLOT as they are. Only the two
+ * largest indices can't be told apart from those, and no engine allows a
+ * table that large.
+ */
+pub fn slot_value(raw: i32) -> i64 {
+    match raw {
+        EMPTY_SLOT | OVERFLOW_SLOT => raw as i64,
+        _ => raw as u32 as i64,
+    }
+}
+
+// The i32 operand for a table index or callsite id, None if it's outside the 32-bit index space
+pub fn index_const(idx: i64) -> Option<i32> {
+    u32::try_from(idx).ok().map(|idx| idx as i32)
+}
+
+// What happens to an event counter that reaches i32::MAX
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CounterMode {
+    Wrap,
+    Saturate,
+}
+
+pub const COUNTER_MODES: &[&str] = &["wrap", "saturate"];
+
+impl CounterMode {
+    pub fn from_name(name: &str) -> CounterMode {
+        match name {
+            "wrap" => CounterMode::Wrap,
+            "saturate" => CounterMode::Saturate,
+            _ => panic!("unknown counter mode: {}", name),
+        }
+    }
+}
+
+/*
+ * How the event counters (indirect, slowcalls, profiling_block_* and
+ * profiling_import_*) start out and count. Wrapping is a plain i32.add; a
+ * saturating counter sticks at i32::MAX, which is what a long running
+ * workload wants since a wrapped count reads as a cold (or negative) one.
+ * Both are straight-line code, so they can go anywhere in a block.
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct CounterPolicy {
+    pub init: i32,
+    pub mode: CounterMode,
+}
+
+impl Default for CounterPolicy {
+    fn default() -> CounterPolicy {
+        CounterPolicy {
+            init: 0,
+            mode: CounterMode::Wrap,
+        }
+    }
+}
+
+impl CounterPolicy {
+    pub fn add_counter(&self, module: &mut Module) -> GlobalId {
+        module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(self.init)))
+    }
+
+    // The instructions adding one to `counter`
+    pub fn increment(&self, counter: GlobalId) -> Vec<Instr> {
+        let mut instrs = vec![Instr::GlobalGet(GlobalGet { global: counter })];
+        match self.mode {
+            CounterMode::Wrap => instrs.push(Instr::Const(Const {
+                value: Value::I32(1),
+            })),
+            // counter + (counter != i32::MAX)
+            CounterMode::Saturate => instrs.extend(vec![
+                Instr::GlobalGet(GlobalGet { global: counter }),
+                Instr::Const(Const {
+                    value: Value::I32(i32::MAX),
+                }),
+                Instr::Binop(Binop {
+                    op: BinaryOp::I32Ne,
+                }),
+            ]),
+        }
+        instrs.push(Instr::Binop(Binop {
+            op: BinaryOp::I32Add,
+        }));
+        instrs.push(Instr::GlobalSet(GlobalSet { global: counter }));
+        instrs
+    }
+}
+
+/*
+ * What a callsite's slots do once they're all taken and a new target shows
+ * up. `Overflow` gives up on the callsite: every slot becomes OVERFLOW_SLOT.
+ * `Lru` keeps the slots in most-recently-seen order, evicts the least
+ * recently seen target, and counts the targets that came in without being
+ * in the window (profiling_distinct_{id}, saturating). That count is the
+ * number of distinct targets until the first eviction and an upper bound on
+ * it after, so the optimizer can tell a callsite that saw more targets than
+ * it kept, and fall back to the call_indirect there instead of trapping.
+ */
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SlotPolicy {
+    Overflow,
+    Lru,
+}
+
+pub const SLOT_POLICIES: &[&str] = &["overflow", "lru"];
+
+impl SlotPolicy {
+    pub fn from_name(name: &str) -> SlotPolicy {
+        match name {
+            "overflow" => SlotPolicy::Overflow,
+            "lru" => SlotPolicy::Lru,
+            _ => panic!("unknown slot policy: {}", name),
+        }
+    }
+}
+
+impl Default for SlotPolicy {
+    fn default() -> SlotPolicy {
+        SlotPolicy::Overflow
+    }
+}
+
+/*
+ * The SlotPolicy::Lru recording of one callsite, for the profiling stub, in
+ * a block of its own: if `callsite` is this one, move `target` to the front
+ * of `slots` (shifting the ones in front of it back, or all of them when
+ * it's new, which drops the last) and count it in `distinct` when it's new;
+ * then branch to `done`. Other callsites just leave the block.
+ */
+pub fn record_lru(
+    block: &mut InstrSeqBuilder,
+    done: InstrSeqId,
+    callsite: LocalId,
+    target: LocalId,
+    id: i32,
+    slots: &[GlobalId],
+    distinct: GlobalId,
+) {
+    let saturating = CounterPolicy {
+        init: 0,
+        mode: CounterMode::Saturate,
+    };
+    let other_callsite = block.id();
+    block
+        .local_get(callsite)
+        .i32_const(id)
+        .binop(BinaryOp::I32Ne)
+        .br_if(other_callsite);
+    for hit in 0..slots.len() {
+        block
+            .global_get(slots[hit])
+            .local_get(target)
+            .binop(BinaryOp::I32Eq)
+            .if_else(
+                None,
+                |then| {
+                    for slot in (1..=hit).rev() {
+                        then.global_get(slots[slot - 1]).global_set(slots[slot]);
+                    }
+                    then.local_get(target).global_set(slots[0]).br(done);
+                },
+                |_| {},
+            );
+    }
+    for slot in (1..slots.len()).rev() {
+        block.global_get(slots[slot - 1]).global_set(slots[slot]);
+    }
+    block.local_get(target).global_set(slots[0]);
+    for instr in saturating.increment(distinct) {
+        block.instr(instr);
+    }
+    block.br(done);
+}
@@ -0,0 +1,140 @@
+use crate::manifest::MANIFEST_SECTION;
+use crate::meta::META_SECTION;
+use std::collections::HashSet;
+use std::ops::Range;
+use wasmparser::BinaryReader;
+
+// Sections that describe code by offset, which the instrumentation moves: DWARF and
+// the code metadata sections (branch hints, compilation hints, ...)
+pub const DROPPED_BY_DEFAULT: &[&str] = &[".debug_*", "metadata.code.*"];
+// Sections walrus or this tool writes themselves, whatever the input had
+const REGENERATED: &[&str] = &["name", "producers", META_SECTION, MANIFEST_SECTION];
+
+// One section of a binary
+struct Section {
+    // Custom sections only
+    name: Option<String>,
+    // The whole section, id and size included
+    range: Range<usize>,
+}
+
+fn sections(wasm: &[u8]) -> Vec<Section> {
+    let mut reader = BinaryReader::new(wasm, 0);
+    // Magic and version
+    reader.read_bytes(8).unwrap();
+    let mut sections = vec![];
+    while !reader.eof() {
+        let start = reader.original_position();
+        let id = reader.read_u8().unwrap();
+        let size = reader.read_var_u32().unwrap() as usize;
+        let contents = reader.original_position();
+        let name = match id {
+            0 => Some(reader.read_string().unwrap().to_string()),
+            _ => None,
+        };
+        reader
+            .read_bytes(contents + size - reader.original_position())
+            .unwrap();
+        sections.push(Section {
+            name,
+            range: start..contents + size,
+        });
+    }
+    sections
+}
+
+// Whether `name` is `pattern`, or starts with it when it ends in `*`
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => name.starts_with(prefix),
+        None => name == pattern,
+    }
+}
+
+// Whether an input section called `name` makes it into the output
+pub fn keeps(name: &str, keep: &[String], drop: &[String]) -> bool {
+    if drop.iter().any(|pattern| matches(pattern, name)) {
+        return false;
+    }
+    keep.iter().any(|pattern| matches(pattern, name))
+        || !DROPPED_BY_DEFAULT
+            .iter()
+            .any(|pattern| matches(pattern, name))
+}
+
+/*
+ * Give `output` the input's custom sections as `--keep-custom` and
+ * `--drop-custom` say: walrus keeps the ones it doesn't know as raw bytes
+ * but emits them all after the code, drops DWARF, and keeps the code
+ * metadata sections whose offsets no longer match. Every kept section of
+ * the input goes into the output byte for byte: the ones the input had in
+ * front of every other section (`dylink.0` has to be first) in front, the
+ * rest at the end. The sections walrus and this tool regenerate (names,
+ * producers, our meta and manifest) are left as emitted, unless dropped.
+ */
+pub fn apply_custom_policy(
+    input: &[u8],
+    output: Vec<u8>,
+    keep: &[String],
+    drop: &[String],
+) -> Vec<u8> {
+    let input_sections = sections(input);
+    let leading = input_sections
+        .iter()
+        .position(|section| section.name.is_none())
+        .unwrap_or(input_sections.len());
+    let regenerated = |name: &str| REGENERATED.contains(&name);
+    let passed_through: Vec<(usize, &Section)> = input_sections
+        .iter()
+        .enumerate()
+        .filter(|(_, section)| {
+            section
+                .name
+                .as_deref()
+                .map_or(false, |name| !regenerated(name))
+        })
+        .collect();
+    let input_names: HashSet<&str> = passed_through
+        .iter()
+        .filter_map(|(_, section)| section.name.as_deref())
+        .collect();
+
+    let mut dropped: Vec<&str> = vec![];
+    let mut front = vec![];
+    let mut back = vec![];
+    for (pos, section) in &passed_through {
+        let name = section.name.as_deref().unwrap();
+        if !keeps(name, keep, drop) {
+            if !dropped.contains(&name) {
+                dropped.push(name);
+            }
+            continue;
+        }
+        let bytes = &input[section.range.clone()];
+        if *pos < leading {
+            front.extend_from_slice(bytes);
+        } else {
+            back.extend_from_slice(bytes);
+        }
+    }
+    if !dropped.is_empty() {
+        println!(
+            "Dropping the input's {} custom sections (--keep-custom keeps them as they are)",
+            dropped.join(", ")
+        );
+    }
+
+    let mut wasm = output[..8].to_vec();
+    wasm.extend(front);
+    for section in sections(&output) {
+        if let Some(name) = section.name.as_deref() {
+            // Put back as the input had it, or dropped
+            if input_names.contains(name) || drop.iter().any(|pattern| matches(pattern, name)) {
+                continue;
+            }
+        }
+        wasm.extend_from_slice(&output[section.range]);
+    }
+    wasm.extend(back);
+    wasm
+}
@@ -0,0 +1,194 @@
+use crate::blockcounters::enumerate_blocks;
+use crate::symbolize::display_name;
+use crate::Profile;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use walrus::ir::*;
+use walrus::*;
+
+// Built-in weights, by walrus instruction or operator name
+const DEFAULT_WEIGHTS: &[(&str, u64)] = &[
+    ("Block", 0),
+    ("Loop", 0),
+    ("Call", 5),
+    ("CallIndirect", 20),
+    ("Load", 3),
+    ("Store", 3),
+    ("BrTable", 4),
+    ("MemoryGrow", 100),
+    ("MemoryCopy", 50),
+    ("MemoryFill", 50),
+    ("I32DivS", 20),
+    ("I32DivU", 20),
+    ("I32RemS", 20),
+    ("I32RemU", 20),
+    ("I64DivS", 30),
+    ("I64DivU", 30),
+    ("I64RemS", 30),
+    ("I64RemU", 30),
+    ("F32Div", 10),
+    ("F64Div", 15),
+    ("F32Sqrt", 10),
+    ("F64Sqrt", 15),
+];
+
+/*
+ * Static cycles per instruction, to turn block counts into a rough time
+ * profile. An instruction is weighed by its operator name for binops and
+ * unops (I32DivS, F64Sqrt), then its instruction name (CallIndirect, Load,
+ * Binop), then `default`. Names are walrus' variant names.
+ */
+#[derive(Debug, Clone)]
+pub struct CostTable {
+    pub default: u64,
+    pub weights: HashMap<String, u64>,
+}
+
+impl Default for CostTable {
+    fn default() -> Self {
+        CostTable {
+            default: 1,
+            weights: DEFAULT_WEIGHTS
+                .iter()
+                .map(|(name, weight)| (name.to_string(), *weight))
+                .collect(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CostTableFile {
+    default: Option<u64>,
+    #[serde(default)]
+    weights: HashMap<String, u64>,
+}
+
+// The leading identifier of a Debug string: "I32DivS", "Call(Call { .. })" => "Call"
+fn variant(debug: String) -> String {
+    debug
+        .chars()
+        .take_while(|c| c.is_ascii_alphanumeric())
+        .collect()
+}
+
+impl CostTable {
+    /*
+     * The built-in table with a TOML file's weights on top:
+     *
+     *   default = 1
+     *   [weights]
+     *   CallIndirect = 40
+     *   I64DivS = 60
+     */
+    pub fn from_toml(text: &str) -> Result<CostTable, String> {
+        let file: CostTableFile = toml::from_str(text).map_err(|e| e.to_string())?;
+        let mut table = CostTable::default();
+        if let Some(default) = file.default {
+            table.default = default;
+        }
+        table.weights.extend(file.weights);
+        Ok(table)
+    }
+
+    pub fn read(path: &str) -> CostTable {
+        let text = std::fs::read_to_string(path).unwrap();
+        CostTable::from_toml(&text).unwrap_or_else(|e| panic!("invalid cost table {}: {}", path, e))
+    }
+
+    pub fn weight(&self, instr: &Instr) -> u64 {
+        let op = match instr {
+            Instr::Binop(binop) => Some(variant(format!("{:?}", binop.op))),
+            Instr::Unop(unop) => Some(variant(format!("{:?}", unop.op))),
+            _ => None,
+        };
+        op.and_then(|op| self.weights.get(&op))
+            .or_else(|| self.weights.get(&variant(format!("{:?}", instr))))
+            .cloned()
+            .unwrap_or(self.default)
+    }
+}
+
+// Estimated cycles spent in one function
+#[derive(Debug)]
+pub struct FunctionCycles {
+    pub func: FunctionId,
+    pub cycles: u64,
+    // Entry block count
+    pub calls: u64,
+}
+
+/*
+ * Weigh every block's own instructions (not those of the blocks nested in
+ * it) and multiply by how often the profile saw the block entered. This
+ * needs --block-counters: a --coarse profile only counts function entries.
+ * Instructions after a branch out of their block are counted as if they
+ * ran, so the estimate errs high.
+ */
+pub fn estimate_cycles(
+    module: &Module,
+    profile: &Profile,
+    table: &CostTable,
+) -> Vec<FunctionCycles> {
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let mut cycles: HashMap<FunctionId, FunctionCycles> = HashMap::new();
+    let mut seen = HashSet::new();
+    for (idx, (f_id, seq)) in enumerate_blocks(module, &original_funcs).iter().enumerate() {
+        let entered = match profile.blocks.get(&idx) {
+            // Wrapped counters are still right up to u32::MAX
+            Some(count) => *count as u32 as u64,
+            None => continue,
+        };
+        let func = module.funcs.get(*f_id).kind.unwrap_local();
+        let weight: u64 = func
+            .block(*seq)
+            .instrs
+            .iter()
+            .map(|(instr, _)| table.weight(instr))
+            .sum();
+        let estimate = cycles.entry(*f_id).or_insert(FunctionCycles {
+            func: *f_id,
+            cycles: 0,
+            calls: 0,
+        });
+        estimate.cycles += weight * entered;
+        // The first block of each function is its entry block
+        if seen.insert(*f_id) {
+            estimate.calls = entered;
+        }
+    }
+    let mut cycles: Vec<FunctionCycles> = cycles.into_values().collect();
+    cycles.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.func.cmp(&b.func)));
+    cycles
+}
+
+pub fn cycles_report(
+    module: &Module,
+    profile: &Profile,
+    table: &CostTable,
+    top: usize,
+    demangle: bool,
+) {
+    let cycles = estimate_cycles(module, profile, table);
+    let total: u64 = cycles.iter().map(|f| f.cycles).sum();
+    println!("== Estimated cycles (static instruction weights) ==");
+    println!(
+        "{:>14} {:>7} {:>10} {:>10}  {}",
+        "cycles", "share", "calls", "per call", "function"
+    );
+    for estimate in cycles.iter().take(top) {
+        let per_call = match estimate.calls {
+            0 => "-".to_string(),
+            calls => (estimate.cycles / calls).to_string(),
+        };
+        println!(
+            "{:>14} {:>6.1}% {:>10} {:>10}  {}",
+            estimate.cycles,
+            estimate.cycles as f64 / std::cmp::max(total, 1) as f64 * 100.0,
+            estimate.calls,
+            per_call,
+            display_name(module, estimate.func, demangle)
+        );
+    }
+}
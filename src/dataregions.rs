@@ -0,0 +1,318 @@
+use crate::counters::CounterPolicy;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use walrus::ir::*;
+use walrus::*;
+
+// Loads and stores are counted per region of this many bytes (a cache line)
+pub const REGION_BYTES: u32 = 64;
+
+// The memory the data segments initialize (profiling memories come later)
+fn data_memory(module: &Module) -> Option<MemoryId> {
+    module.memories.iter().next().map(|memory| memory.id())
+}
+
+/*
+ * The regions of the data memory the active data segments at constant
+ * offsets cover, as start address ==> end address. Each segment is cut
+ * into REGION_BYTES pieces from its own offset, so a region never spans
+ * two segments.
+ */
+pub fn data_regions(module: &Module) -> BTreeMap<u32, u32> {
+    let mut regions = BTreeMap::new();
+    let memory = match data_memory(module) {
+        Some(memory) => memory,
+        None => return regions,
+    };
+    for data in module.data.iter() {
+        if let DataKind::Active(ActiveData {
+            memory: m,
+            location: ActiveDataLocation::Absolute(offset),
+        }) = data.kind
+        {
+            if m != memory {
+                continue;
+            }
+            let end = offset.saturating_add(data.value.len() as u32);
+            let mut start = offset;
+            while start < end {
+                let region_end = std::cmp::min(start.saturating_add(REGION_BYTES), end);
+                let entry = regions.entry(start).or_insert(region_end);
+                *entry = std::cmp::max(*entry, region_end);
+                start = region_end;
+            }
+        }
+    }
+    regions
+}
+
+// Bump the counter of the region holding `addr`: a binary search over `regions`
+fn record_region(
+    block: &mut InstrSeqBuilder,
+    addr: LocalId,
+    regions: &[(u32, u32, GlobalId)],
+    policy: &CounterPolicy,
+) {
+    if let [(start, end, counter)] = regions {
+        // addr - start < len, unsigned, so addresses below start miss too
+        block
+            .local_get(addr)
+            .i32_const(*start as i32)
+            .binop(BinaryOp::I32Sub)
+            .i32_const((end - start) as i32)
+            .binop(BinaryOp::I32LtU)
+            .if_else(
+                None,
+                |then| {
+                    for instr in policy.increment(*counter) {
+                        then.instr(instr);
+                    }
+                },
+                |_| {},
+            );
+        return;
+    }
+    let (low, high) = regions.split_at(regions.len() / 2);
+    block
+        .local_get(addr)
+        .i32_const(high[0].0 as i32)
+        .binop(BinaryOp::I32LtU)
+        .if_else(
+            None,
+            |then| record_region(then, addr, low, policy),
+            |otherwise| record_region(otherwise, addr, high, policy),
+        );
+}
+
+fn stored_type(kind: &StoreKind) -> ValType {
+    match kind {
+        StoreKind::I32 { .. } | StoreKind::I32_8 { .. } | StoreKind::I32_16 { .. } => ValType::I32,
+        StoreKind::I64 { .. }
+        | StoreKind::I64_8 { .. }
+        | StoreKind::I64_16 { .. }
+        | StoreKind::I64_32 { .. } => ValType::I64,
+        StoreKind::F32 => ValType::F32,
+        StoreKind::F64 => ValType::F64,
+        StoreKind::V128 => ValType::V128,
+    }
+}
+
+// Position, offset and stored type (None for loads) of every load / store of `memory` in `func`
+fn accesses(
+    func: &LocalFunction,
+    memory: MemoryId,
+) -> Vec<(InstrSeqId, usize, u32, Option<ValType>)> {
+    let mut points = vec![];
+    let mut seqs_to_process: Vec<InstrSeqId> = vec![func.entry_block()];
+    while let Some(current_seq) = seqs_to_process.pop() {
+        for (pos, (instr, _)) in func.block(current_seq).instrs.iter().enumerate() {
+            match instr {
+                Instr::Load(load) if load.memory == memory => {
+                    points.push((current_seq, pos, load.arg.offset, None))
+                }
+                Instr::Store(store) if store.memory == memory => points.push((
+                    current_seq,
+                    pos,
+                    store.arg.offset,
+                    Some(stored_type(&store.kind)),
+                )),
+                Instr::Block(b) => seqs_to_process.push(b.seq),
+                Instr::Loop(l) => seqs_to_process.push(l.seq),
+                Instr::IfElse(if_else) => {
+                    seqs_to_process.push(if_else.consequent);
+                    seqs_to_process.push(if_else.alternative);
+                }
+                _ => (),
+            }
+        }
+    }
+    points
+}
+
+/*
+ * Count the loads and stores `funcs` make into each data region (see
+ * data_regions): every load / store of the data memory first passes its
+ * effective address to data_region_stub, which finds the region and bumps
+ * its counter, exported as profiling_data_{start address}. Accesses outside
+ * the data segments (the stack, the heap) aren't counted, nor are the bulk
+ * memory instructions. Inserting shifts instruction positions, so this runs
+ * after the passes keyed by them. Returns how many regions got a counter.
+ */
+pub fn instrument_data_accesses(
+    module: &mut Module,
+    funcs: &[FunctionId],
+    export_prefix: &str,
+    policy: &CounterPolicy,
+) -> usize {
+    let regions = data_regions(module);
+    let memory = match data_memory(module) {
+        Some(memory) if !regions.is_empty() => memory,
+        _ => return 0,
+    };
+    let mut counters = vec![];
+    for (start, end) in &regions {
+        let counter = policy.add_counter(module);
+        module.exports.add(
+            &format!("{}profiling_data_{}", export_prefix, start),
+            counter,
+        );
+        counters.push((*start, *end, counter));
+    }
+
+    let addr = module.locals.add(ValType::I32);
+    let mut stub = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[]);
+    stub.name("data_region_stub".to_string());
+    record_region(&mut stub.func_body(), addr, &counters, policy);
+    let stub = stub.finish(vec![addr], &mut module.funcs);
+
+    let address = module.locals.add(ValType::I32);
+    let mut values: HashMap<ValType, LocalId> = HashMap::new();
+    for func_id in funcs {
+        let points = accesses(module.funcs.get(*func_id).kind.unwrap_local(), memory);
+        for (_, _, _, stored) in &points {
+            if let Some(ty) = stored {
+                values.entry(*ty).or_insert_with(|| module.locals.add(*ty));
+            }
+        }
+        let func = module.funcs.get_mut(*func_id).kind.unwrap_local_mut();
+        // Back to front, so the insertions don't move the points still to come
+        for (seq, pos, offset, stored) in points.into_iter().rev() {
+            let instrs = &mut func.block_mut(seq).instrs;
+            let loc = instrs[pos].1;
+            // [addr (value)] => [addr (value)], with the stub called on addr + offset
+            let mut record = vec![];
+            if let Some(ty) = stored {
+                record.push(Instr::LocalSet(LocalSet { local: values[&ty] }));
+            }
+            record.extend(vec![
+                Instr::LocalTee(LocalTee { local: address }),
+                Instr::Const(Const {
+                    value: Value::I32(offset as i32),
+                }),
+                Instr::Binop(Binop {
+                    op: BinaryOp::I32Add,
+                }),
+                Instr::Call(Call { func: stub }),
+                Instr::LocalGet(LocalGet { local: address }),
+            ]);
+            if let Some(ty) = stored {
+                record.push(Instr::LocalGet(LocalGet { local: values[&ty] }));
+            }
+            instrs.splice(pos..pos, record.into_iter().map(|instr| (instr, loc)));
+        }
+    }
+    println!(
+        "Counting the accesses of {} data regions ({} bytes each)",
+        counters.len(),
+        REGION_BYTES
+    );
+    counters.len()
+}
+
+/*
+ * Split the active data segments where the profile's region counts (start
+ * address ==> accesses, see instrument_data_accesses) go from accessed to
+ * untouched, and emit the pieces hottest first, so the hot constants sit
+ * next to each other in the data section and VectorVisor's memory subsystem
+ * can stage them as a few contiguous ranges. The code computes the data's
+ * addresses, so nothing moves in memory; only the segments change. That
+ * keeps the initialized memory the same as long as no two segments overlap
+ * (the later one would win), so the pass bails out if any might, and it
+ * leaves alone the segments memory.init / data.drop refer to. Returns how
+ * many segments it emitted.
+ */
+pub fn reorder_data_segments(module: &mut Module, counts: &HashMap<usize, i32>) -> usize {
+    let memory = match data_memory(module) {
+        Some(memory) => memory,
+        None => return 0,
+    };
+    let mut referenced = HashSet::new();
+    for (_, func) in module.funcs.iter_local() {
+        referenced.extend(func.used_data_segments().iter().cloned());
+    }
+    let mut segments: Vec<(DataId, u32, Vec<u8>)> = vec![];
+    for data in module.data.iter() {
+        match data.kind {
+            DataKind::Active(ActiveData {
+                memory: m,
+                location: ActiveDataLocation::Absolute(offset),
+            }) if m == memory => {
+                if !referenced.contains(&data.id()) {
+                    segments.push((data.id(), offset, data.value.clone()));
+                }
+            }
+            DataKind::Active(ActiveData { memory: m, .. }) if m == memory => {
+                println!("Not reordering the data segments: one sits at a global's offset");
+                return 0;
+            }
+            _ => (),
+        }
+    }
+    let mut ranges: Vec<(u32, u32)> = module
+        .data
+        .iter()
+        .filter_map(|data| match data.kind {
+            DataKind::Active(ActiveData {
+                memory: m,
+                location: ActiveDataLocation::Absolute(offset),
+            }) if m == memory => Some((offset, offset.saturating_add(data.value.len() as u32))),
+            _ => None,
+        })
+        .collect();
+    ranges.sort();
+    if ranges.windows(2).any(|pair| pair[1].0 < pair[0].1) {
+        println!("Not reordering the data segments: some of them overlap");
+        return 0;
+    }
+
+    // (offset, bytes, accesses), each segment cut where its regions turn hot or cold
+    let mut pieces: Vec<(u32, Vec<u8>, i64)> = vec![];
+    for (_, offset, value) in &segments {
+        let mut piece: Option<(u32, Vec<u8>, i64)> = None;
+        for (i, chunk) in value.chunks(REGION_BYTES as usize).enumerate() {
+            let start = offset + i as u32 * REGION_BYTES;
+            let count = counts.get(&(start as usize)).cloned().unwrap_or(0).max(0) as i64;
+            match &mut piece {
+                Some((_, bytes, accesses)) if (*accesses > 0) == (count > 0) => {
+                    bytes.extend_from_slice(chunk);
+                    *accesses += count;
+                }
+                _ => {
+                    pieces.extend(piece.take());
+                    piece = Some((start, chunk.to_vec(), count));
+                }
+            }
+        }
+        pieces.extend(piece);
+    }
+    // Stable, so the untouched pieces keep their order after the hot ones
+    pieces.sort_by_key(|(_, _, accesses)| std::cmp::Reverse(*accesses));
+
+    let data_segments = &mut module.memories.get_mut(memory).data_segments;
+    for (id, _, _) in &segments {
+        data_segments.remove(id);
+    }
+    for (id, _, _) in &segments {
+        module.data.delete(*id);
+    }
+    let hot = pieces
+        .iter()
+        .filter(|(_, _, accesses)| *accesses > 0)
+        .count();
+    for (offset, bytes, _) in &pieces {
+        let id = module.data.add(
+            DataKind::Active(ActiveData {
+                memory,
+                location: ActiveDataLocation::Absolute(*offset),
+            }),
+            bytes.clone(),
+        );
+        module.memories.get_mut(memory).data_segments.insert(id);
+    }
+    println!(
+        "Split {} data segments into {} ({} accessed), hottest first",
+        segments.len(),
+        pieces.len(),
+        hot
+    );
+    pieces.len()
+}
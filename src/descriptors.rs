@@ -0,0 +1,81 @@
+use crate::manifest::DescriptorLayout;
+use std::collections::HashMap;
+use walrus::*;
+
+pub const DESCRIPTOR_VERSION: u32 = 1;
+// Descriptor entry for a callsite that has no globals (skipped, see --min-func-size)
+pub const NO_GLOBAL: u32 = u32::MAX;
+
+// Index of `global` in the emitted global index space: imports first, then
+// local globals in the order walrus emits them
+fn global_index(module: &Module, global: GlobalId) -> u32 {
+    let imported = module
+        .globals
+        .iter()
+        .filter(|g| matches!(g.kind, GlobalKind::Import(_)))
+        .count();
+    let local = module
+        .globals
+        .iter()
+        .filter(|g| matches!(g.kind, GlobalKind::Local(_)))
+        .position(|g| g.id() == global)
+        .unwrap();
+    (imported + local) as u32
+}
+
+/*
+ * `--compact-exports`: rather than exporting every slot global, export one i32
+ * global holding the callsite count and a dedicated memory initialized with
+ * a descriptor table, all little-endian u32s:
+ *
+ *   version, callsite count, window,
+ *   then per callsite, `window` global indices (NO_GLOBAL if uninstrumented,
+ *   or past the end of a callsite with fewer slots, see --prior-profile)
+ *
+ * Hosts read the slots by global index (e.g. from a core dump) and turn them
+ * back into `profiling_global_{callsite}_{slot}` values. Must run after every
+ * global has been added, since it bakes in their final indices.
+ */
+pub fn add_descriptor_table(
+    module: &mut Module,
+    slots: &HashMap<usize, Vec<GlobalId>>,
+    callsites: usize,
+    window: usize,
+    export_prefix: &str,
+) -> DescriptorLayout {
+    let mut table = vec![DESCRIPTOR_VERSION, callsites as u32, window as u32];
+    for idx in 0..callsites {
+        match slots.get(&idx) {
+            Some(globals) => {
+                table.extend(globals.iter().map(|g| global_index(module, *g)));
+                table.extend(std::iter::repeat(NO_GLOBAL).take(window - globals.len()));
+            }
+            None => table.extend(std::iter::repeat(NO_GLOBAL).take(window)),
+        }
+    }
+    let bytes: Vec<u8> = table.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+    let pages = std::cmp::max(1, (bytes.len() as u64 + 65535) / 65536) as u32;
+    let memory = module.memories.add_local(false, pages, Some(pages));
+    module.data.add(
+        DataKind::Active(ActiveData {
+            memory,
+            location: ActiveDataLocation::Absolute(0),
+        }),
+        bytes,
+    );
+    let count = module.globals.add_local(
+        ValType::I32,
+        false,
+        InitExpr::Value(ir::Value::I32(callsites as i32)),
+    );
+
+    let layout = DescriptorLayout {
+        count_export: format!("{}profiling_callsites", export_prefix),
+        memory_export: format!("{}profiling_descriptors", export_prefix),
+        version: DESCRIPTOR_VERSION,
+    };
+    module.exports.add(&layout.count_export, count);
+    module.exports.add(&layout.memory_export, memory);
+    layout
+}
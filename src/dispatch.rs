@@ -0,0 +1,160 @@
+use crate::callsites::Callsite;
+use crate::report::func_name;
+use crate::symbolize::demangle;
+use std::collections::BTreeMap;
+use walrus::ir::*;
+use walrus::*;
+
+// Offset of the first method in a Rust trait object's vtable on wasm32: the
+// drop glue, size and alignment come first
+const RUST_VTABLE_METHODS: u32 = 12;
+// Names of the functions that call closures: the closure bodies themselves
+// (`{{closure}}`), the Fn* trait shims, and C++ std::function's invoker
+const CLOSURE_MARKERS: &[&str] = &[
+    "{{closure}}",
+    "core::ops::function::Fn",
+    "call_once",
+    "call_mut",
+    "std::function",
+    "std::__2::function",
+    "_M_invoke",
+    "__invoke",
+];
+
+// How the program got to a call_indirect, as far as its bytecode tells
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DispatchKind {
+    // A method slot loaded from a vtable: C++ virtual calls and Rust `dyn` calls
+    Vtable,
+    // An entry of an array of function pointers, or a function pointer field of a
+    // struct, as C dispatch tables do
+    FunctionPointerTable,
+    // A call in a closure body, or through one of the shims that call closures
+    Closure,
+    // A function pointer passed in, or an index computed some other way
+    Unknown,
+}
+
+impl DispatchKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DispatchKind::Vtable => "vtable",
+            DispatchKind::FunctionPointerTable => "fn-table",
+            DispatchKind::Closure => "closure",
+            DispatchKind::Unknown => "other",
+        }
+    }
+
+    // What the kind usually means for --window and --slot-policy
+    pub fn hint(&self) -> &'static str {
+        match self {
+            DispatchKind::Vtable => "few implementations per call, a small window catches them",
+            DispatchKind::FunctionPointerTable => {
+                "as many targets as the table has entries, size the window to it"
+            }
+            DispatchKind::Closure => {
+                "the targets change as the program runs, try --slot-policy lru"
+            }
+            DispatchKind::Unknown => "",
+        }
+    }
+}
+
+fn is_closure_caller(module: &Module, func: FunctionId) -> bool {
+    let name = func_name(module, func);
+    let name = demangle(&name).unwrap_or(name);
+    CLOSURE_MARKERS.iter().any(|marker| name.contains(marker))
+}
+
+/*
+ * Classify the table index of the call_indirect at `seq[pos]` by the code
+ * that computed it, right before the call: a load from a loaded pointer
+ * (`this->vptr[n]`) or a method slot of a Rust vtable is a vtable call, a
+ * load from `base + i * 4` or a struct field a function pointer table, and a
+ * local is followed back to the store into it in the same block.
+ */
+fn classify_index(seq: &[(Instr, InstrLocId)], pos: usize) -> DispatchKind {
+    let mut producer = match pos.checked_sub(1) {
+        Some(producer) => producer,
+        None => return DispatchKind::Unknown,
+    };
+    if let Instr::LocalGet(get) = &seq[producer].0 {
+        let set = seq[..producer].iter().rposition(|(instr, _)| match instr {
+            Instr::LocalSet(set) => set.local == get.local,
+            Instr::LocalTee(tee) => tee.local == get.local,
+            _ => false,
+        });
+        producer = match set {
+            Some(set) if set > 0 => set - 1,
+            // A parameter, or a local set somewhere else
+            _ => return DispatchKind::Unknown,
+        };
+    }
+    let load = match &seq[producer].0 {
+        Instr::Load(load) if producer > 0 => load,
+        _ => return DispatchKind::Unknown,
+    };
+    match &seq[producer - 1].0 {
+        Instr::Load(_) => DispatchKind::Vtable,
+        Instr::LocalGet(_) if load.arg.offset >= RUST_VTABLE_METHODS => DispatchKind::Vtable,
+        Instr::LocalGet(_) => DispatchKind::FunctionPointerTable,
+        Instr::Binop(Binop {
+            op: BinaryOp::I32Add,
+        }) => DispatchKind::FunctionPointerTable,
+        _ => DispatchKind::Unknown,
+    }
+}
+
+/*
+ * The dispatch pattern behind each of `callsites` (see enumerate_callsites),
+ * in the same order. Closures are told apart by the name section (a module
+ * without names has none), the rest by the instructions that compute the
+ * table index. Heuristics both: an optimizer that keeps the index on the
+ * stack across blocks, or spills it, leaves the callsite as Unknown.
+ */
+pub fn dispatch_kinds(module: &Module, callsites: &[Callsite]) -> Vec<DispatchKind> {
+    let mut kinds: BTreeMap<(FunctionId, InstrLocId), DispatchKind> = BTreeMap::new();
+    for (id, func) in module.funcs.iter_local() {
+        let closure = is_closure_caller(module, id);
+        let mut seqs_to_process: Vec<InstrSeqId> = vec![func.entry_block()];
+        while let Some(current_seq) = seqs_to_process.pop() {
+            let seq = &func.block(current_seq).instrs;
+            for (pos, (instr, loc)) in seq.iter().enumerate() {
+                match instr {
+                    Instr::CallIndirect(_) => {
+                        let kind = if closure {
+                            DispatchKind::Closure
+                        } else {
+                            classify_index(seq, pos)
+                        };
+                        kinds.insert((id, *loc), kind);
+                    }
+                    Instr::Block(b) => seqs_to_process.push(b.seq),
+                    Instr::Loop(l) => seqs_to_process.push(l.seq),
+                    Instr::IfElse(if_else) => {
+                        seqs_to_process.push(if_else.consequent);
+                        seqs_to_process.push(if_else.alternative);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    callsites
+        .iter()
+        .map(|callsite| kinds[&(callsite.func, callsite.loc)])
+        .collect()
+}
+
+// How many callsites of each kind there are, with what that means for them
+pub fn dispatch_summary(kinds: &[DispatchKind]) {
+    let mut counts: BTreeMap<DispatchKind, usize> = BTreeMap::new();
+    for kind in kinds {
+        *counts.entry(*kind).or_insert(0) += 1;
+    }
+    println!("== Dispatch patterns ==");
+    for (kind, count) in counts {
+        let line = format!("{:>8}  {:<10} {}", count, kind.name(), kind.hint());
+        println!("{}", line.trim_end());
+    }
+}
@@ -0,0 +1,130 @@
+use gimli::{EndianSlice, LittleEndian};
+use std::collections::HashMap;
+use walrus::ir::InstrLocId;
+use walrus::LocalFunction;
+use wasmparser::{Parser, Payload};
+
+type Reader<'a> = EndianSlice<'a, LittleEndian>;
+
+/*
+ * Source locations from a module's DWARF line tables, so report output can
+ * point at the source behind a callsite or function. Wasm DWARF addresses
+ * are offsets into the code section, and walrus keeps the offset of every
+ * parsed instruction in the original binary (InstrLocId), so all that's
+ * needed to join the two is where the code section starts.
+ */
+pub struct SourceMap {
+    code_start: u64,
+    // (address, file and line), sorted by address. `None` ends a sequence.
+    rows: Vec<(u64, Option<(String, u64)>)>,
+}
+
+fn file_path(
+    dwarf: &gimli::Dwarf<Reader>,
+    unit: &gimli::Unit<Reader>,
+    header: &gimli::LineProgramHeader<Reader>,
+    file: &gimli::FileEntry<Reader>,
+) -> gimli::Result<String> {
+    let name = dwarf.attr_string(unit, file.path_name())?;
+    let name = name.to_string_lossy();
+    if name.starts_with('/') {
+        return Ok(name.to_string());
+    }
+    match file.directory(header) {
+        Some(dir) => Ok(format!(
+            "{}/{}",
+            dwarf.attr_string(unit, dir)?.to_string_lossy(),
+            name
+        )),
+        None => Ok(name.to_string()),
+    }
+}
+
+fn line_rows(dwarf: &gimli::Dwarf<Reader>) -> gimli::Result<Vec<(u64, Option<(String, u64)>)>> {
+    let mut rows = vec![];
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        let program = match unit.line_program.clone() {
+            Some(program) => program,
+            None => continue,
+        };
+        let mut program_rows = program.rows();
+        while let Some((header, row)) = program_rows.next_row()? {
+            if row.end_sequence() {
+                rows.push((row.address(), None));
+                continue;
+            }
+            if let Some(file) = row.file(header) {
+                let line = row.line().map(|line| line.get()).unwrap_or(0);
+                rows.push((
+                    row.address(),
+                    Some((file_path(dwarf, &unit, header, file)?, line)),
+                ));
+            }
+        }
+    }
+    // A sequence may start where the previous one ended
+    rows.sort_by_key(|(addr, row)| (*addr, row.is_some()));
+    Ok(rows)
+}
+
+// `name at file:line`, when the location is known
+pub fn annotate(name: String, location: Option<String>) -> String {
+    match location {
+        Some(location) => format!("{} at {}", name, location),
+        None => name,
+    }
+}
+
+impl SourceMap {
+    // None if the module has no line tables (or they don't parse)
+    pub fn load(wasm: &[u8]) -> Option<SourceMap> {
+        let mut sections: HashMap<&str, &[u8]> = HashMap::new();
+        let mut code_start = None;
+        for payload in Parser::new(0).parse_all(wasm) {
+            match payload.ok()? {
+                Payload::CodeSectionStart { range, .. } => code_start = Some(range.start as u64),
+                Payload::CustomSection(reader) if reader.name().starts_with(".debug_") => {
+                    sections.insert(reader.name(), reader.data());
+                }
+                _ => (),
+            }
+        }
+        if !sections.contains_key(".debug_line") {
+            return None;
+        }
+        let dwarf = gimli::Dwarf::load(|id| -> gimli::Result<Reader> {
+            let data = sections.get(id.name()).cloned().unwrap_or(&[]);
+            Ok(EndianSlice::new(data, LittleEndian))
+        })
+        .ok()?;
+        Some(SourceMap {
+            code_start: code_start?,
+            rows: line_rows(&dwarf).ok()?,
+        })
+    }
+
+    // File and line of the instruction at `loc` in the original binary
+    pub fn lookup(&self, loc: InstrLocId) -> Option<(&str, u64)> {
+        if loc.is_default() {
+            return None;
+        }
+        let addr = (loc.data() as u64).checked_sub(self.code_start)?;
+        let idx = self.rows.partition_point(|(row_addr, _)| *row_addr <= addr);
+        let (_, row) = self.rows.get(idx.checked_sub(1)?)?;
+        row.as_ref().map(|(file, line)| (file.as_str(), *line))
+    }
+
+    // "file:line" of the instruction at `loc`
+    pub fn locate(&self, loc: InstrLocId) -> Option<String> {
+        self.lookup(loc)
+            .map(|(file, line)| format!("{}:{}", file, line))
+    }
+
+    // Where the function's first instruction came from
+    pub fn locate_function(&self, func: &LocalFunction) -> Option<String> {
+        let (_, loc) = func.block(func.entry_block()).instrs.first()?;
+        self.locate(*loc)
+    }
+}
@@ -0,0 +1,77 @@
+use walrus::ir::*;
+use walrus::*;
+
+// Marks a module built for dynamic linking (position-independent code)
+pub const DYLINK_SECTIONS: &[&str] = &["dylink.0", "dylink"];
+// Where the loader placed the module's part of the shared function table
+pub const TABLE_BASE: &str = "__table_base";
+
+/*
+ * The imported global a position-independent module's table indices are
+ * relative to: Emscripten and wasi-sdk side modules (and main modules built
+ * with -fPIC) carry a dylink.0 section and place their element segments at
+ * `global.get $__table_base`, which the loader picks at instantiation. None
+ * for any other module.
+ */
+pub fn table_base(module: &Module) -> Option<GlobalId> {
+    if !module
+        .customs
+        .iter()
+        .any(|(_, section)| DYLINK_SECTIONS.contains(&section.name()))
+    {
+        return None;
+    }
+    module.imports.iter().find_map(|import| match import.kind {
+        ImportKind::Global(global)
+            if import.name == TABLE_BASE && module.globals.get(global).ty == ValType::I32 =>
+        {
+            Some(global)
+        }
+        _ => None,
+    })
+}
+
+/*
+ * Make a profiling stub see base-relative table indices: the index is
+ * rebased before anything records it, and the base added back for the
+ * call_indirect itself. Must run after every pass that adds recording to the
+ * stubs, which all go in front of the call_indirect.
+ */
+pub fn rebase_stub(module: &mut Module, stub: FunctionId, base: GlobalId) {
+    let func = module.funcs.get_mut(stub).kind.unwrap_local_mut();
+    let args = func.args.clone();
+    let indirect_call_value = args[args.len() - 2];
+    let builder = func.builder_mut();
+    let mut body = builder.func_body();
+    let call = body
+        .instrs()
+        .iter()
+        .rposition(|(instr, _)| matches!(instr, Instr::CallIndirect(_)))
+        .expect("profiling stub without a call_indirect");
+    body.instr_at(
+        call,
+        Binop {
+            op: BinaryOp::I32Add,
+        },
+    );
+    body.instr_at(call, GlobalGet { global: base });
+    body.instr_at(
+        0,
+        LocalSet {
+            local: indirect_call_value,
+        },
+    );
+    body.instr_at(
+        0,
+        Binop {
+            op: BinaryOp::I32Sub,
+        },
+    );
+    body.instr_at(0, GlobalGet { global: base });
+    body.instr_at(
+        0,
+        LocalGet {
+            local: indirect_call_value,
+        },
+    );
+}
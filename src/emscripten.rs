@@ -0,0 +1,46 @@
+use std::collections::HashMap;
+use walrus::*;
+
+/*
+ * Emscripten routes every call that may throw or longjmp (C++ exceptions,
+ * setjmp/longjmp) through `env.invoke_{sig}(index, args...)` imports: the JS
+ * side calls table[index](args...) inside a try/catch. Its function table is
+ * exported (__indirect_function_table) and grown and filled from JS by
+ * addFunction and dynamic linking, so the element segments are only where
+ * the table starts out.
+ */
+pub const TABLE_EXPORT: &str = "__indirect_function_table";
+
+fn is_invoke(import: &Import) -> bool {
+    import.module == "env" && import.name.starts_with("invoke_")
+}
+
+// The invoke_* trampolines, with the signature of the table entries each one calls
+pub fn invoke_imports(module: &Module) -> HashMap<FunctionId, (Vec<ValType>, Vec<ValType>)> {
+    module
+        .imports
+        .iter()
+        .filter(|import| is_invoke(import))
+        .filter_map(|import| match import.kind {
+            ImportKind::Function(f_id) => {
+                let ty = module.types.get(module.funcs.get(f_id).ty());
+                match ty.params().split_first() {
+                    Some((ValType::I32, params)) => {
+                        Some((f_id, (params.to_vec(), ty.results().to_vec())))
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Whether the module looks like it came out of Emscripten
+pub fn looks_like_emscripten(module: &Module) -> bool {
+    module.imports.iter().any(is_invoke)
+        || module
+            .exports
+            .iter()
+            .any(|export| export.name == TABLE_EXPORT)
+}
@@ -0,0 +1,173 @@
+use crate::callsites::Callsite;
+use crate::counters::CounterPolicy;
+use crate::manifest::EntryPoint;
+use crate::selfcheck::instrs;
+use crate::Profile;
+use std::collections::HashSet;
+use walrus::ir::*;
+use walrus::*;
+
+// The exported functions, numbered in export order (profiling_entry_{id})
+pub fn enumerate_entries(module: &Module) -> Vec<(FunctionId, EntryPoint)> {
+    module
+        .exports
+        .iter()
+        .filter_map(|export| match export.item {
+            ExportItem::Function(func) => Some((func, export.name.clone())),
+            _ => None,
+        })
+        .enumerate()
+        .map(|(id, (func, name))| (func, EntryPoint { id, name }))
+        .collect()
+}
+
+/*
+ * Record which export the host invoked: every exported function is replaced
+ * in the export section by a wrapper that stores the export's id in the
+ * {prefix}profiling_entry tag (-1 until the first call), bumps
+ * {prefix}profiling_entry_{id} and forwards the call. Calls from inside the
+ * module go straight to the function, so only the host's invocations count.
+ * A host that dumps the globals after each invocation gets profiles tagged
+ * with the handler they belong to, see merge::merge_into and segment_by_entry.
+ */
+pub fn instrument_entries(
+    module: &mut Module,
+    entries: &[(FunctionId, EntryPoint)],
+    export_prefix: &str,
+    policy: &CounterPolicy,
+) -> Vec<EntryPoint> {
+    let tag = module
+        .globals
+        .add_local(ValType::I32, true, InitExpr::Value(Value::I32(-1)));
+    module
+        .exports
+        .add(&format!("{}profiling_entry", export_prefix), tag);
+
+    for (func, entry) in entries {
+        let ty = module.types.get(module.funcs.get(*func).ty()).clone();
+        let counter = policy.add_counter(module);
+
+        let mut stub = FunctionBuilder::new(&mut module.types, ty.params(), ty.results());
+        stub.name(format!("entry_stub_{}", entry.id));
+        let params: Vec<LocalId> = ty.params().iter().map(|p| module.locals.add(*p)).collect();
+        let mut body = stub.func_body();
+        body.i32_const(entry.id as i32).global_set(tag);
+        for instr in policy.increment(counter) {
+            body.instr(instr);
+        }
+        for param in &params {
+            body.local_get(*param);
+        }
+        body.call(*func);
+        let stub = stub.finish(params, &mut module.funcs);
+        let export = module.exports.iter_mut().find(|e| e.name == entry.name);
+        export.unwrap().item = ExportItem::Function(stub);
+
+        module.exports.add(
+            &format!("{}profiling_entry_{}", export_prefix, entry.id),
+            counter,
+        );
+    }
+    println!("Instrumented {} entry points", entries.len());
+    entries.iter().map(|(_, entry)| entry.clone()).collect()
+}
+
+/*
+ * The functions `root` can end up running: whatever it calls or takes a
+ * reference to, transitively, and once any of those makes an indirect call,
+ * everything in the tables.
+ */
+pub fn reachable_functions(module: &Module, root: FunctionId) -> HashSet<FunctionId> {
+    let mut reached: HashSet<FunctionId> = HashSet::new();
+    reached.insert(root);
+    let mut tables_reached = false;
+    let mut to_visit = vec![root];
+    while let Some(id) = to_visit.pop() {
+        let func = match &module.funcs.get(id).kind {
+            FunctionKind::Local(func) => func,
+            _ => continue,
+        };
+        let mut callees = vec![];
+        for instr in instrs(func) {
+            match instr {
+                Instr::Call(call) => callees.push(call.func),
+                Instr::RefFunc(ref_func) => callees.push(ref_func.func),
+                Instr::CallIndirect(_) if !tables_reached => {
+                    tables_reached = true;
+                    for elem in module.elements.iter() {
+                        callees.extend(elem.members.iter().flatten());
+                    }
+                }
+                _ => (),
+            }
+        }
+        for callee in callees {
+            if reached.insert(callee) {
+                to_visit.push(callee);
+            }
+        }
+    }
+    reached
+}
+
+/*
+ * --entry-point (optimize mode): devirtualize with only what one handler
+ * called, since handlers tend to exercise different targets and the merged
+ * slots make every shared callsite polymorphic. The callsite data is that
+ * entry's share of the profile (Profile::by_entry), or the whole profile
+ * when it is a segment tagged with the entry (merge::segment_by_entry).
+ * Only the callsites the handler can reach keep it; the rest are left
+ * indirect, since its data says nothing about the code other handlers run.
+ */
+pub fn select_entry_point(
+    module: &Module,
+    name: &str,
+    callsites: &[Callsite],
+    profile: &mut Profile,
+) -> Result<(), String> {
+    let entries = enumerate_entries(module);
+    let (func, entry) = entries
+        .iter()
+        .find(|(_, entry)| entry.name == name)
+        .ok_or_else(|| format!("--entry-point {}: no exported function by that name", name))?;
+    match (profile.by_entry.remove(&entry.id), profile.entry) {
+        (Some(map), _) => {
+            profile.map = map;
+            // Counted across every handler
+            profile.target_counts.clear();
+        }
+        (None, Some(tag)) if tag == entry.id => (),
+        (None, Some(tag)) => {
+            let other = entries
+                .get(tag)
+                .map(|(_, other)| other.name.clone())
+                .unwrap_or_else(|| format!("#{}", tag));
+            return Err(format!(
+                "--entry-point {}: the profile was collected on {}",
+                name, other
+            ));
+        }
+        (None, None) => {
+            return Err(format!(
+                "--entry-point {}: the profile has no data for it, collect with --entry-counters",
+                name
+            ))
+        }
+    }
+    let reached = reachable_functions(module, *func);
+    let reachable = |idx: &usize| {
+        callsites
+            .get(*idx)
+            .map_or(false, |callsite| reached.contains(&callsite.func))
+    };
+    let profiled = profile.map.len();
+    profile.map.retain(|idx, _| reachable(idx));
+    profile.target_counts.retain(|idx, _| reachable(idx));
+    println!(
+        "Optimizing for {}: {} of its {} profiled callsites are reachable from it",
+        name,
+        profile.map.len(),
+        profiled
+    );
+    Ok(())
+}
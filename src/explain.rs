@@ -0,0 +1,353 @@
+use crate::callsites::{callsite_keys, enumerate_callsites};
+use crate::pipeline::InstrumentOptions;
+use crate::profilemap::resolve_in_table;
+use crate::report::func_name;
+use crate::schema::MapValue;
+use crate::symbolize::{display_key, display_name};
+use crate::typecompat::func_matches;
+use crate::Profile;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use walrus::*;
+
+// What the pipeline knows about the module when it decides what to do with each callsite
+pub struct Decisions<'a> {
+    pub module: &'a Module,
+    pub table: Option<TableId>,
+    pub map: &'a Option<Profile>,
+    // process_map's verdict for each callsite (optimize mode only)
+    pub modified_map: &'a HashMap<usize, MapValue>,
+    pub tiny_funcs: &'a HashSet<FunctionId>,
+    // wasm-bindgen glue, see bindgen::shim_functions
+    pub shim_funcs: &'a HashSet<FunctionId>,
+    // Not reachable from the --reachable-only roots
+    pub unreachable_funcs: &'a HashSet<FunctionId>,
+    pub entry_funcs: &'a HashSet<FunctionId>,
+    // The profile's slots before --force-devirt and --devirt-static filled some in
+    pub observed: &'a HashMap<usize, Vec<i64>>,
+    pub static_targets: &'a HashMap<usize, (i64, FunctionId)>,
+    // Devirtualized callsites that call their only possible target without a guard
+    pub unguarded: &'a HashSet<usize>,
+    // Devirtualized calls fall back to the call_indirect (--emscripten with a mutable table)
+    pub fallback: bool,
+}
+
+// What happened to a callsite, why, and which kind of rule decided it
+pub struct Verdict {
+    // "instrumented", "indirect", "devirtualized" or "unreachable"
+    pub action: &'static str,
+    // "profile", "override", "static", "threshold" or "safety"
+    pub rule: &'static str,
+    pub reason: String,
+    pub targets: Vec<FunctionId>,
+}
+
+fn verdict(action: &'static str, rule: &'static str, reason: String) -> Verdict {
+    Verdict {
+        action,
+        rule,
+        reason,
+        targets: vec![],
+    }
+}
+
+fn describe_slot(decisions: &Decisions, slot: i64, demangle: bool) -> String {
+    match slot {
+        -1 => "-1 (empty)".to_string(),
+        -2 => "-2 (window overflowed)".to_string(),
+        idx if idx < -2 => format!("{} (invalid)", idx),
+        idx => match decisions
+            .table
+            .and_then(|table| resolve_in_table(decisions.module, table, idx))
+        {
+            Some(func) => format!(
+                "{} -> {}",
+                idx,
+                display_name(decisions.module, func, demangle)
+            ),
+            None => format!("{} -> not in the table", idx),
+        },
+    }
+}
+
+/*
+ * Walk through the same checks the pipeline makes for a callsite, in the same
+ * order, and say which one settled it. Kept next to (rather than inside) the
+ * rewriting loop, so any new check there needs a matching line here.
+ */
+pub fn decide(
+    decisions: &Decisions,
+    options: &InstrumentOptions,
+    idx: usize,
+    key: &str,
+    func: FunctionId,
+    ty: TypeId,
+) -> Verdict {
+    if decisions.tiny_funcs.contains(&func) {
+        return verdict(
+            "indirect",
+            "threshold",
+            format!(
+                "the function has fewer than --min-func-size {} instructions",
+                options.min_func_size
+            ),
+        );
+    }
+    if decisions.shim_funcs.contains(&func) {
+        return verdict(
+            "indirect",
+            "safety",
+            "wasm-bindgen glue, only called from JS".to_string(),
+        );
+    }
+    if decisions.unreachable_funcs.contains(&func) {
+        return verdict(
+            "indirect",
+            "static",
+            "the function is unreachable from the --reachable-only roots".to_string(),
+        );
+    }
+    if options.skip_entry_callsites && decisions.entry_funcs.contains(&func) {
+        return verdict(
+            "indirect",
+            "safety",
+            "entry function (--skip-entry-callsites)".to_string(),
+        );
+    }
+    if decisions.map.is_none() {
+        return verdict("instrumented", "profile", String::new());
+    }
+    if options.retain_callsites.contains(key) {
+        return verdict(
+            "indirect",
+            "override",
+            "listed in --retain-callsites".to_string(),
+        );
+    }
+    if decisions.table.is_none() {
+        return verdict(
+            "indirect",
+            "safety",
+            "no function table to resolve targets against".to_string(),
+        );
+    }
+    let slots = match decisions.map.as_ref().unwrap().map.get(&idx) {
+        Some(slots) => slots,
+        None => {
+            return verdict(
+                "indirect",
+                "profile",
+                "the profile has no data for this callsite".to_string(),
+            )
+        }
+    };
+    match decisions.modified_map.get(&idx) {
+        Some(MapValue {
+            f_id: Some(targets),
+            ..
+        }) => {
+            let rule = if options.force_devirt.iter().any(|(k, _)| k == key) {
+                "override"
+            } else if options.devirt_static
+                && !decisions.observed.contains_key(&idx)
+                && decisions.static_targets.contains_key(&idx)
+            {
+                "static"
+            } else {
+                "profile"
+            };
+            let names: Vec<String> = targets
+                .iter()
+                .map(|f| display_name(decisions.module, *f, options.demangle))
+                .collect();
+            let reason = if decisions.unguarded.contains(&idx) {
+                format!(
+                    "direct call to {}, the only function the callsite can reach",
+                    names.join(", ")
+                )
+            } else {
+                format!(
+                    "guarded direct call to {}, {} otherwise",
+                    names.join(", "),
+                    if decisions.fallback {
+                        "call_indirect"
+                    } else {
+                        "trap"
+                    }
+                )
+            };
+            Verdict {
+                action: "devirtualized",
+                rule,
+                reason,
+                targets: targets.clone(),
+            }
+        }
+        Some(MapValue { f_bool: true, .. }) => verdict(
+            "unreachable",
+            "profile",
+            "every slot is -1 (never executed while profiling)".to_string(),
+        ),
+        _ if decisions.fallback && slots.iter().all(|slot| *slot == -1) => verdict(
+            "indirect",
+            "safety",
+            "never executed while profiling, but the table can change at runtime".to_string(),
+        ),
+        _ if slots.iter().any(|slot| *slot < -2) => verdict(
+            "indirect",
+            "safety",
+            "the profile has invalid slot values".to_string(),
+        ),
+        // Every observed target has another type, process_map dropped them all
+        _ if slots.iter().filter(|slot| **slot >= 0).all(|slot| {
+            let target = decisions
+                .table
+                .and_then(|table| resolve_in_table(decisions.module, table, *slot));
+            target.map_or(false, |target| !func_matches(decisions.module, target, ty))
+        }) && slots.iter().any(|slot| *slot >= 0) =>
+        {
+            verdict(
+                "indirect",
+                "safety",
+                "no observed target has the callsite's type".to_string(),
+            )
+        }
+        _ if slots.iter().any(|slot| *slot >= 0) => verdict(
+            "indirect",
+            "safety",
+            "an observed target isn't in the table".to_string(),
+        ),
+        _ => verdict(
+            "indirect",
+            "threshold",
+            "more targets than the window could track".to_string(),
+        ),
+    }
+}
+
+// The line --explain prints for a verdict
+fn describe(verdict: &Verdict) -> String {
+    match verdict.action {
+        "instrumented" => "instrumented".to_string(),
+        "indirect" => format!("left indirect: {}", verdict.reason),
+        "unreachable" => format!("replaced with unreachable: {}", verdict.reason),
+        action => format!("{}: {}", action, verdict.reason),
+    }
+}
+
+/*
+ * `--explain KEY` / `--explain-all`: print each selected callsite's profile
+ * slots, what they resolve to, which overrides apply and the resulting
+ * disposition.
+ */
+pub fn explain(decisions: &Decisions, options: &InstrumentOptions) {
+    let callsites = enumerate_callsites(decisions.module, decisions.table);
+    let keys = callsite_keys(decisions.module, &callsites);
+    for key in &options.explain {
+        if !keys.contains(key) {
+            println!("--explain: no callsite {} in this module", key);
+        }
+    }
+    for (idx, (callsite, key)) in callsites.iter().zip(keys.iter()).enumerate() {
+        if !options.explain_all && !options.explain.contains(key) {
+            continue;
+        }
+        println!("callsite {} ({}):", idx, display_key(key, options.demangle));
+        if let Some(profile) = decisions.map {
+            match profile.map.get(&idx) {
+                Some(slots) => {
+                    let slots: Vec<String> = slots
+                        .iter()
+                        .map(|slot| describe_slot(decisions, *slot, options.demangle))
+                        .collect();
+                    println!("  slots: {}", slots.join(", "));
+                }
+                None => println!("  slots: none recorded"),
+            }
+            if let Some((_, target)) = options.force_devirt.iter().find(|(k, _)| k == key) {
+                println!("  forced to {} by --force-devirt", target);
+            }
+        }
+        println!(
+            "  {}",
+            describe(&decide(
+                decisions,
+                options,
+                idx,
+                key,
+                callsite.func,
+                callsite.ty
+            ))
+        );
+    }
+}
+
+// One line of the --decisions audit log
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DecisionRecord {
+    pub callsite: usize,
+    pub key: String,
+    pub func: String,
+    // Offset of the call_indirect in the original binary
+    pub offset: u32,
+    // The profile's slots, before any override
+    pub observed: Vec<i64>,
+    // The functions the observed table indices resolve to
+    pub observed_targets: Vec<String>,
+    pub disposition: String,
+    pub targets: Vec<String>,
+    pub rule: String,
+    pub reason: String,
+    // Whether the rewritten callsite can trap where the original didn't
+    pub may_trap: bool,
+}
+
+// What the optimizer did with every callsite, for the --decisions audit log
+pub fn decision_log(decisions: &Decisions, options: &InstrumentOptions) -> Vec<DecisionRecord> {
+    let callsites = enumerate_callsites(decisions.module, decisions.table);
+    let keys = callsite_keys(decisions.module, &callsites);
+    let name = |func| func_name(decisions.module, func);
+    callsites
+        .iter()
+        .zip(keys)
+        .enumerate()
+        .map(|(idx, (callsite, key))| {
+            let observed = decisions.observed.get(&idx).cloned().unwrap_or_default();
+            let observed_targets = observed
+                .iter()
+                .filter_map(|slot| {
+                    decisions
+                        .table
+                        .and_then(|table| resolve_in_table(decisions.module, table, *slot))
+                })
+                .map(name)
+                .collect();
+            let verdict = decide(decisions, options, idx, &key, callsite.func, callsite.ty);
+            DecisionRecord {
+                callsite: idx,
+                func: name(callsite.func),
+                key,
+                offset: callsite.loc.data(),
+                observed,
+                observed_targets,
+                disposition: verdict.action.to_string(),
+                targets: verdict.targets.iter().cloned().map(name).collect(),
+                rule: verdict.rule.to_string(),
+                reason: verdict.reason,
+                may_trap: match verdict.action {
+                    "devirtualized" => !decisions.fallback,
+                    "unreachable" => true,
+                    _ => false,
+                },
+            }
+        })
+        .collect()
+}
+
+pub fn write_decisions(path: &str, records: &[DecisionRecord]) {
+    let mut file = std::fs::File::create(path).unwrap();
+    for record in records {
+        writeln!(file, "{}", serde_json::to_string(record).unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+use crate::blockcounters::enumerate_blocks;
+use crate::contexttree::ContextNode;
+use crate::counters::slot_value;
+use crate::manifest::{ContextTreeLayout, Manifest};
+use crate::profilemap::resolve_table_index;
+use crate::symbolize::{display, display_key, display_name};
+use crate::Profile;
+use serde_json::json;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use walrus::*;
+
+fn target_name(module: Option<&Module>, target: i64, demangle: bool) -> String {
+    match module.and_then(|m| resolve_table_index(m, target).map(|f| display_name(m, f, demangle)))
+    {
+        Some(name) => name,
+        None => format!("table[{}]", target),
+    }
+}
+
+/*
+ * Convert a decoded ring buffer trace into Chrome trace-event JSON.
+ *
+ * There are no timestamps in the trace, so the record index is used as the
+ * time axis. Each callsite gets its own track (tid) and every record becomes a
+ * 1-unit slice named after the target, which makes target shifts over time
+ * easy to spot in chrome://tracing or Perfetto.
+ */
+pub fn trace_to_chrome(
+    records: &[(i32, i32)],
+    manifest: &Manifest,
+    module: Option<&Module>,
+    demangle: bool,
+) -> Value {
+    let mut events = vec![];
+    let keys: HashMap<usize, &str> = manifest
+        .callsites
+        .iter()
+        .map(|c| (c.id, c.key.as_str()))
+        .collect();
+    let mut named = HashSet::new();
+    for (ts, (callsite, target)) in records.iter().enumerate() {
+        if named.insert(*callsite) {
+            let key = keys
+                .get(&(*callsite as usize))
+                .cloned()
+                .unwrap_or("<unknown>");
+            events.push(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": 0,
+                "tid": callsite,
+                "args": { "name": format!("callsite {} ({})", callsite, display_key(key, demangle)) },
+            }));
+        }
+        events.push(json!({
+            "name": target_name(module, slot_value(*target), demangle),
+            "cat": "call_indirect",
+            "ph": "X",
+            "ts": ts,
+            "dur": 1,
+            "pid": 0,
+            "tid": callsite,
+            "args": { "target": slot_value(*target) },
+        }));
+    }
+    json!({ "traceEvents": events, "displayTimeUnit": "ns" })
+}
+
+/*
+ * Convert aggregated counters into Chrome trace-event JSON.
+ *
+ * Aggregated counts have no time dimension either, so functions are laid out
+ * back to back with a duration equal to their invocation count (a bar chart
+ * of the hottest functions), and branch counters become counter tracks.
+ */
+pub fn profile_to_chrome(module: &Module, profile: &Profile, demangle: bool) -> Value {
+    let mut events = vec![];
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+
+    let mut calls = vec![];
+    let mut seen = HashSet::new();
+    for (idx, (f_id, _)) in enumerate_blocks(module, &original_funcs).iter().enumerate() {
+        if seen.insert(*f_id) {
+            if let Some(count) = profile.blocks.get(&idx) {
+                if *count > 0 {
+                    calls.push((*f_id, *count as u64));
+                }
+            }
+        }
+    }
+    calls.sort_by(|a, b| b.1.cmp(&a.1));
+    let mut ts = 0;
+    for (f_id, count) in calls {
+        events.push(json!({
+            "name": display_name(module, f_id, demangle),
+            "cat": "function",
+            "ph": "X",
+            "ts": ts,
+            "dur": count,
+            "pid": 0,
+            "tid": 0,
+            "args": { "calls": count },
+        }));
+        ts += count;
+    }
+
+    let mut branches: Vec<(&usize, &(u64, u64))> = profile.branches.iter().collect();
+    branches.sort();
+    for (idx, (taken, not_taken)) in branches {
+        events.push(json!({
+            "name": format!("branch {}", idx),
+            "ph": "C",
+            "ts": 0,
+            "pid": 1,
+            "args": { "taken": taken, "not_taken": not_taken },
+        }));
+    }
+    json!({ "traceEvents": events, "displayTimeUnit": "ns" })
+}
+
+/*
+ * Convert a decoded calling-context tree (see contexttree) into folded
+ * stacks, one "main;run;dispatch 42" line per calling context, for inferno,
+ * flamegraph.pl and speedscope. There is no timing either, so each line
+ * carries the calls of its last frame and a frame's width is the calls made
+ * in its subtree, which approximates where the time goes. Frames are named
+ * from the original binary's name section when it is given (the tree numbers
+ * its functions in the same order), else from the names the manifest
+ * recorded when instrumenting.
+ */
+pub fn context_to_folded(
+    nodes: &[ContextNode],
+    layout: &ContextTreeLayout,
+    module: Option<&Module>,
+    demangle: bool,
+) -> String {
+    let funcs: Vec<FunctionId> = match module {
+        Some(m) => m.funcs.iter_local().map(|(id, _)| id).collect(),
+        None => vec![],
+    };
+    let name = |func: usize| {
+        let name = match (module, funcs.get(func), layout.functions.get(func)) {
+            (Some(m), Some(id), _) => display_name(m, *id, demangle),
+            (_, _, Some(name)) => display(name, demangle),
+            _ => format!("func_{}", func),
+        };
+        // ';' separates the frames
+        name.replace(';', ":")
+    };
+    let by_id: HashMap<usize, &ContextNode> = nodes.iter().map(|node| (node.id, node)).collect();
+    let mut stacks: BTreeMap<String, u64> = BTreeMap::new();
+    for node in nodes {
+        let mut frames = vec![name(node.func)];
+        let mut parent = node.parent;
+        while let Some(caller) = by_id.get(&parent) {
+            frames.push(name(caller.func));
+            parent = caller.parent;
+        }
+        frames.reverse();
+        *stacks.entry(frames.join(";")).or_insert(0) += node.calls as u64;
+    }
+    stacks
+        .iter()
+        .map(|(stack, calls)| format!("{} {}\n", stack, calls))
+        .collect()
+}
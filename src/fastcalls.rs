@@ -1,3 +1,8 @@
+use crate::analysiscache::{function_indices, AnalysisCache, CachedScan};
+use crate::callgraph::{BitSet, CallGraph};
+use crate::callsites::{entry_functions, table_is_exported};
+use crate::counters::CounterPolicy;
+use crate::importclasses::{CostClass, ImportClasses};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::hash::Hash;
@@ -7,32 +12,54 @@ use walrus::ir::*;
 use walrus::FunctionKind::Import;
 use walrus::*;
 
-#[derive(Debug, Clone, Eq)]
-pub struct FastCallScan {
+// What every function's scan looks calls up in, built once and shared by all of them
+#[derive(Debug)]
+pub struct ScanContext {
+    // Imported function ==> its cost class (invoke_* imports aside)
+    import_classes: HashMap<FunctionId, CostClass>,
+    all_funcs: HashSet<(FunctionId, Type)>,
+    all_types: HashMap<TypeId, Type>,
+    // invoke_* import ==> signature of the table entries it calls (--emscripten)
+    invokes: HashMap<FunctionId, (Vec<ValType>, Vec<ValType>)>,
+    entry_funcs: HashSet<FunctionId>,
+}
+
+#[derive(Debug, Clone)]
+pub struct FastCallScan<'a> {
     is_fastcall: bool,
+    // The worst class of the imports it calls directly
+    class: CostClass,
     // keep track of ambiguous calls
     deps: HashSet<FunctionId>,
     func_id: FunctionId,
-    imported_funcs: HashSet<FunctionId>,
-    all_funcs: HashSet<(FunctionId, Type)>,
-    all_types: HashMap<TypeId, Type>,
-    start_id: FunctionId,
+    context: &'a ScanContext,
 }
 
-impl Hash for FastCallScan {
+impl Hash for FastCallScan<'_> {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.func_id.hash(state);
     }
 }
-impl PartialEq for FastCallScan {
+impl PartialEq for FastCallScan<'_> {
     fn eq(&self, other: &Self) -> bool {
         self.func_id == other.func_id
     }
 }
+impl Eq for FastCallScan<'_> {}
 
-impl VisitorMut for FastCallScan {
+/*
+ * Roughly what a scan holds on to until the slowcall sets are resolved: its
+ * deps, at about twice their size once the hash set's spare capacity is
+ * counted. The call_indirects make this O(functions x table size) for
+ * modules with big tables, so it is what --max-memory budgets.
+ */
+fn scan_bytes(scan: &FastCallScan) -> usize {
+    std::mem::size_of::<FastCallScan>() + scan.deps.capacity() * 2 * std::mem::size_of::<FunctionId>()
+}
+
+impl VisitorMut for FastCallScan<'_> {
     fn visit_instr_mut(&mut self, instr: &mut walrus::ir::Instr, idx: &mut walrus::InstrLocId) {
-        if self.start_id == self.func_id {
+        if self.context.entry_funcs.contains(&self.func_id) {
             self.is_fastcall = false;
             return;
         }
@@ -42,10 +69,11 @@ impl VisitorMut for FastCallScan {
                 // Add all possible targets based on type signature
                 // *Unless* one of those targets is actually recursive!
                 let all: Vec<&FunctionId> = self
+                    .context
                     .all_funcs
                     .iter()
                     // Ignore functions which don't match the call target
-                    .filter(|(x, y)| y == self.all_types.get(&call_indirect.ty).unwrap())
+                    .filter(|(x, y)| y == self.context.all_types.get(&call_indirect.ty).unwrap())
                     .map(|(x, y)| x)
                     .collect();
                 for call in &all {
@@ -62,8 +90,26 @@ impl VisitorMut for FastCallScan {
                 // Recursive calls taint our fastcall pass
                 if self.func_id == idx.func {
                     self.is_fastcall = false;
-                } else if self.imported_funcs.contains(&idx.func) {
-                    self.is_fastcall = false;
+                } else if let Some(class) = self.context.import_classes.get(&idx.func) {
+                    // Free and cheap imports don't leave the fast path
+                    self.class = std::cmp::max(self.class, *class);
+                    if class.is_slow() {
+                        self.is_fastcall = false;
+                    }
+                } else if let Some((params, results)) = self.context.invokes.get(&idx.func) {
+                    // invoke_* calls back into the table, like a call_indirect
+                    let all: Vec<FunctionId> = self
+                        .context
+                        .all_funcs
+                        .iter()
+                        .filter(|(_, ty)| ty.params() == &params[..] && ty.results() == &results[..])
+                        .map(|(x, _)| *x)
+                        .collect();
+                    if all.contains(&self.func_id) {
+                        self.is_fastcall = false;
+                    } else {
+                        self.deps.extend(all);
+                    }
                 } else {
                     // if the call isn't recursive && isn't a system call, add it as a possible
                     // dependency
@@ -75,82 +121,120 @@ impl VisitorMut for FastCallScan {
     }
 }
 
-fn contains_slowcall(scan: &FastCallScan, slowset: &HashSet<&FastCallScan>) -> bool {
-    let mut slow_set = HashSet::new();
-    for call in slowset {
-        slow_set.insert(call.func_id);
-    }
-    scan.deps
-        .iter()
-        .filter(|x| slow_set.contains(x))
-        .collect::<Vec<&FunctionId>>()
-        .len()
-        > 0
+fn type_lookup(ty_id: TypeId, module: &Module) -> Type {
+    module.types.get(ty_id).clone()
 }
 
-fn check_fastcall_deps(scan: &FastCallScan, fastset: &HashSet<&FastCallScan>) -> bool {
-    let mut fset = HashSet::new();
-    for call in fastset {
-        fset.insert(call.func_id);
-    }
-    scan.deps
+// Everything besides its body a function's scan depends on, by function index
+fn scan_context(
+    module: &Module,
+    indices: &HashMap<FunctionId, u32>,
+    import_classes: &HashMap<FunctionId, CostClass>,
+    call_table: &HashSet<(FunctionId, Type)>,
+    invokes: &HashMap<FunctionId, (Vec<ValType>, Vec<ValType>)>,
+    entry_funcs: &HashSet<FunctionId>,
+) -> String {
+    let sorted = |funcs: &mut dyn Iterator<Item = &FunctionId>| {
+        let mut funcs: Vec<u32> = funcs.map(|f_id| indices[f_id]).collect();
+        funcs.sort_unstable();
+        funcs
+    };
+    let types: Vec<(&[ValType], &[ValType])> = module
+        .types
+        .iter()
+        .map(|ty| (ty.params(), ty.results()))
+        .collect();
+    let mut table: Vec<(u32, &[ValType], &[ValType])> = call_table
         .iter()
-        .filter(|x| fset.contains(x))
-        .collect::<Vec<&FunctionId>>()
-        .len()
-        == scan.deps.len()
+        .map(|(f_id, ty)| (indices[f_id], ty.params(), ty.results()))
+        .collect();
+    table.sort_unstable_by_key(|entry| entry.0);
+    let mut invokes: Vec<(u32, &[ValType], &[ValType])> = invokes
+        .iter()
+        .map(|(f_id, (params, results))| (indices[f_id], &params[..], &results[..]))
+        .collect();
+    invokes.sort_unstable_by_key(|invoke| invoke.0);
+    let mut imports: Vec<(u32, CostClass)> = import_classes
+        .iter()
+        .map(|(f_id, class)| (indices[f_id], *class))
+        .collect();
+    imports.sort_unstable();
+    let context = format!(
+        "{:?}{:?}{:?}{:?}{:?}",
+        types,
+        table,
+        imports,
+        invokes,
+        sorted(&mut entry_funcs.iter())
+    );
+    crate::manifest::fingerprint(context.as_bytes())
 }
 
-fn type_lookup(ty_id: TypeId, module: &Module) -> Type {
-    module.types.get(ty_id).clone()
+pub struct SlowcallAnalysis {
+    pub slowcalls: HashSet<FunctionId>,
+    // Local function ==> the worst cost class of the imports it may end up calling
+    pub classes: HashMap<FunctionId, CostClass>,
 }
 
-pub fn compute_slowcalls(module: &mut Module) -> HashSet<FunctionId> {
+/*
+ * With `emscripten`, the invoke_* imports aren't treated as opaque host
+ * calls: the host just calls the table entry at their first argument, so
+ * they count as an indirect call of their remaining signature. The entries
+ * of an exported table are slowcalls, like the entry functions. With a
+ * `cache`, functions it has a scan of aren't scanned again. Once the scans
+ * hold more than `max_memory` bytes (see scan_bytes), the functions scanned
+ * after that with any deps are taken to be slowcalls without keeping their
+ * deps: more stubs than needed, but the analysis finishes (and their class
+ * only counts the imports they call themselves).
+ *
+ * Calls to imports go by their cost class in `classes`: free and cheap ones
+ * leave the caller a fastcall, slow and forbidden ones make it a slowcall.
+ * Every function is also classified with the worst class it can reach
+ * along the call graph, table entries included.
+ */
+pub fn compute_slowcalls(
+    module: &mut Module,
+    table: Option<TableId>,
+    emscripten: bool,
+    classes: &ImportClasses,
+    mut cache: Option<&mut AnalysisCache>,
+    max_memory: Option<usize>,
+) -> SlowcallAnalysis {
     let mut set = HashSet::new();
+    let invokes = if emscripten {
+        crate::emscripten::invoke_imports(module)
+    } else {
+        HashMap::new()
+    };
 
     // Get the WASI/system call func ids
-    let mut imported_funcs = HashSet::new();
+    let mut import_classes = HashMap::new();
     module.imports.iter().for_each(|func| match func.kind {
         ImportKind::Function(f_id) => {
-            // We optimize out fd_write in most of our benchmarks + proc_exit
-            if func.name != "proc_exit" && func.name != "fd_write" {
-                imported_funcs.insert(f_id);
+            if !invokes.contains_key(&f_id) {
+                import_classes.insert(f_id, classes.class(&func.module, &func.name));
             }
         }
         _ => (),
     });
 
 
-    // the "_start" func also cannot be optimized
-    // For some bizarre reason, this functionality is broken in walrus
-    //let start_id = module.start.unwrap().clone();
-    let start_id: FunctionId = module
-        .exports
-        .iter()
-        .filter(|export| export.name == "_start")
-        .map(|export| {
-            // Get the function id from the export
-            match export.item {
-                ExportItem::Function(f_id) => f_id,
-                _ => panic!("No function id associated with _start!"),
-            }
-        })
-        .collect::<Vec<FunctionId>>()[0];
+    // the "_start" func (and the start section function) also cannot be optimized
+    let mut entry_funcs = entry_functions(module);
 
     // Get the set of possible indirect call targets
     let call_table: HashSet<(FunctionId, Type)> =
-        if let Some(indirect_call_table) = module.tables.main_function_table().unwrap() {
+        if let Some(indirect_call_table) = table {
             module
             .tables
             .get(indirect_call_table)
             .elem_segments
             .iter()
             .map(|x| module.elements.get(*x))
-            .collect::<Vec<&Element>>()[0]
-            .members
-            .iter()
-            .map(|x| {
-                let id = x.unwrap();
+            .flat_map(|e| e.members.iter())
+            // Skip null entries
+            .filter_map(|x| *x)
+            .map(|id| {
                 let func_ty_id = module.funcs.get(id).ty();
                 let ty = type_lookup(func_ty_id, module);
                 (id, ty)
@@ -161,6 +245,16 @@ pub fn compute_slowcalls(module: &mut Module) -> HashSet<FunctionId> {
             HashSet::new()
         };
 
+    // Code outside the module can call any entry of an exported table directly,
+    // so each of them is an entry point as far as VV is concerned
+    if let Some(table) = table.filter(|table| table_is_exported(module, *table)) {
+        println!(
+            "The function table is exported, treating its {} entries as externally reachable",
+            call_table.len()
+        );
+        entry_funcs.extend(call_table.iter().map(|(id, _)| *id));
+    }
+
 
     let types: Vec<(TypeId, Type)> = module
         .types
@@ -172,92 +266,138 @@ pub fn compute_slowcalls(module: &mut Module) -> HashSet<FunctionId> {
         mod_types.insert(ty_id, ty);
     }
 
+    let indices = function_indices(module);
+    let ids: Vec<FunctionId> = module.funcs.iter().map(|func| func.id()).collect();
+    let context = cache.as_ref().map(|_| {
+        scan_context(module, &indices, &import_classes, &call_table, &invokes, &entry_funcs)
+    });
+    let shared = ScanContext {
+        import_classes,
+        all_funcs: call_table,
+        all_types: mod_types,
+        invokes,
+        entry_funcs,
+    };
+
     let mut scan_results = vec![];
-    module.funcs.iter_local_mut().for_each(|(id, func)| {
-        let entry = func.entry_block();
-        let mut scan = FastCallScan {
-            is_fastcall: true,
-            func_id: id,
-            deps: HashSet::new(),
-            imported_funcs: imported_funcs.clone(),
-            all_funcs: call_table.clone(),
-            all_types: mod_types.clone(),
-            start_id: start_id,
+    let mut reused = 0;
+    let mut held = 0;
+    let mut degraded = 0;
+    module.funcs.iter_local_mut().enumerate().for_each(|(nth, (id, func))| {
+        let key = match (&cache, &context) {
+            (Some(cache), Some(context)) => cache.key(context, indices[&id], nth),
+            _ => None,
         };
-        walrus::ir::dfs_pre_order_mut(&mut scan, func, entry);
+        let cached = key.as_ref().and_then(|key| cache.as_ref()?.entries.get(key));
+        let over_budget = max_memory.map_or(false, |budget| held > budget);
+        let mut scan = if let Some(cached) = cached {
+            reused += 1;
+            FastCallScan {
+                is_fastcall: cached.is_fastcall,
+                class: cached.class,
+                func_id: id,
+                deps: cached.deps.iter().map(|idx| ids[*idx as usize]).collect(),
+                context: &shared,
+            }
+        } else {
+            let entry = func.entry_block();
+            let mut scan = FastCallScan {
+                is_fastcall: true,
+                class: CostClass::Free,
+                func_id: id,
+                deps: HashSet::new(),
+                context: &shared,
+            };
+            walrus::ir::dfs_pre_order_mut(&mut scan, func, entry);
+            // Degraded scans aren't what a full analysis finds, so they aren't cached
+            if let (Some(cache), Some(key), false) = (cache.as_mut(), key, over_budget) {
+                let mut deps: Vec<u32> = scan.deps.iter().map(|f_id| indices[f_id]).collect();
+                deps.sort_unstable();
+                cache.entries.insert(
+                    key,
+                    CachedScan { is_fastcall: scan.is_fastcall, class: scan.class, deps },
+                );
+            }
+            scan
+        };
+        if over_budget && !scan.deps.is_empty() {
+            scan.is_fastcall = false;
+            scan.deps = HashSet::new();
+            degraded += 1;
+        }
+        held += scan_bytes(&scan);
         scan_results.push(scan);
     });
+    if degraded > 0 {
+        println!(
+            "The fastcall analysis went over --max-memory, taking {} functions with calls to be slowcalls",
+            degraded
+        );
+    }
+    if cache.is_some() {
+        println!(
+            "Reused the analysis of {} of {} functions",
+            reused,
+            scan_results.len()
+        );
+    }
 
     // Each func is now in one of three states
     // 1) Confirmed to be a fastcall
     // 2) Confirmed to be a slowcall
     // 3) Ambiguous
 
-    // We only want to instrument known *slowcalls*
-    // First, create a separate set of known fastcalls (remove these from the primary set as well)
-    let mut fastcalls: HashSet<&FastCallScan> = scan_results
-        .iter()
-        .filter(|x| x.is_fastcall == true && x.deps.len() == 0)
-        .collect();
-    let mut slowcalls: HashSet<&FastCallScan> = scan_results
-        .iter()
-        .filter(|x| x.is_fastcall == false)
-        .collect();
-
-    // Contains ambiguous calls
-    let mut unknown: HashSet<&FastCallScan> = scan_results
-        .iter()
-        .filter(|x| x.is_fastcall == true && x.deps.len() > 0)
-        .collect();
-    // Next, loop over the set of ambiguous calls, checking to see if any of them can be optimized
-    let mut prev = 0;
-    while unknown.len() != prev {
-        prev = unknown.len();
-        {
-            // Now filter to find the slowcalls
-            let slow = unknown
-                .iter()
-                .filter(|x| contains_slowcall(&x, &slowcalls))
-                .collect::<HashSet<&&FastCallScan>>();
-            for call in &slow {
-                slowcalls.insert(call);
-            }
-
-            // Find the confirmed fastcalls
-            let fast = unknown
-                .iter()
-                .filter(|x| check_fastcall_deps(&x, &fastcalls))
-                .collect::<HashSet<&&FastCallScan>>();
-            for call in fast {
-                fastcalls.insert(call);
-            }
-        }
-        // Adjust which calls we don't know about yet
-        for call in &slowcalls {
-            unknown.remove(call);
-        }
-        for call in &fastcalls {
-            unknown.remove(call);
+    // We only want to instrument known *slowcalls*: a function is a fastcall
+    // if it is one by itself and everything it may call is a fastcall too
+    let nodes: Vec<(FunctionId, &HashSet<FunctionId>)> =
+        scan_results.iter().map(|scan| (scan.func_id, &scan.deps)).collect();
+    let graph = CallGraph::new(&nodes);
+    let mut eligible = BitSet::new(scan_results.len());
+    for (node, scan) in scan_results.iter().enumerate() {
+        if scan.is_fastcall {
+            eligible.insert(node);
         }
     }
-
-    // At this point any remaining calls that are still unknown must be slowcalls
-    for call in &unknown {
-        slowcalls.insert(call);
-    }
-
-    for call in &slowcalls {
-        set.insert(call.func_id);
+    // Whatever isn't settled (calls a slowcall, or is in a cycle) must be a slowcall
+    let fastcalls = graph.settled(&eligible);
+    for (node, scan) in scan_results.iter().enumerate() {
+        if !fastcalls.contains(node) {
+            set.insert(scan.func_id);
+        }
     }
 
     // Note these numbers are close but don't match *exactly* to VV's output on the same binary
     println!(
         "Speculatively identified {} fastcalls and {} slowcalls",
-        fastcalls.len(),
-        slowcalls.len()
+        fastcalls.count(),
+        set.len()
     );
 
-    set
+    // The imports in the table are called through call_indirects, so they're deps
+    let mut reaches: Vec<CostClass> = scan_results
+        .iter()
+        .map(|scan| {
+            scan.deps
+                .iter()
+                .filter_map(|dep| shared.import_classes.get(dep))
+                .fold(scan.class, |worst, class| std::cmp::max(worst, *class))
+        })
+        .collect();
+    graph.propagate_max(&mut reaches);
+    let mut counts = [0; 4];
+    for class in &reaches {
+        counts[*class as usize] += 1;
+    }
+    println!(
+        "Functions by the worst import they reach: {} free, {} cheap, {} slow, {} forbidden",
+        counts[0], counts[1], counts[2], counts[3]
+    );
+    let classes = graph.funcs.iter().cloned().zip(reaches).collect();
+
+    SlowcallAnalysis {
+        slowcalls: set,
+        classes,
+    }
 }
 
 struct CallScanner {
@@ -286,12 +426,14 @@ impl VisitorMut for CallScanner {
  * 1) Generate a new function stub for each slowcall
  *  1.1) Each function stub must increment a global counter
  * 2) Replace all function call points with a call to our stub instead
+ * Returns each slowcall's stub, see redirect_table_entries.
  */
 pub fn generate_slowcall_stubs(
     module: &mut Module,
     slowcalls: &HashSet<FunctionId>,
     slowcall_ctr: &GlobalId,
-) -> () {
+    policy: &CounterPolicy,
+) -> HashMap<FunctionId, FunctionId> {
     let mut func_mapping = HashMap::new();
     let mut call_stub_ctr = 0;
     for func in slowcalls {
@@ -309,11 +451,9 @@ pub fn generate_slowcall_stubs(
         let mut func_body = call_stub.func_body();
 
         // Increment the slowcall ctr
-        func_body
-            .global_get(*slowcall_ctr)
-            .i32_const(1)
-            .binop(BinaryOp::I32Add)
-            .global_set(*slowcall_ctr);
+        for instr in policy.increment(*slowcall_ctr) {
+            func_body.instr(instr);
+        }
 
         for idx in 0..(param_locals.len()) {
             func_body.local_get(param_locals[idx]);
@@ -325,10 +465,44 @@ pub fn generate_slowcall_stubs(
     }
 
     // Now that we have generated the stubs, we need to  replace the actual calls in the program
+    redirect_calls(module, &func_mapping);
+    func_mapping
+}
+
+/*
+ * Route the call_indirects to slowcalls through their stubs too
+ * (--count-indirect-slowcalls): every active and passive element segment
+ * entry that is a key of `mapping` becomes its stub, which has the same
+ * type, so the table indices stay put. Declared segments are left alone,
+ * they only make the ref.func instructions valid. Entries the host writes
+ * into the table at runtime aren't counted. Returns how many entries were
+ * patched.
+ */
+pub fn redirect_table_entries(
+    module: &mut Module,
+    mapping: &HashMap<FunctionId, FunctionId>,
+) -> usize {
+    let mut patched = 0;
+    for elem in module.elements.iter_mut() {
+        if matches!(elem.kind, ElementKind::Declared) {
+            continue;
+        }
+        for member in elem.members.iter_mut().flatten() {
+            if let Some(stub) = mapping.get(member) {
+                *member = *stub;
+                patched += 1;
+            }
+        }
+    }
+    patched
+}
+
+// Replace every direct call to a key of `mapping` with a call to its stub (except in the stub itself)
+pub fn redirect_calls(module: &mut Module, mapping: &HashMap<FunctionId, FunctionId>) -> () {
     module.funcs.iter_local_mut().for_each(|(id, func)| {
         let entry = func.entry_block();
         let mut scan = CallScanner {
-            mapping: func_mapping.clone(),
+            mapping: mapping.clone(),
             curr_func: id,
         };
         walrus::ir::dfs_pre_order_mut(&mut scan, func, entry);
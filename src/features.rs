@@ -0,0 +1,237 @@
+use std::collections::BTreeSet;
+use wasmparser::{
+    CompositeInnerType, Operator, Parser, Payload, RefType, TypeRef, ValType as WasmValType,
+};
+
+// Proposals walrus always parses
+pub const BASELINE: &[&str] = &[
+    "mvp",
+    "sign_extension",
+    "saturating_float_to_int",
+    "multi_value",
+];
+// Proposals walrus parses unless restricted to stable features, selectable
+// with --enable-feature. All of them are on by default.
+pub const OPTIONAL: &[&str] = &[
+    "bulk_memory",
+    "reference_types",
+    "simd",
+    "threads",
+    "multi_memory",
+];
+
+// CLI spelling of a proposal: `multi-value` for `multi_value`
+pub fn flag_name(feature: &str) -> String {
+    feature.replace('_', "-")
+}
+
+pub fn feature_name(flag: &str) -> String {
+    flag.replace('-', "_")
+}
+
+// The proposals we accept given --enable-feature (none given: all of them)
+fn enabled_set(enabled: &[String]) -> BTreeSet<String> {
+    let optional: Vec<String> = if enabled.is_empty() {
+        OPTIONAL.iter().map(|f| f.to_string()).collect()
+    } else {
+        enabled.iter().map(|f| feature_name(f)).collect()
+    };
+    BASELINE
+        .iter()
+        .map(|f| f.to_string())
+        .chain(optional)
+        .collect()
+}
+
+/*
+ * The walrus parse configuration for --enable-feature. walrus (and the
+ * validator it runs) only has one switch for the optional proposals, so it
+ * is restricted to stable features when none of them are enabled; `check`
+ * enforces the exact set.
+ */
+pub fn module_config(enabled: &[String]) -> walrus::ModuleConfig {
+    let mut config = walrus::ModuleConfig::new();
+    let only_stable = !enabled.is_empty()
+        && !enabled
+            .iter()
+            .any(|f| OPTIONAL.contains(&feature_name(f).as_str()));
+    config.only_stable_features(only_stable);
+    config
+}
+
+// The proposal an instruction comes from, named as in wasmparser
+macro_rules! define_proposal {
+    ($( @$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident ($($ann:tt)*) )*) => {
+        fn proposal(op: &Operator) -> &'static str {
+            match op {
+                $( Operator::$op { .. } => stringify!($proposal), )*
+                _ => "unknown",
+            }
+        }
+    };
+}
+wasmparser::for_each_operator!(define_proposal);
+
+fn val_type(used: &mut BTreeSet<&'static str>, ty: &WasmValType) {
+    match ty {
+        WasmValType::V128 => {
+            used.insert("simd");
+        }
+        WasmValType::Ref(r) if *r == RefType::FUNCREF || *r == RefType::EXTERNREF => {
+            used.insert("reference_types");
+        }
+        WasmValType::Ref(_) => {
+            used.insert("gc");
+        }
+        _ => (),
+    }
+}
+
+fn memory(used: &mut BTreeSet<&'static str>, memories: &mut usize, ty: &wasmparser::MemoryType) {
+    *memories += 1;
+    if ty.shared {
+        used.insert("shared_memory");
+    }
+    if ty.memory64 {
+        used.insert("memory64");
+    }
+}
+
+// 64-bit tables come with memory64; slots and profiles already hold any u32 table index
+fn table(used: &mut BTreeSet<&'static str>, ty: &wasmparser::TableType) {
+    if ty.table64 {
+        used.insert("memory64");
+    }
+}
+
+/*
+ * Every proposal the module uses: from its instructions, type section
+ * (GC types, v128 and reference types in signatures), memories and tags.
+ * Parse errors are left for walrus to report.
+ */
+pub fn detect(wasm: &[u8]) -> Result<BTreeSet<&'static str>, wasmparser::BinaryReaderError> {
+    let mut used = BTreeSet::new();
+    used.insert("mvp");
+    let mut memories = 0;
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload? {
+            Payload::TypeSection(reader) => {
+                for group in reader {
+                    let group = group?;
+                    if group.is_explicit_rec_group() {
+                        used.insert("gc");
+                    }
+                    for sub in group.types() {
+                        if !sub.is_final || sub.supertype_idx.is_some() {
+                            used.insert("gc");
+                        }
+                        match &sub.composite_type.inner {
+                            CompositeInnerType::Func(func) => {
+                                if func.results().len() > 1 {
+                                    used.insert("multi_value");
+                                }
+                                for ty in func.params().iter().chain(func.results()) {
+                                    val_type(&mut used, ty);
+                                }
+                            }
+                            _ => {
+                                used.insert("gc");
+                            }
+                        }
+                    }
+                }
+            }
+            Payload::ImportSection(reader) => {
+                for import in reader {
+                    match import?.ty {
+                        TypeRef::Memory(ty) => memory(&mut used, &mut memories, &ty),
+                        TypeRef::Table(ty) => table(&mut used, &ty),
+                        TypeRef::Tag(_) => {
+                            used.insert("exceptions");
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Payload::TableSection(reader) => {
+                for table_def in reader {
+                    table(&mut used, &table_def?.ty);
+                }
+            }
+            Payload::MemorySection(reader) => {
+                for ty in reader {
+                    memory(&mut used, &mut memories, &ty?);
+                }
+            }
+            Payload::TagSection(_) => {
+                used.insert("exceptions");
+            }
+            Payload::CodeSectionEntry(body) => {
+                let mut reader = body.get_operators_reader()?;
+                while !reader.eof() {
+                    used.insert(proposal(&reader.read()?));
+                }
+            }
+            _ => (),
+        }
+    }
+    if memories > 1 {
+        used.insert("multi_memory");
+    }
+    Ok(used)
+}
+
+// What to do about a proposal we can't process
+fn hint(feature: &str) -> &'static str {
+    match feature {
+        "gc" | "function_references" => "compile without GC types / typed function references",
+        "exceptions" | "legacy_exceptions" => {
+            "compile without wasm exception handling (e.g. -fno-exceptions, or -fwasm-exceptions off)"
+        }
+        "tail_call" => "compile without tail calls (e.g. -mno-tail-call)",
+        "relaxed_simd" => "compile without relaxed SIMD (e.g. -mno-relaxed-simd)",
+        "memory64" => "build for wasm32",
+        "shared_memory" => {
+            "slot globals are per instance, so each thread records its own profile; pass --allow-shared-memory and combine the per-thread dumps"
+        }
+        _ => "not supported by walrus",
+    }
+}
+
+/*
+ * Fail fast, listing what the module uses and what would make it
+ * processable, instead of panicking somewhere inside a pass.
+ */
+pub fn check(wasm: &[u8], enabled: &[String], allow_shared_memory: bool) -> Result<(), String> {
+    let used = match detect(wasm) {
+        Ok(used) => used,
+        // Let walrus produce its (usually better) parse error
+        Err(_) => return Ok(()),
+    };
+    let accepted = enabled_set(enabled);
+    let unsupported: Vec<&str> = used
+        .iter()
+        .filter(|f| !accepted.contains(**f))
+        .filter(|f| !(allow_shared_memory && **f == "shared_memory"))
+        .cloned()
+        .collect();
+    if unsupported.is_empty() {
+        return Ok(());
+    }
+    let mut msg = format!(
+        "module uses proposals this tool can't process (detected: {})",
+        used.iter().cloned().collect::<Vec<&str>>().join(", ")
+    );
+    for feature in unsupported {
+        if OPTIONAL.contains(&feature) {
+            msg.push_str(&format!(
+                "\n  {}: pass --enable-feature {}",
+                feature,
+                flag_name(feature)
+            ));
+        } else {
+            msg.push_str(&format!("\n  {}: {}", feature, hint(feature)));
+        }
+    }
+    Err(msg)
+}
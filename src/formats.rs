@@ -0,0 +1,475 @@
+use crate::counters::slot_value;
+use crate::merge::most_recent;
+use crate::slotmemory::{decode_slot_memory, encode_slot_memory};
+use crate::valueprofile::merge_votes;
+use crate::Profile;
+use std::collections::{BTreeMap, HashMap};
+
+// On-disk encodings of `Profile`. msgpack is the canonical format.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProfileFormat {
+    Msgpack,
+    Json,
+    Cbor,
+    Csv,
+    Globals,
+    // A raw dump of the --slot-memory memory; only the callsite slots
+    SlotMemory,
+}
+
+pub const PROFILE_FORMATS: &[&str] = &["msgpack", "json", "cbor", "csv", "globals", "slot-memory"];
+
+impl ProfileFormat {
+    pub fn from_name(name: &str) -> Result<ProfileFormat, String> {
+        match name {
+            "msgpack" => Ok(ProfileFormat::Msgpack),
+            "json" => Ok(ProfileFormat::Json),
+            "cbor" => Ok(ProfileFormat::Cbor),
+            "csv" => Ok(ProfileFormat::Csv),
+            "globals" => Ok(ProfileFormat::Globals),
+            "slot-memory" => Ok(ProfileFormat::SlotMemory),
+            _ => Err(format!("unknown profile format: {}", name)),
+        }
+    }
+}
+
+/*
+ * Flat CSV: one `callsite,target,count` row per observed target.
+ *
+ * Targets >= 0 are table indices. A row with target -2 marks a callsite that
+ * saw more targets than the window could hold, and a callsite with only a -1
+ * row was never executed. Only the callsite slots and target counts survive
+ * the round trip. A malformed row is an error naming its line.
+ */
+fn decode_csv(text: &str) -> Result<Profile, String> {
+    let mut rows: BTreeMap<usize, Vec<(i64, u64)>> = BTreeMap::new();
+    for (line_no, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("callsite") {
+            continue;
+        }
+        let malformed = || format!("malformed csv row {}: {}", line_no + 1, line);
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim()).collect();
+        if fields.len() < 2 {
+            return Err(malformed());
+        }
+        let callsite: usize = fields[0].parse().map_err(|_| malformed())?;
+        let target: i64 = fields[1].parse().map_err(|_| malformed())?;
+        let count: u64 = match fields.get(2) {
+            Some(count) if !count.is_empty() => count.parse().map_err(|_| malformed())?,
+            _ => 1,
+        };
+        rows.entry(callsite).or_default().push((target, count));
+    }
+
+    let mut profile = Profile::default();
+    for (callsite, targets) in rows {
+        let slots = if targets.iter().any(|(t, _)| *t == -2) {
+            vec![-2]
+        } else {
+            let mut counts: BTreeMap<i64, u64> = BTreeMap::new();
+            for (target, count) in targets.iter().filter(|(t, c)| *t >= 0 && *c > 0) {
+                *counts.entry(*target).or_insert(0) += count;
+            }
+            // Hottest targets first
+            let mut counts: Vec<(i64, u64)> = counts.into_iter().collect();
+            counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            if counts.is_empty() {
+                vec![-1]
+            } else {
+                let slots = counts.iter().map(|(t, _)| *t).collect();
+                profile.target_counts.insert(callsite, counts);
+                slots
+            }
+        };
+        profile.map.insert(callsite, slots);
+    }
+    Ok(profile)
+}
+
+fn encode_csv(profile: &Profile) -> Vec<u8> {
+    if !profile.blocks.is_empty()
+        || !profile.branches.is_empty()
+        || !profile.br_tables.is_empty()
+        || !profile.imports.is_empty()
+        || !profile.values.is_empty()
+        || !profile.memory.is_empty()
+        || !profile.loops.is_empty()
+        || !profile.global_accesses.is_empty()
+        || !profile.data_accesses.is_empty()
+        || !profile.entries.is_empty()
+        || !profile.by_entry.is_empty()
+        || !profile.distinct_targets.is_empty()
+    {
+        println!("warning: csv only holds callsite targets, dropping all other counters");
+    }
+    let mut out = String::from("callsite,target,count\n");
+    let mut callsites: Vec<(&usize, &Vec<i64>)> = profile.map.iter().collect();
+    callsites.sort();
+    for (callsite, slots) in callsites {
+        let targets: Vec<&i64> = slots.iter().filter(|t| **t >= 0).collect();
+        if !targets.is_empty() {
+            // Targets without a recorded count get 1
+            let counts: HashMap<i64, u64> = profile
+                .target_counts
+                .get(callsite)
+                .map(|counts| counts.iter().cloned().collect())
+                .unwrap_or_default();
+            for target in targets {
+                let count = counts.get(target).unwrap_or(&1);
+                out.push_str(&format!("{},{},{}\n", callsite, target, count));
+            }
+        } else if !slots.is_empty() && slots.iter().all(|t| *t == -2) {
+            out.push_str(&format!("{},-2,0\n", callsite));
+        } else {
+            out.push_str(&format!("{},-1,0\n", callsite));
+        }
+    }
+    out.into_bytes()
+}
+
+/*
+ * Raw dump of the exported globals, one `name=value` per line, e.g.
+ *
+ * profiling_global_3_0=17
+ * profiling_block_12=4096
+ * profiling_branch_5_taken=10
+ * profiling_br_table_0_3=250
+ * profiling_import_2=77
+ * profiling_access_0=90210
+ * profiling_data_1024=5120
+ * profiling_entry_1=12
+ * profiling_entry=1
+ * profiling_value_4_value=8
+ * profiling_distinct_3=9
+ * slowcalls=1234
+ *
+ * A dump may contain the globals of several instances (lanes) back to back.
+ * Counters are summed (memory high-water marks and max trip counts take the max), and each callsite's slots are merged: the union of the
+ * observed targets, or -2 if any instance overflowed its window (the most
+ * recent targets and the largest distinct count with --slot-policy lru, see
+ * merge::most_recent). Value
+ * profiles are combined with valueprofile::merge_votes. For binaries
+ * instrumented with --export-prefix, only names carrying `prefix` are read.
+ */
+pub fn decode_globals(text: &str, prefix: &str) -> Profile {
+    // callsite ==> slot ==> values seen across instances
+    let mut slots: BTreeMap<usize, BTreeMap<usize, Vec<i64>>> = BTreeMap::new();
+    // value id ==> field ==> values seen across instances
+    let mut values: BTreeMap<usize, BTreeMap<String, Vec<i32>>> = BTreeMap::new();
+    let mut profile = Profile::default();
+    for line in text.lines() {
+        let line = line.trim();
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => continue,
+        };
+        let name = match name.strip_prefix(prefix) {
+            Some(name) => name,
+            None => continue,
+        };
+        let value: i64 = match value.parse() {
+            Ok(value) => value,
+            Err(_) => {
+                println!("skipping non-integer global: {}", line);
+                continue;
+            }
+        };
+        if let Some(rest) = name.strip_prefix("profiling_global_") {
+            match rest.split_once('_') {
+                Some((idx, slot)) => {
+                    let (idx, slot) = match (index(name, idx), index(name, slot)) {
+                        (Some(idx), Some(slot)) => (idx, slot),
+                        _ => continue,
+                    };
+                    slots
+                        .entry(idx)
+                        .or_default()
+                        .entry(slot)
+                        .or_default()
+                        .push(slot_value(value as i32));
+                }
+                None => println!("skipping malformed callsite global: {}", name),
+            }
+        } else if let Some(idx) = name.strip_prefix("profiling_block_") {
+            let idx = match index(name, idx) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let count = profile.blocks.entry(idx).or_insert(0);
+            *count = count.saturating_add(value as i32);
+        } else if let Some(rest) = name.strip_prefix("profiling_branch_") {
+            if let Some(idx) = rest.strip_suffix("_not_taken") {
+                if let Some(idx) = index(name, idx) {
+                    profile.branches.entry(idx).or_insert((0, 0)).1 += value as u64;
+                }
+            } else if let Some(idx) = rest.strip_suffix("_taken") {
+                if let Some(idx) = index(name, idx) {
+                    profile.branches.entry(idx).or_insert((0, 0)).0 += value as u64;
+                }
+            }
+        } else if let Some(rest) = name.strip_prefix("profiling_br_table_") {
+            match rest.split_once('_') {
+                Some((idx, arm)) => {
+                    let (idx, arm) = match (index(name, idx), index(name, arm)) {
+                        (Some(idx), Some(arm)) => (idx, arm),
+                        _ => continue,
+                    };
+                    let arms = profile.br_tables.entry(idx).or_default();
+                    if arms.len() <= arm {
+                        arms.resize(arm + 1, 0);
+                    }
+                    arms[arm] = arms[arm].saturating_add(value as i32);
+                }
+                None => println!("skipping malformed br_table global: {}", name),
+            }
+        } else if let Some(rest) = name.strip_prefix("profiling_value_") {
+            match rest.split_once('_') {
+                Some((idx, field)) => {
+                    if let Some(idx) = index(name, idx) {
+                        values
+                            .entry(idx)
+                            .or_default()
+                            .entry(field.to_string())
+                            .or_default()
+                            .push(value as i32);
+                    }
+                }
+                None => println!("skipping malformed value global: {}", name),
+            }
+        } else if let Some(rest) = name.strip_prefix("profiling_memory_") {
+            let (idx, field) = match rest.split_once('_') {
+                Some((idx, field)) => match index(name, idx) {
+                    Some(idx) => (idx, field),
+                    None => continue,
+                },
+                None => {
+                    println!("skipping malformed memory global: {}", name);
+                    continue;
+                }
+            };
+            let entry = profile.memory.entry(idx).or_insert((0, 0));
+            match field {
+                "grown" => entry.0 = entry.0.saturating_add(value as i32),
+                "max_pages" => entry.1 = std::cmp::max(entry.1, value as i32),
+                _ => println!("skipping malformed memory global: {}", name),
+            }
+        } else if let Some(rest) = name.strip_prefix("profiling_loop_") {
+            let (idx, field) = match rest.split_once('_') {
+                Some((idx, field)) => match index(name, idx) {
+                    Some(idx) => (idx, field),
+                    None => continue,
+                },
+                None => {
+                    println!("skipping malformed loop global: {}", name);
+                    continue;
+                }
+            };
+            let entry = profile.loops.entry(idx).or_insert((0, 0, 0));
+            match field {
+                "entries" => entry.0 = entry.0.saturating_add(value as i32),
+                "iterations" => entry.1 = entry.1.saturating_add(value as i32),
+                "max_trip" => entry.2 = std::cmp::max(entry.2, value as i32),
+                _ => println!("skipping malformed loop global: {}", name),
+            }
+        } else if let Some(idx) = name.strip_prefix("profiling_import_") {
+            let idx = match index(name, idx) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let count = profile.imports.entry(idx).or_insert(0);
+            *count = count.saturating_add(value as i32);
+        } else if let Some(idx) = name.strip_prefix("profiling_access_") {
+            let idx = match index(name, idx) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let count = profile.global_accesses.entry(idx).or_insert(0);
+            *count = count.saturating_add(value as i32);
+        } else if let Some(addr) = name.strip_prefix("profiling_data_") {
+            let addr = match index(name, addr) {
+                Some(addr) => addr,
+                None => continue,
+            };
+            let count = profile.data_accesses.entry(addr).or_insert(0);
+            *count = count.saturating_add(value as i32);
+        } else if let Some(idx) = name.strip_prefix("profiling_entry_") {
+            let idx = match index(name, idx) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let count = profile.entries.entry(idx).or_insert(0);
+            *count = count.saturating_add(value as i32);
+        } else if name == "profiling_entry" {
+            // -1 until the host invokes an export
+            profile.entry = usize::try_from(value).ok();
+        } else if let Some(idx) = name.strip_prefix("profiling_distinct_") {
+            let idx = match index(name, idx) {
+                Some(idx) => idx,
+                None => continue,
+            };
+            let count = profile.distinct_targets.entry(idx).or_insert(0);
+            *count = std::cmp::max(*count, value.max(0) as u64);
+        } else if name == "slowcalls" {
+            profile.slowcalls = Some(profile.slowcalls.unwrap_or(0).saturating_add(value as i32));
+        }
+    }
+
+    for (idx, fields) in values {
+        let field = |name: &str| fields.get(name).cloned().unwrap_or_default();
+        let (value, votes, calls) = (field("value"), field("votes"), field("calls"));
+        let merged = value
+            .into_iter()
+            .zip(votes)
+            .zip(calls)
+            .map(|((value, votes), calls)| (value, votes, calls))
+            .reduce(merge_votes);
+        if let Some(merged) = merged {
+            profile.values.insert(idx, merged);
+        }
+    }
+
+    for (idx, per_slot) in slots {
+        let window = per_slot.len();
+        let values: Vec<i64> = per_slot.into_values().flatten().collect();
+        let merged = if profile.distinct_targets.contains_key(&idx) {
+            most_recent(values.into_iter(), window)
+        } else if values.contains(&-2) {
+            vec![-2; window]
+        } else {
+            let mut targets: Vec<i64> = values.into_iter().filter(|v| *v >= 0).collect();
+            targets.sort();
+            targets.dedup();
+            if targets.len() > window {
+                vec![-2; window]
+            } else {
+                targets.resize(window, -1);
+                targets
+            }
+        };
+        profile.map.insert(idx, merged);
+    }
+    profile
+}
+
+// The index part of an export name. Any export can match a prefix above,
+// so a non-numeric one is reported and skipped rather than trusted.
+fn index(name: &str, idx: &str) -> Option<usize> {
+    let idx = idx.parse().ok();
+    if idx.is_none() {
+        println!("skipping malformed global: {}", name);
+    }
+    idx
+}
+
+fn encode_globals(profile: &Profile) -> Vec<u8> {
+    if !profile.by_entry.is_empty() {
+        println!("warning: a globals dump has no per-entry callsite data, dropping it");
+    }
+    let mut out = String::new();
+    let mut callsites: Vec<(&usize, &Vec<i64>)> = profile.map.iter().collect();
+    callsites.sort();
+    for (idx, slots) in callsites {
+        for (slot, value) in slots.iter().enumerate() {
+            out.push_str(&format!("profiling_global_{}_{}={}\n", idx, slot, value));
+        }
+    }
+    let blocks: BTreeMap<&usize, &i32> = profile.blocks.iter().collect();
+    for (idx, count) in blocks {
+        out.push_str(&format!("profiling_block_{}={}\n", idx, count));
+    }
+    let branches: BTreeMap<&usize, &(u64, u64)> = profile.branches.iter().collect();
+    for (idx, (taken, not_taken)) in branches {
+        out.push_str(&format!("profiling_branch_{}_taken={}\n", idx, taken));
+        out.push_str(&format!(
+            "profiling_branch_{}_not_taken={}\n",
+            idx, not_taken
+        ));
+    }
+    let br_tables: BTreeMap<&usize, &Vec<i32>> = profile.br_tables.iter().collect();
+    for (idx, arms) in br_tables {
+        for (arm, count) in arms.iter().enumerate() {
+            out.push_str(&format!("profiling_br_table_{}_{}={}\n", idx, arm, count));
+        }
+    }
+    let values: BTreeMap<&usize, &(i32, i32, i32)> = profile.values.iter().collect();
+    for (idx, (value, votes, calls)) in values {
+        out.push_str(&format!("profiling_value_{}_value={}\n", idx, value));
+        out.push_str(&format!("profiling_value_{}_votes={}\n", idx, votes));
+        out.push_str(&format!("profiling_value_{}_calls={}\n", idx, calls));
+    }
+    let memory: BTreeMap<&usize, &(i32, i32)> = profile.memory.iter().collect();
+    for (idx, (grown, max_pages)) in memory {
+        out.push_str(&format!("profiling_memory_{}_grown={}\n", idx, grown));
+        out.push_str(&format!(
+            "profiling_memory_{}_max_pages={}\n",
+            idx, max_pages
+        ));
+    }
+    let loops: BTreeMap<&usize, &(i32, i32, i32)> = profile.loops.iter().collect();
+    for (idx, (entries, iterations, max_trip)) in loops {
+        out.push_str(&format!("profiling_loop_{}_entries={}\n", idx, entries));
+        out.push_str(&format!(
+            "profiling_loop_{}_iterations={}\n",
+            idx, iterations
+        ));
+        out.push_str(&format!("profiling_loop_{}_max_trip={}\n", idx, max_trip));
+    }
+    let imports: BTreeMap<&usize, &i32> = profile.imports.iter().collect();
+    for (idx, count) in imports {
+        out.push_str(&format!("profiling_import_{}={}\n", idx, count));
+    }
+    let accesses: BTreeMap<&usize, &i32> = profile.global_accesses.iter().collect();
+    for (idx, count) in accesses {
+        out.push_str(&format!("profiling_access_{}={}\n", idx, count));
+    }
+    let regions: BTreeMap<&usize, &i32> = profile.data_accesses.iter().collect();
+    for (addr, count) in regions {
+        out.push_str(&format!("profiling_data_{}={}\n", addr, count));
+    }
+    let entries: BTreeMap<&usize, &i32> = profile.entries.iter().collect();
+    for (idx, count) in entries {
+        out.push_str(&format!("profiling_entry_{}={}\n", idx, count));
+    }
+    if let Some(entry) = profile.entry {
+        out.push_str(&format!("profiling_entry={}\n", entry));
+    }
+    let distinct: BTreeMap<&usize, &u64> = profile.distinct_targets.iter().collect();
+    for (idx, count) in distinct {
+        out.push_str(&format!("profiling_distinct_{}={}\n", idx, count));
+    }
+    if let Some(slowcalls) = profile.slowcalls {
+        out.push_str(&format!("slowcalls={}\n", slowcalls));
+    }
+    out.into_bytes()
+}
+
+pub fn decode_profile(buf: &[u8], format: ProfileFormat) -> Result<Profile, String> {
+    let text = || std::str::from_utf8(buf).map_err(|e| e.to_string());
+    match format {
+        ProfileFormat::Msgpack => rmp_serde::decode::from_read(buf).map_err(|e| e.to_string()),
+        ProfileFormat::Json => serde_json::from_slice(buf).map_err(|e| e.to_string()),
+        ProfileFormat::Cbor => ciborium::de::from_reader(buf).map_err(|e| e.to_string()),
+        ProfileFormat::Csv => decode_csv(text()?),
+        ProfileFormat::Globals => Ok(decode_globals(text()?, "")),
+        ProfileFormat::SlotMemory => Ok(Profile {
+            map: decode_slot_memory(buf),
+            ..Profile::default()
+        }),
+    }
+}
+
+pub fn encode_profile(profile: &Profile, format: ProfileFormat) -> Vec<u8> {
+    match format {
+        ProfileFormat::Msgpack => rmp_serde::to_vec_named(profile).unwrap(),
+        ProfileFormat::Json => serde_json::to_vec_pretty(profile).unwrap(),
+        ProfileFormat::Cbor => {
+            let mut buf = vec![];
+            ciborium::ser::into_writer(profile, &mut buf).unwrap();
+            buf
+        }
+        ProfileFormat::Csv => encode_csv(profile),
+        ProfileFormat::Globals => encode_globals(profile),
+        ProfileFormat::SlotMemory => encode_slot_memory(&profile.map),
+    }
+}
@@ -0,0 +1,215 @@
+use crate::counters::CounterPolicy;
+use std::collections::{HashMap, HashSet};
+use walrus::ir::*;
+use walrus::*;
+
+// The module's globals in index space order, imports first (profiling_access_{id})
+pub fn enumerate_globals(module: &Module) -> Vec<GlobalId> {
+    module.globals.iter().map(|global| global.id()).collect()
+}
+
+// Position of every global.get / global.set of one of `globals` in `func`
+fn accesses(
+    func: &LocalFunction,
+    globals: &HashMap<GlobalId, usize>,
+) -> Vec<(InstrSeqId, usize, usize)> {
+    let mut points = vec![];
+    let mut seqs_to_process: Vec<InstrSeqId> = vec![func.entry_block()];
+    while let Some(current_seq) = seqs_to_process.pop() {
+        for (pos, (instr, _)) in func.block(current_seq).instrs.iter().enumerate() {
+            let global = match instr {
+                Instr::GlobalGet(get) => Some(get.global),
+                Instr::GlobalSet(set) => Some(set.global),
+                Instr::Block(b) => {
+                    seqs_to_process.push(b.seq);
+                    None
+                }
+                Instr::Loop(l) => {
+                    seqs_to_process.push(l.seq);
+                    None
+                }
+                Instr::IfElse(if_else) => {
+                    seqs_to_process.push(if_else.consequent);
+                    seqs_to_process.push(if_else.alternative);
+                    None
+                }
+                _ => None,
+            };
+            if let Some(id) = global.and_then(|global| globals.get(&global)) {
+                points.push((current_seq, pos, *id));
+            }
+        }
+    }
+    points
+}
+
+/*
+ * Count the reads and writes of each of the module's own globals (the ones
+ * in `globals`, as enumerate_globals numbered them before we added any) in
+ * `funcs`: every access gets a counter increment in front of it, and the
+ * counter is exported as profiling_access_{id}. Globals nothing touches get
+ * no counter. Inserting shifts instruction positions, so this runs after the
+ * passes keyed by them. Returns how many globals got a counter.
+ */
+pub fn instrument_global_accesses(
+    module: &mut Module,
+    funcs: &[FunctionId],
+    globals: &[GlobalId],
+    export_prefix: &str,
+    policy: &CounterPolicy,
+) -> usize {
+    let ids: HashMap<GlobalId, usize> = globals
+        .iter()
+        .enumerate()
+        .map(|(id, global)| (*global, id))
+        .collect();
+    let mut counters: HashMap<usize, GlobalId> = HashMap::new();
+    for func_id in funcs {
+        let points = accesses(module.funcs.get(*func_id).kind.unwrap_local(), &ids);
+        for (_, _, id) in &points {
+            if !counters.contains_key(id) {
+                let counter = policy.add_counter(module);
+                module.exports.add(
+                    &format!("{}profiling_access_{}", export_prefix, id),
+                    counter,
+                );
+                counters.insert(*id, counter);
+            }
+        }
+        let func = module.funcs.get_mut(*func_id).kind.unwrap_local_mut();
+        // Back to front, so the insertions don't move the points still to come
+        for (seq, pos, id) in points.into_iter().rev() {
+            let instrs = &mut func.block_mut(seq).instrs;
+            let loc = instrs[pos].1;
+            let increment = policy.increment(counters[&id]);
+            instrs.splice(pos..pos, increment.into_iter().map(|instr| (instr, loc)));
+        }
+    }
+    println!("Counting the accesses of {} globals", counters.len());
+    counters.len()
+}
+
+// `order` with each global whose initializer reads another local global moved after that one
+fn sources_first(module: &Module, order: &[(usize, GlobalId)]) -> Vec<(usize, GlobalId)> {
+    let ids: HashMap<GlobalId, usize> = order.iter().map(|(id, global)| (*global, *id)).collect();
+    let mut placed: HashSet<GlobalId> = HashSet::new();
+    let mut sorted = vec![];
+    for (_, global) in order {
+        // The chain of sources, read back to front
+        let mut chain = vec![*global];
+        while let GlobalKind::Local(InitExpr::Global(source)) =
+            &module.globals.get(*chain.last().unwrap()).kind
+        {
+            if !ids.contains_key(source) || placed.contains(source) {
+                break;
+            }
+            chain.push(*source);
+        }
+        for global in chain.into_iter().rev() {
+            if placed.insert(global) {
+                sorted.push((ids[&global], global));
+            }
+        }
+    }
+    sorted
+}
+
+/*
+ * Reorder the module's own globals by the profile's access counts (global
+ * id ==> accesses, see instrument_global_accesses), hottest first, so the
+ * globals a VectorVisor kernel keeps touching sit next to each other in its
+ * global storage. Globals without a count keep their relative order after
+ * the counted ones. Imported globals come first in the index space no
+ * matter what, so they stay put. walrus emits globals in the order they
+ * were added, so every global is added again in the new order and the
+ * instructions and exports referring to them are rewritten before the old
+ * ones are deleted. So are the constant expressions: with the GC proposal
+ * an initializer can read a local global, which then has to be added before
+ * the global reading it, and data and element segment offsets can read them
+ * too.
+ */
+pub fn reorder_globals(module: &mut Module, counts: &HashMap<usize, i32>) {
+    let mut order: Vec<(usize, GlobalId)> = enumerate_globals(module)
+        .into_iter()
+        .enumerate()
+        .filter(|(_, global)| matches!(module.globals.get(*global).kind, GlobalKind::Local(_)))
+        .collect();
+    order.sort_by_key(|(id, _)| std::cmp::Reverse(counts.get(id).cloned().unwrap_or(0)));
+    let order = sources_first(module, &order);
+
+    let mut mapping: HashMap<GlobalId, GlobalId> = HashMap::new();
+    for (_, old) in &order {
+        let global = module.globals.get(*old);
+        let (ty, mutable, name) = (global.ty, global.mutable, global.name.clone());
+        let init = match &global.kind {
+            GlobalKind::Local(InitExpr::Global(source)) => {
+                InitExpr::Global(mapping.get(source).cloned().unwrap_or(*source))
+            }
+            GlobalKind::Local(init) => init.clone(),
+            GlobalKind::Import(_) => unreachable!(),
+        };
+        let new = module.globals.add_local(ty, mutable, init);
+        module.globals.get_mut(new).name = name;
+        mapping.insert(*old, new);
+    }
+    let remap = |global: &mut GlobalId| {
+        if let Some(new) = mapping.get(global) {
+            *global = *new;
+        }
+    };
+
+    for (_, func) in module.funcs.iter_local_mut() {
+        let mut seqs_to_process: Vec<InstrSeqId> = vec![func.entry_block()];
+        while let Some(current_seq) = seqs_to_process.pop() {
+            for (instr, _) in func.block_mut(current_seq).instrs.iter_mut() {
+                match instr {
+                    Instr::GlobalGet(get) => remap(&mut get.global),
+                    Instr::GlobalSet(set) => remap(&mut set.global),
+                    Instr::Block(b) => seqs_to_process.push(b.seq),
+                    Instr::Loop(l) => seqs_to_process.push(l.seq),
+                    Instr::IfElse(if_else) => {
+                        seqs_to_process.push(if_else.consequent);
+                        seqs_to_process.push(if_else.alternative);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    for export in module.exports.iter_mut() {
+        if let ExportItem::Global(global) = &mut export.item {
+            remap(global);
+        }
+    }
+    let segments: Vec<DataId> = module.data.iter().map(|data| data.id()).collect();
+    for segment in segments {
+        if let DataKind::Active(ActiveData {
+            location: ActiveDataLocation::Relative(global),
+            ..
+        }) = &mut module.data.get_mut(segment).kind
+        {
+            remap(global);
+        }
+    }
+    for elem in module.elements.iter_mut() {
+        if let ElementKind::Active {
+            offset: InitExpr::Global(global),
+            ..
+        } = &mut elem.kind
+        {
+            remap(global);
+        }
+    }
+
+    for (_, old) in &order {
+        module.globals.delete(*old);
+    }
+    println!(
+        "Reordered {} globals by access count ({} counted)",
+        order.len(),
+        order
+            .iter()
+            .filter(|(id, _)| counts.contains_key(id))
+            .count()
+    );
+}
@@ -0,0 +1,367 @@
+use crate::counters::CounterMode;
+use crate::descriptors::NO_GLOBAL;
+use crate::manifest::Manifest;
+use std::fmt::Display;
+
+// Languages emit-glue can generate host code for
+pub const GLUE_LANGS: &[&str] = &["c-header"];
+
+// A C string literal; octal escapes, since a \x escape swallows the hex digits after it
+fn c_string(text: &str) -> String {
+    let mut out = String::from("\"");
+    for byte in text.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                out.push('\\');
+                out.push(byte as char);
+            }
+            0x20..=0x7e => out.push(byte as char),
+            _ => out.push_str(&format!("\\{:03o}", byte)),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn define(out: &mut String, name: &str, value: impl Display) {
+    out.push_str(&format!("#define {} {}\n", name, value));
+}
+
+fn comment(out: &mut String, text: &str) {
+    out.push_str(&format!("/* {} */\n", text));
+}
+
+fn array(out: &mut String, decl: &str, values: &[String]) {
+    // C doesn't allow empty arrays
+    if !values.is_empty() {
+        out.push_str(&format!(
+            "static const {} = {{\n    {},\n}};\n",
+            decl,
+            values.join(",\n    ")
+        ));
+    }
+}
+
+/*
+ * A C header describing how to read the profiling state of the binary the
+ * manifest belongs to: the names of the exported globals, the layout of the
+ * trace buffer, slot memory and descriptor table, and the callsite count,
+ * ids and windows. Hosts include it instead of hardcoding any of these, so
+ * re-instrumenting with other options can't leave them reading stale names.
+ * Offsets are in bytes, into memories of little-endian 32-bit words.
+ */
+pub fn c_header(manifest: &Manifest) -> String {
+    let name = |suffix: &str| c_string(&format!("{}{}", manifest.export_prefix, suffix));
+    let mut out = String::new();
+    comment(&mut out, "Generated by vv-profiler emit-glue, do not edit");
+    out.push_str("#ifndef VV_PROFILING_H\n#define VV_PROFILING_H\n\n");
+    if let Some(fingerprint) = &manifest.fingerprint {
+        define(&mut out, "VV_FINGERPRINT", c_string(fingerprint));
+    }
+    define(
+        &mut out,
+        "VV_EXPORT_PREFIX",
+        c_string(&manifest.export_prefix),
+    );
+
+    out.push('\n');
+    comment(
+        &mut out,
+        "Event counters start at VV_COUNTER_INIT, saturating ones stick at INT32_MAX",
+    );
+    define(
+        &mut out,
+        "VV_COUNTER_INIT",
+        format!("({})", manifest.counters.init),
+    );
+    let saturates = manifest.counters.mode == CounterMode::Saturate;
+    define(&mut out, "VV_COUNTER_SATURATES", saturates as u8);
+    define(&mut out, "VV_INDIRECT_GLOBAL", name("indirect"));
+    define(&mut out, "VV_SLOWCALLS_GLOBAL", name("slowcalls"));
+
+    out.push('\n');
+    comment(
+        &mut out,
+        "Callsite slots hold a table index, VV_EMPTY_SLOT or VV_OVERFLOW_SLOT",
+    );
+    comment(&mut out, "Uninstrumented callsites have no slot globals");
+    define(
+        &mut out,
+        "VV_CALLSITE_COUNT",
+        format!("{}u", manifest.callsites.len()),
+    );
+    define(&mut out, "VV_WINDOW", format!("{}u", manifest.window));
+    define(
+        &mut out,
+        "VV_EMPTY_SLOT",
+        format!("({})", manifest.counters.empty_slot),
+    );
+    define(
+        &mut out,
+        "VV_OVERFLOW_SLOT",
+        format!("({})", manifest.counters.overflow_slot),
+    );
+    comment(&mut out, "callsite id, slot");
+    define(
+        &mut out,
+        "VV_SLOT_GLOBAL_FORMAT",
+        name("profiling_global_%u_%u"),
+    );
+    let ids: Vec<String> = manifest
+        .callsites
+        .iter()
+        .map(|callsite| format!("{}u", callsite.id))
+        .collect();
+    let windows: Vec<String> = manifest
+        .callsites
+        .iter()
+        .map(|callsite| {
+            let window = manifest.callsite_windows.get(&callsite.id);
+            format!("{}u", window.unwrap_or(&manifest.window))
+        })
+        .collect();
+    let keys: Vec<String> = manifest
+        .callsites
+        .iter()
+        .map(|callsite| c_string(&callsite.key))
+        .collect();
+    array(
+        &mut out,
+        "unsigned vv_callsite_ids[VV_CALLSITE_COUNT]",
+        &ids,
+    );
+    array(
+        &mut out,
+        "unsigned vv_callsite_windows[VV_CALLSITE_COUNT]",
+        &windows,
+    );
+    array(
+        &mut out,
+        "char *const vv_callsite_keys[VV_CALLSITE_COUNT]",
+        &keys,
+    );
+
+    out.push('\n');
+    comment(
+        &mut out,
+        "Other counters, present with the flags that add them",
+    );
+    for (macro_name, suffix) in [
+        ("VV_BLOCK_GLOBAL_FORMAT", "profiling_block_%u"),
+        ("VV_BRANCH_TAKEN_GLOBAL_FORMAT", "profiling_branch_%u_taken"),
+        (
+            "VV_BRANCH_NOT_TAKEN_GLOBAL_FORMAT",
+            "profiling_branch_%u_not_taken",
+        ),
+        ("VV_BR_TABLE_GLOBAL_FORMAT", "profiling_br_table_%u_%u"),
+        ("VV_VALUE_GLOBAL_FORMAT", "profiling_value_%u_%s"),
+        ("VV_MEMORY_GLOBAL_FORMAT", "profiling_memory_%u_%s"),
+        ("VV_LOOP_GLOBAL_FORMAT", "profiling_loop_%u_%s"),
+        ("VV_IMPORT_GLOBAL_FORMAT", "profiling_import_%u"),
+        ("VV_ACCESS_GLOBAL_FORMAT", "profiling_access_%u"),
+        ("VV_DATA_GLOBAL_FORMAT", "profiling_data_%u"),
+        ("VV_ENTRY_GLOBAL_FORMAT", "profiling_entry_%u"),
+        ("VV_ENTRY_TAG_GLOBAL", "profiling_entry"),
+    ] {
+        define(&mut out, macro_name, name(suffix));
+    }
+    if !manifest.imports.is_empty() {
+        define(
+            &mut out,
+            "VV_IMPORT_COUNT",
+            format!("{}u", manifest.imports.len()),
+        );
+        let imports: Vec<String> = manifest
+            .imports
+            .iter()
+            .map(|import| c_string(&format!("{}.{}", import.module, import.name)))
+            .collect();
+        array(
+            &mut out,
+            "char *const vv_imports[VV_IMPORT_COUNT]",
+            &imports,
+        );
+    }
+    if !manifest.entries.is_empty() {
+        define(
+            &mut out,
+            "VV_ENTRY_COUNT",
+            format!("{}u", manifest.entries.len()),
+        );
+        let entries: Vec<String> = manifest
+            .entries
+            .iter()
+            .map(|entry| c_string(&entry.name))
+            .collect();
+        array(&mut out, "char *const vv_entries[VV_ENTRY_COUNT]", &entries);
+    }
+
+    if let Some(trace) = &manifest.trace {
+        out.push('\n');
+        comment(
+            &mut out,
+            "--trace: a ring buffer of (callsite id, table index) records",
+        );
+        define(&mut out, "VV_TRACE_MEMORY", c_string(&trace.memory_export));
+        define(
+            &mut out,
+            "VV_TRACE_CURSOR_GLOBAL",
+            c_string(&trace.cursor_export),
+        );
+        define(&mut out, "VV_TRACE_ENTRIES", format!("{}u", trace.entries));
+        define(
+            &mut out,
+            "VV_TRACE_RECORD_SIZE",
+            format!("{}u", trace.record_size),
+        );
+        define(&mut out, "VV_TRACE_CALLSITE_OFFSET", "0u");
+        define(&mut out, "VV_TRACE_TARGET_OFFSET", "4u");
+    }
+    if let Some(slots) = &manifest.slot_memory {
+        out.push('\n');
+        comment(
+            &mut out,
+            "--slot-memory: a header, each callsite's window, then `stride` slots per callsite",
+        );
+        comment(&mut out, "Slots hold the value + 1, so 0 is VV_EMPTY_SLOT");
+        define(&mut out, "VV_SLOT_MEMORY", c_string(&slots.memory_export));
+        define(
+            &mut out,
+            "VV_SLOT_MEMORY_VERSION",
+            format!("{}u", slots.version),
+        );
+        define(&mut out, "VV_SLOT_MEMORY_VERSION_OFFSET", "0u");
+        define(&mut out, "VV_SLOT_MEMORY_CALLSITES_OFFSET", "4u");
+        define(&mut out, "VV_SLOT_MEMORY_STRIDE_OFFSET", "8u");
+        define(
+            &mut out,
+            "VV_SLOT_MEMORY_WINDOW_OFFSET(callsite)",
+            "(12u + 4u * (callsite))",
+        );
+        define(
+            &mut out,
+            "VV_SLOT_MEMORY_SLOT_OFFSET(callsites, stride, callsite, slot)",
+            "(12u + 4u * (callsites) + 4u * ((callsite) * (stride) + (slot)))",
+        );
+    }
+    if let Some(snapshots) = &manifest.snapshots {
+        out.push('\n');
+        comment(
+            &mut out,
+            "--snapshot-every: a header, then a ring buffer of `capacity` counter snapshots",
+        );
+        define(
+            &mut out,
+            "VV_SNAPSHOT_MEMORY",
+            c_string(&snapshots.memory_export),
+        );
+        define(
+            &mut out,
+            "VV_SNAPSHOT_VERSION",
+            format!("{}u", snapshots.version),
+        );
+        define(&mut out, "VV_SNAPSHOT_VERSION_OFFSET", "0u");
+        define(&mut out, "VV_SNAPSHOT_COUNTERS_OFFSET", "4u");
+        define(&mut out, "VV_SNAPSHOT_CAPACITY_OFFSET", "8u");
+        define(&mut out, "VV_SNAPSHOT_TAKEN_OFFSET", "12u");
+        define(
+            &mut out,
+            "VV_SNAPSHOT_OFFSET(counters, capacity, n, counter)",
+            "(16u + 4u * (((n) % (capacity)) * (counters) + (counter)))",
+        );
+        let counters: Vec<String> = snapshots
+            .counters
+            .iter()
+            .map(|counter| c_string(counter))
+            .collect();
+        define(
+            &mut out,
+            "VV_SNAPSHOT_COUNTER_COUNT",
+            format!("{}u", counters.len()),
+        );
+        array(
+            &mut out,
+            "char *const vv_snapshot_counters[VV_SNAPSHOT_COUNTER_COUNT]",
+            &counters,
+        );
+    }
+    if let Some(events) = &manifest.host_events {
+        out.push('\n');
+        comment(
+            &mut out,
+            "--host-events: the host provides this import, called on every indirect call",
+        );
+        define(&mut out, "VV_EVENT_IMPORT_MODULE", c_string(&events.module));
+        define(&mut out, "VV_EVENT_IMPORT_NAME", c_string(&events.name));
+        define(&mut out, "VV_EVENT_VERSION", format!("{}u", events.version));
+        let params: Vec<String> = events
+            .params
+            .iter()
+            .map(|param| format!("int32_t {}", param))
+            .collect();
+        out.push_str("#include <stdint.h>\n");
+        out.push_str(&format!("void {}({});\n", events.name, params.join(", ")));
+    }
+    if let Some(tree) = &manifest.context_tree {
+        out.push('\n');
+        comment(
+            &mut out,
+            "--context-tree: a header, then `capacity` nodes of (parent, function, callsite, calls)",
+        );
+        comment(
+            &mut out,
+            "Node n sits at VV_CONTEXT_NODE_OFFSET(n), empty while its calls are 0",
+        );
+        define(&mut out, "VV_CONTEXT_MEMORY", c_string(&tree.memory_export));
+        define(&mut out, "VV_CONTEXT_VERSION", format!("{}u", tree.version));
+        define(&mut out, "VV_CONTEXT_VERSION_OFFSET", "0u");
+        define(&mut out, "VV_CONTEXT_CAPACITY_OFFSET", "4u");
+        define(&mut out, "VV_CONTEXT_NODES_OFFSET", "8u");
+        define(&mut out, "VV_CONTEXT_OVERFLOW_OFFSET", "12u");
+        define(&mut out, "VV_CONTEXT_NODE_OFFSET(node)", "(16u * (node))");
+        let functions: Vec<String> = tree.functions.iter().map(|name| c_string(name)).collect();
+        define(
+            &mut out,
+            "VV_CONTEXT_FUNCTION_COUNT",
+            format!("{}u", functions.len()),
+        );
+        array(
+            &mut out,
+            "char *const vv_context_functions[VV_CONTEXT_FUNCTION_COUNT]",
+            &functions,
+        );
+    }
+    if let Some(descriptors) = &manifest.descriptors {
+        out.push('\n');
+        comment(
+            &mut out,
+            "--compact-exports: a header, then `window` global indices per callsite",
+        );
+        define(
+            &mut out,
+            "VV_DESCRIPTOR_MEMORY",
+            c_string(&descriptors.memory_export),
+        );
+        define(
+            &mut out,
+            "VV_DESCRIPTOR_COUNT_GLOBAL",
+            c_string(&descriptors.count_export),
+        );
+        define(
+            &mut out,
+            "VV_DESCRIPTOR_VERSION",
+            format!("{}u", descriptors.version),
+        );
+        define(&mut out, "VV_DESCRIPTOR_VERSION_OFFSET", "0u");
+        define(&mut out, "VV_DESCRIPTOR_CALLSITES_OFFSET", "4u");
+        define(&mut out, "VV_DESCRIPTOR_WINDOW_OFFSET", "8u");
+        define(
+            &mut out,
+            "VV_DESCRIPTOR_OFFSET(window, callsite, slot)",
+            "(12u + 4u * ((callsite) * (window) + (slot)))",
+        );
+        define(&mut out, "VV_NO_GLOBAL", format!("{}u", NO_GLOBAL));
+    }
+    out.push_str("\n#endif /* VV_PROFILING_H */\n");
+    out
+}
@@ -0,0 +1,53 @@
+use crate::counters::CounterPolicy;
+use crate::manifest::HostEventAbi;
+use walrus::*;
+
+pub const HOST_EVENT_VERSION: u32 = 1;
+pub const HOST_EVENT_MODULE: &str = "env";
+pub const HOST_EVENT_NAME: &str = "vv_profile_event";
+
+/*
+ * `--host-events`: rather than filling slot globals, every indirect call
+ * stub calls the host's env.vv_profile_event(callsite id, table index)
+ * before forwarding the call, so a host with its own telemetry pipeline
+ * can stream the events instead of dumping globals. The indirect and
+ * slowcalls counters are still exported. The import is added after the
+ * module's own, so it shifts the indices of the module's functions; the
+ * manifest records the ABI the host has to provide.
+ */
+pub fn add_event_import(module: &mut Module) -> (FunctionId, HostEventAbi) {
+    let ty = module.types.add(&[ValType::I32, ValType::I32], &[]);
+    let (func, _) = module.add_import_func(HOST_EVENT_MODULE, HOST_EVENT_NAME, ty);
+    module.funcs.get_mut(func).name = Some(HOST_EVENT_NAME.to_string());
+    let abi = HostEventAbi {
+        module: HOST_EVENT_MODULE.to_string(),
+        name: HOST_EVENT_NAME.to_string(),
+        version: HOST_EVENT_VERSION,
+        params: vec!["callsite".to_string(), "target".to_string()],
+    };
+    (func, abi)
+}
+
+// Prepend `indirect += 1; vv_profile_event(callsite, target)` to an indirect call stub
+pub fn record_events(
+    module: &mut Module,
+    stub: FunctionId,
+    event: FunctionId,
+    indirect: GlobalId,
+    policy: &CounterPolicy,
+) {
+    let func = module.funcs.get_mut(stub).kind.unwrap_local_mut();
+    let args = func.args.clone();
+    let call_target = args[args.len() - 1];
+    let indirect_call_value = args[args.len() - 2];
+    let mut func_body = func.builder_mut().func_body();
+    func_body.block_at(0, None, |block| {
+        for instr in policy.increment(indirect) {
+            block.instr(instr);
+        }
+        block
+            .local_get(call_target)
+            .local_get(indirect_call_value)
+            .call(event);
+    });
+}
@@ -0,0 +1,112 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// What calling an import costs a VV fast path, cheapest first
+#[derive(
+    Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum CostClass {
+    // Optimized out or as good as a local call (proc_exit, fd_write in the benchmarks)
+    #[default]
+    Free,
+    // A host call a fast path can afford
+    Cheap,
+    // Leaves the fast path: the caller is a slowcall
+    Slow,
+    // Must never be reached from a fast path
+    Forbidden,
+}
+
+pub const COST_CLASSES: &[&str] = &["free", "cheap", "slow", "forbidden"];
+
+impl CostClass {
+    pub fn name(&self) -> &'static str {
+        COST_CLASSES[*self as usize]
+    }
+
+    // Whether a call to an import of this class makes its caller a slowcall
+    pub fn is_slow(&self) -> bool {
+        *self >= CostClass::Slow
+    }
+}
+
+/*
+ * The cost class of each import, for the slowcall analysis
+ * (fastcalls::compute_slowcalls). Keys are `module.name`, a bare `name`
+ * for that name in any module, or either ending in `*` for a prefix; the
+ * exact key wins over the bare name, and both over the longest prefix.
+ * Imports nothing matches get `default`.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ImportClasses {
+    #[serde(default = "slow")]
+    pub default: CostClass,
+    #[serde(default)]
+    pub imports: BTreeMap<String, CostClass>,
+}
+
+fn slow() -> CostClass {
+    CostClass::Slow
+}
+
+// What the analysis always let through, whatever module they come from
+impl Default for ImportClasses {
+    fn default() -> ImportClasses {
+        ImportClasses {
+            default: CostClass::Slow,
+            imports: [
+                ("proc_exit".to_string(), CostClass::Free),
+                ("fd_write".to_string(), CostClass::Free),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    }
+}
+
+impl ImportClasses {
+    /*
+     * The built-in classes with a TOML file's on top:
+     *
+     *   default = "slow"
+     *   [imports]
+     *   "wasi_snapshot_preview1.clock_time_get" = "cheap"
+     *   "env.emscripten_*" = "cheap"
+     *   "env.abort" = "forbidden"
+     */
+    pub fn from_toml(text: &str) -> Result<ImportClasses, String> {
+        let file: ImportClasses = toml::from_str(text).map_err(|e| e.to_string())?;
+        let mut classes = ImportClasses::default();
+        classes.default = file.default;
+        classes.imports.extend(file.imports);
+        Ok(classes)
+    }
+
+    pub fn read(path: &str) -> ImportClasses {
+        let text = std::fs::read_to_string(path).unwrap();
+        ImportClasses::from_toml(&text)
+            .unwrap_or_else(|e| panic!("invalid import classes {}: {}", path, e))
+    }
+
+    pub fn class(&self, module: &str, name: &str) -> CostClass {
+        let qualified = format!("{}.{}", module, name);
+        if let Some(class) = self
+            .imports
+            .get(&qualified)
+            .or_else(|| self.imports.get(name))
+        {
+            return *class;
+        }
+        self.imports
+            .iter()
+            .filter_map(|(key, class)| {
+                let prefix = key.strip_suffix('*')?;
+                (qualified.starts_with(prefix) || name.starts_with(prefix))
+                    .then_some((prefix.len(), *class))
+            })
+            .max_by_key(|(len, _)| *len)
+            .map_or(self.default, |(_, class)| class)
+    }
+}
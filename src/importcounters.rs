@@ -0,0 +1,75 @@
+use crate::counters::CounterPolicy;
+use crate::fastcalls::redirect_calls;
+use crate::manifest::ImportEntry;
+use std::collections::HashMap;
+use walrus::ir::*;
+use walrus::*;
+
+// The imported functions, numbered in import order (profiling_import_{id})
+pub fn enumerate_imports(module: &Module) -> Vec<(FunctionId, ImportEntry)> {
+    module
+        .funcs
+        .iter()
+        .filter_map(|func| match &func.kind {
+            FunctionKind::Import(import) => Some((func.id(), module.imports.get(import.import))),
+            _ => None,
+        })
+        .enumerate()
+        .map(|(id, (func, import))| {
+            (
+                func,
+                ImportEntry {
+                    id,
+                    module: import.module.clone(),
+                    name: import.name.clone(),
+                },
+            )
+        })
+        .collect()
+}
+
+/*
+ * Count the calls to each imported (host / WASI) function: like the slowcall
+ * stubs, every import gets a wrapper that bumps its own counter before
+ * forwarding the call, and direct calls are redirected to the wrapper. Calls
+ * through the table still go straight to the import and aren't counted.
+ *
+ * Must run after generate_slowcall_stubs, so the slowcall stubs of imports
+ * call our wrapper and nothing is missed.
+ */
+pub fn instrument_imports(
+    module: &mut Module,
+    export_prefix: &str,
+    policy: &CounterPolicy,
+) -> Vec<ImportEntry> {
+    let imports = enumerate_imports(module);
+    let mut mapping = HashMap::new();
+    for (func, entry) in &imports {
+        let ty = module.types.get(module.funcs.get(*func).ty()).clone();
+        let counter = policy.add_counter(module);
+
+        let mut stub = FunctionBuilder::new(&mut module.types, ty.params(), ty.results());
+        stub.name(format!("import_stub_{}", entry.id));
+        let params: Vec<LocalId> = ty.params().iter().map(|p| module.locals.add(*p)).collect();
+        let mut body = stub.func_body();
+        for instr in policy.increment(counter) {
+            body.instr(instr);
+        }
+        for param in &params {
+            body.local_get(*param);
+        }
+        body.call(*func);
+        mapping.insert(*func, stub.finish(params, &mut module.funcs));
+
+        module.exports.add(
+            &format!("{}profiling_import_{}", export_prefix, entry.id),
+            counter,
+        );
+    }
+    redirect_calls(module, &mapping);
+    println!(
+        "Instrumented {} imported functions with counters",
+        imports.len()
+    );
+    imports.into_iter().map(|(_, entry)| entry).collect()
+}
@@ -0,0 +1,106 @@
+use crate::report::func_name;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use walrus::*;
+
+pub const INDEX_MAP_VERSION: u32 = 1;
+
+// One function of either binary; `original` is None for the functions we added
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FunctionIndex {
+    pub name: String,
+    pub original: Option<u32>,
+    pub output: u32,
+}
+
+/*
+ * Function indices of the input binary against those of the binary we
+ * emitted, for translating VectorVisor logs and crash reports that name
+ * functions by index back to the original build. Indices move because
+ * imports we add go before every local function, the stubs we add are
+ * interleaved with the module's own functions and walrus emits the local
+ * functions largest first. The cleanup pass only removes added functions,
+ * so every input function has an output index.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FunctionIndexMap {
+    pub version: u32,
+    // The input's functions in index order, then the added ones in output order
+    pub functions: Vec<FunctionIndex>,
+}
+
+/*
+ * The index each function will get when `module` is emitted. This mirrors
+ * walrus' emitter: imported functions in import order, then the local ones
+ * by decreasing size (in instructions), ties broken by id.
+ */
+pub fn emitted_indices(module: &Module) -> HashMap<FunctionId, u32> {
+    let imported = module
+        .imports
+        .iter()
+        .filter_map(|import| match import.kind {
+            ImportKind::Function(func) => Some(func),
+            _ => None,
+        });
+    let mut local: Vec<(FunctionId, u64)> = module
+        .funcs
+        .iter_local()
+        .map(|(id, func)| (id, func.size()))
+        .collect();
+    local.sort_by_key(|(id, size)| (std::cmp::Reverse(*size), *id));
+    imported
+        .chain(local.into_iter().map(|(id, _)| id))
+        .enumerate()
+        .map(|(idx, id)| (id, idx as u32))
+        .collect()
+}
+
+/*
+ * Map the input's functions, `input` in original index order with the names
+ * they had when parsed, to their indices in `module` as it is about to be
+ * emitted.
+ */
+pub fn function_index_map(module: &Module, input: &[(FunctionId, String)]) -> FunctionIndexMap {
+    let emitted = emitted_indices(module);
+    let mut functions: Vec<FunctionIndex> = input
+        .iter()
+        .enumerate()
+        .map(|(original, (id, name))| FunctionIndex {
+            name: name.clone(),
+            original: Some(original as u32),
+            output: emitted[id],
+        })
+        .collect();
+    let known: HashSet<FunctionId> = input.iter().map(|(id, _)| *id).collect();
+    let mut added: Vec<(FunctionId, u32)> = emitted
+        .iter()
+        .filter(|(id, _)| !known.contains(id))
+        .map(|(id, idx)| (*id, *idx))
+        .collect();
+    added.sort_by_key(|(_, idx)| *idx);
+    functions.extend(added.into_iter().map(|(id, idx)| FunctionIndex {
+        name: func_name(module, id),
+        original: None,
+        output: idx,
+    }));
+    FunctionIndexMap {
+        version: INDEX_MAP_VERSION,
+        functions,
+    }
+}
+
+impl FunctionIndexMap {
+    pub fn write(&self, path: &str) {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, json).unwrap();
+    }
+
+    pub fn read(path: &str) -> FunctionIndexMap {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+
+    // The function at `index` in the emitted binary
+    pub fn by_output(&self, index: u32) -> Option<&FunctionIndex> {
+        self.functions.iter().find(|f| f.output == index)
+    }
+}
@@ -1,24 +1,57 @@
+use crate::callsites::Callsite;
+use crate::counters::index_const;
+use crate::report::func_name;
 use crate::MapValue;
+use crate::typecompat::func_matches;
 use crate::Profile;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use walrus::ir::*;
 use walrus::*;
 
+/*
+ * The order a dispatch stub compares the targets in: by descending call
+ * count when the profile has the callsite's target counts, so the hottest
+ * target costs one comparison, and in slot order otherwise (or for ties).
+ */
+fn hottest_first(slots: &[i64], counts: Option<&Vec<(i64, u64)>>) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..slots.len()).collect();
+    if let Some(counts) = counts {
+        let count = |slot: i64| {
+            counts
+                .iter()
+                .find(|(t, _)| *t == slot)
+                .map_or(0, |(_, c)| *c)
+        };
+        order.sort_by_key(|idx| std::cmp::Reverse(count(slots[*idx])));
+    }
+    order
+}
+
 pub fn generate_stubs(
     module: &mut Module,
     final_types: &mut HashSet<(TypeId, TableId)>,
-    stubs: &mut HashMap<TypeId, FunctionId>,
+    // One stub per call_indirect type and table, so each calls through its own table
+    stubs: &mut HashMap<(TypeId, TableId), FunctionId>,
     modified_map: &mut HashMap<usize, MapValue>,
+    // The callsites modified_map's keys number, see enumerate_callsites
+    callsites: &[Callsite],
     map: &Option<Profile>,
     is_opt: bool,
+    fallback: Option<TableId>,
+    // Devirtualized callsites that call their target directly, without a stub
+    unguarded: &HashSet<usize>,
+    // What the profile's table indices are relative to, see dylink
+    table_base: Option<GlobalId>,
+    // Callsites whose profile missed targets (SlotPolicy::Lru), which fall back to
+    // the call_indirect through this table whatever `fallback` says
+    partial: &HashMap<usize, TableId>,
 ) {
     let mut idx = 0;
     if !is_opt {
         for (ty, tab) in final_types.clone() {
             // Look up parameters / results from the type id
             let mut params = Vec::from(module.types.get(ty).params());
-            let old_params = params.clone();
             // call target location (for profiling)
             params.push(ValType::I32);
             // call_indirect target value
@@ -49,37 +82,45 @@ pub fn generate_stubs(
                 func_body.local_get(param_locals[idx]);
             }
 
-            // Find the *correct* type for the indirect call
-            let new_ty = module.types.find(&old_params, &results).unwrap();
-            assert!(new_ty == ty, "type mismatch when creating stubs");
+            // Use the callsite's own type: the type section may hold structurally
+            // equal duplicates (LLVM emits these), and types.find would pick the first
             func_body.call_indirect(ty, tab);
 
             let indirect_stub_id = indirect_stub.finish(param_locals, &mut module.funcs);
             //stub_locals.insert(indirect_stub_id, vec![counter, set_value]);
-            stubs.insert(ty, indirect_stub_id);
+            stubs.insert((ty, tab), indirect_stub_id);
         }
     } else {
         // When optimizing we still need to construct new functions!
         // For each indirect call we are directizing, we create a stub that takes in an
         // extra i32 param, to avoid dealing with extra
-        //dbg!(&modified_map);
         for (key, val) in &modified_map.clone() {
             match &val.f_id {
+                Some(id) if unguarded.contains(key) => {
+                    println!(
+                        "Calling function: {} directly at target site: {} (the only possible target)",
+                        func_name(module, id[0]),
+                        key
+                    );
+                }
                 Some(id) if id.len() > 0 => {
-                    //dbg!(&id);
                     // If we have some function, we want to make a function that calls it for us!
-                    // First get the types of the old function
+                    // The stub has the call_indirect's own type, which process_map checked the
+                    // targets against; a profile entry for a callsite the module doesn't have
+                    // (another build's) has nothing to rewrite
+                    let ty_id = match callsites.get(*key) {
+                        Some(callsite) => callsite.ty,
+                        None => continue,
+                    };
                     for value in id {
                         println!(
                             "Optimizing function: {} at target site: {}",
-                            &module.funcs.get(*value).name.as_ref().unwrap(),
+                            func_name(module, *value),
                             key
                         );
                     }
-                    // all function call targets should have the same type here...
-                    let ty_id = module.funcs.get(id[0]).ty();
+                    debug_assert!(id.iter().all(|f| func_matches(module, *f, ty_id)));
                     let mut params = Vec::from(module.types.get(ty_id).params());
-                    let old_params = params.clone();
                     // call target location (to trap if we messed up & maintain the same params)
                     params.push(ValType::I32);
 
@@ -96,19 +137,37 @@ pub fn generate_stubs(
                     }
                     let mut func_body = temp.func_body();
 
-                    // Check that the call target matches
-                    let target = map.as_ref().unwrap().map.get(key).unwrap();
+                    // Check that the call target matches (the observed targets line
+                    // up with `id`, in slot order)
+                    let profile = map.as_ref().unwrap();
+                    let slots: Vec<i64> = profile.map[key]
+                        .iter()
+                        .filter(|slot| **slot >= 0)
+                        .cloned()
+                        .collect();
+                    let target: Vec<i32> = slots
+                        .iter()
+                        .map(|slot| index_const(*slot).expect("table index out of range"))
+                        .collect();
 
                     // For each function that can be called:
-                    // 1) Check if we have to trap (can't find the call!)
+                    // 1) Check if we have to trap (can't find the call!), or make the
+                    //    indirect call after all if the table can change at runtime
                     // 2) emit the call
                     // 3) update the modified map
 
-                    // If call target matches...
-                    for call_idx in 0..id.len() {
+                    // If call target matches... (each block goes in front of the
+                    // previous ones, so the hottest target is added last). The blocks
+                    // are void: a match returns the target's results, however many
+                    // there are, straight from the stub.
+                    let order = hottest_first(&slots, profile.target_counts.get(key));
+                    for call_idx in order.into_iter().rev() {
                         func_body.block_at(0, None, |block| {
+                            block.i32_const(target[call_idx]);
+                            if let Some(base) = table_base {
+                                block.global_get(base).binop(BinaryOp::I32Add);
+                            }
                             block
-                                .i32_const(target[call_idx])
                                 .local_get(param_locals[params.len() - 1])
                                 .binop(BinaryOp::I32Eq)
                                 .if_else(
@@ -125,7 +184,17 @@ pub fn generate_stubs(
                                 );
                         });
                     }
-                    func_body.unreachable();
+                    match fallback.or_else(|| partial.get(key).cloned()) {
+                        Some(table) => {
+                            for idx in 0..params.len() {
+                                func_body.local_get(param_locals[idx]);
+                            }
+                            func_body.call_indirect(ty_id, table);
+                        }
+                        None => {
+                            func_body.unreachable();
+                        }
+                    }
 
                     let new_id = temp.finish(param_locals, &mut module.funcs);
 
@@ -134,9 +203,6 @@ pub fn generate_stubs(
                         f_bool: false,
                     };
                     modified_map.insert(*key, val);
-
-                    let new_ty = module.types.find(&old_params, &results).unwrap();
-                    assert!(new_ty == ty_id, "type mismatch when creating stubs");
                 }
                 _ => (),
             }
@@ -0,0 +1,130 @@
+use crate::blockcounters::enumerate_blocks;
+use crate::dwarf::SourceMap;
+use crate::reachability::{describe_roots, reachable_functions, Root};
+use crate::symbolize::display_name;
+use crate::Profile;
+use std::collections::{BTreeMap, HashSet};
+use walrus::*;
+
+#[derive(Default)]
+struct FileCoverage {
+    // line ==> hits
+    lines: BTreeMap<u64, u64>,
+    // (line, function, calls)
+    funcs: Vec<(u64, String, u64)>,
+}
+
+/*
+ * Block counts as an lcov tracefile, for genhtml and editor coverage
+ * plugins. With DWARF line tables every instruction of a block is credited
+ * to its source line with the block's count (a line shared by several
+ * blocks gets the highest), and each function is listed at its first line.
+ * Without them the whole module is reported as `fallback_file`, with block
+ * id + 1 as the line number, which is still enough to see what ran.
+ * Given `roots`, the functions unreachable from them are left out: no run
+ * could have covered them, so they'd only drag the totals down.
+ */
+pub fn export_lcov(
+    module: &Module,
+    profile: &Profile,
+    sources: Option<&SourceMap>,
+    fallback_file: &str,
+    demangle: bool,
+    roots: Option<&[Root]>,
+) -> String {
+    if profile.blocks.is_empty() {
+        println!("no block counts in the profile (collect it with --block-counters), the coverage will be empty");
+    }
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let reachable = roots.map(|roots| reachable_functions(module, roots, &HashSet::new()));
+    if let (Some(roots), Some(reachable)) = (roots, &reachable) {
+        println!(
+            "Leaving {} functions unreachable from {} out of the coverage",
+            original_funcs
+                .iter()
+                .filter(|id| !reachable.contains(id))
+                .count(),
+            describe_roots(roots, &[])
+        );
+    }
+    let mut files: BTreeMap<String, FileCoverage> = BTreeMap::new();
+
+    for (idx, (f_id, seq)) in enumerate_blocks(module, &original_funcs).iter().enumerate() {
+        // The entry block count is the function's call count
+        if reachable
+            .as_ref()
+            .map_or(false, |reachable| !reachable.contains(f_id))
+        {
+            continue;
+        }
+        let count = std::cmp::max(profile.blocks.get(&idx).cloned().unwrap_or(0), 0) as u64;
+        let func = module.funcs.get(*f_id).kind.unwrap_local();
+        let is_entry = func.entry_block() == *seq;
+        match sources {
+            Some(sources) => {
+                for (_, loc) in &func.block(*seq).instrs {
+                    if let Some((file, line)) = sources.lookup(*loc) {
+                        let hits = files
+                            .entry(file.to_string())
+                            .or_default()
+                            .lines
+                            .entry(line)
+                            .or_insert(0);
+                        *hits = std::cmp::max(*hits, count);
+                    }
+                }
+                if is_entry {
+                    let first = func.block(*seq).instrs.first();
+                    if let Some((file, line)) = first.and_then(|(_, loc)| sources.lookup(*loc)) {
+                        files.entry(file.to_string()).or_default().funcs.push((
+                            line,
+                            display_name(module, *f_id, demangle),
+                            count,
+                        ));
+                    }
+                }
+            }
+            None => {
+                let coverage = files.entry(fallback_file.to_string()).or_default();
+                coverage.lines.insert(idx as u64 + 1, count);
+                if is_entry {
+                    coverage.funcs.push((
+                        idx as u64 + 1,
+                        display_name(module, *f_id, demangle),
+                        count,
+                    ));
+                }
+            }
+        }
+    }
+
+    let mut out = String::from("TN:\n");
+    for (file, coverage) in files {
+        out.push_str(&format!("SF:{}\n", file));
+        for (line, name, _) in &coverage.funcs {
+            out.push_str(&format!("FN:{},{}\n", line, name));
+        }
+        for (_, name, calls) in &coverage.funcs {
+            out.push_str(&format!("FNDA:{},{}\n", calls, name));
+        }
+        out.push_str(&format!("FNF:{}\n", coverage.funcs.len()));
+        out.push_str(&format!(
+            "FNH:{}\n",
+            coverage
+                .funcs
+                .iter()
+                .filter(|(_, _, calls)| *calls > 0)
+                .count()
+        ));
+        for (line, hits) in &coverage.lines {
+            out.push_str(&format!("DA:{},{}\n", line, hits));
+        }
+        out.push_str(&format!("LF:{}\n", coverage.lines.len()));
+        out.push_str(&format!(
+            "LH:{}\n",
+            coverage.lines.values().filter(|hits| **hits > 0).count()
+        ));
+        out.push_str("end_of_record\n");
+    }
+    out
+}
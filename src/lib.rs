@@ -0,0 +1,78 @@
+pub mod analysiscache;
+pub mod blockcounters;
+pub mod bindgen;
+pub mod branches;
+pub mod brtables;
+pub mod callgraph;
+pub mod callsitelock;
+pub mod callsites;
+pub mod cleanup;
+pub mod coldsplit;
+pub mod compilationhints;
+pub mod compression;
+pub mod constfold;
+pub mod contexttree;
+pub mod coredump;
+pub mod costs;
+pub mod counters;
+pub mod customsections;
+pub mod cycles;
+pub mod dataregions;
+pub mod descriptors;
+pub mod dispatch;
+pub mod dwarf;
+pub mod dylink;
+pub mod emscripten;
+pub mod entrypoints;
+pub mod explain;
+pub mod export;
+pub mod fastcalls;
+pub mod features;
+pub mod formats;
+pub mod globalaccess;
+pub mod glue;
+pub mod hostevents;
+pub mod importclasses;
+pub mod importcounters;
+pub mod indexmap;
+pub mod instrument;
+pub mod lcov;
+pub mod linked;
+pub mod llvmprof;
+pub mod loops;
+pub mod manifest;
+pub mod megamorphic;
+pub mod merge;
+pub mod memgrowth;
+pub mod meta;
+pub mod outputs;
+pub mod overhead;
+pub mod pipeline;
+pub mod profilemap;
+pub mod reachability;
+pub mod report;
+pub mod schema;
+pub mod selfcheck;
+#[cfg(feature = "serve")]
+pub mod serve;
+pub mod slotmemory;
+pub mod smoketest;
+pub mod snapshots;
+pub mod startinit;
+pub mod strip;
+pub mod symbolize;
+pub mod tableimage;
+pub mod testsupport;
+pub mod trace;
+pub mod tracereport;
+#[cfg(feature = "tui")]
+pub mod tui;
+pub mod typecompat;
+pub mod valueprofile;
+#[cfg(feature = "verify")]
+pub mod verify;
+pub mod vvhints;
+pub mod wasmopt;
+pub mod watch;
+
+pub use schema::{MapValue, Profile};
@@ -0,0 +1,289 @@
+use crate::callsitelock::{lock_keys, CallsiteLock};
+use crate::dylink::table_base;
+use crate::manifest::{fingerprint, Manifest};
+use crate::pipeline::{self, Error, InstrumentOptions, Output};
+use crate::profilemap::function_table;
+use crate::tableimage::TableImage;
+use crate::Profile;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use walrus::*;
+
+pub const LINK_MANIFEST_VERSION: u32 = 1;
+
+/*
+ * A dynamically linked module set: a main module and side modules sharing
+ * one imported function table. Every module is instrumented on its own, then
+ * its callsite ids are moved up by the callsites of the modules before it,
+ * so the slot globals of every module dump into one profile without
+ * colliding: concatenating the dumps of all modules (in any order) gives the
+ * profile of the set.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkManifest {
+    pub version: u32,
+    // In link order, the main module first
+    pub modules: Vec<LinkedModule>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkedModule {
+    pub name: String,
+    // The module's callsite ids are offset..offset + callsites in the profile,
+    // unless they come from a callsites.lock (see the manifest's ids)
+    pub offset: usize,
+    pub callsites: usize,
+    pub manifest: Manifest,
+}
+
+impl LinkManifest {
+    pub fn write(&self, path: &str) {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, json).unwrap();
+    }
+
+    pub fn read(path: &str) -> LinkManifest {
+        serde_json::from_str(&std::fs::read_to_string(path).unwrap()).unwrap()
+    }
+}
+
+// Only the callsite slots are renumbered, every other profile is keyed per module
+fn check_options(options: &InstrumentOptions) -> Result<(), Error> {
+    let per_module = [
+        (options.block_counters, "--block-counters"),
+        (options.split_cold, "--split-cold"),
+        (options.divergence, "--divergence"),
+        (options.br_table_counters.is_some(), "--br-table-counters"),
+        (options.peel_br_tables.is_some(), "--peel-br-tables"),
+        (options.trace, "--trace"),
+        (options.import_counters, "--import-counters"),
+        (options.entry_counters, "--entry-counters"),
+        (options.entry_point.is_some(), "--entry-point"),
+        (options.global_counters, "--global-counters"),
+        (options.reorder_globals, "--reorder-globals"),
+        (options.data_counters, "--data-counters"),
+        (options.reorder_data, "--reorder-data"),
+        (options.snapshot_every.is_some(), "--snapshot-every"),
+        (options.value_profile, "--value-profile"),
+        (options.specialize, "--specialize"),
+        (options.memory_counters, "--memory-counters"),
+        (options.loop_counters, "--loop-counters"),
+        (options.slot_memory, "--slot-memory"),
+        (options.host_events, "--host-events"),
+        (options.context_tree, "--context-tree"),
+        (options.compact_exports, "--compact-exports"),
+        (options.coarse, "--coarse"),
+        (options.max_callsites.is_some(), "--max-callsites"),
+    ];
+    match per_module.iter().find(|(set, _)| *set) {
+        Some((_, flag)) => Err(Error::InvalidOptions(format!(
+            "{} can't be combined with --link-module, only the callsite slots are numbered across modules",
+            flag
+        ))),
+        None => Ok(()),
+    }
+}
+
+/*
+ * Instrument each module of the set, numbering the callsites across all of
+ * them, or with the ids `lock` has for them (keyed "{module}:{callsite}").
+ * Returns the instrumented modules, in the same order, and the link manifest
+ * recording where each module's callsites start.
+ */
+pub fn instrument_set(
+    modules: &[(String, Vec<u8>)],
+    options: &InstrumentOptions,
+    mut lock: Option<&mut CallsiteLock>,
+) -> Result<(Vec<Vec<u8>>, LinkManifest), Error> {
+    check_options(options)?;
+    let mut outputs = vec![];
+    let mut linked = vec![];
+    let mut offset = 0;
+    for (name, wasm) in modules {
+        let mut output = pipeline::run(wasm, None, options)?;
+        let callsites = output.manifest.callsites.len();
+        let ids: Vec<usize> = match &mut lock {
+            Some(lock) => {
+                let keys: Vec<String> = output
+                    .manifest
+                    .callsites
+                    .iter()
+                    .map(|callsite| format!("{}:{}", name, callsite.key))
+                    .collect();
+                lock.allocate(&keys)
+            }
+            None => (offset..offset + callsites).collect(),
+        };
+        pipeline::renumber_callsites(&mut output, &ids, options)?;
+        println!("{}: callsites {}..{}", name, offset, offset + callsites);
+        outputs.push(output.wasm);
+        linked.push(LinkedModule {
+            name: name.clone(),
+            offset,
+            callsites,
+            manifest: output.manifest,
+        });
+        offset += callsites;
+    }
+    Ok((
+        outputs,
+        LinkManifest {
+            version: LINK_MANIFEST_VERSION,
+            modules: linked,
+        },
+    ))
+}
+
+/*
+ * Where each module's functions sit in the shared table, as far as its
+ * element segments say: table index ==> (module, export name). Only exported
+ * functions can be reached from another module, and the segments of a
+ * position-independent module are only placed relative to its __table_base
+ * (see dylink), so its entries can't be resolved.
+ */
+fn exported_table_entries(modules: &[Module]) -> HashMap<i64, (usize, String)> {
+    let mut entries = HashMap::new();
+    for (idx, module) in modules.iter().enumerate() {
+        let table = match function_table(module, None) {
+            Some(table) if table_base(module).is_none() => table,
+            _ => continue,
+        };
+        let names: HashMap<FunctionId, &str> = module
+            .exports
+            .iter()
+            .filter_map(|export| match export.item {
+                ExportItem::Function(f_id) => Some((f_id, export.name.as_str())),
+                _ => None,
+            })
+            .collect();
+        for (index, f_id) in TableImage::build(module, table).entries {
+            if let Some(name) = names.get(&f_id) {
+                entries.insert(index, (idx, name.to_string()));
+            }
+        }
+    }
+    entries
+}
+
+/*
+ * Optimize each module of the set with its part of the combined profile.
+ * A table index a module's own segments don't cover may be another module's
+ * function; when this module imports that function (by its export name) the
+ * callsite is devirtualized to a call to the import. With a `lock` the
+ * modules may have been rebuilt since they were instrumented, and the
+ * profile is mapped through the lock's ids instead of the link manifest's.
+ * Returns one output per module, in order.
+ */
+pub fn optimize_set(
+    modules: &[(String, Vec<u8>)],
+    profile: &Profile,
+    link: &LinkManifest,
+    options: &InstrumentOptions,
+    lock: Option<&CallsiteLock>,
+) -> Result<Vec<Output>, Error> {
+    check_options(options)?;
+    if link.version != LINK_MANIFEST_VERSION || link.modules.len() != modules.len() {
+        return Err(Error::InvalidOptions(format!(
+            "the link manifest lists {} modules (version {}), expected {} (version {})",
+            link.modules.len(),
+            link.version,
+            modules.len(),
+            LINK_MANIFEST_VERSION
+        )));
+    }
+    let config = crate::features::module_config(&options.enable_features);
+    let mut parsed = vec![];
+    for ((name, wasm), entry) in modules.iter().zip(&link.modules) {
+        if lock.is_none() && entry.manifest.fingerprint.as_deref() != Some(&fingerprint(wasm)) {
+            return Err(Error::InvalidOptions(format!(
+                "{} isn't the module {} was instrumented from",
+                name, entry.name
+            )));
+        }
+        parsed.push(
+            config
+                .parse(wasm)
+                .map_err(|e| Error::Parse(e.to_string()))?,
+        );
+    }
+    let entries = exported_table_entries(&parsed);
+
+    let mut outputs = vec![];
+    for (idx, ((name, wasm), entry)) in modules.iter().zip(&link.modules).enumerate() {
+        let module = &parsed[idx];
+        let table = function_table(module, options.table_index);
+        // The profile's id for each callsite of the module
+        let ids: Vec<Option<usize>> = match lock {
+            Some(lock) => lock_keys(module, table, Some(name))
+                .iter()
+                .map(|key| lock.ids.get(key).cloned())
+                .collect(),
+            None => entry
+                .manifest
+                .callsites
+                .iter()
+                .map(|callsite| Some(callsite.id))
+                .collect(),
+        };
+        let imports: HashMap<&str, &Import> = module
+            .imports
+            .iter()
+            .filter(|import| matches!(import.kind, ImportKind::Function(_)))
+            .map(|import| (import.name.as_str(), import))
+            .collect();
+        let mut map = HashMap::new();
+        let mut target_counts = HashMap::new();
+        let mut linked_targets = HashMap::new();
+        for (position, id) in ids.iter().enumerate() {
+            let slots = match id.and_then(|id| profile.map.get(&id)) {
+                Some(slots) => slots,
+                None => continue,
+            };
+            for slot in slots.iter().filter(|slot| **slot >= 0) {
+                let local = table
+                    .and_then(|table| crate::profilemap::resolve_in_table(module, table, *slot));
+                match (local, entries.get(slot)) {
+                    (None, Some((owner, name))) if *owner != idx => {
+                        if let Some(import) = imports.get(name.as_str()) {
+                            linked_targets
+                                .insert(*slot, (import.module.clone(), import.name.clone()));
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            map.insert(position, slots.clone());
+            if let Some(counts) = id.and_then(|id| profile.target_counts.get(&id)) {
+                target_counts.insert(position, counts.clone());
+            }
+        }
+        let options = InstrumentOptions {
+            linked_targets,
+            ..options.clone()
+        };
+        let local = Profile {
+            map,
+            target_counts,
+            ..Profile::default()
+        };
+        outputs.push(pipeline::run(wasm, Some(local), &options)?);
+    }
+    Ok(outputs)
+}
+
+// The functions `targets` (table index ==> imported (module, name)) name in `module`
+pub fn linked_functions(
+    module: &Module,
+    targets: &HashMap<i64, (String, String)>,
+) -> HashMap<i64, FunctionId> {
+    targets
+        .iter()
+        .filter_map(|(slot, (import_module, name))| {
+            let import = module.imports.find(import_module, name)?;
+            match module.imports.get(import).kind {
+                ImportKind::Function(f_id) => Some((*slot, f_id)),
+                _ => None,
+            }
+        })
+        .collect()
+}
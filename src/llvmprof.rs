@@ -0,0 +1,206 @@
+use crate::blockcounters::enumerate_blocks;
+use crate::report::func_name;
+use crate::Profile;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use walrus::*;
+
+// One function record of LLVM's text instrumentation profile format
+// (`llvm-profdata show --text` / `llvm-profdata merge --text`)
+#[derive(Debug, Clone)]
+pub struct ProfRecord {
+    pub name: String,
+    pub hash: u64,
+    pub counters: Vec<u64>,
+}
+
+// Static functions are recorded as "file.c;name" (or "file.c:name" in older
+// LLVM, where only a lone ':' separates: "::" belongs to a C++ name)
+fn symbol_name(name: &str) -> &str {
+    if let Some(idx) = name.rfind(';') {
+        return &name[idx + 1..];
+    }
+    let bytes = name.as_bytes();
+    let lone_colon = (0..bytes.len()).rev().find(|&i| {
+        bytes[i] == b':' && bytes.get(i + 1) != Some(&b':') && (i == 0 || bytes[i - 1] != b':')
+    });
+    match lone_colon {
+        Some(idx) => &name[idx + 1..],
+        None => name,
+    }
+}
+
+/*
+ * Parse the text format. Records are separated by blank lines:
+ *
+ * name
+ * # Func Hash:
+ * 1234
+ * # Num Counters:
+ * 2
+ * # Counter Values:
+ * 100
+ * 50
+ *
+ * Header flags (":ir", ":fe", ...) and value profile data are ignored.
+ */
+pub fn parse_proftext(text: &str) -> Vec<ProfRecord> {
+    let mut records = vec![];
+    let mut lines: Vec<&str> = vec![];
+    for line in text.lines().chain(std::iter::once("")) {
+        let line = line.trim();
+        if line.starts_with('#') || line.starts_with(':') {
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(line);
+            continue;
+        }
+        if lines.len() >= 3 {
+            let hash = lines[1].parse::<u64>();
+            let num = lines[2].parse::<usize>();
+            match (hash, num) {
+                (Ok(hash), Ok(num)) if lines.len() >= 3 + num => {
+                    let counters = lines[3..3 + num]
+                        .iter()
+                        .map(|c| c.parse::<u64>().unwrap_or(0))
+                        .collect();
+                    records.push(ProfRecord {
+                        name: lines[0].to_string(),
+                        hash,
+                        counters,
+                    });
+                }
+                _ => println!("skipping malformed proftext record: {}", lines[0]),
+            }
+        }
+        lines.clear();
+    }
+    records
+}
+
+pub fn write_proftext(records: &[ProfRecord]) -> String {
+    let mut out = String::new();
+    out.push_str("# Exported by vv-profiler\n");
+    for record in records {
+        out.push_str(&format!(
+            "{}\n# Func Hash:\n{}\n# Num Counters:\n{}\n# Counter Values:\n",
+            record.name,
+            record.hash,
+            record.counters.len()
+        ));
+        for counter in &record.counters {
+            out.push_str(&format!("{}\n", counter));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+// function ==> block id of its entry block
+fn entry_blocks(module: &Module) -> HashMap<FunctionId, usize> {
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let mut entries = HashMap::new();
+    let mut seen = HashSet::new();
+    for (idx, (f_id, _)) in enumerate_blocks(module, &original_funcs).iter().enumerate() {
+        if seen.insert(*f_id) {
+            entries.insert(*f_id, idx);
+        }
+    }
+    entries
+}
+
+/*
+ * Import function entry counts from an LLVM profile. The first counter of
+ * each record is the function entry count (for both frontend and IR
+ * instrumentation), which maps onto the entry block counter of the function
+ * with the same symbol name in the name section.
+ */
+pub fn import_llvm(module: &Module, records: &[ProfRecord]) -> Profile {
+    let entries = entry_blocks(module);
+    let by_name: HashMap<String, FunctionId> = module
+        .funcs
+        .iter_local()
+        .map(|(id, _)| (func_name(module, id), id))
+        .collect();
+
+    let mut profile = Profile::default();
+    let mut unmatched = 0;
+    for record in records {
+        match by_name.get(symbol_name(&record.name)) {
+            Some(f_id) if !record.counters.is_empty() => {
+                let count = std::cmp::min(record.counters[0], i32::MAX as u64) as i32;
+                profile.blocks.insert(entries[f_id], count);
+            }
+            _ => unmatched += 1,
+        }
+    }
+    println!(
+        "Imported entry counts for {} functions ({} records had no matching symbol)",
+        records.len() - unmatched,
+        unmatched
+    );
+    profile
+}
+
+/*
+ * Export function entry counts as an LLVM text profile.
+ *
+ * clang -fprofile-use only accepts records whose hash and counter layout match
+ * the compiled function, which we can't compute from the wasm. So when a
+ * template (a text profile of a clang-instrumented build of the same code) is
+ * given, we keep its hashes and scale its counters so the entry counter
+ * matches what we observed. Without a template we emit entry-count-only
+ * records with a zero hash, which `llvm-profdata show` understands but clang
+ * will reject as a hash mismatch.
+ */
+pub fn export_llvm(
+    module: &Module,
+    profile: &Profile,
+    template: Option<&[ProfRecord]>,
+) -> Vec<ProfRecord> {
+    let entries = entry_blocks(module);
+    let mut counts: HashMap<String, u64> = HashMap::new();
+    for (f_id, idx) in &entries {
+        if let Some(count) = profile.blocks.get(idx) {
+            counts.insert(func_name(module, *f_id), std::cmp::max(*count, 0) as u64);
+        }
+    }
+
+    match template {
+        Some(template) => template
+            .iter()
+            .filter_map(|record| {
+                let count = *counts.get(symbol_name(&record.name))?;
+                let entry = record.counters.first().cloned().unwrap_or(0);
+                let counters = record
+                    .counters
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, c)| match (idx, entry) {
+                        (0, _) => count,
+                        (_, 0) => 0,
+                        _ => ((*c as u128 * count as u128) / entry as u128) as u64,
+                    })
+                    .collect();
+                Some(ProfRecord {
+                    name: record.name.clone(),
+                    hash: record.hash,
+                    counters,
+                })
+            })
+            .collect(),
+        None => {
+            let mut records: Vec<ProfRecord> = counts
+                .into_iter()
+                .map(|(name, count)| ProfRecord {
+                    name,
+                    hash: 0,
+                    counters: vec![count],
+                })
+                .collect();
+            records.sort_by(|a, b| a.name.cmp(&b.name));
+            records
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use crate::counters::CounterPolicy;
+use crate::symbolize::display_name;
+use crate::Profile;
+use walrus::ir::*;
+use walrus::*;
+
+// A loop is trip-count-stable if its average trip count is at least this share of its longest
+pub const STABLE_RATIO: f64 = 0.9;
+
+#[derive(Clone, Copy, Debug)]
+pub struct LoopInfo {
+    pub func: FunctionId,
+    // The loop body
+    pub seq: InstrSeqId,
+    // 1 for a loop that isn't nested in another loop of the same function
+    pub depth: usize,
+    // Instructions in the body, including nested blocks and loops
+    pub body_instrs: usize,
+}
+
+fn seq_instrs(func: &LocalFunction, seq: InstrSeqId) -> usize {
+    func.block(seq)
+        .instrs
+        .iter()
+        .map(|(instr, _)| {
+            1 + match instr {
+                Instr::Block(b) => seq_instrs(func, b.seq),
+                Instr::Loop(l) => seq_instrs(func, l.seq),
+                Instr::IfElse(if_else) => {
+                    seq_instrs(func, if_else.consequent) + seq_instrs(func, if_else.alternative)
+                }
+                _ => 0,
+            }
+        })
+        .sum()
+}
+
+fn scan_loops(
+    f_id: FunctionId,
+    func: &LocalFunction,
+    seq: InstrSeqId,
+    depth: usize,
+    loops: &mut Vec<LoopInfo>,
+) {
+    for (instr, _) in &func.block(seq).instrs {
+        match instr {
+            Instr::Block(b) => scan_loops(f_id, func, b.seq, depth, loops),
+            Instr::Loop(l) => {
+                loops.push(LoopInfo {
+                    func: f_id,
+                    seq: l.seq,
+                    depth: depth + 1,
+                    body_instrs: seq_instrs(func, l.seq),
+                });
+                scan_loops(f_id, func, l.seq, depth + 1, loops);
+            }
+            Instr::IfElse(if_else) => {
+                scan_loops(f_id, func, if_else.consequent, depth, loops);
+                scan_loops(f_id, func, if_else.alternative, depth, loops);
+            }
+            _ => (),
+        }
+    }
+}
+
+/*
+ * Number every loop in the given functions, outer loops before the loops
+ * nested in them. Like the block ids, the index of each entry is the loop id
+ * in the profile (profiling_loop_{id}_*), so this must be computed on the
+ * *original* module in both runs.
+ */
+pub fn enumerate_loops(module: &Module, funcs: &[FunctionId]) -> Vec<LoopInfo> {
+    let mut loops = vec![];
+    for f_id in funcs {
+        if let FunctionKind::Local(func) = &module.funcs.get(*f_id).kind {
+            scan_loops(*f_id, func, func.entry_block(), 0, &mut loops);
+        }
+    }
+    loops
+}
+
+// The sequence holding the `loop` instruction for body `seq`, and its position there
+fn loop_position(func: &LocalFunction, seq: InstrSeqId) -> Option<(InstrSeqId, usize)> {
+    let mut seqs = vec![func.entry_block()];
+    while let Some(current) = seqs.pop() {
+        for (pos, (instr, _)) in func.block(current).instrs.iter().enumerate() {
+            match instr {
+                Instr::Loop(l) if l.seq == seq => return Some((current, pos)),
+                Instr::Loop(l) => seqs.push(l.seq),
+                Instr::Block(b) => seqs.push(b.seq),
+                Instr::IfElse(if_else) => {
+                    seqs.push(if_else.consequent);
+                    seqs.push(if_else.alternative);
+                }
+                _ => (),
+            }
+        }
+    }
+    None
+}
+
+/*
+ * Profile the trip counts of each loop with three globals:
+ *
+ *   {prefix}profiling_loop_{id}_entries     times the loop was entered
+ *   {prefix}profiling_loop_{id}_iterations  times its body ran
+ *   {prefix}profiling_loop_{id}_max_trip    most iterations in a single entry
+ *
+ * Entering the loop resets a per-loop local that the body counts up, and the
+ * body raises max_trip as it goes, since a loop can be left by a branch to
+ * any enclosing label and there's no single exit to record it at. The
+ * average trip count (iterations / entries) against max_trip says how stable
+ * the trip count is.
+ */
+pub fn instrument_loops(
+    module: &mut Module,
+    loops: &[LoopInfo],
+    export_prefix: &str,
+    policy: &CounterPolicy,
+) {
+    for (idx, info) in loops.iter().enumerate() {
+        let entries = policy.add_counter(module);
+        let iterations = policy.add_counter(module);
+        let max_trip = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+        for (name, global) in [
+            ("entries", entries),
+            ("iterations", iterations),
+            ("max_trip", max_trip),
+        ] {
+            module.exports.add(
+                &format!("{}profiling_loop_{}_{}", export_prefix, idx, name),
+                global,
+            );
+        }
+        let trip = module.locals.add(ValType::I32);
+
+        let func = module.funcs.get_mut(info.func).kind.unwrap_local_mut();
+        let (parent, pos) = loop_position(func, info.seq).unwrap();
+        let mut enter = policy.increment(entries);
+        enter.push(Instr::Const(Const {
+            value: Value::I32(0),
+        }));
+        enter.push(Instr::LocalSet(LocalSet { local: trip }));
+        let mut body = func.builder_mut().instr_seq(parent);
+        for instr in enter.into_iter().rev() {
+            body.instr_at(pos, instr);
+        }
+
+        let mut iterate = vec![
+            Instr::LocalGet(LocalGet { local: trip }),
+            Instr::Const(Const {
+                value: Value::I32(1),
+            }),
+            Instr::Binop(Binop {
+                op: BinaryOp::I32Add,
+            }),
+            Instr::LocalSet(LocalSet { local: trip }),
+        ];
+        iterate.extend(policy.increment(iterations));
+        // max_trip = trip > max_trip ? trip : max_trip
+        iterate.extend(vec![
+            Instr::LocalGet(LocalGet { local: trip }),
+            Instr::GlobalGet(GlobalGet { global: max_trip }),
+            Instr::LocalGet(LocalGet { local: trip }),
+            Instr::GlobalGet(GlobalGet { global: max_trip }),
+            Instr::Binop(Binop {
+                op: BinaryOp::I32GtU,
+            }),
+            Instr::Select(Select { ty: None }),
+            Instr::GlobalSet(GlobalSet { global: max_trip }),
+        ]);
+        let mut body = func.builder_mut().instr_seq(info.seq);
+        for instr in iterate.into_iter().rev() {
+            body.instr_at(0, instr);
+        }
+    }
+    println!("Instrumented {} loops with trip counters", loops.len());
+}
+
+pub struct LoopCandidate {
+    pub id: usize,
+    pub info: LoopInfo,
+    pub entries: i32,
+    pub iterations: i32,
+    pub max_trip: i32,
+    pub avg_trip: f64,
+}
+
+/*
+ * Loops worth unrolling or vectorizing: hot (at least `min_iterations`
+ * iterations in the profile), short (at most `max_body` instructions) and
+ * with a stable trip count (see STABLE_RATIO). Hottest first.
+ */
+pub fn unroll_candidates(
+    module: &Module,
+    profile: &Profile,
+    min_iterations: i32,
+    max_body: usize,
+) -> Vec<LoopCandidate> {
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let mut candidates: Vec<LoopCandidate> = enumerate_loops(module, &original_funcs)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(id, info)| {
+            let (entries, iterations, max_trip) = *profile.loops.get(&id)?;
+            if entries <= 0 || iterations < min_iterations || info.body_instrs > max_body {
+                return None;
+            }
+            let avg_trip = iterations as f64 / entries as f64;
+            if avg_trip < STABLE_RATIO * max_trip as f64 {
+                return None;
+            }
+            Some(LoopCandidate {
+                id,
+                info,
+                entries,
+                iterations,
+                max_trip,
+                avg_trip,
+            })
+        })
+        .collect();
+    candidates.sort_by(|a, b| b.iterations.cmp(&a.iterations).then(a.id.cmp(&b.id)));
+    candidates
+}
+
+pub fn print_loop_report(
+    module: &Module,
+    candidates: &[LoopCandidate],
+    top: usize,
+    demangle: bool,
+) {
+    println!("== Hot loops with stable trip counts ==");
+    println!(
+        "{:>6} {:>12} {:>10} {:>9} {:>8} {:>6} {:>6}  {}",
+        "loop", "iterations", "entries", "avg trip", "max trip", "body", "depth", "function"
+    );
+    for candidate in candidates.iter().take(top) {
+        println!(
+            "{:>6} {:>12} {:>10} {:>9.1} {:>8} {:>6} {:>6}  {}",
+            candidate.id,
+            candidate.iterations,
+            candidate.entries,
+            candidate.avg_trip,
+            candidate.max_trip,
+            candidate.info.body_instrs,
+            candidate.info.depth,
+            display_name(module, candidate.info.func, demangle)
+        );
+    }
+}
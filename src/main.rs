@@ -1,57 +1,40 @@
-mod fastcalls;
-mod instrument;
-mod profilemap;
-
-use clap::{value_t, App, Arg};
-use fastcalls::*;
-use instrument::generate_stubs;
-use profilemap::process_map;
-use profilemap::MapValue;
-use rmp_serde::decode;
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::collections::HashSet;
-use std::fs::File;
-use std::io::Read;
-use walrus::ir::Instr::*;
-use walrus::ir::Value;
-use walrus::ir::VisitorMut;
-use walrus::ir::*;
-use walrus::DataKind::Active;
-use walrus::FunctionBuilder;
-use walrus::FunctionId;
-use walrus::GlobalId;
-use walrus::InstrSeqBuilder;
-use walrus::TableId;
-use walrus::TypeId;
-use walrus::ValType;
-
-#[derive(Deserialize, Debug)]
-pub struct Profile {
-    map: HashMap<usize, Vec<i32>>,
-}
-
-#[derive(Debug)]
-struct TypeScan {
-    ty: Vec<(TypeId, TableId)>,
-}
-
-impl VisitorMut for TypeScan {
-    fn visit_instr_mut(&mut self, instr: &mut walrus::ir::Instr, idx: &mut walrus::InstrLocId) {
-        match instr {
-            CallIndirect(call_indirect) => {
-                self.ty.push((call_indirect.ty, call_indirect.table));
-            }
-            _ => {}
-        }
-    }
-}
+use clap::{value_t, App, AppSettings, Arg, SubCommand};
+use vv_profiler::callsitelock::{lock_keys, CallsiteLock};
+use vv_profiler::compression::{Compression, COMPRESSIONS};
+use vv_profiler::counters::{CounterMode, CounterPolicy, SlotPolicy, COUNTER_MODES, SLOT_POLICIES};
+use vv_profiler::cycles::CostTable;
+use vv_profiler::dwarf::SourceMap;
+use vv_profiler::formats::{ProfileFormat, PROFILE_FORMATS};
+use vv_profiler::glue::GLUE_LANGS;
+use vv_profiler::importclasses::ImportClasses;
+use vv_profiler::manifest::{self, read_callsite_keys, Manifest};
+use vv_profiler::profilemap::adaptive_windows;
+use vv_profiler::profilemap::function_table;
+use vv_profiler::profilemap::read_globals_dump;
+use vv_profiler::profilemap::read_profile;
+use vv_profiler::profilemap::read_profile_as;
+use vv_profiler::profilemap::write_profile;
+use vv_profiler::profilemap::write_profile_as;
+use vv_profiler::reachability::{roots_from_names, ROOTS};
+#[cfg(feature = "serve")]
+use vv_profiler::serve;
+use vv_profiler::startinit::{InitMode, INIT_MODES};
+#[cfg(feature = "tui")]
+use vv_profiler::tui;
+#[cfg(feature = "verify")]
+use vv_profiler::verify;
+use vv_profiler::Profile;
+use vv_profiler::{
+    contexttree, coredump, costs, explain, export, features, glue, lcov, linked, llvmprof, loops,
+    outputs, pipeline, report, smoketest, snapshots, tracereport, vvhints, wasmopt, watch,
+};
 
 fn main() {
     let matches = App::new("vv-profiler")
         .version("0.1")
         .author("Sam Ginzburg <ginzburg.sam@gmail.com>")
         .about("A WASM profiling utility for VectorVisor")
+        .setting(AppSettings::SubcommandsNegateReqs)
         .arg(
             Arg::with_name("input")
                 .required(true)
@@ -83,6 +66,16 @@ fn main() {
                 .number_of_values(1)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("profile_format")
+                .long("profile-format")
+                .default_value("msgpack")
+                .possible_values(PROFILE_FORMATS)
+                .help("Encoding of the --profile data (globals: a raw `name=value` dump of the exported globals)")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("window")
                 .short("w")
@@ -93,385 +86,1889 @@ fn main() {
                 .number_of_values(1)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("block_counters")
+                .long("block-counters")
+                .help("Also instrument every block with an execution counter (required for --split-cold)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("split_cold")
+                .long("split-cold")
+                .help("Outline cold blocks of hot functions into separate functions (requires block counts in the profile)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("hot_threshold")
+                .long("hot-threshold")
+                .default_value("1000")
+                .help("Minimum number of invocations for a function to be considered hot (--split-cold, --specialize, --emit-vv-hints, --hot-functions-from)")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("import_counters")
+                .long("import-counters")
+                .help("Count the calls to every imported (host/WASI) function")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("entry_counters")
+                .long("entry-counters")
+                .help("Tag and count the host's calls to every exported function, so dumps can be segmented per entry point")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("entry_point")
+                .long("entry-point")
+                .requires("optimize")
+                .help("Devirtualize with only this exported function's share of the profile (needs --entry-counters dumps); callsites it can't reach stay indirect")
+                .multiple(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("global_counters")
+                .long("global-counters")
+                .help("Count the reads and writes of every global (for --reorder-globals)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("reorder_globals")
+                .long("reorder-globals")
+                .requires("optimize")
+                .help("Reorder the module's globals by the profile's access counts, hottest first (requires global access counts)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("data_counters")
+                .long("data-counters")
+                .help("Count the loads and stores into each 64-byte region of the data segments (for --reorder-data)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("reorder_data")
+                .long("reorder-data")
+                .requires("optimize")
+                .help("Split the data segments at the profile's hot regions and emit them hottest first (requires data region counts)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("coarse")
+                .long("coarse")
+                .conflicts_with_all(&["optimize", "trace", "hot_functions_from"])
+                .help("Only count function entries: a cheap first profiling run for --hot-functions-from")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("hot_functions_from")
+                .long("hot-functions-from")
+                .value_name("PROFILE")
+                .help("Profile of a --coarse run (in --profile-format); only instrument callsites in functions called at least --hot-threshold times")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reachable_only")
+                .long("reachable-only")
+                .value_name("ROOTS")
+                .possible_values(ROOTS)
+                .use_delimiter(true)
+                .min_values(0)
+                .help("Leave functions no call path from these roots (comma-separated; default: all of them) reaches uninstrumented")
+                .multiple(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reachable_from")
+                .long("reachable-from")
+                .value_name("NAME")
+                .requires("reachable_only")
+                .help("Another --reachable-only root, by export or function name (repeatable), e.g. the real entry points of an --export-all build")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("prior_profile")
+                .long("prior-profile")
+                .value_name("PROFILE")
+                .conflicts_with("optimize")
+                .help("Profile of an earlier run (in --profile-format); size each callsite's slots from what it saw, skipping never-executed ones")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_callsites")
+                .long("max-callsites")
+                .value_name("N")
+                .conflicts_with("optimize")
+                .help("Instrument at most N callsites, those in functions with the most callers and then the largest first")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("callsite_round")
+                .long("callsite-round")
+                .value_name("R")
+                .requires("max_callsites")
+                .help("Instrument the R-th chunk of --max-callsites callsites instead of the first (0); merge the profiles of every round")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("re_instrument")
+                .long("re-instrument")
+                .conflicts_with("optimize")
+                .help("If the input was already instrumented, strip that instrumentation and instrument it again instead of failing")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("emscripten")
+                .long("emscripten")
+                .help("Emscripten output: treat invoke_* imports as calls into the table, and when the table can change at runtime fall back to call_indirect instead of trapping in devirtualized calls")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("skip_static_callsites")
+                .long("skip-static-callsites")
+                .help("Don't instrument callsites whose type matches exactly one table entry")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("devirt_static")
+                .long("devirt-static")
+                .requires("optimize")
+                .help("Devirtualize callsites whose type matches exactly one table entry, when the profile doesn't cover them")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("loop_counters")
+                .long("loop-counters")
+                .help("Count the entries, iterations and longest trip of every loop (see analyze --loops)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("memory_counters")
+                .long("memory-counters")
+                .help("Count the pages each memory grows by and its max size, for right-sizing memory limits")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("count_indirect_slowcalls")
+                .long("count-indirect-slowcalls")
+                .help("Also count the slowcalls made through the function table, by pointing their element segment entries at the counting stubs")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("value_profile")
+                .long("value-profile")
+                .help("Record the most common value of every i32 function parameter (for --specialize)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("specialize")
+                .long("specialize")
+                .requires("optimize")
+                .help("Clone hot functions with their most common argument value baked in, behind a guard (requires value profiles)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("divergence")
+                .long("divergence")
+                .help("Also instrument every conditional branch with a (taken, not taken) counter pair")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("br_table_counters")
+                .long("br-table-counters")
+                .value_name("MIN_ARMS")
+                .help("Also count how often each arm is taken in every br_table with at least MIN_ARMS arms (the default included)")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("peel_br_tables")
+                .long("peel-br-tables")
+                .value_name("ARMS")
+                .requires("optimize")
+                .help("Test the ARMS hottest arms of each profiled br_table before branching through it (requires br_table arm counts)")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Append (callsite, target) records to a ring buffer in a dedicated memory instead of aggregating into slots")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("trace_entries")
+                .long("trace-entries")
+                .default_value("65536")
+                .help("Number of records in the --trace ring buffer (must be a power of two)")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("snapshot_every")
+                .long("snapshot-every")
+                .help("Copy the counters into a ring buffer of snapshots every N slowcalls, to tell warm-up from steady state")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("snapshot_capacity")
+                .long("snapshot-capacity")
+                .default_value("64")
+                .help("Number of snapshots the --snapshot-every ring buffer keeps")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("snapshot_tick")
+                .long("snapshot-tick")
+                .requires("snapshot_every")
+                .help("MODULE.NAME of an imported () -> i32 host function asked every --snapshot-every slowcalls; a snapshot is taken when it returns non-zero")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("counter_init")
+                .long("counter-init")
+                .default_value("0")
+                .allow_hyphen_values(true)
+                .help("Initial value of the event counters (indirect, slowcalls, block, branch and import counters); the manifest records it")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("counter_mode")
+                .long("counter-mode")
+                .default_value("wrap")
+                .possible_values(COUNTER_MODES)
+                .help("What an event counter does at i32::MAX: wrap around, or saturate and stay there")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("slot_policy")
+                .long("slot-policy")
+                .default_value("overflow")
+                .possible_values(SLOT_POLICIES)
+                .help("What a callsite's full slots do on a new target: overflow gives up on the callsite, lru evicts the least recently seen target and counts the distinct ones (profiling_distinct_*), so megamorphic callsites still get devirtualized")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("init_mode")
+                .long("init-mode")
+                .default_value("init-expr")
+                .possible_values(INIT_MODES)
+                .help("How the profiling state gets its initial values: global initializers and data segments, or an init function chained before the start function")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("table_index")
+                .long("table-index")
+                .value_name("N")
+                .help("Index of the table call_indirect targets refer to, when the module has several (imported tables count first)")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("skip_entry_callsites")
+                .long("skip-entry-callsites")
+                .help("Don't instrument or optimize call_indirects in _start and the start section function")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("min_func_size")
+                .long("min-func-size")
+                .value_name("N")
+                .default_value("0")
+                .help("Leave functions with fewer than N instructions uninstrumented")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("self_check")
+                .long("self-check")
+                .help("Re-parse the output and check the callsite rewriting invariants")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .long("manifest")
+                .value_name("")
+                .help("Write a JSON manifest describing each instrumented callsite")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("decisions")
+                .long("decisions")
+                .value_name("JSONL")
+                .requires("optimize")
+                .help("Write what happened to each callsite and which rule decided it, one JSON record per line")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("retain_callsites")
+                .long("retain-callsites")
+                .value_name("FILE")
+                .help("File listing callsite keys (from the manifest, one per line) that must stay indirect calls")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("force_devirt")
+                .long("force-devirt")
+                .value_name("CALLSITE=FUNCTION")
+                .help("Devirtualize a callsite (by manifest key) to the named function, regardless of the profile")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("explain")
+                .long("explain")
+                .value_name("CALLSITE")
+                .help("Print why a callsite (by manifest key) was instrumented, devirtualized or left alone")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("explain_all")
+                .long("explain-all")
+                .help("--explain every callsite")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no_demangle")
+                .long("no-demangle")
+                .global(true)
+                .help("Show function names as they are in the name section, without demangling Rust/C++ symbols")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("export_prefix")
+                .long("export-prefix")
+                .value_name("PREFIX")
+                .default_value("")
+                .help("Prepended to every export the instrumentation adds (and expected on a `globals` --profile dump)")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("slot_memory")
+                .long("slot-memory")
+                .conflicts_with_all(&["optimize", "trace", "compact_exports"])
+                .help("Keep the callsite slots in an exported memory, so the stubs don't grow with the number of callsites (dump it as a `slot-memory` profile)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("host_events")
+                .long("host-events")
+                .conflicts_with_all(&["optimize", "trace", "slot_memory", "compact_exports"])
+                .help("Call an imported env.vv_profile_event(callsite, target) on every indirect call instead of filling slot globals, for hosts that stream events (the manifest records the ABI)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("context_tree")
+                .long("context-tree")
+                .conflicts_with("optimize")
+                .help("Also build a calling-context tree in a dedicated memory, keyed by function and indirect callsite (see context-report)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("context_nodes")
+                .long("context-nodes")
+                .default_value("4096")
+                .help("Number of nodes the --context-tree hash table holds (must be a power of two)")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compact_exports")
+                .long("compact-exports")
+                .help("Export a callsite count and a descriptor table of global indices instead of every slot global")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("max_overhead")
+                .long("max-overhead")
+                .value_name("N")
+                .help("Fail if the estimated extra instructions per indirect call exceed N")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_size_increase")
+                .long("max-size-increase")
+                .value_name("PCT")
+                .help("Fail if the instrumented binary is more than PCT percent larger than the input, listing what the bytes went to")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_memory")
+                .long("max-memory")
+                .value_name("MB")
+                .help("Soft memory budget for the slowcall analysis; past it, functions with calls are taken to be slowcalls instead of analyzed further")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("size_warn_only")
+                .long("size-warn-only")
+                .requires("max_size_increase")
+                .help("Only warn when --max-size-increase is exceeded")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("allow_shared_memory")
+                .long("allow-shared-memory")
+                .help("Instrument modules with a shared memory; each thread records its own profile")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("enable_feature")
+                .long("enable-feature")
+                .value_name("FEATURE")
+                .possible_values(&[
+                    "mvp",
+                    "bulk-memory",
+                    "reference-types",
+                    "simd",
+                    "threads",
+                    "multi-memory",
+                ])
+                .help("Accept modules using this proposal (repeatable; default: all of them, mvp: none)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("run_wasm_opt")
+                .long("run-wasm-opt")
+                .value_name("ARGS")
+                .requires("optimize")
+                .help("Run Binaryen's wasm-opt (or $WASM_OPT) on the optimized output, e.g. --run-wasm-opt=\"-O3 --enable-simd\" (default -O2)")
+                .multiple(false)
+                .min_values(0)
+                .require_equals(true)
+                .allow_hyphen_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("smoke_test")
+                .long("smoke-test")
+                .value_name("CMD")
+                .help("Run CMD on each emitted binary, its path substituted for {} (e.g. \"wasmtime run {}\"), and fail if it fails")
+                .multiple(false)
+                .number_of_values(1)
+                .allow_hyphen_values(true)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("emit_instrumented")
+                .long("emit-instrumented")
+                .value_name("PATH")
+                .requires("optimize")
+                .help("Also write an instrumented build of the optimized binary to PATH, for continued collection")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ab_split")
+                .long("ab-split")
+                .value_name("PATH")
+                .requires("optimize")
+                .conflicts_with_all(&["emit_instrumented", "link_module"])
+                .help("Also write a conservative build to PATH, with a call_indirect fallback behind every devirtualized call and unexecuted callsites left indirect, to canary against the aggressive --output (both share the --manifest; --decisions describes --output)")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("link_module")
+                .long("link-module")
+                .value_names(&["SIDE", "OUT"])
+                .requires("link_manifest")
+                .conflicts_with_all(&["emit_instrumented", "emit_vv_hints", "manifest"])
+                .help("A side module sharing --input's function table, and where to write its output; callsites are numbered across the whole set (repeat for each side module, in link order)")
+                .multiple(true)
+                .number_of_values(2)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("callsite_lock")
+                .long("callsite-lock")
+                .value_name("PATH")
+                .conflicts_with("emit_instrumented")
+                .help("Allocation file (callsites.lock) keeping each callsite's id stable across rebuilds: instrumenting allocates ids in it, optimizing maps the profile back through it")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch")
+                .long("watch")
+                .help("Stay running and redo the run whenever the input module(s) or the --optimize profile change; the runs share an analysis cache next to the output unless --analysis-cache is given")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("watch_interval")
+                .long("watch-interval")
+                .value_name("MS")
+                .default_value("500")
+                .help("Milliseconds between checks of the watched files")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("import_classes")
+                .long("import-classes")
+                .value_name("FILE")
+                .help("TOML map of imports (module.name, name, or a prefix ending in *) to a cost class: free, cheap, slow or forbidden. Free and cheap imports leave their callers fastcalls")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("analysis_cache")
+                .long("analysis-cache")
+                .value_name("PATH")
+                .help("Cache the per-function fastcall analysis in this file, so runs on a rebuilt module only analyze the functions that changed")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .help("Report how long emitting and writing the outputs took")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("link_manifest")
+                .long("link-manifest")
+                .value_name("PATH")
+                .requires("link_module")
+                .help("The module set's manifest: written when instrumenting with --link-module, read back when optimizing")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("emit_vv_hints")
+                .long("emit-vv-hints")
+                .value_name("PATH")
+                .requires("optimize")
+                .help("Write VectorVisor partitioning hints (hot functions, call counts, devirtualized call density) to PATH as JSON")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compilation_hints")
+                .long("compilation-hints")
+                .requires("optimize")
+                .conflicts_with("run_wasm_opt")
+                .help("Mark functions hot, lazy or cold by their call counts in a compilation-hints custom section, for engines that tier compilation by it (requires block counts in the profile)")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("keep_custom")
+                .long("keep-custom")
+                .value_name("NAME")
+                .help("Copy the input's custom section NAME into the output byte for byte, even one dropped by default (.debug_*, metadata.code.*); a trailing * matches a prefix (repeatable)")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("drop_custom")
+                .long("drop-custom")
+                .value_name("NAME")
+                .help("Leave custom section NAME out of the output; a trailing * matches a prefix (repeatable). Unknown sections are kept byte for byte otherwise")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("emit_index_map")
+                .long("emit-index-map")
+                .value_name("PATH")
+                .conflicts_with_all(&["run_wasm_opt", "link_module"])
+                .help("Write the input's function indices against the output's to PATH as JSON, to translate VectorVisor logs and crash reports back to the original build")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("embed_manifest")
+                .long("embed-manifest")
+                .conflicts_with("link_module")
+                .help("Embed the manifest and an empty profile in the instrumented binary (the --emit-instrumented one when optimizing) as a vv.pgo.manifest custom section")
+                .multiple(false)
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("instrumented_binary")
+                .long("instrumented-binary")
+                .value_name("PATH")
+                .requires("optimize")
+                .help("The instrumented binary the --profile came from: its embedded manifest (--embed-manifest) is checked against --input and supplies the export prefix of a `globals` dump")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("instrumented_manifest")
+                .long("instrumented-manifest")
+                .value_name("PATH")
+                .requires("emit_instrumented")
+                .help("Write the manifest of the --emit-instrumented binary to PATH")
+                .multiple(false)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("report")
+                .about("Summarize the profiling data collected for a .wasm binary")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .short("i")
+                        .long("input")
+                        .help("The original (uninstrumented) .wasm binary")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .required(true)
+                        .long("profile")
+                        .help("The collected profiling data")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .default_value("20")
+                        .help("Number of entries to show in each section")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("interactive")
+                        .long("interactive")
+                        .help("Browse the profile in a terminal UI (requires the `tui` feature)")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("cost_table")
+                        .long("cost-table")
+                        .help("TOML file of per-instruction cycle weights for the estimated cycles (default: built-in weights)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("table_index")
+                        .long("table-index")
+                        .value_name("N")
+                        .help("The --table-index the binary was instrumented with")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("analyze")
+                .about("Static analysis of a .wasm binary (optionally combined with a profile)")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .short("i")
+                        .long("input")
+                        .help("The original (uninstrumented) .wasm binary")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .help("Profiling data collected with --block-counters, for dynamic call counts")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("costs")
+                        .long("costs")
+                        .help("Per-function instruction counts, loop depth, locals, and register pressure")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("loops")
+                        .long("loops")
+                        .requires("profile")
+                        .help("Hot, short loops with stable trip counts (unrolling/vectorization candidates), from a --loop-counters profile")
+                        .takes_value(false),
+                )
+                .arg(
+                    Arg::with_name("min_iterations")
+                        .long("min-iterations")
+                        .default_value("10000")
+                        .help("--loops: iterations a loop needs to count as hot")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max_body")
+                        .long("max-body")
+                        .default_value("64")
+                        .help("--loops: largest loop body, in instructions, worth unrolling")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .default_value("20")
+                        .help("Number of entries to show in each section")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("trace-report")
+                .about("Analyze a ring buffer dumped from a binary instrumented with --trace")
+                .arg(
+                    Arg::with_name("manifest")
+                        .required(true)
+                        .long("manifest")
+                        .help("The manifest written when instrumenting with --trace")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("trace")
+                        .required(true)
+                        .long("trace")
+                        .help("Raw dump of the exported trace_buffer memory")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cursor")
+                        .required(true)
+                        .long("cursor")
+                        .help("Final value of the exported trace_cursor global")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("phases")
+                        .long("phases")
+                        .default_value("4")
+                        .help("Number of equally sized phases to split the trace into")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("snapshot-report")
+                .about("Show how the counters evolved across the snapshots of a binary instrumented with --snapshot-every")
+                .arg(
+                    Arg::with_name("manifest")
+                        .required(true)
+                        .long("manifest")
+                        .help("The manifest written when instrumenting with --snapshot-every")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("snapshots")
+                        .required(true)
+                        .long("snapshots")
+                        .help("Raw dump of the exported profiling_snapshots memory")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("context-report")
+                .about("Show the calling-context tree of a binary instrumented with --context-tree (export --format folded draws it)")
+                .arg(
+                    Arg::with_name("manifest")
+                        .required(true)
+                        .long("manifest")
+                        .help("The manifest written when instrumenting with --context-tree")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("tree")
+                        .required(true)
+                        .long("tree")
+                        .help("Raw dump of the exported profiling_context_tree memory")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Convert collected trace or counter data for existing profile viewers")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .default_value("chrome-trace")
+                        .possible_values(&["chrome-trace", "llvm-proftext", "lcov", "folded"])
+                        .help("Output format")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .short("o")
+                        .long("output")
+                        .help("Where to write the exported data")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .short("i")
+                        .long("input")
+                        .help("The original .wasm binary (resolves function names)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("profile")
+                        .long("profile")
+                        .help("Aggregated profiling data to export")
+                        .requires("input")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("trace")
+                        .long("trace")
+                        .help("Raw dump of the trace_buffer memory to export")
+                        .requires_all(&["manifest", "cursor"])
+                        .required_unless_one(&["profile", "context_tree"])
+                        .conflicts_with("profile")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("context_tree")
+                        .long("context-tree")
+                        .help("folded only: raw dump of the profiling_context_tree memory to export")
+                        .requires("manifest")
+                        .conflicts_with_all(&["profile", "trace"])
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .long("manifest")
+                        .help("The manifest written when instrumenting with --trace or --context-tree")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("cursor")
+                        .long("cursor")
+                        .help("Final value of the exported trace_cursor global")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("reachable_only")
+                        .long("reachable-only")
+                        .value_name("ROOTS")
+                        .possible_values(ROOTS)
+                        .use_delimiter(true)
+                        .min_values(0)
+                        .help("lcov only: leave out the functions no call path from these roots (comma-separated; default: all of them) reaches")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("template")
+                        .long("template")
+                        .help("llvm-proftext only: text profile of a clang-instrumented build, supplies function hashes")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Convert profiling data from other tools into a profile for --profile")
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .default_value("llvm-proftext")
+                        .possible_values(&["llvm-proftext", "core-dump", "wizer"])
+                        .help("Input format (core-dump: a wasm core dump of an instrumented binary, e.g. from wasmtime --coredump-on-trap; wizer: an instrumented binary pre-initialized by Wizer after a warm-up run)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .short("i")
+                        .long("input")
+                        .help("The original .wasm binary (symbols are matched via the name section); for core-dump and wizer, the instrumented binary that was running")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("data")
+                        .required(true)
+                        .long("data")
+                        .help("The profiling data to import")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .short("o")
+                        .long("output")
+                        .help("Where to write the converted profile")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("compress")
+                        .long("compress")
+                        .default_value("none")
+                        .possible_values(COMPRESSIONS)
+                        .help("Compress the written profile (detected automatically on read)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("compress_level")
+                        .long("compress-level")
+                        .help("Compression level (gzip: 0-9, default 6; zstd: 1-22, default 3)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Convert a profile between encodings")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .short("i")
+                        .long("input")
+                        .help("The profile to convert")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .short("o")
+                        .long("output")
+                        .help("Where to write the converted profile")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("from")
+                        .long("from")
+                        .default_value("msgpack")
+                        .possible_values(PROFILE_FORMATS)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .default_value("msgpack")
+                        .possible_values(PROFILE_FORMATS)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("compress")
+                        .long("compress")
+                        .default_value("none")
+                        .possible_values(COMPRESSIONS)
+                        .help("Compress the written profile (detected automatically on read)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("compress_level")
+                        .long("compress-level")
+                        .help("Compression level (gzip: 0-9, default 6; zstd: 1-22, default 3)")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("emit-glue")
+                .about("Generate host code describing an instrumented binary's profiling state")
+                .arg(
+                    Arg::with_name("manifest")
+                        .required(true)
+                        .long("manifest")
+                        .help("The manifest written when instrumenting")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("lang")
+                        .long("lang")
+                        .default_value("c-header")
+                        .possible_values(GLUE_LANGS)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .short("o")
+                        .long("output")
+                        .help("Where to write the generated code")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("serve")
+                .about("Aggregate profiles POSTed by a fleet of instances (requires the `serve` feature)")
+                .arg(
+                    Arg::with_name("input")
+                        .required(true)
+                        .short("i")
+                        .long("input")
+                        .help("The original .wasm binary the profiles are collected on")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("listen")
+                        .long("listen")
+                        .default_value("127.0.0.1:7878")
+                        .help("Address to listen on")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .required(true)
+                        .short("o")
+                        .long("output")
+                        .help("Where to periodically write the aggregated profile")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .default_value("60")
+                        .help("Seconds between writes of the aggregated profile")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("half_life")
+                        .long("half-life")
+                        .help("Decay merged data with this half-life (seconds), so stale targets age out")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("table_index")
+                        .long("table-index")
+                        .value_name("N")
+                        .help("The --table-index the binary was instrumented with")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Run the original and optimized binaries on the same inputs and compare them (requires the `verify` feature)")
+                .arg(
+                    Arg::with_name("a")
+                        .required(true)
+                        .short("a")
+                        .help("The original .wasm binary (a WASI command)")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("b")
+                        .required(true)
+                        .short("b")
+                        .help("The optimized .wasm binary")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("args")
+                        .long("args")
+                        .help("Whitespace separated arguments for one run; repeat for more runs")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .allow_hyphen_values(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("stdin")
+                        .long("stdin")
+                        .help("File fed to stdin on every run")
+                        .takes_value(true),
+                ),
+        )
         .get_matches();
 
-    let input = value_t!(matches.value_of("input"), String).unwrap_or_else(|e| e.exit());
-    let output = value_t!(matches.value_of("output"), String).unwrap_or_else(|e| e.exit());
-    let indirect_window = value_t!(matches.value_of("window"), usize).unwrap_or_else(|e| e.exit());
-    assert!(indirect_window <= 50);
-
-    let optimize: Option<&str> = matches.value_of("optimize");
-    let is_opt = match optimize {
-        Some(_) => true,
-        _ => false,
-    };
-    let map: Option<Profile> = match optimize {
-        Some(path) => {
-            let mut file = File::open(path).unwrap();
-            let mut buf = vec![];
-            file.read_to_end(&mut buf);
-            decode::from_read(&buf as &[u8]).unwrap()
+    if let Some(sub) = matches.subcommand_matches("verify") {
+        #[cfg(feature = "verify")]
+        {
+            let runs: Vec<Vec<String>> = match sub.values_of("args") {
+                Some(args) => args
+                    .map(|run| run.split_whitespace().map(|a| a.to_string()).collect())
+                    .collect(),
+                None => vec![vec![]],
+            };
+            let stdin = sub
+                .value_of("stdin")
+                .map(|path| std::fs::read(path).unwrap())
+                .unwrap_or_default();
+            if !verify::verify(
+                sub.value_of("a").unwrap(),
+                sub.value_of("b").unwrap(),
+                &runs,
+                &stdin,
+            ) {
+                std::process::exit(1);
+            }
+            return;
         }
-        _ => None,
-    };
-    //dbg!(&map);
-
-    let mut module = walrus::Module::from_file(input).unwrap();
+        #[cfg(not(feature = "verify"))]
+        {
+            let _ = sub;
+            eprintln!("verify requires building with `--features verify`");
+            std::process::exit(1);
+        }
+    }
 
-    // Identify slowcalls that we need to instrument
-    let slowcalls = if !is_opt {
-        compute_slowcalls(&mut module)
-    } else {
-        // No-op since we don't need to instrument anything
-        HashSet::new()
-    };
+    if let Some(sub) = matches.subcommand_matches("serve") {
+        #[cfg(feature = "serve")]
+        {
+            let wasm = std::fs::read(sub.value_of("input").unwrap()).unwrap();
+            let module = walrus::Module::from_buffer(&wasm).unwrap();
+            let interval = value_t!(sub.value_of("interval"), u64).unwrap_or_else(|e| e.exit());
+            let table_index = sub
+                .value_of("table_index")
+                .map(|_| value_t!(sub.value_of("table_index"), u32).unwrap_or_else(|e| e.exit()));
+            let served = serve::serve(
+                &module,
+                function_table(&module, table_index),
+                &vv_profiler::manifest::fingerprint(&wasm),
+                sub.value_of("listen").unwrap(),
+                sub.value_of("output").unwrap(),
+                std::time::Duration::from_secs(interval),
+                sub.value_of("half_life")
+                    .map(|_| value_t!(sub.value_of("half_life"), f64).unwrap_or_else(|e| e.exit())),
+            );
+            if let Err(e) = served {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+            return;
+        }
+        #[cfg(not(feature = "serve"))]
+        {
+            let _ = sub;
+            eprintln!("serve requires building with `--features serve`");
+            std::process::exit(1);
+        }
+    }
 
-    // We need to map the profiling data to FunctionId refs in the AST
-    // We parse table 0, get the offset, and then iterate through the functions
-    let mut modified_map: HashMap<usize, MapValue> = HashMap::new();
-    //let tab_id = module.tables.main_function_table().unwrap().unwrap();
-    //let table = module.tables.get(tab_id);
-    if is_opt {
-        process_map(&module, &map, &mut modified_map);
+    if let Some(sub) = matches.subcommand_matches("convert") {
+        let from = or_exit(ProfileFormat::from_name(sub.value_of("from").unwrap()));
+        let to = or_exit(ProfileFormat::from_name(sub.value_of("to").unwrap()));
+        let profile = or_exit(read_profile_as(sub.value_of("input").unwrap(), from));
+        let compression = Compression::from_args(
+            sub.value_of("compress").unwrap(),
+            sub.value_of("compress_level"),
+        );
+        write_profile_as(sub.value_of("output").unwrap(), &profile, to, compression);
+        println!(
+            "Converted {} callsites, {} blocks, {} branches",
+            profile.map.len(),
+            profile.blocks.len(),
+            profile.branches.len()
+        );
+        return;
     }
 
-    let original_map = modified_map.clone();
-    // Scan for all indirect call types
-    let types: Vec<Vec<(TypeId, TableId)>> = module
-        .funcs
-        .iter_local_mut()
-        .map(|(id, func)| {
-            let entry = func.entry_block();
-            let mut scan = TypeScan { ty: vec![] };
-            walrus::ir::dfs_pre_order_mut(&mut scan, func, entry);
+    if let Some(sub) = matches.subcommand_matches("emit-glue") {
+        let manifest = Manifest::read(sub.value_of("manifest").unwrap());
+        let code = match sub.value_of("lang").unwrap() {
+            "c-header" => glue::c_header(&manifest),
+            lang => panic!("unknown glue language: {}", lang),
+        };
+        std::fs::write(sub.value_of("output").unwrap(), code).unwrap();
+        return;
+    }
 
-            scan.ty
-        })
-        .collect();
+    if let Some(sub) = matches.subcommand_matches("import") {
+        let module = walrus::Module::from_file(sub.value_of("input").unwrap()).unwrap();
+        let data = sub.value_of("data").unwrap();
+        let state = match sub.value_of("format") {
+            Some("core-dump") => {
+                Some(coredump::profile_from_core_dump(&module, &std::fs::read(data).unwrap()))
+            }
+            Some("wizer") => {
+                Some(coredump::profile_from_snapshot(&module, &std::fs::read(data).unwrap()))
+            }
+            _ => None,
+        };
+        let profile = match state {
+            Some(state) => state.unwrap_or_else(|e| {
+                eprintln!("{}: {}", data, e);
+                std::process::exit(1);
+            }),
+            None => {
+                let text = std::fs::read_to_string(data).unwrap();
+                llvmprof::import_llvm(&module, &llvmprof::parse_proftext(&text))
+            }
+        };
+        let compression = Compression::from_args(
+            sub.value_of("compress").unwrap(),
+            sub.value_of("compress_level"),
+        );
+        write_profile(sub.value_of("output").unwrap(), &profile, compression);
+        return;
+    }
 
-    let mut final_types: HashSet<(TypeId, TableId)> = HashSet::new();
-    for ty in types {
-        final_types.extend(ty);
+    if let Some(sub) = matches.subcommand_matches("export") {
+        let demangle = !sub.is_present("no_demangle");
+        let module = sub
+            .value_of("input")
+            .map(|path| walrus::Module::from_file(path).unwrap());
+        if sub.value_of("format") == Some("llvm-proftext") {
+            let profile =
+                or_exit(read_profile(sub.value_of("profile").expect("--profile is required")));
+            let template = sub
+                .value_of("template")
+                .map(|path| llvmprof::parse_proftext(&std::fs::read_to_string(path).unwrap()));
+            let records = llvmprof::export_llvm(
+                module.as_ref().expect("--input is required"),
+                &profile,
+                template.as_deref(),
+            );
+            std::fs::write(
+                sub.value_of("output").unwrap(),
+                llvmprof::write_proftext(&records),
+            )
+            .unwrap();
+            return;
+        }
+        if sub.value_of("format") == Some("folded") {
+            let manifest = Manifest::read(sub.value_of("manifest").expect("--manifest is required"));
+            let layout = manifest
+                .context_tree
+                .expect("manifest was not generated with --context-tree");
+            let buf = std::fs::read(sub.value_of("context_tree").expect("--context-tree is required"))
+                .unwrap();
+            let (nodes, _) = contexttree::decode_context_tree(&buf, &layout);
+            std::fs::write(
+                sub.value_of("output").unwrap(),
+                export::context_to_folded(&nodes, &layout, module.as_ref(), demangle),
+            )
+            .unwrap();
+            return;
+        }
+        if sub.value_of("format") == Some("lcov") {
+            let input = sub.value_of("input").expect("--input is required");
+            let wasm = std::fs::read(input).unwrap();
+            let profile =
+                or_exit(read_profile(sub.value_of("profile").expect("--profile is required")));
+            let roots = sub.is_present("reachable_only").then(|| {
+                roots_from_names(sub.values_of("reachable_only").into_iter().flatten())
+            });
+            let tracefile = lcov::export_lcov(
+                module.as_ref().unwrap(),
+                &profile,
+                SourceMap::load(&wasm).as_ref(),
+                input,
+                demangle,
+                roots.as_deref(),
+            );
+            std::fs::write(sub.value_of("output").unwrap(), tracefile).unwrap();
+            return;
+        }
+        let json = if let Some(path) = sub.value_of("trace") {
+            let manifest = Manifest::read(sub.value_of("manifest").unwrap());
+            let cursor = value_t!(sub.value_of("cursor"), u64).unwrap_or_else(|e| e.exit());
+            let layout = manifest
+                .trace
+                .clone()
+                .expect("manifest was not generated with --trace");
+            let buf = std::fs::read(path).unwrap();
+            let records =
+                tracereport::decode_trace(&buf, cursor, layout.entries, layout.record_size);
+            export::trace_to_chrome(&records, &manifest, module.as_ref(), demangle)
+        } else if let Some(path) = sub.value_of("profile") {
+            export::profile_to_chrome(module.as_ref().unwrap(), &or_exit(read_profile(path)), demangle)
+        } else {
+            // clap only lets --context-tree stand in for --trace/--profile
+            eprintln!("--context-tree can only be exported with --format folded");
+            std::process::exit(1);
+        };
+        std::fs::write(sub.value_of("output").unwrap(), json.to_string()).unwrap();
+        return;
     }
 
-    // For each indirect call type generate a new function in the module to serve as a stub
-    let mut stubs: HashMap<TypeId, FunctionId> = HashMap::new();
+    if let Some(sub) = matches.subcommand_matches("trace-report") {
+        let manifest = Manifest::read(sub.value_of("manifest").unwrap());
+        let cursor = value_t!(sub.value_of("cursor"), u64).unwrap_or_else(|e| e.exit());
+        let phases = value_t!(sub.value_of("phases"), usize).unwrap_or_else(|e| e.exit());
+        let layout = manifest
+            .trace
+            .clone()
+            .expect("manifest was not generated with --trace");
+        let buf = std::fs::read(sub.value_of("trace").unwrap()).unwrap();
+        let records = tracereport::decode_trace(&buf, cursor, layout.entries, layout.record_size);
+        tracereport::trace_report(
+            &records,
+            &manifest,
+            phases,
+            cursor > layout.entries as u64,
+            !sub.is_present("no_demangle"),
+        );
+        return;
+    }
 
-    // Generate stubs to replace indirect calls + add instrumentation
-    generate_stubs(
-        &mut module,
-        &mut final_types,
-        &mut stubs,
-        &mut modified_map,
-        &map,
-        is_opt,
-    );
+    if let Some(sub) = matches.subcommand_matches("context-report") {
+        let manifest = Manifest::read(sub.value_of("manifest").unwrap());
+        let layout = manifest
+            .context_tree
+            .clone()
+            .expect("manifest was not generated with --context-tree");
+        let buf = std::fs::read(sub.value_of("tree").unwrap()).unwrap();
+        let (nodes, overflowed) = contexttree::decode_context_tree(&buf, &layout);
+        let demangle = !sub.is_present("no_demangle");
+        contexttree::context_report(&nodes, overflowed, &manifest, &layout, demangle);
+        return;
+    }
 
-    // values
-    let mut skip_funcs: HashSet<FunctionId> = HashSet::new();
-    for id in stubs.values() {
-        skip_funcs.insert(*id);
+    if let Some(sub) = matches.subcommand_matches("snapshot-report") {
+        let manifest = Manifest::read(sub.value_of("manifest").unwrap());
+        let layout = manifest
+            .snapshots
+            .expect("manifest was not generated with --snapshot-every");
+        let buf = std::fs::read(sub.value_of("snapshots").unwrap()).unwrap();
+        snapshots::snapshot_report(&snapshots::decode_snapshots(&buf, &layout));
+        return;
     }
 
-    // Track each indirect call we replace
-    // We want to know which calls we can replace with direct calls after profiling
-    let mut global_index = 0;
+    if let Some(sub) = matches.subcommand_matches("analyze") {
+        let input = value_t!(sub.value_of("input"), String).unwrap_or_else(|e| e.exit());
+        let top = value_t!(sub.value_of("top"), usize).unwrap_or_else(|e| e.exit());
+        let wasm = std::fs::read(input).unwrap();
+        let module = walrus::Module::from_buffer(&wasm).unwrap();
+        let profile = sub.value_of("profile").map(|path| or_exit(read_profile(path)));
+        if sub.is_present("costs") {
+            let mut costs = costs::compute_costs(&module, &profile);
+            costs::print_costs(
+                &module,
+                &mut costs,
+                top,
+                !sub.is_present("no_demangle"),
+                SourceMap::load(&wasm).as_ref(),
+            );
+        }
+        if sub.is_present("loops") {
+            let min_iterations =
+                value_t!(sub.value_of("min_iterations"), i32).unwrap_or_else(|e| e.exit());
+            let max_body = value_t!(sub.value_of("max_body"), usize).unwrap_or_else(|e| e.exit());
+            let candidates = loops::unroll_candidates(
+                &module,
+                profile.as_ref().unwrap(),
+                min_iterations,
+                max_body,
+            );
+            loops::print_loop_report(&module, &candidates, top, !sub.is_present("no_demangle"));
+        }
+        return;
+    }
 
-    module.funcs.iter_local_mut().for_each(|(id, func)| {
-        // Skip the stubs we created...
-        if !skip_funcs.contains(&id) {
-            let mut body = func.entry_block();
-            let mut count: usize = 0;
-            let mut insertion_point: Vec<(InstrSeqId, usize, TypeId)> = vec![];
-            let mut seqs_to_process: Vec<InstrSeqId> = vec![];
-            seqs_to_process.push(body);
-            drop(body);
+    if let Some(sub) = matches.subcommand_matches("report") {
+        let input = value_t!(sub.value_of("input"), String).unwrap_or_else(|e| e.exit());
+        let top = value_t!(sub.value_of("top"), usize).unwrap_or_else(|e| e.exit());
+        let wasm = std::fs::read(input).unwrap();
+        let module = walrus::Module::from_buffer(&wasm).unwrap();
+        let profile = or_exit(read_profile(sub.value_of("profile").unwrap()));
+        let demangle = !sub.is_present("no_demangle");
+        let table_index = sub
+            .value_of("table_index")
+            .map(|_| value_t!(sub.value_of("table_index"), u32).unwrap_or_else(|e| e.exit()));
+        let table = function_table(&module, table_index);
+        if sub.is_present("interactive") {
+            #[cfg(feature = "tui")]
+            tui::run(&module, table, &profile, demangle).unwrap();
+            #[cfg(not(feature = "tui"))]
+            {
+                eprintln!("report --interactive requires building with `--features tui`");
+                std::process::exit(1);
+            }
+        } else {
+            let weights = sub
+                .value_of("cost_table")
+                .map_or_else(CostTable::default, CostTable::read);
+            report::print_report(
+                &module,
+                table,
+                &profile,
+                top,
+                demangle,
+                SourceMap::load(&wasm).as_ref(),
+                &weights,
+            );
+        }
+        return;
+    }
 
-            while seqs_to_process.len() > 0 {
-                let current_seq = seqs_to_process.pop().unwrap();
-                let bmut = func.block_mut(current_seq);
-                let mut offset = 0;
-                for (instr, loc) in &bmut.instrs {
-                    match instr {
-                        CallIndirect(call) => {
-                            insertion_point.push((current_seq.clone(), count + offset, call.ty));
-                            if !is_opt {
-                                offset += 1;
-                            }
-                        }
-                        Block(b) => {
-                            seqs_to_process.push(b.seq);
-                        }
-                        Loop(l) => {
-                            seqs_to_process.push(l.seq);
-                        }
-                        IfElse(if_else) => {
-                            seqs_to_process.push(if_else.consequent);
-                            seqs_to_process.push(if_else.alternative);
-                        }
-                        _ => {}
-                    }
-                    count += 1;
-                }
-                count = 0;
+    if matches.is_present("watch") {
+        let mut paths = vec![matches.value_of("input").unwrap().to_string()];
+        if let Some(sides) = matches.values_of("link_module") {
+            paths.extend(sides.step_by(2).map(|side| side.to_string()));
+        }
+        if let Some(profile) = matches.value_of("optimize") {
+            paths.push(profile.to_string());
+        }
+        let interval =
+            value_t!(matches.value_of("watch_interval"), u64).unwrap_or_else(|e| e.exit());
+        // Every run is a fresh process, so a failed run doesn't end the watch
+        let exe = std::env::current_exe().unwrap();
+        let cache = watch::analysis_cache_path(matches.value_of("output").unwrap());
+        let args = watch::run_args(&std::env::args().skip(1).collect::<Vec<_>>(), &cache);
+        watch::watch(&paths, std::time::Duration::from_millis(interval), || {
+            match std::process::Command::new(&exe).args(&args).status() {
+                Ok(status) if status.success() => (),
+                Ok(status) => println!("Run failed ({}), waiting for the next change", status),
+                Err(e) => println!(
+                    "Can't run {} ({}), waiting for the next change",
+                    exe.display(),
+                    e
+                ),
             }
-            drop(body);
+        });
+        return;
+    }
 
-            if !is_opt {
-                // Process each sequence
-                for (seq, point, ty) in insertion_point {
-                    let mut body = func.builder_mut().instr_seq(seq);
-                    body.instr_at(
-                        point,
-                        walrus::ir::Call {
-                            func: *stubs.clone().get(&ty).unwrap(),
-                        },
-                    );
-                    body.instr_at(
-                        point,
-                        walrus::ir::Const {
-                            value: Value::I32(global_index),
-                        },
-                    );
-                    body.instrs_mut().remove(point + 2);
-                    global_index += 1;
-                }
-            } else {
-                // If we are optimizing the binary, we replace indirect calls directly here!
-                // We either:
-                // 1) Replace the indirect call with a direct call (if value is defined)
-                // 2) Replace the indirect call with an unreachable statement if it is never called
-                // 3) Keep the indirect call in place as-is
-                //
-                // We must also keep the number of instructions constant (to handle offsets)
-                for (seq, point, ty) in insertion_point {
-                    let map_val: &MapValue = modified_map.get(&(global_index as usize)).unwrap();
-                    let orig_map_val: &MapValue =
-                        original_map.get(&(global_index as usize)).unwrap();
-                    let mut body = func.builder_mut().instr_seq(seq);
-                    match map_val {
-                        // Replace the call
-                        MapValue {
-                            f_id: Some(id),
-                            f_bool: _b,
-                        } => {
-                            // Remove the indirect call + the idx
-                            // id should be a vec of size 1
-                            assert!(id.len() == 1, "id is of len: {}", id.len());
-                            body.instr_at(point, walrus::ir::Call { func: id[0] });
-                            // We now have Call --> CallIndirect, with "Call" at point
-                            body.instrs_mut().remove(point + 1);
-                        }
-                        // Replace the call with `unreachable`
-                        MapValue {
-                            f_id: None,
-                            f_bool: true,
-                        } => {
-                            body.instr_at(point, walrus::ir::Unreachable {});
-                            body.instrs_mut().remove(point + 1);
-                        }
-                        // Retain the indirect call (no-op)
-                        MapValue {
-                            f_id: None,
-                            f_bool: false,
-                        } => {
-                            println!("retaining call...");
-                        }
-                        _ => {
-                            panic!("unhandled case: {:?}", map_val);
-                        }
-                    }
-                    global_index += 1;
+    let input = value_t!(matches.value_of("input"), String).unwrap_or_else(|e| e.exit());
+    let output = value_t!(matches.value_of("output"), String).unwrap_or_else(|e| e.exit());
+    // --smoke-test: every emitted binary has to get through the command
+    let smoke_test = |paths: &[String]| {
+        if let Some(command) = matches.value_of("smoke_test") {
+            for path in paths {
+                if let Err(e) = smoketest::run_smoke_test(command, path) {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
                 }
             }
         }
-    });
-
-    let mut indirect_id = None;
-    let mut slowcalls_id = None;
-    if !is_opt {
-        indirect_id = Some(module.globals.add_local(
-            walrus::ValType::I32,
-            true,
-            walrus::InitExpr::Value(Value::I32(0)),
-        ));
+    };
+    let indirect_window = value_t!(matches.value_of("window"), usize).unwrap_or_else(|e| e.exit());
+    let block_counters = matches.is_present("block_counters");
+    let split_cold = matches.is_present("split_cold");
+    let hot_threshold =
+        value_t!(matches.value_of("hot_threshold"), i32).unwrap_or_else(|e| e.exit());
+    let divergence = matches.is_present("divergence");
+    let trace = matches.is_present("trace");
+    let trace_entries =
+        value_t!(matches.value_of("trace_entries"), u32).unwrap_or_else(|e| e.exit());
+    let context_nodes =
+        value_t!(matches.value_of("context_nodes"), u32).unwrap_or_else(|e| e.exit());
+    let snapshot_capacity =
+        value_t!(matches.value_of("snapshot_capacity"), u32).unwrap_or_else(|e| e.exit());
+    let min_func_size =
+        value_t!(matches.value_of("min_func_size"), usize).unwrap_or_else(|e| e.exit());
 
-        slowcalls_id = Some(module.globals.add_local(
-            walrus::ValType::I32,
-            true,
-            walrus::InitExpr::Value(Value::I32(0)),
-        ));
-    }
+    let optimize: Option<&str> = matches.value_of("optimize");
+    let profile_format =
+        or_exit(ProfileFormat::from_name(matches.value_of("profile_format").unwrap()));
+    let export_prefix = matches.value_of("export_prefix").unwrap().to_string();
+    let wasm_bytes = std::fs::read(&input).unwrap();
 
-    if !is_opt {
-        // Now insert globals to track each call site
-        let mut global_map: HashMap<usize, Vec<GlobalId>> = HashMap::new();
-        // Insert X many globals per-call site
-        // We do this to track cases where just a few different targets are possible
-        for idx in 0..(global_index as usize) {
-            let mut new_globals = vec![];
-            for inner_idx in 0..indirect_window {
-                new_globals.push(module.globals.add_local(
-                    walrus::ValType::I32,
-                    true,
-                    walrus::InitExpr::Value(Value::I32(-1)),
-                ));
+    // The manifest embedded with --embed-manifest, in the binary the profile came
+    // from: --instrumented-binary, or --input when optimizing the instrumented binary
+    let embedded = match (optimize, matches.value_of("instrumented_binary")) {
+        (Some(_), Some(path)) => {
+            let embedded = manifest::embedded_manifest(&std::fs::read(path).unwrap());
+            if embedded.is_none() {
+                eprintln!("{} has no embedded manifest, instrument it with --embed-manifest", path);
+                std::process::exit(1);
             }
-            global_map.insert(
-                idx, // e.g., Map 0,1,2,3,4 --> to the same call site to mimic an array
-                new_globals,
-            );
+            embedded
         }
+        (Some(_), None) => manifest::embedded_manifest(&wasm_bytes),
+        (None, _) => None,
+    };
+    if let (Some(embedded), Some(path)) = (&embedded, matches.value_of("instrumented_binary")) {
+        match &embedded.manifest.fingerprint {
+            Some(fingerprint) if *fingerprint != manifest::fingerprint(&wasm_bytes) => {
+                eprintln!("{} was instrumented from a different build than {}", path, input);
+                std::process::exit(1);
+            }
+            _ => println!(
+                "Read the manifest embedded in {} ({} callsites)",
+                path,
+                embedded.manifest.callsites.len()
+            ),
+        }
+    }
+    // A `globals` dump carries the prefix the binary was instrumented with
+    let dump_prefix = match &embedded {
+        Some(embedded) if matches.occurrences_of("export_prefix") == 0 => {
+            embedded.manifest.export_prefix.clone()
+        }
+        _ => export_prefix.clone(),
+    };
+    let read_as_format = |path: &str| {
+        or_exit(match profile_format {
+            ProfileFormat::Globals => read_globals_dump(path, &dump_prefix),
+            _ => read_profile_as(path, profile_format),
+        })
+    };
+    let map: Option<Profile> = optimize.map(read_as_format);
 
-        // Construct a mapping of function id ==> bools, to identify fastcalls
-        // TODO
-
-        // Now time to go back and modify the indirect call stubs to modify local values
-        for function_idx in skip_funcs {
-            let id = function_idx;
-            let func = module.funcs.get_mut(function_idx).kind.unwrap_local_mut();
-            let args = &func.args.clone();
-            let call_target = args[args.len() - 1];
-            let indirect_call_value = args[args.len() - 2];
-            let mut func_builder = func.builder_mut();
-            let mut func_body = func_builder.func_body();
-            //let local_vals = stub_locals.get(&id).unwrap();
-            //let counter = local_vals[0];
-            //let set_value =  local_vals[1];
-            //let counter = module.locals.add(ValType::I32);
-            let set_value = module.locals.add(ValType::I32);
-            func_body.block_at(0, None, |block| {
-                block
-                    .global_get(indirect_id.unwrap())
-                    .i32_const(1)
-                    .binop(BinaryOp::I32Add)
-                    .global_set(indirect_id.unwrap())
-                    .i32_const(0)
-                    .local_set(set_value);
-            });
-            drop(func_body);
-            let mut block_seq = func_builder.dangling_instr_seq(None);
-            let block_seq_id = block_seq.id();
-            for global_idx in 0..global_index as usize {
-                /*
-                 * We have an array of values representing each call site
-                 * We "iterate" through the "array" to find an open slot
-                 *
-                 * For each slot:
-                 * if the matching global is -1, set the value ( and set_value <- true)
-                 *  after setting, we break out.
-                 *
-                 * if after falling through all available slots, set_value != true
-                 * set all globals for this call site to -2
-                 *
-                 */
-                for array_value in global_map.get(&global_idx).unwrap() {
-                    block_seq.block(None, |block| {
-                        // Check which call target we are in
-                        block
-                            .local_get(call_target)
-                            .i32_const((global_idx).try_into().unwrap())
-                            .binop(BinaryOp::I32Eq)
-                            .if_else(
-                                None,
-                                |then| {
-                                    // For each target, we want to check if the previous indirect call
-                                    // matches...
-                                    then.global_get(*array_value)
-                                        .i32_const(-1)
-                                        .binop(BinaryOp::I32Eq)
-                                        // OR if the value is already set
-                                        .global_get(*array_value)
-                                        .local_get(indirect_call_value)
-                                        .binop(BinaryOp::I32Eq)
-                                        .binop(BinaryOp::I32Or)
-                                        // if the global == -1, then the function hasn't been called yet!
-                                        // we can set the global value...
-                                        .if_else(
-                                            None,
-                                            |then| {
-                                                then.local_get(indirect_call_value)
-                                                    .global_set(*array_value)
-                                                    .i32_const(1)
-                                                    .local_set(set_value)
-                                                    .br(block_seq_id);
-                                            },
-                                            |_| {},
-                                        );
-                                },
-                                |else_| {},
-                            );
-                    });
+    let callsite_windows = matches
+        .value_of("prior_profile")
+        .map(|path| adaptive_windows(&read_as_format(path), indirect_window))
+        .unwrap_or_default();
+    let hot_functions = matches.value_of("hot_functions_from").map(|path| {
+        let coarse = read_as_format(path);
+        let module = walrus::Module::from_buffer(&wasm_bytes).unwrap();
+        let hot = costs::hot_functions(&module, &coarse, hot_threshold);
+        println!("{} hot functions in the coarse profile", hot.len());
+        hot
+    });
+    let options = pipeline::InstrumentOptions {
+        window: indirect_window,
+        block_counters,
+        split_cold,
+        hot_threshold,
+        divergence,
+        br_table_counters: matches.value_of("br_table_counters").map(|_| {
+            value_t!(matches.value_of("br_table_counters"), usize).unwrap_or_else(|e| e.exit())
+        }),
+        peel_br_tables: matches.value_of("peel_br_tables").map(|_| {
+            value_t!(matches.value_of("peel_br_tables"), usize).unwrap_or_else(|e| e.exit())
+        }),
+        trace,
+        trace_entries,
+        snapshot_every: matches.value_of("snapshot_every").map(|_| {
+            value_t!(matches.value_of("snapshot_every"), u32).unwrap_or_else(|e| e.exit())
+        }),
+        snapshot_capacity,
+        snapshot_tick: matches
+            .value_of("snapshot_tick")
+            .map(|value| match value.split_once('.') {
+                Some((module, name)) => (module.to_string(), name.to_string()),
+                None => {
+                    eprintln!("--snapshot-tick expects MODULE.NAME, got {}", value);
+                    std::process::exit(1);
                 }
+            }),
+        self_check: matches.is_present("self_check"),
+        skip_entry_callsites: matches.is_present("skip_entry_callsites"),
+        min_func_size,
+        retain_callsites: matches
+            .value_of("retain_callsites")
+            .map(read_callsite_keys)
+            .unwrap_or_default(),
+        explain: matches
+            .values_of("explain")
+            .map(|values| values.map(|v| v.to_string()).collect())
+            .unwrap_or_default(),
+        explain_all: matches.is_present("explain_all"),
+        export_prefix,
+        compact_exports: matches.is_present("compact_exports"),
+        max_overhead: matches.value_of("max_overhead").map(|_| {
+            value_t!(matches.value_of("max_overhead"), u64).unwrap_or_else(|e| e.exit())
+        }),
+        max_size_increase: matches.value_of("max_size_increase").map(|_| {
+            value_t!(matches.value_of("max_size_increase"), f64).unwrap_or_else(|e| e.exit())
+        }),
+        size_warn_only: matches.is_present("size_warn_only"),
+        max_memory: matches.value_of("max_memory").map(|_| {
+            value_t!(matches.value_of("max_memory"), usize).unwrap_or_else(|e| e.exit())
+        }),
+        allow_shared_memory: matches.is_present("allow_shared_memory"),
+        import_counters: matches.is_present("import_counters"),
+        entry_counters: matches.is_present("entry_counters"),
+        entry_point: matches.value_of("entry_point").map(|name| name.to_string()),
+        global_counters: matches.is_present("global_counters"),
+        reorder_globals: matches.is_present("reorder_globals"),
+        data_counters: matches.is_present("data_counters"),
+        reorder_data: matches.is_present("reorder_data"),
+        value_profile: matches.is_present("value_profile"),
+        memory_counters: matches.is_present("memory_counters"),
+        count_indirect_slowcalls: matches.is_present("count_indirect_slowcalls"),
+        loop_counters: matches.is_present("loop_counters"),
+        slot_memory: matches.is_present("slot_memory"),
+        host_events: matches.is_present("host_events"),
+        context_tree: matches.is_present("context_tree"),
+        context_nodes,
+        coarse: matches.is_present("coarse"),
+        hot_functions,
+        reachable_only: matches.is_present("reachable_only").then(|| {
+            roots_from_names(matches.values_of("reachable_only").into_iter().flatten())
+        }),
+        reachable_from: matches
+            .values_of("reachable_from")
+            .map(|names| names.map(|name| name.to_string()).collect())
+            .unwrap_or_default(),
+        callsite_windows,
+        skip_static_callsites: matches.is_present("skip_static_callsites"),
+        max_callsites: matches.value_of("max_callsites").map(|_| {
+            value_t!(matches.value_of("max_callsites"), usize).unwrap_or_else(|e| e.exit())
+        }),
+        callsite_round: matches.value_of("callsite_round").map_or(0, |_| {
+            value_t!(matches.value_of("callsite_round"), usize).unwrap_or_else(|e| e.exit())
+        }),
+        devirt_static: matches.is_present("devirt_static"),
+        re_instrument: matches.is_present("re_instrument"),
+        emscripten: matches.is_present("emscripten"),
+        // run_ab_split sets it for the conservative build
+        conservative: false,
+        // Filled in per module by linked::optimize_set
+        linked_targets: std::collections::HashMap::new(),
+        import_classes: matches
+            .value_of("import_classes")
+            .map(ImportClasses::read)
+            .unwrap_or_default(),
+        analysis_cache: matches.value_of("analysis_cache").map(|path| path.to_string()),
+        verbose: matches.is_present("verbose"),
+        demangle: !matches.is_present("no_demangle"),
+        counters: CounterPolicy {
+            init: value_t!(matches.value_of("counter_init"), i32).unwrap_or_else(|e| e.exit()),
+            mode: CounterMode::from_name(matches.value_of("counter_mode").unwrap()),
+        },
+        init_mode: InitMode::from_name(matches.value_of("init_mode").unwrap()),
+        compilation_hints: matches.is_present("compilation_hints"),
+        keep_custom: matches
+            .values_of("keep_custom")
+            .map(|values| values.map(|v| v.to_string()).collect())
+            .unwrap_or_default(),
+        drop_custom: matches
+            .values_of("drop_custom")
+            .map(|values| values.map(|v| v.to_string()).collect())
+            .unwrap_or_default(),
+        slot_policy: SlotPolicy::from_name(matches.value_of("slot_policy").unwrap()),
+        specialize: matches.is_present("specialize"),
+        enable_features: matches
+            .values_of("enable_feature")
+            .map(|values| values.map(|v| v.to_string()).collect())
+            .unwrap_or_default(),
+        force_devirt: matches
+            .values_of("force_devirt")
+            .map(|values| {
+                values
+                    .map(|value| match value.split_once('=') {
+                        Some((key, func)) => (key.to_string(), func.to_string()),
+                        None => {
+                            eprintln!("--force-devirt expects CALLSITE=FUNCTION, got {}", value);
+                            std::process::exit(1);
+                        }
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+        table_index: matches.value_of("table_index").map(|_| {
+            value_t!(matches.value_of("table_index"), u32).unwrap_or_else(|e| e.exit())
+        }),
+    };
+    let mut lock = matches.value_of("callsite_lock").map(CallsiteLock::load);
+    // Profiles collected under locked ids are keyed by position in this build
+    let map = match (map, &lock) {
+        (Some(map), Some(lock)) if !matches.is_present("link_module") => {
+            let module = features::module_config(&options.enable_features)
+                .parse(&wasm_bytes)
+                .unwrap();
+            let table = function_table(&module, options.table_index);
+            Some(lock.localize(&lock_keys(&module, table, None), map))
+        }
+        (map, _) => map,
+    };
+    if let Some(path) = matches.value_of("emit_vv_hints") {
+        let module = features::module_config(&options.enable_features)
+            .parse(&wasm_bytes)
+            .unwrap();
+        let table = function_table(&module, options.table_index);
+        vvhints::compute_hints(&module, table, map.as_ref().unwrap(), hot_threshold).write(path);
+    }
+    if let Some(sides) = matches.values_of("link_module") {
+        let sides: Vec<&str> = sides.collect();
+        let name = |path: &str| {
+            std::path::Path::new(path)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .to_string()
+        };
+        let mut modules = vec![(name(&input), wasm_bytes)];
+        let mut outputs = vec![output.clone()];
+        for side in sides.chunks(2) {
+            modules.push((name(side[0]), std::fs::read(side[0]).unwrap()));
+            outputs.push(side[1].to_string());
+        }
+        let link_manifest = matches.value_of("link_manifest").unwrap();
+        let result = match &map {
+            Some(map) => {
+                let link = linked::LinkManifest::read(link_manifest);
+                linked::optimize_set(&modules, map, &link, &options, lock.as_ref())
+                    .map(|outputs| outputs.into_iter().map(|output| output.wasm).collect())
             }
-            drop(block_seq);
-            let mut func_body = func_builder.func_body();
-            func_body.instr_at(
-                1,
-                walrus::ir::Instr::Block(walrus::ir::Block { seq: block_seq_id }),
-            );
-            let mut block_seq = func_builder.dangling_instr_seq(None);
-            let block_seq_id = block_seq.id();
-            // now check if we failed to set any of the slots for our call target
-            // we have to do this for each call target all over again...
-            for global_idx in 0..global_index as usize {
-                let arr = global_map.get(&(global_idx as usize)).unwrap();
-                block_seq 
-                    .local_get(call_target)
-                    .i32_const((global_idx).try_into().unwrap())
-                    .binop(BinaryOp::I32Eq)
-                    .if_else(
-                        None,
-                        |then| {
-                            then.local_get(set_value)
-                                .i32_const(1)
-                                .binop(BinaryOp::I32Ne)
-                                .if_else(
-                                    None,
-                                    |then| {
-                                        for idx in 0..indirect_window {
-                                            then.i32_const(-2).global_set(arr[idx]);
-                                        }
-                                    },
-                                    |_| {},
-                                );
-                        },
-                        |_| {},
-                    );
+            None => {
+                linked::instrument_set(&modules, &options, lock.as_mut()).map(|(wasm, link)| {
+                    link.write(link_manifest);
+                    wasm
+                })
             }
-            let mut func_body = func_builder.func_body();
-            func_body.instr_at(
-                2,
-                walrus::ir::Instr::Block(walrus::ir::Block { seq: block_seq_id }),
-            );
-            //end
         }
-
-        // Now that we have instrumented the indirect calls,
-        // we will instrument the regular slowcalls
-    
-        // Don't include these exported globals in the final optimized binary
-        if !is_opt {
-            module.exports.add(&format!("indirect"), indirect_id.unwrap());
-            module.exports.add(&format!("slowcalls"), slowcalls_id.unwrap());
+        .unwrap_or_else(|e: pipeline::Error| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        for (path, wasm) in outputs.iter().zip(result) {
+            std::fs::write(path, wasm).unwrap();
+        }
+        smoke_test(&outputs);
+        if let (Some(lock), None) = (&lock, optimize) {
+            lock.write(matches.value_of("callsite_lock").unwrap());
         }
+        return;
+    }
+    let (mut result, instrumented, conservative) = match (
+        map,
+        matches.value_of("emit_instrumented"),
+        matches.value_of("ab_split"),
+    ) {
+        (Some(map), Some(_), _) => pipeline::run_and_reinstrument(&wasm_bytes, map, &options)
+            .map(|(result, instrumented)| (result, Some(instrumented), None)),
+        (Some(map), _, Some(_)) => pipeline::run_ab_split(&wasm_bytes, map, &options)
+            .map(|(result, conservative)| (result, None, Some(conservative))),
+        (map, _, _) => pipeline::run(&wasm_bytes, map, &options).map(|result| (result, None, None)),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if let (Some(lock), None) = (&mut lock, optimize) {
+        let keys: Vec<String> = result
+            .manifest
+            .callsites
+            .iter()
+            .map(|callsite| callsite.key.clone())
+            .collect();
+        let ids = lock.allocate(&keys);
+        pipeline::renumber_callsites(&mut result, &ids, &options).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        });
+        lock.write(matches.value_of("callsite_lock").unwrap());
+    }
+    if matches.is_present("embed_manifest") && optimize.is_none() {
+        manifest::embed_manifest(&mut result.wasm, &result.manifest);
+    }
+    let mut pending = vec![outputs::write_in_background(
+        &output,
+        std::mem::take(&mut result.wasm),
+    )];
+    let mut written = vec![output.clone()];
+    if let Some(conservative) = conservative {
+        let path = matches.value_of("ab_split").unwrap();
+        pending.push(outputs::write_in_background(path, conservative.wasm));
+        written.push(path.to_string());
+    }
 
-        // Export all of our globals
-        for (idx, g) in global_map {
-            // We represent each callsite using multuple global values
-            for inner_idx in 0..g.len() {
-                module.exports.add(
-                    &format!("profiling_global_{}_{}", idx, inner_idx),
-                    g[inner_idx],
-                );
+    if matches.is_present("run_wasm_opt") {
+        for write in pending.drain(..) {
+            write.finish(options.verbose);
+        }
+        let args: Vec<String> = matches
+            .value_of("run_wasm_opt")
+            .unwrap_or("-O2")
+            .split_whitespace()
+            .map(|arg| arg.to_string())
+            .collect();
+        for path in &written {
+            match wasmopt::run_wasm_opt(path, &args) {
+                Ok((before, after)) => println!(
+                    "wasm-opt: {} -> {} bytes ({:+} bytes)",
+                    before,
+                    after,
+                    after as i64 - before as i64
+                ),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
             }
         }
     }
 
-    if !is_opt {
-        generate_slowcall_stubs(&mut module, &slowcalls, &slowcalls_id.unwrap())
+    if let Some(path) = matches.value_of("manifest") {
+        result.manifest.write(path);
     }
+    if let Some(path) = matches.value_of("decisions") {
+        explain::write_decisions(path, &result.decisions);
+    }
+    if let Some(path) = matches.value_of("emit_index_map") {
+        result.index_map.write(path);
+    }
+    if let Some(mut instrumented) = instrumented {
+        if matches.is_present("embed_manifest") {
+            manifest::embed_manifest(&mut instrumented.wasm, &instrumented.manifest);
+        }
+        let path = matches.value_of("emit_instrumented").unwrap();
+        pending.push(outputs::write_in_background(path, instrumented.wasm));
+        written.push(path.to_string());
+        if let Some(path) = matches.value_of("instrumented_manifest") {
+            instrumented.manifest.write(path);
+        }
+    }
+    for write in pending {
+        write.finish(options.verbose);
+    }
+    smoke_test(&written);
+}
 
-    let wasm = module.emit_wasm();
-    std::fs::write(output, wasm).unwrap();
+// Reports a bad input (a profile that doesn't decode, say) and exits
+fn or_exit<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    })
 }
@@ -0,0 +1,112 @@
+pub use crate::schema::{
+    CallsiteChunk, CallsiteEntry, ContextTreeLayout, CounterLayout, DescriptorLayout, EntryPoint,
+    HostEventAbi, ImportEntry, Manifest, SlotMemoryLayout, SnapshotLayout, TraceLayout,
+};
+use crate::Profile;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use wasmparser::{Parser, Payload};
+
+// Custom section carrying the manifest inside the instrumented binary (--embed-manifest)
+pub const MANIFEST_SECTION: &str = "vv.pgo.manifest";
+
+/*
+ * Identifies the original (uninstrumented) binary a profile belongs to, so
+ * profiles from a different build can be rejected instead of silently
+ * devirtualizing the wrong callsites. 64-bit FNV-1a of the module bytes,
+ * which is stable across toolchains unlike std's DefaultHasher.
+ */
+pub fn fingerprint(wasm: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in wasm {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+impl Manifest {
+    pub fn write(&self, path: &str) {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, json).unwrap();
+    }
+
+    pub fn read(path: &str) -> Manifest {
+        let mut file = File::open(path).unwrap();
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        serde_json::from_str(&buf).unwrap()
+    }
+}
+
+// What MANIFEST_SECTION holds: the manifest, and the empty profile a collector fills in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmbeddedManifest {
+    pub manifest: Manifest,
+    // Every callsite with no slots yet, see Profile::map
+    pub profile: Profile,
+}
+
+pub fn leb128(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+/*
+ * Append MANIFEST_SECTION to an emitted binary, so the manifest travels with
+ * it between CI stages instead of as a sidecar file. Custom sections may go
+ * anywhere, so appending one leaves the rest of the binary (and its function
+ * indices) untouched.
+ */
+pub fn embed_manifest(wasm: &mut Vec<u8>, manifest: &Manifest) {
+    let embedded = EmbeddedManifest {
+        manifest: manifest.clone(),
+        profile: Profile {
+            map: manifest.callsites.iter().map(|c| (c.id, vec![])).collect(),
+            ..Profile::default()
+        },
+    };
+    let data = serde_json::to_vec(&embedded).unwrap();
+    let mut section = vec![];
+    leb128(&mut section, MANIFEST_SECTION.len() as u32);
+    section.extend_from_slice(MANIFEST_SECTION.as_bytes());
+    section.extend(data);
+    wasm.push(0);
+    leb128(wasm, section.len() as u32);
+    wasm.extend(section);
+}
+
+// The MANIFEST_SECTION of a binary, if it has a well-formed one
+pub fn embedded_manifest(wasm: &[u8]) -> Option<EmbeddedManifest> {
+    for payload in Parser::new(0).parse_all(wasm) {
+        match payload.ok()? {
+            Payload::CustomSection(reader) if reader.name() == MANIFEST_SECTION => {
+                return serde_json::from_slice(reader.data())
+                    .map_err(|e| println!("ignoring malformed {} section: {}", MANIFEST_SECTION, e))
+                    .ok();
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+// A list of callsite keys, one per line (blank lines are ignored)
+pub fn read_callsite_keys(path: &str) -> HashSet<String> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_string())
+        .collect()
+}
@@ -0,0 +1,174 @@
+use crate::callsites::{callsite_keys, enumerate_callsites};
+use crate::dispatch::{dispatch_kinds, DispatchKind};
+use crate::dwarf::{annotate, SourceMap};
+use crate::profilemap::function_table;
+use crate::report::func_name;
+use crate::symbolize::display_name;
+use crate::tableimage::TableImage;
+use crate::typecompat::func_matches;
+use crate::Profile;
+use walrus::*;
+
+// The largest --window (see pipeline::check_options)
+const MAX_WINDOW: usize = 50;
+// Share of a callsite's calls that makes its hottest target worth forcing
+const DOMINANT_SHARE: f64 = 0.8;
+
+// What to do about a megamorphic callsite
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recommendation {
+    // One target takes most of the calls (by the collector's counts): --force-devirt it
+    ForceDevirt(String),
+    // Every type-compatible table entry fits in a window this large
+    IncreaseWindow(usize),
+    // The table is filled at runtime, so its fan-out is unknown: keep the most recent
+    // targets instead of giving up on the callsite
+    SlotPolicyLru,
+    // More compatible entries than any window holds
+    RefactorDispatch,
+}
+
+impl Recommendation {
+    pub fn describe(&self, key: &str) -> String {
+        match self {
+            Recommendation::ForceDevirt(target) => {
+                format!(
+                    "--force-devirt {}={} (it takes most of the calls)",
+                    key, target
+                )
+            }
+            Recommendation::IncreaseWindow(window) => {
+                format!("--window {} (every compatible table entry fits)", window)
+            }
+            Recommendation::SlotPolicyLru => {
+                "--slot-policy lru (the table is filled at runtime)".to_string()
+            }
+            Recommendation::RefactorDispatch => {
+                "refactor the dispatch (more compatible entries than a window holds)".to_string()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Megamorphic {
+    pub callsite: usize,
+    pub key: String,
+    pub func: FunctionId,
+    // Slots the callsite was recorded with
+    pub window: usize,
+    // Table entries whose type the callsite's call_indirect accepts, None when the
+    // table's segments aren't all placed statically
+    pub fan_out: Option<usize>,
+    pub dispatch: DispatchKind,
+    pub recommendation: Recommendation,
+}
+
+/*
+ * The callsites of `profile` that saw more targets than their window: every
+ * slot overflowed (-2), or, with --slot-policy lru, more distinct targets
+ * than the slots kept. Ordered by fan-out, widest first, since those are the
+ * ones a bigger window won't fix. `table` numbers the callsites, see
+ * enumerate_callsites.
+ */
+pub fn megamorphic_callsites(
+    module: &Module,
+    table: Option<TableId>,
+    profile: &Profile,
+) -> Vec<Megamorphic> {
+    let callsites = enumerate_callsites(module, table);
+    let keys = callsite_keys(module, &callsites);
+    let kinds = dispatch_kinds(module, &callsites);
+    let image = function_table(module, None).map(|table| TableImage::build(module, table));
+    let mut megamorphic = vec![];
+    for (idx, callsite) in callsites.iter().enumerate() {
+        let slots = match profile.map.get(&idx) {
+            Some(slots) if !slots.is_empty() => slots,
+            _ => continue,
+        };
+        let kept = slots.iter().filter(|slot| **slot >= 0).count() as u64;
+        let overflowed = slots.iter().all(|slot| *slot == -2)
+            || profile
+                .distinct_targets
+                .get(&idx)
+                .map_or(false, |distinct| *distinct > kept);
+        if !overflowed {
+            continue;
+        }
+        let fan_out = image
+            .as_ref()
+            .filter(|image| image.unplaced == 0)
+            .map(|image| {
+                image
+                    .entries
+                    .values()
+                    .filter(|func| func_matches(module, **func, callsite.ty))
+                    .count()
+            });
+        let dominant = profile.target_counts.get(&idx).and_then(|counts| {
+            let total: u64 = counts.iter().map(|(_, count)| count).sum();
+            let (slot, count) = counts.iter().max_by_key(|(_, count)| *count)?;
+            let func = image.as_ref()?.get(*slot)?;
+            let share = *count as f64 / total as f64;
+            (share >= DOMINANT_SHARE && func_matches(module, func, callsite.ty)).then_some(func)
+        });
+        let recommendation = match (dominant, fan_out) {
+            (Some(func), _) => Recommendation::ForceDevirt(func_name(module, func)),
+            (None, Some(fan_out)) if fan_out <= MAX_WINDOW => {
+                Recommendation::IncreaseWindow(std::cmp::max(fan_out, slots.len() + 1))
+            }
+            (None, Some(_)) => Recommendation::RefactorDispatch,
+            (None, None) => Recommendation::SlotPolicyLru,
+        };
+        megamorphic.push(Megamorphic {
+            callsite: idx,
+            key: keys[idx].clone(),
+            func: callsite.func,
+            window: slots.len(),
+            fan_out,
+            dispatch: kinds[idx],
+            recommendation,
+        });
+    }
+    megamorphic.sort_by(|a, b| b.fan_out.cmp(&a.fan_out).then(a.callsite.cmp(&b.callsite)));
+    megamorphic
+}
+
+// The `top` widest megamorphic callsites with what to do about them, if there are any
+pub fn megamorphic_report(
+    module: &Module,
+    table: Option<TableId>,
+    profile: &Profile,
+    top: usize,
+    demangle: bool,
+    sources: Option<&SourceMap>,
+) {
+    let megamorphic = megamorphic_callsites(module, table, profile);
+    if megamorphic.is_empty() {
+        return;
+    }
+    let callsites = enumerate_callsites(module, table);
+    println!("== Megamorphic callsites ({}) ==", megamorphic.len());
+    println!(
+        "{:>8} {:>6} {:>8}  {:<8} {:<40} {}",
+        "callsite", "window", "fan-out", "dispatch", "function", "recommendation"
+    );
+    for site in megamorphic.iter().take(top) {
+        let fan_out = match site.fan_out {
+            Some(fan_out) => fan_out.to_string(),
+            None => "?".to_string(),
+        };
+        println!(
+            "{:>8} {:>6} {:>8}  {:<8} {:<40} {}",
+            site.callsite,
+            site.window,
+            fan_out,
+            site.dispatch.name(),
+            annotate(
+                display_name(module, site.func, demangle),
+                sources.and_then(|s| s.locate(callsites[site.callsite].loc))
+            ),
+            site.recommendation.describe(&site.key)
+        );
+    }
+}
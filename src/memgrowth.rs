@@ -0,0 +1,105 @@
+use std::collections::HashMap;
+use walrus::ir::*;
+use walrus::*;
+
+struct GrowScanner {
+    stubs: HashMap<MemoryId, FunctionId>,
+    curr_func: FunctionId,
+}
+
+// Replace each memory.grow with a call to the stub for its memory
+impl VisitorMut for GrowScanner {
+    fn visit_instr_mut(&mut self, instr: &mut Instr, _: &mut InstrLocId) {
+        if let Instr::MemoryGrow(grow) = instr {
+            match self.stubs.get(&grow.memory) {
+                Some(stub) if *stub != self.curr_func => *instr = Instr::Call(Call { func: *stub }),
+                _ => (),
+            }
+        }
+    }
+}
+
+/*
+ * Track how each memory grows: every memory.grow goes through a stub that
+ * performs it and, when it succeeds, adds the delta to
+ * {prefix}profiling_memory_{idx}_grown and raises
+ * {prefix}profiling_memory_{idx}_max_pages to the new size. The high-water
+ * mark starts at the declared initial size, so a run that never grows still
+ * reports what it was given.
+ */
+pub fn instrument_memory_growth(module: &mut Module, export_prefix: &str) -> usize {
+    let memories: Vec<(MemoryId, u32)> = module
+        .memories
+        .iter()
+        .map(|m| (m.id(), m.initial))
+        .collect();
+    let mut stubs = HashMap::new();
+    for (idx, (memory, initial)) in memories.iter().enumerate() {
+        let grown = module
+            .globals
+            .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+        let max_pages = module.globals.add_local(
+            ValType::I32,
+            true,
+            InitExpr::Value(Value::I32(*initial as i32)),
+        );
+        module.exports.add(
+            &format!("{}profiling_memory_{}_grown", export_prefix, idx),
+            grown,
+        );
+        module.exports.add(
+            &format!("{}profiling_memory_{}_max_pages", export_prefix, idx),
+            max_pages,
+        );
+
+        let mut stub = FunctionBuilder::new(&mut module.types, &[ValType::I32], &[ValType::I32]);
+        stub.name(format!("memory_grow_stub_{}", idx));
+        let delta = module.locals.add(ValType::I32);
+        let result = module.locals.add(ValType::I32);
+        stub.func_body()
+            .local_get(delta)
+            .memory_grow(*memory)
+            .local_tee(result)
+            .i32_const(-1)
+            .binop(BinaryOp::I32Ne)
+            .if_else(
+                None,
+                |then| {
+                    then.global_get(grown)
+                        .local_get(delta)
+                        .binop(BinaryOp::I32Add)
+                        .global_set(grown)
+                        // memory.size is unsigned, so is the high-water mark
+                        .memory_size(*memory)
+                        .global_get(max_pages)
+                        .binop(BinaryOp::I32GtU)
+                        .if_else(
+                            None,
+                            |raise| {
+                                raise.memory_size(*memory).global_set(max_pages);
+                            },
+                            |_| {},
+                        );
+                },
+                |_| {},
+            )
+            .local_get(result);
+        stubs.insert(*memory, stub.finish(vec![delta], &mut module.funcs));
+    }
+
+    let funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    for id in funcs {
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        let mut scan = GrowScanner {
+            stubs: stubs.clone(),
+            curr_func: id,
+        };
+        dfs_pre_order_mut(&mut scan, func, entry);
+    }
+    println!(
+        "Instrumented {} memories with growth counters",
+        memories.len()
+    );
+    memories.len()
+}
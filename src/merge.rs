@@ -0,0 +1,451 @@
+use crate::valueprofile::merge_votes;
+use crate::Profile;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/*
+ * Merge the slots two runs observed for the same callsite (see
+ * merge_lru_slots for callsites recorded with SlotPolicy::Lru).
+ *
+ * The result holds the union of the observed targets. If either side
+ * overflowed its window (-2), or the union no longer fits, the merged callsite
+ * is marked as overflowed too, since devirtualizing it would trap on targets
+ * that one of the runs has seen.
+ */
+pub fn merge_slots(a: &[i64], b: &[i64]) -> Vec<i64> {
+    let window = std::cmp::max(a.len(), b.len());
+    if a.contains(&-2) || b.contains(&-2) {
+        return vec![-2; window];
+    }
+    let mut targets: Vec<i64> = a
+        .iter()
+        .chain(b.iter())
+        .filter(|t| **t >= 0)
+        .cloned()
+        .collect();
+    targets.sort();
+    targets.dedup();
+    if targets.len() > window {
+        return vec![-2; window];
+    }
+    targets.resize(window, -1);
+    targets
+}
+
+/*
+ * The `window` most recent of `targets`, which lists the targets of one or
+ * more SlotPolicy::Lru windows slot by slot (every window's most recent
+ * first), padded with -1. Nothing overflows: these callsites count what
+ * they missed in Profile::distinct_targets.
+ */
+pub fn most_recent(targets: impl Iterator<Item = i64>, window: usize) -> Vec<i64> {
+    let mut kept: Vec<i64> = vec![];
+    for target in targets.filter(|t| *t >= 0) {
+        if !kept.contains(&target) {
+            kept.push(target);
+        }
+    }
+    kept.resize(window, -1);
+    kept
+}
+
+// Merge the slots of a SlotPolicy::Lru callsite, see most_recent
+pub fn merge_lru_slots(a: &[i64], b: &[i64]) -> Vec<i64> {
+    let window = std::cmp::max(a.len(), b.len());
+    let interleaved = (0..window).flat_map(|slot| a.get(slot).into_iter().chain(b.get(slot)));
+    most_recent(interleaved.cloned(), window)
+}
+
+// Union `other`'s callsite slots into `acc`'s (`lru`: callsites recorded with SlotPolicy::Lru)
+fn merge_map(
+    acc: &mut HashMap<usize, Vec<i64>>,
+    other: &HashMap<usize, Vec<i64>>,
+    lru: &HashSet<usize>,
+) {
+    for (idx, slots) in other {
+        let merged = match acc.get(idx) {
+            Some(existing) if lru.contains(idx) => merge_lru_slots(existing, slots),
+            Some(existing) => merge_slots(existing, slots),
+            None => slots.clone(),
+        };
+        acc.insert(*idx, merged);
+    }
+}
+
+/*
+ * Accumulate `other` into `acc`: slots are unioned, counters are summed. A
+ * dump tagged with an entry point also adds its slots to that entry's own
+ * callsite data (Profile::by_entry), so an aggregate keeps what each handler
+ * called. The tag itself is left alone, it only means something per dump.
+ */
+pub fn merge_into(acc: &mut Profile, other: &Profile) {
+    let lru: HashSet<usize> = acc
+        .distinct_targets
+        .keys()
+        .chain(other.distinct_targets.keys())
+        .cloned()
+        .collect();
+    merge_map(&mut acc.map, &other.map, &lru);
+    if let Some(entry) = other.entry {
+        merge_map(acc.by_entry.entry(entry).or_default(), &other.map, &lru);
+    }
+    for (entry, map) in &other.by_entry {
+        merge_map(acc.by_entry.entry(*entry).or_default(), map, &lru);
+    }
+    // Each run's count is a lower bound of the distinct targets both saw
+    for (idx, count) in &other.distinct_targets {
+        let entry = acc.distinct_targets.entry(*idx).or_insert(0);
+        *entry = std::cmp::max(*entry, *count);
+    }
+    for (idx, counts) in &other.target_counts {
+        let acc_counts = acc.target_counts.entry(*idx).or_default();
+        for (target, count) in counts {
+            match acc_counts.iter_mut().find(|(t, _)| t == target) {
+                Some((_, acc_count)) => *acc_count = acc_count.saturating_add(*count),
+                None => acc_counts.push((*target, *count)),
+            }
+        }
+    }
+    for (idx, count) in &other.blocks {
+        let entry = acc.blocks.entry(*idx).or_insert(0);
+        *entry = entry.saturating_add(*count);
+    }
+    for (idx, (taken, not_taken)) in &other.branches {
+        let entry = acc.branches.entry(*idx).or_insert((0, 0));
+        entry.0 = entry.0.saturating_add(*taken);
+        entry.1 = entry.1.saturating_add(*not_taken);
+    }
+    for (idx, arms) in &other.br_tables {
+        let entry = acc.br_tables.entry(*idx).or_default();
+        if entry.len() < arms.len() {
+            entry.resize(arms.len(), 0);
+        }
+        for (acc_count, count) in entry.iter_mut().zip(arms) {
+            *acc_count = acc_count.saturating_add(*count);
+        }
+    }
+    for (idx, count) in &other.imports {
+        let entry = acc.imports.entry(*idx).or_insert(0);
+        *entry = entry.saturating_add(*count);
+    }
+    for (idx, count) in &other.global_accesses {
+        let entry = acc.global_accesses.entry(*idx).or_insert(0);
+        *entry = entry.saturating_add(*count);
+    }
+    for (addr, count) in &other.data_accesses {
+        let entry = acc.data_accesses.entry(*addr).or_insert(0);
+        *entry = entry.saturating_add(*count);
+    }
+    for (idx, count) in &other.entries {
+        let entry = acc.entries.entry(*idx).or_insert(0);
+        *entry = entry.saturating_add(*count);
+    }
+    for (idx, (grown, max_pages)) in &other.memory {
+        let entry = acc.memory.entry(*idx).or_insert((0, 0));
+        entry.0 = entry.0.saturating_add(*grown);
+        entry.1 = std::cmp::max(entry.1, *max_pages);
+    }
+    for (idx, (entries, iterations, max_trip)) in &other.loops {
+        let entry = acc.loops.entry(*idx).or_insert((0, 0, 0));
+        entry.0 = entry.0.saturating_add(*entries);
+        entry.1 = entry.1.saturating_add(*iterations);
+        entry.2 = std::cmp::max(entry.2, *max_trip);
+    }
+    for (idx, votes) in &other.values {
+        let merged = match acc.values.get(idx) {
+            Some(existing) => merge_votes(*existing, *votes),
+            None => *votes,
+        };
+        acc.values.insert(*idx, merged);
+    }
+    if let Some(slowcalls) = other.slowcalls {
+        acc.slowcalls = Some(acc.slowcalls.unwrap_or(0).saturating_add(slowcalls));
+    }
+}
+
+/*
+ * Split per-invocation dumps by the entry point they were taken after (the
+ * profiling_entry tag, see entrypoints) and merge each handler's dumps into
+ * a profile of its own, tagged with the entry, for optimize --entry-point. Dumps
+ * without a tag (taken before the host invoked anything) are dropped.
+ */
+pub fn segment_by_entry(profiles: &[Profile]) -> BTreeMap<usize, Profile> {
+    let mut segments: BTreeMap<usize, Profile> = BTreeMap::new();
+    for profile in profiles {
+        if let Some(entry) = profile.entry {
+            let segment = segments.entry(entry).or_default();
+            merge_into(segment, profile);
+            segment.entry = Some(entry);
+        }
+    }
+    segments
+}
+
+// Weights below this are dropped (a target last seen ~4.3 half-lives ago)
+const MIN_WEIGHT: f64 = 0.05;
+
+/*
+ * Running aggregate where every profile's contribution decays exponentially
+ * with its age, so targets that stop occurring eventually fall out of the
+ * merged slots instead of being devirtualized (and guarded) forever.
+ *
+ * Everything is kept as weights relative to `updated`: each observed target
+ * and overflow contributes the weight of the profile it came from, counters
+ * contribute their count times that weight. The per-entry callsite data
+ * (Profile::by_entry) isn't kept.
+ */
+pub struct DecayingProfile {
+    half_life: f64,
+    updated: u64,
+    targets: HashMap<usize, HashMap<i64, f64>>,
+    overflow: HashMap<usize, f64>,
+    windows: HashMap<usize, usize>,
+    // Profile::distinct_targets, the max seen (it doesn't decay)
+    distinct: HashMap<usize, u64>,
+    // Callsites whose every target decayed away
+    expired: HashSet<usize>,
+    blocks: HashMap<usize, f64>,
+    branches: HashMap<usize, (f64, f64)>,
+    br_tables: HashMap<usize, Vec<f64>>,
+    slowcalls: Option<f64>,
+    imports: HashMap<usize, f64>,
+    global_accesses: HashMap<usize, f64>,
+    data_accesses: HashMap<usize, f64>,
+    entries: HashMap<usize, f64>,
+    // (value, votes, calls), with the votes and calls weighted
+    values: HashMap<usize, (i32, f64, f64)>,
+    // (pages grown, max pages): growth decays, the high-water mark never does
+    memory: HashMap<usize, (f64, i32)>,
+    // (entries, iterations, max trip): the counts decay, the max trip never does
+    loops: HashMap<usize, (f64, f64, i32)>,
+}
+
+impl DecayingProfile {
+    // `half_life` and all timestamps are in seconds
+    pub fn new(half_life: f64, now: u64) -> DecayingProfile {
+        assert!(half_life > 0.0, "--half-life must be positive");
+        DecayingProfile {
+            half_life,
+            updated: now,
+            targets: HashMap::new(),
+            overflow: HashMap::new(),
+            windows: HashMap::new(),
+            distinct: HashMap::new(),
+            expired: HashSet::new(),
+            blocks: HashMap::new(),
+            branches: HashMap::new(),
+            br_tables: HashMap::new(),
+            slowcalls: None,
+            imports: HashMap::new(),
+            global_accesses: HashMap::new(),
+            data_accesses: HashMap::new(),
+            entries: HashMap::new(),
+            values: HashMap::new(),
+            memory: HashMap::new(),
+            loops: HashMap::new(),
+        }
+    }
+
+    fn weight(&self, age: u64) -> f64 {
+        0.5f64.powf(age as f64 / self.half_life)
+    }
+
+    // Age everything we have so far to `now`
+    fn decay_to(&mut self, now: u64) {
+        if now <= self.updated {
+            return;
+        }
+        let factor = self.weight(now - self.updated);
+        self.updated = now;
+        for (idx, weights) in self.targets.iter_mut() {
+            let had_targets = !weights.is_empty();
+            weights.values_mut().for_each(|w| *w *= factor);
+            weights.retain(|_, w| *w >= MIN_WEIGHT);
+            if had_targets && weights.is_empty() {
+                self.expired.insert(*idx);
+            }
+        }
+        self.overflow.values_mut().for_each(|w| *w *= factor);
+        self.overflow.retain(|_, w| *w >= MIN_WEIGHT);
+        self.blocks.values_mut().for_each(|c| *c *= factor);
+        for (taken, not_taken) in self.branches.values_mut() {
+            *taken *= factor;
+            *not_taken *= factor;
+        }
+        if let Some(slowcalls) = self.slowcalls.as_mut() {
+            *slowcalls *= factor;
+        }
+        for arms in self.br_tables.values_mut() {
+            arms.iter_mut().for_each(|c| *c *= factor);
+        }
+        self.imports.values_mut().for_each(|c| *c *= factor);
+        self.global_accesses.values_mut().for_each(|c| *c *= factor);
+        self.data_accesses.values_mut().for_each(|c| *c *= factor);
+        self.entries.values_mut().for_each(|c| *c *= factor);
+        for (grown, _) in self.memory.values_mut() {
+            *grown *= factor;
+        }
+        for (entries, iterations, _) in self.loops.values_mut() {
+            *entries *= factor;
+            *iterations *= factor;
+        }
+        for (_, votes, calls) in self.values.values_mut() {
+            *votes *= factor;
+            *calls *= factor;
+        }
+    }
+
+    // Add a profile collected at `timestamp` (profiles from the future count as fresh)
+    pub fn add(&mut self, profile: &Profile, timestamp: u64, now: u64) {
+        self.decay_to(now);
+        let weight = self.weight(now.saturating_sub(timestamp));
+        for (idx, slots) in &profile.map {
+            let window = self.windows.entry(*idx).or_insert(0);
+            *window = std::cmp::max(*window, slots.len());
+            if slots.contains(&-2) {
+                *self.overflow.entry(*idx).or_insert(0.0) += weight;
+            }
+            let weights = self.targets.entry(*idx).or_default();
+            for target in slots.iter().filter(|t| **t >= 0) {
+                *weights.entry(*target).or_insert(0.0) += weight;
+                self.expired.remove(idx);
+            }
+        }
+        for (idx, count) in &profile.distinct_targets {
+            let entry = self.distinct.entry(*idx).or_insert(0);
+            *entry = std::cmp::max(*entry, *count);
+        }
+        for (idx, count) in &profile.blocks {
+            *self.blocks.entry(*idx).or_insert(0.0) += *count as f64 * weight;
+        }
+        for (idx, (taken, not_taken)) in &profile.branches {
+            let entry = self.branches.entry(*idx).or_insert((0.0, 0.0));
+            entry.0 += *taken as f64 * weight;
+            entry.1 += *not_taken as f64 * weight;
+        }
+        if let Some(slowcalls) = profile.slowcalls {
+            *self.slowcalls.get_or_insert(0.0) += slowcalls as f64 * weight;
+        }
+        for (idx, count) in &profile.imports {
+            *self.imports.entry(*idx).or_insert(0.0) += *count as f64 * weight;
+        }
+        for (idx, count) in &profile.global_accesses {
+            *self.global_accesses.entry(*idx).or_insert(0.0) += *count as f64 * weight;
+        }
+        for (addr, count) in &profile.data_accesses {
+            *self.data_accesses.entry(*addr).or_insert(0.0) += *count as f64 * weight;
+        }
+        for (idx, arms) in &profile.br_tables {
+            let entry = self.br_tables.entry(*idx).or_default();
+            if entry.len() < arms.len() {
+                entry.resize(arms.len(), 0.0);
+            }
+            for (acc_count, count) in entry.iter_mut().zip(arms) {
+                *acc_count += *count as f64 * weight;
+            }
+        }
+        for (idx, count) in &profile.entries {
+            *self.entries.entry(*idx).or_insert(0.0) += *count as f64 * weight;
+        }
+        for (idx, (grown, max_pages)) in &profile.memory {
+            let entry = self.memory.entry(*idx).or_insert((0.0, 0));
+            entry.0 += *grown as f64 * weight;
+            entry.1 = std::cmp::max(entry.1, *max_pages);
+        }
+        for (idx, (entries, iterations, max_trip)) in &profile.loops {
+            let entry = self.loops.entry(*idx).or_insert((0.0, 0.0, 0));
+            entry.0 += *entries as f64 * weight;
+            entry.1 += *iterations as f64 * weight;
+            entry.2 = std::cmp::max(entry.2, *max_trip);
+        }
+        // Same rule as merge_votes, on the weighted counts
+        for (idx, (value, votes, calls)) in &profile.values {
+            let (votes, calls) = (*votes as f64 * weight, *calls as f64 * weight);
+            let entry = self.values.entry(*idx).or_insert((*value, 0.0, 0.0));
+            entry.2 += calls;
+            if entry.0 == *value {
+                entry.1 += votes;
+            } else if entry.1 >= votes {
+                entry.1 -= votes;
+            } else {
+                *entry = (*value, votes - entry.1, entry.2);
+            }
+        }
+    }
+
+    // The merged profile as of `now`
+    pub fn snapshot(&mut self, now: u64) -> Profile {
+        self.decay_to(now);
+        let mut profile = Profile::default();
+        for (idx, window) in &self.windows {
+            // A callsite that only went quiet recently is not the same as one
+            // that never executed, so keep the indirect call rather than trap
+            let slots = if self.overflow.contains_key(idx) || self.expired.contains(idx) {
+                vec![-2; *window]
+            } else {
+                let mut targets: Vec<i64> = match self.targets.get(idx) {
+                    Some(weights) => weights.keys().cloned().collect(),
+                    None => vec![],
+                };
+                targets.sort();
+                if targets.len() > *window && self.distinct.contains_key(idx) {
+                    // SlotPolicy::Lru: keep the heaviest targets
+                    let weights = &self.targets[idx];
+                    targets.sort_by(|a, b| weights[b].partial_cmp(&weights[a]).unwrap());
+                    targets.truncate(*window);
+                    targets
+                } else if targets.len() > *window {
+                    vec![-2; *window]
+                } else {
+                    targets.resize(*window, -1);
+                    targets
+                }
+            };
+            profile.map.insert(*idx, slots);
+        }
+        for (idx, count) in &self.blocks {
+            profile.blocks.insert(*idx, count.round() as i32);
+        }
+        for (idx, (taken, not_taken)) in &self.branches {
+            profile
+                .branches
+                .insert(*idx, (taken.round() as u64, not_taken.round() as u64));
+        }
+        profile.distinct_targets = self.distinct.clone();
+        profile.slowcalls = self.slowcalls.map(|s| s.round() as i32);
+        for (idx, count) in &self.imports {
+            profile.imports.insert(*idx, count.round() as i32);
+        }
+        for (idx, count) in &self.global_accesses {
+            profile.global_accesses.insert(*idx, count.round() as i32);
+        }
+        for (addr, count) in &self.data_accesses {
+            profile.data_accesses.insert(*addr, count.round() as i32);
+        }
+        for (idx, arms) in &self.br_tables {
+            let arms = arms.iter().map(|count| count.round() as i32).collect();
+            profile.br_tables.insert(*idx, arms);
+        }
+        for (idx, count) in &self.entries {
+            profile.entries.insert(*idx, count.round() as i32);
+        }
+        for (idx, (grown, max_pages)) in &self.memory {
+            profile
+                .memory
+                .insert(*idx, (grown.round() as i32, *max_pages));
+        }
+        for (idx, (entries, iterations, max_trip)) in &self.loops {
+            profile.loops.insert(
+                *idx,
+                (entries.round() as i32, iterations.round() as i32, *max_trip),
+            );
+        }
+        for (idx, (value, votes, calls)) in &self.values {
+            profile
+                .values
+                .insert(*idx, (*value, votes.round() as i32, calls.round() as i32));
+        }
+        profile
+    }
+}
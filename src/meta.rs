@@ -0,0 +1,85 @@
+use crate::manifest::fingerprint;
+use crate::pipeline::InstrumentOptions;
+use crate::Profile;
+use serde::{Deserialize, Serialize};
+use walrus::*;
+
+// Custom section recording every run of this tool over a binary, oldest first
+pub const META_SECTION: &str = "vv.pgo.meta";
+pub const TOOL_NAME: &str = "vv-profiler";
+
+// One run of the tool, as recorded in META_SECTION
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ToolRun {
+    pub tool: String,
+    pub version: String,
+    // "instrument" or "optimize"
+    pub mode: String,
+    pub options: InstrumentOptions,
+    // manifest::fingerprint of the binary this run was given
+    pub input_fingerprint: String,
+    // profile_fingerprint of the profile used to optimize
+    pub profile_fingerprint: Option<String>,
+}
+
+/*
+ * Fingerprint of a profile's contents. The maps are hashed in key order, so
+ * the same counters give the same fingerprint whatever format or order they
+ * were stored in.
+ */
+pub fn profile_fingerprint(profile: &Profile) -> String {
+    let mut map: Vec<_> = profile.map.iter().collect();
+    map.sort();
+    let mut blocks: Vec<_> = profile.blocks.iter().collect();
+    blocks.sort();
+    let mut branches: Vec<_> = profile.branches.iter().collect();
+    branches.sort();
+    let mut values: Vec<_> = profile.values.iter().collect();
+    values.sort();
+    let canonical =
+        serde_json::to_vec(&(map, blocks, branches, profile.slowcalls, values)).unwrap();
+    fingerprint(&canonical)
+}
+
+// The runs recorded in the module's META_SECTION, oldest first
+pub fn tool_runs(module: &Module) -> Vec<ToolRun> {
+    for (_, section) in module.customs.iter() {
+        if section.name() == META_SECTION {
+            let data = section.data(&IdsToIndices::default());
+            return serde_json::from_slice(&data).unwrap_or_else(|e| {
+                println!("ignoring malformed {} section: {}", META_SECTION, e);
+                vec![]
+            });
+        }
+    }
+    vec![]
+}
+
+// Drop the latest run from META_SECTION, once what it did has been undone
+pub fn forget_last_run(module: &mut Module) {
+    let mut runs = tool_runs(module);
+    runs.pop();
+    module.customs.remove_raw(META_SECTION);
+    if !runs.is_empty() {
+        module.customs.add(RawCustomSection {
+            name: META_SECTION.to_string(),
+            data: serde_json::to_vec(&runs).unwrap(),
+        });
+    }
+}
+
+/*
+ * Record `run` in the emitted binary: the tool is appended to the standard
+ * `producers` section (processed-by), and the full settings go in
+ * META_SECTION after those of any earlier runs.
+ */
+pub fn record_run(module: &mut Module, run: ToolRun) {
+    module.producers.add_processed_by(&run.tool, &run.version);
+    let mut runs = tool_runs(module);
+    runs.push(run);
+    module.customs.remove_raw(META_SECTION);
+    module.customs.add(RawCustomSection {
+        name: META_SECTION.to_string(),
+        data: serde_json::to_vec(&runs).unwrap(),
+    });
+}
@@ -0,0 +1,51 @@
+use std::fs::File;
+use std::io::Write;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+// Each write() hands the kernel this much, so a 100MB binary goes out in a few dozen
+const WRITE_CHUNK_BYTES: usize = 4 << 20;
+
+/*
+ * A binary going out to disk on a thread of its own, so the next output
+ * (the conservative build, the re-instrumented binary, the manifests) is
+ * prepared while the last one is still being written. It's written next to
+ * `path` and renamed into place, so nothing watching the path (see watch)
+ * ever reads half a binary; `finish` waits for the rename.
+ */
+pub struct PendingWrite {
+    path: String,
+    handle: JoinHandle<std::io::Result<(usize, u128)>>,
+}
+
+pub fn write_in_background(path: &str, wasm: Vec<u8>) -> PendingWrite {
+    let target = path.to_string();
+    let handle = std::thread::spawn(move || {
+        let started = Instant::now();
+        let partial = format!("{}.partial", target);
+        let mut file = File::create(&partial)?;
+        for chunk in wasm.chunks(WRITE_CHUNK_BYTES) {
+            file.write_all(chunk)?;
+        }
+        drop(file);
+        std::fs::rename(&partial, &target)?;
+        Ok((wasm.len(), started.elapsed().as_millis()))
+    });
+    PendingWrite {
+        path: path.to_string(),
+        handle,
+    }
+}
+
+impl PendingWrite {
+    pub fn finish(self, verbose: bool) {
+        let (bytes, ms) = self
+            .handle
+            .join()
+            .unwrap()
+            .unwrap_or_else(|e| panic!("couldn't write {}: {}", self.path, e));
+        if verbose {
+            println!("Wrote {} bytes to {} in {} ms", bytes, self.path, ms);
+        }
+    }
+}
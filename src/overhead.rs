@@ -0,0 +1,184 @@
+use crate::indexmap::emitted_indices;
+use crate::report::func_name;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use walrus::*;
+use wasmparser::{Parser, Payload};
+
+/*
+ * Back-of-the-envelope cost of the instrumentation, computed from the shape
+ * of the stubs the pipeline generates rather than measured. Numbers are
+ * worst case instructions executed per call, which is what matters for
+ * deciding whether a build is still representative of production.
+ */
+#[derive(Debug, Clone)]
+pub struct Overhead {
+    // Extra instructions executed per instrumented call_indirect
+    pub per_indirect_call: u64,
+    // Extra instructions executed per call to a slowcall-stubbed function
+    pub per_slowcall: u64,
+    // Extra call frames on the way to an indirect / slowcall target
+    pub call_depth: u32,
+    pub input_bytes: usize,
+    pub output_bytes: usize,
+}
+
+// How the stubs record an indirect call (the default, --trace, --slot-memory, --host-events)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recording {
+    SlotGlobals,
+    Trace,
+    SlotMemory,
+    HostEvents,
+}
+
+/*
+ * Each profiling stub first bumps the `indirect` counter (4 instructions),
+ * then checks every slot of every callsite in turn (block; local.get; const;
+ * eq; if), and for the callsite being recorded also tests and maybe sets the
+ * slot (8 more per slot, 5 to record the hit). The overflow pass afterwards
+ * does one 4 instruction check per callsite, plus the set check and up to one
+ * global.set pair per slot for the callsite itself. Forwarding the call
+ * costs a local.get per param plus the call_indirect.
+ *
+ * With --trace the slot scan is replaced by the ring buffer append, a fixed
+ * 19 instructions. With --slot-memory it's a loop over the callsite's own
+ * slots: 16 instructions to find them, 25 per slot scanned, and on overflow
+ * 14 per slot to mark them, independent of the number of callsites.
+ * With --host-events it's the counter bump and the call to the host, 7
+ * instructions plus whatever the host does with the event.
+ */
+pub fn estimate(
+    callsites: usize,
+    window: usize,
+    max_params: usize,
+    recording: Recording,
+    input_bytes: usize,
+    output_bytes: usize,
+) -> Overhead {
+    let (n, w) = (callsites as u64, window as u64);
+    let record = match recording {
+        Recording::SlotGlobals => 4 + 5 * n * w + 8 * w + 5 + 4 * n + 3 + 2 * w,
+        Recording::Trace => 19,
+        Recording::SlotMemory => 16 + 25 * w + 8 + 14 * w,
+        Recording::HostEvents => 4 + 3,
+    };
+    Overhead {
+        per_indirect_call: record + max_params as u64 + 1,
+        // global.get; const; add; global.set, then re-push the args and call
+        per_slowcall: 4 + max_params as u64 + 1,
+        call_depth: 1,
+        input_bytes,
+        output_bytes,
+    }
+}
+
+pub fn print_overhead(overhead: &Overhead) {
+    println!("== Estimated instrumentation overhead ==");
+    println!(
+        "indirect calls: ~{} extra instructions per call, +{} call depth",
+        overhead.per_indirect_call, overhead.call_depth
+    );
+    println!(
+        "slowcalls:      ~{} extra instructions per call, +{} call depth",
+        overhead.per_slowcall, overhead.call_depth
+    );
+    let growth = if overhead.input_bytes > 0 {
+        (overhead.output_bytes as f64 / overhead.input_bytes as f64 - 1.0) * 100.0
+    } else {
+        0.0
+    };
+    println!(
+        "code size:      {} -> {} bytes ({:+.1}%)",
+        overhead.input_bytes, overhead.output_bytes, growth
+    );
+}
+
+// Bytes of each section of a binary, custom sections by name
+fn section_sizes(wasm: &[u8]) -> BTreeMap<String, usize> {
+    let mut sizes = BTreeMap::new();
+    for payload in Parser::new(0).parse_all(wasm).flatten() {
+        let (name, range) = match payload {
+            Payload::TypeSection(r) => ("types".to_string(), r.range()),
+            Payload::ImportSection(r) => ("imports".to_string(), r.range()),
+            Payload::FunctionSection(r) => ("function declarations".to_string(), r.range()),
+            Payload::TableSection(r) => ("tables".to_string(), r.range()),
+            Payload::MemorySection(r) => ("memories".to_string(), r.range()),
+            Payload::GlobalSection(r) => ("globals".to_string(), r.range()),
+            Payload::ExportSection(r) => ("exports".to_string(), r.range()),
+            Payload::ElementSection(r) => ("elements".to_string(), r.range()),
+            Payload::DataSection(r) => ("data".to_string(), r.range()),
+            Payload::CodeSectionStart { range, .. } => ("code".to_string(), range),
+            Payload::CustomSection(r) => (format!("custom section {}", r.name()), r.range()),
+            _ => continue,
+        };
+        *sizes.entry(name).or_insert(0) += range.end - range.start;
+    }
+    sizes
+}
+
+/*
+ * Where the bytes `output` has over `input` went, biggest first: the
+ * functions we added, grouped by name with the numbering dropped
+ * (indirect_stub_*), what the inline counters added to the module's own
+ * functions, and the growth of every other section (globals, exports, the
+ * manifest and meta custom sections, ...). `module` is the output as it
+ * was emitted and `input_funcs` the functions it was parsed with.
+ */
+pub fn size_contributors(
+    module: &Module,
+    input_funcs: &HashSet<FunctionId>,
+    input: &[u8],
+    output: &[u8],
+) -> Vec<(String, i64)> {
+    let by_index: HashMap<u32, FunctionId> = emitted_indices(module)
+        .into_iter()
+        .map(|(id, idx)| (idx, id))
+        .collect();
+    let imported = module
+        .imports
+        .iter()
+        .filter(|import| matches!(import.kind, ImportKind::Function(_)))
+        .count() as u32;
+    let mut added: BTreeMap<String, i64> = BTreeMap::new();
+    let bodies = Parser::new(0)
+        .parse_all(output)
+        .flatten()
+        .filter_map(|payload| match payload {
+            Payload::CodeSectionEntry(body) => Some(body.range().end - body.range().start),
+            _ => None,
+        });
+    for (pos, bytes) in bodies.enumerate() {
+        match by_index.get(&(imported + pos as u32)) {
+            Some(func) if !input_funcs.contains(func) => {
+                let name = func_name(module, *func);
+                let group = name.trim_end_matches(|c: char| c.is_ascii_digit());
+                let group = if group.len() < name.len() {
+                    format!("{}*", group)
+                } else {
+                    name.clone()
+                };
+                *added.entry(group).or_insert(0) += bytes as i64;
+            }
+            _ => (),
+        }
+    }
+
+    let before = section_sizes(input);
+    let after = section_sizes(output);
+    let mut contributors: Vec<(String, i64)> = added
+        .iter()
+        .map(|(group, bytes)| (format!("added functions {}", group), *bytes))
+        .collect();
+    for (section, size) in &after {
+        let mut growth = *size as i64 - before.get(section).cloned().unwrap_or(0) as i64;
+        if section == "code" {
+            growth -= added.values().sum::<i64>();
+            contributors.push(("counters in the original functions".to_string(), growth));
+        } else {
+            contributors.push((section.clone(), growth));
+        }
+    }
+    contributors.retain(|(_, bytes)| *bytes > 0);
+    contributors.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    contributors
+}
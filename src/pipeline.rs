@@ -0,0 +1,2107 @@
+use crate::analysiscache::AnalysisCache;
+use crate::bindgen;
+use crate::blockcounters::{enumerate_blocks, instrument_blocks};
+use crate::branches::{enumerate_branches, instrument_branches};
+use crate::brtables::{enumerate_br_tables, instrument_br_tables, peel_br_tables};
+use crate::callsites::{
+    callsite_key, entry_functions, enumerate_callsites, prioritize_callsites, static_targets,
+    table_is_exported, table_is_mutable, unguarded_callsites, Callsite,
+};
+use crate::cleanup::remove_unused_functions;
+use crate::coldsplit::split_cold_blocks;
+use crate::compilationhints::{
+    compilation_hints_section, function_hotness, insert_before_code, Hotness,
+};
+use crate::constfold::{fold_calls, fold_constant_selectors};
+use crate::contexttree::instrument_context_tree;
+use crate::counters::{record_lru, CounterPolicy, SlotPolicy, EMPTY_SLOT, OVERFLOW_SLOT};
+use crate::customsections::apply_custom_policy;
+use crate::dataregions::{instrument_data_accesses, reorder_data_segments};
+use crate::descriptors::add_descriptor_table;
+use crate::dylink;
+use crate::emscripten;
+use crate::entrypoints::{enumerate_entries, instrument_entries, select_entry_point};
+use crate::explain;
+use crate::fastcalls::*;
+use crate::features;
+use crate::globalaccess::{enumerate_globals, instrument_global_accesses, reorder_globals};
+use crate::hostevents;
+use crate::importclasses::ImportClasses;
+use crate::importcounters::instrument_imports;
+use crate::indexmap::{function_index_map, FunctionIndexMap};
+use crate::instrument::generate_stubs;
+use crate::linked;
+use crate::loops::{enumerate_loops, instrument_loops, LoopInfo};
+use crate::manifest::{self, CallsiteChunk, CallsiteEntry, CounterLayout, EntryPoint, Manifest};
+use crate::memgrowth::instrument_memory_growth;
+use crate::meta::{self, ToolRun};
+use crate::overhead;
+use crate::profilemap::{force_devirt, function_table, process_map};
+use crate::reachability::{
+    describe_roots, exports_everything, named_functions, reachable_functions, Root,
+};
+use crate::report;
+use crate::schema::MapValue;
+use crate::selfcheck;
+use crate::slotmemory;
+use crate::snapshots::add_snapshots;
+use crate::startinit::{chain_start_init, InitMode};
+use crate::strip;
+use crate::tableimage::TableImage;
+use crate::trace;
+use crate::valueprofile::{enumerate_value_params, instrument_values, specialize_functions};
+use crate::Profile;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use walrus::ir::Instr::*;
+use walrus::ir::Value;
+use walrus::ir::VisitorMut;
+use walrus::ir::*;
+use walrus::FunctionId;
+use walrus::GlobalId;
+use walrus::MemoryId;
+use walrus::Module;
+use walrus::TableId;
+use walrus::TypeId;
+use walrus::ValType;
+
+// Everything that controls what the instrumentation/optimization pass does
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstrumentOptions {
+    // Number of distinct targets tracked per callsite
+    pub window: usize,
+    pub block_counters: bool,
+    pub split_cold: bool,
+    pub hot_threshold: i32,
+    pub divergence: bool,
+    // Count every arm of the br_tables with at least this many arms, see brtables
+    pub br_table_counters: Option<usize>,
+    // Peel up to this many hot arms of each profiled br_table into br_ifs (optimize mode)
+    pub peel_br_tables: Option<usize>,
+    pub trace: bool,
+    pub trace_entries: u32,
+    // Snapshot the counters every this many slowcalls, see snapshots
+    pub snapshot_every: Option<u32>,
+    // Snapshots kept in the ring buffer
+    pub snapshot_capacity: u32,
+    // (module, name) of a host () -> i32 function deciding when to snapshot
+    pub snapshot_tick: Option<(String, String)>,
+    pub self_check: bool,
+    // Which table call_indirect targets are resolved against, see profilemap::function_table
+    pub table_index: Option<u32>,
+    // Leave call_indirects in `_start` and the start section function alone
+    pub skip_entry_callsites: bool,
+    // Functions with fewer instructions than this are neither slowcall-stubbed nor
+    // callsite-instrumented
+    pub min_func_size: usize,
+    // Callsite keys (see CallsiteEntry::key) the optimizer must leave indirect
+    pub retain_callsites: HashSet<String>,
+    // (callsite key, target function name) pairs that override the profile
+    pub force_devirt: Vec<(String, String)>,
+    // Callsite keys to print the reasoning for, see explain::explain
+    pub explain: HashSet<String>,
+    pub explain_all: bool,
+    // Prepended to the name of every export we add
+    pub export_prefix: String,
+    // Export one callsite count global and a descriptor table instead of every slot
+    pub compact_exports: bool,
+    // Fail if the estimated extra instructions per indirect call exceed this
+    pub max_overhead: Option<u64>,
+    // Fail if the output is more than this many percent larger than the input
+    pub max_size_increase: Option<f64>,
+    // Only warn when max_size_increase is exceeded
+    pub size_warn_only: bool,
+    // Soft budget (in MB) for the fastcall analysis, see fastcalls::compute_slowcalls
+    pub max_memory: Option<usize>,
+    // Cost class of each import for the fastcall analysis (--import-classes)
+    pub import_classes: ImportClasses,
+    // Instrument modules with a shared memory, each thread keeping its own slots
+    pub allow_shared_memory: bool,
+    // Optional proposals to accept (features::OPTIONAL, CLI spelling); empty for all
+    pub enable_features: Vec<String>,
+    // Count the calls to every imported function
+    pub import_counters: bool,
+    // Tag and count the host's calls to every exported function, see entrypoints
+    pub entry_counters: bool,
+    // Devirtualize with only this export's callsite data (optimize mode), see entrypoints
+    pub entry_point: Option<String>,
+    // Count the reads and writes of every global, see globalaccess
+    pub global_counters: bool,
+    // Reorder the globals by the profile's access counts (optimize mode)
+    pub reorder_globals: bool,
+    // Count the loads and stores into each data region, see dataregions
+    pub data_counters: bool,
+    // Split and reorder the data segments by the profile's region counts (optimize mode)
+    pub reorder_data: bool,
+    // Record the most common value of every i32 parameter
+    pub value_profile: bool,
+    // Clone hot functions on their profiled argument values (optimize mode)
+    pub specialize: bool,
+    // Count pages grown and the max size reached, per memory
+    pub memory_counters: bool,
+    // Count the slowcalls made through the table as well, see fastcalls::redirect_table_entries
+    pub count_indirect_slowcalls: bool,
+    // Count entries, iterations and the longest trip of every loop
+    pub loop_counters: bool,
+    // Keep the callsite slots in a memory the stubs index into, see slotmemory
+    pub slot_memory: bool,
+    // Report every indirect call to an imported host function instead, see hostevents
+    pub host_events: bool,
+    // Build a calling-context tree of up to `context_nodes` nodes, see contexttree
+    pub context_tree: bool,
+    pub context_nodes: u32,
+    // Only count function entries (the first phase of coarse -> fine profiling)
+    pub coarse: bool,
+    // Only instrument callsites in these functions (by report::func_name), see costs::hot_functions
+    pub hot_functions: Option<HashSet<String>>,
+    // Leave the functions no call path from these roots reaches uninstrumented, see reachability
+    pub reachable_only: Option<Vec<Root>>,
+    // More --reachable-only roots, by export or function name
+    pub reachable_from: Vec<String>,
+    // Per-callsite slot counts overriding `window` (0: don't instrument), see
+    // profilemap::adaptive_windows
+    pub callsite_windows: HashMap<usize, usize>,
+    // Leave callsites with a single possible target (callsites::static_targets) uninstrumented
+    pub skip_static_callsites: bool,
+    // Devirtualize those callsites when optimizing, unless the profile says otherwise
+    pub devirt_static: bool,
+    // Instrument at most this many callsites per run, in callsites::prioritize_callsites order
+    pub max_callsites: Option<usize>,
+    // Which chunk of max_callsites callsites to instrument (0 for the first)
+    pub callsite_round: usize,
+    // Strip an earlier run's instrumentation instead of refusing the input, see strip
+    pub re_instrument: bool,
+    // Emscripten output: invoke_* imports call into the table, which JS can change, see emscripten
+    pub emscripten: bool,
+    // Guard every devirtualized call with a call_indirect fallback and leave unexecuted callsites indirect
+    pub conservative: bool,
+    // Table indices another module of a linked set fills in: index ==> the
+    // (module, name) this module imports that function as, see linked
+    pub linked_targets: HashMap<i64, (String, String)>,
+    // File caching the per-function fastcall scan between runs, see analysiscache.
+    // It doesn't change the output, so it isn't recorded with the other options
+    #[serde(skip)]
+    pub analysis_cache: Option<String>,
+    // Report phase timings; likewise not recorded
+    #[serde(skip)]
+    pub verbose: bool,
+    // Initial value and overflow behaviour of the event counters (--counter-init, --counter-mode)
+    pub counters: CounterPolicy,
+    // Demangle function names in --explain output
+    pub demangle: bool,
+    // How the added globals and memories get their initial values, see startinit
+    pub init_mode: InitMode,
+    // Mark functions hot/lazy/cold in a compilation hints section (optimize mode), see compilationhints
+    pub compilation_hints: bool,
+    // Input custom sections to keep byte for byte / to drop (exact names, or
+    // prefixes ending in `*`), see customsections
+    pub keep_custom: Vec<String>,
+    pub drop_custom: Vec<String>,
+    // What a callsite's slots do once they're full (--slot-policy)
+    pub slot_policy: SlotPolicy,
+}
+
+impl Default for InstrumentOptions {
+    fn default() -> InstrumentOptions {
+        InstrumentOptions {
+            window: 1,
+            block_counters: false,
+            split_cold: false,
+            hot_threshold: 1000,
+            divergence: false,
+            br_table_counters: None,
+            peel_br_tables: None,
+            trace: false,
+            trace_entries: 65536,
+            snapshot_every: None,
+            snapshot_capacity: 64,
+            snapshot_tick: None,
+            self_check: false,
+            table_index: None,
+            skip_entry_callsites: false,
+            min_func_size: 0,
+            retain_callsites: HashSet::new(),
+            force_devirt: vec![],
+            explain: HashSet::new(),
+            explain_all: false,
+            export_prefix: String::new(),
+            compact_exports: false,
+            max_overhead: None,
+            max_size_increase: None,
+            size_warn_only: false,
+            max_memory: None,
+            import_classes: ImportClasses::default(),
+            allow_shared_memory: false,
+            enable_features: vec![],
+            import_counters: false,
+            entry_counters: false,
+            entry_point: None,
+            global_counters: false,
+            reorder_globals: false,
+            data_counters: false,
+            reorder_data: false,
+            value_profile: false,
+            specialize: false,
+            memory_counters: false,
+            count_indirect_slowcalls: false,
+            loop_counters: false,
+            slot_memory: false,
+            host_events: false,
+            context_tree: false,
+            context_nodes: 4096,
+            coarse: false,
+            hot_functions: None,
+            reachable_only: None,
+            reachable_from: vec![],
+            callsite_windows: HashMap::new(),
+            skip_static_callsites: false,
+            devirt_static: false,
+            max_callsites: None,
+            callsite_round: 0,
+            re_instrument: false,
+            emscripten: false,
+            conservative: false,
+            linked_targets: HashMap::new(),
+            analysis_cache: None,
+            verbose: false,
+            counters: CounterPolicy::default(),
+            demangle: true,
+            init_mode: InitMode::InitExpr,
+            compilation_hints: false,
+            keep_custom: vec![],
+            drop_custom: vec![],
+            slot_policy: SlotPolicy::Overflow,
+        }
+    }
+}
+
+pub struct Output {
+    pub wasm: Vec<u8>,
+    pub manifest: Manifest,
+    // What happened to each callsite (optimize mode only), see explain::decision_log
+    pub decisions: Vec<explain::DecisionRecord>,
+    // The input's function indices against the output's, see indexmap
+    pub index_map: FunctionIndexMap,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    // The input isn't a wasm module we can parse
+    Parse(String),
+    InvalidOptions(String),
+    // The instrumentation would cost more than --max-overhead allows
+    OverBudget(String),
+    // The module uses a proposal we can't process
+    Unsupported(String),
+    // The emitted module broke an invariant the self-check (--self-check) looks for
+    SelfCheck(String),
+    // A bug: some pass got into a state it shouldn't on this input
+    Internal(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::Parse(msg) => write!(f, "failed to parse module: {}", msg),
+            Error::InvalidOptions(msg) => write!(f, "invalid options: {}", msg),
+            Error::OverBudget(msg) => write!(f, "instrumentation overhead over budget: {}", msg),
+            Error::Unsupported(msg) => write!(f, "unsupported module: {}", msg),
+            Error::SelfCheck(msg) => write!(f, "self-check failed: {}", msg),
+            Error::Internal(msg) => write!(f, "internal error: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn check_options(options: &InstrumentOptions) -> Result<(), Error> {
+    if options.window == 0 || options.window > 50 {
+        return Err(Error::InvalidOptions(format!(
+            "window must be in 1..=50, got {}",
+            options.window
+        )));
+    }
+    if let Some((idx, window)) = options.callsite_windows.iter().find(|(_, w)| **w > 50) {
+        return Err(Error::InvalidOptions(format!(
+            "callsite {} window must be at most 50, got {}",
+            idx, window
+        )));
+    }
+    if options.coarse && (options.trace || options.hot_functions.is_some()) {
+        return Err(Error::InvalidOptions(
+            "--coarse only counts function entries, it can't be combined with --trace or --hot-functions-from".to_string(),
+        ));
+    }
+    if !options.reachable_from.is_empty() && options.reachable_only.is_none() {
+        return Err(Error::InvalidOptions(
+            "--reachable-from names roots for --reachable-only, pass that too".to_string(),
+        ));
+    }
+    if options.slot_policy == SlotPolicy::Lru
+        && (options.trace || options.slot_memory || options.host_events || options.compact_exports)
+    {
+        return Err(Error::InvalidOptions(
+            "--slot-policy lru keeps its slots in globals, it can't be combined with --trace, --slot-memory, --host-events or --compact-exports".to_string(),
+        ));
+    }
+    if let Some(name) = options.keep_custom.iter().find(|name| options.drop_custom.contains(name)) {
+        return Err(Error::InvalidOptions(format!(
+            "custom section {} is both kept and dropped",
+            name
+        )));
+    }
+    if options.max_size_increase.map_or(false, |pct| !(pct >= 0.0)) {
+        return Err(Error::InvalidOptions(
+            "--max-size-increase must be a percentage of at least 0".to_string(),
+        ));
+    }
+    if options.peel_br_tables == Some(0) {
+        return Err(Error::InvalidOptions(
+            "--peel-br-tables must peel at least 1 arm".to_string(),
+        ));
+    }
+    if options.max_callsites == Some(0) {
+        return Err(Error::InvalidOptions(
+            "--max-callsites must be at least 1".to_string(),
+        ));
+    }
+    if options.slot_memory && (options.trace || options.compact_exports) {
+        return Err(Error::InvalidOptions(
+            "--slot-memory replaces the slot globals, it can't be combined with --trace or --compact-exports".to_string(),
+        ));
+    }
+    if options.host_events && (options.trace || options.slot_memory || options.compact_exports) {
+        return Err(Error::InvalidOptions(
+            "--host-events replaces the slot globals, it can't be combined with --trace, --slot-memory or --compact-exports".to_string(),
+        ));
+    }
+    if options.trace && !options.trace_entries.is_power_of_two() {
+        return Err(Error::InvalidOptions(format!(
+            "trace entries must be a power of two, got {}",
+            options.trace_entries
+        )));
+    }
+    if options.snapshot_every == Some(0) || options.snapshot_capacity == 0 {
+        return Err(Error::InvalidOptions(
+            "--snapshot-every and --snapshot-capacity must be at least 1".to_string(),
+        ));
+    }
+    if options.snapshot_tick.is_some() && options.snapshot_every.is_none() {
+        return Err(Error::InvalidOptions(
+            "--snapshot-tick is asked every --snapshot-every slowcalls, set both".to_string(),
+        ));
+    }
+    if options.context_tree && !options.context_nodes.is_power_of_two() {
+        return Err(Error::InvalidOptions(format!(
+            "context tree nodes must be a power of two, got {}",
+            options.context_nodes
+        )));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+struct TypeScan {
+    ty: Vec<(TypeId, TableId)>,
+}
+
+impl VisitorMut for TypeScan {
+    fn visit_instr_mut(&mut self, instr: &mut walrus::ir::Instr, idx: &mut walrus::InstrLocId) {
+        match instr {
+            CallIndirect(call_indirect) => {
+                self.ty.push((call_indirect.ty, call_indirect.table));
+            }
+            _ => {}
+        }
+    }
+}
+
+/*
+ * Instrument `wasm` for profiling, or, given the profile collected from an
+ * instrumented run, optimize it. Returns the new binary plus the manifest
+ * describing its callsites.
+ */
+pub fn run(
+    wasm_bytes: &[u8],
+    map: Option<Profile>,
+    options: &InstrumentOptions,
+) -> Result<Output, Error> {
+    let analysis = analyze(Cow::Borrowed(wasm_bytes), map, options)?;
+    emit(analysis, options)
+}
+
+/*
+ * What run works out about the input before deciding what to do with each
+ * callsite: the parsed module with its constant selectors folded (and, when
+ * instrumenting, its branch counters in), the callsite numbering and the
+ * profile resolved against it. Nothing here depends on --conservative, so
+ * run_ab_split works it out once for both builds, see Analysis::fork.
+ */
+struct Analysis<'a> {
+    // The input, with any earlier instrumentation stripped
+    wasm_bytes: Cow<'a, [u8]>,
+    // What walrus parsed instead, see bindgen::funcref_segments_only
+    rewritten: Option<Vec<u8>>,
+    // The manifest's, which re-instrumenting keeps from the original binary
+    fingerprint: Option<String>,
+    module: Module,
+    map: Option<Profile>,
+    profile_fingerprint: Option<String>,
+    table: Option<TableId>,
+    table_base: Option<GlobalId>,
+    folded: BTreeMap<(FunctionId, InstrLocId), FunctionId>,
+    checked_bytes: Option<Vec<u8>>,
+    input_globals: Vec<GlobalId>,
+    input_entries: Vec<(FunctionId, EntryPoint)>,
+    input_funcs: HashSet<FunctionId>,
+    input_global_ids: HashSet<GlobalId>,
+    input_memories: HashSet<MemoryId>,
+    input_order: Vec<(FunctionId, String)>,
+    hotness: Option<HashMap<FunctionId, Hotness>>,
+    original_funcs: Vec<FunctionId>,
+    tiny_funcs: HashSet<FunctionId>,
+    unreachable_funcs: HashSet<FunctionId>,
+    shim_funcs: HashSet<FunctionId>,
+    blocks: Vec<(FunctionId, InstrSeqId)>,
+    value_params: Vec<(FunctionId, usize)>,
+    loops: Vec<LoopInfo>,
+    original_callsites: Vec<Callsite>,
+    static_targets: HashMap<usize, (i64, FunctionId)>,
+    slowcalls: HashSet<FunctionId>,
+    observed: HashMap<usize, Vec<i64>>,
+    modified_map: HashMap<usize, MapValue>,
+}
+
+impl<'a> Analysis<'a> {
+    /*
+     * A second copy of an optimize analysis, for run_ab_split to apply the
+     * other policy to. walrus can't clone a module, so the copy parses the
+     * input again and replays the rewrites analyze made to it (the folded
+     * selectors, the peeled br_tables). Everything worked out from the
+     * profile carries over as is, with its ids moved to the new module: two
+     * parses of the same bytes number every item the same way. Only the
+     * block and loop lists, which point into function bodies, are scanned
+     * again.
+     */
+    fn fork(&self, options: &InstrumentOptions) -> Result<Analysis<'a>, Error> {
+        let profile = match &self.map {
+            Some(profile) => profile,
+            None => {
+                return Err(Error::Internal(
+                    "only an optimize analysis can be forked".to_string(),
+                ))
+            }
+        };
+        let module_bytes = self.rewritten.as_deref().unwrap_or(&self.wasm_bytes);
+        let mut module = features::module_config(&options.enable_features)
+            .parse(module_bytes)
+            .map_err(|e| Error::Parse(e.to_string()))?;
+
+        let funcs = moved(
+            self.module.funcs.iter().map(|f| f.id()),
+            module.funcs.iter().map(|f| f.id()),
+        )?;
+        let types = moved(
+            self.module.types.iter().map(|t| t.id()),
+            module.types.iter().map(|t| t.id()),
+        )?;
+        let tables = moved(
+            self.module.tables.iter().map(|t| t.id()),
+            module.tables.iter().map(|t| t.id()),
+        )?;
+        let globals = moved(
+            self.module.globals.iter().map(|g| g.id()),
+            module.globals.iter().map(|g| g.id()),
+        )?;
+        let memories = moved(
+            self.module.memories.iter().map(|m| m.id()),
+            module.memories.iter().map(|m| m.id()),
+        )?;
+        let func = |id: &FunctionId| funcs[id];
+        let func_set = |ids: &HashSet<FunctionId>| ids.iter().map(func).collect();
+
+        let folded: BTreeMap<(FunctionId, InstrLocId), FunctionId> = self
+            .folded
+            .iter()
+            .map(|((id, loc), target)| ((func(id), *loc), func(target)))
+            .collect();
+        fold_calls(&mut module, &folded);
+
+        let original_funcs: Vec<FunctionId> = self.original_funcs.iter().map(func).collect();
+        let blocks = if self.blocks.is_empty() {
+            vec![]
+        } else {
+            enumerate_blocks(&module, &original_funcs)
+        };
+        let loops = enumerate_loops(&module, &original_funcs);
+        if let Some(max_arms) = options.peel_br_tables {
+            let tables = enumerate_br_tables(&module, &original_funcs);
+            // analyze already warned about the ones that don't match
+            let counts: HashMap<usize, Vec<i32>> = profile
+                .br_tables
+                .iter()
+                .filter(|(idx, arms)| tables.get(**idx).map_or(false, |t| t.arms == arms.len()))
+                .map(|(idx, arms)| (*idx, arms.clone()))
+                .collect();
+            peel_br_tables(&mut module, &tables, &counts, max_arms);
+        }
+
+        Ok(Analysis {
+            wasm_bytes: self.wasm_bytes.clone(),
+            rewritten: self.rewritten.clone(),
+            fingerprint: self.fingerprint.clone(),
+            map: self.map.clone(),
+            profile_fingerprint: self.profile_fingerprint.clone(),
+            table: self.table.map(|table| tables[&table]),
+            table_base: self.table_base.map(|global| globals[&global]),
+            folded,
+            checked_bytes: self.checked_bytes.clone(),
+            input_globals: self.input_globals.iter().map(|g| globals[g]).collect(),
+            input_entries: self
+                .input_entries
+                .iter()
+                .map(|(id, entry)| (func(id), entry.clone()))
+                .collect(),
+            input_funcs: func_set(&self.input_funcs),
+            input_global_ids: self.input_global_ids.iter().map(|g| globals[g]).collect(),
+            input_memories: self.input_memories.iter().map(|m| memories[m]).collect(),
+            input_order: self
+                .input_order
+                .iter()
+                .map(|(id, name)| (func(id), name.clone()))
+                .collect(),
+            hotness: self
+                .hotness
+                .as_ref()
+                .map(|hotness| hotness.iter().map(|(id, h)| (func(id), *h)).collect()),
+            original_funcs,
+            tiny_funcs: func_set(&self.tiny_funcs),
+            unreachable_funcs: func_set(&self.unreachable_funcs),
+            shim_funcs: func_set(&self.shim_funcs),
+            blocks,
+            value_params: self
+                .value_params
+                .iter()
+                .map(|(id, param)| (func(id), *param))
+                .collect(),
+            loops,
+            original_callsites: self
+                .original_callsites
+                .iter()
+                .map(|callsite| Callsite {
+                    func: func(&callsite.func),
+                    ty: types[&callsite.ty],
+                    table: tables[&callsite.table],
+                    loc: callsite.loc,
+                })
+                .collect(),
+            static_targets: self
+                .static_targets
+                .iter()
+                .map(|(idx, (slot, target))| (*idx, (*slot, func(target))))
+                .collect(),
+            slowcalls: func_set(&self.slowcalls),
+            observed: self.observed.clone(),
+            modified_map: self
+                .modified_map
+                .iter()
+                .map(|(idx, value)| {
+                    let f_id = value
+                        .f_id
+                        .as_ref()
+                        .map(|ids| ids.iter().map(func).collect());
+                    (
+                        *idx,
+                        MapValue {
+                            f_id,
+                            f_bool: value.f_bool,
+                        },
+                    )
+                })
+                .collect(),
+            module,
+        })
+    }
+}
+
+// Each item of one parse against the same item of another parse of the same bytes
+fn moved<T: Copy + Eq + std::hash::Hash>(
+    from: impl Iterator<Item = T>,
+    to: impl Iterator<Item = T>,
+) -> Result<HashMap<T, T>, Error> {
+    let (from, to): (Vec<T>, Vec<T>) = (from.collect(), to.collect());
+    if from.len() != to.len() {
+        return Err(Error::Internal(
+            "the input parsed differently the second time".to_string(),
+        ));
+    }
+    Ok(from.into_iter().zip(to).collect())
+}
+
+fn analyze<'a>(
+    wasm_bytes: Cow<'a, [u8]>,
+    map: Option<Profile>,
+    options: &InstrumentOptions,
+) -> Result<Analysis<'a>, Error> {
+    check_options(options)?;
+    let divergence = options.divergence;
+    let is_opt = map.is_some();
+    let profile_fingerprint = map.as_ref().map(meta::profile_fingerprint);
+
+    features::check(
+        &wasm_bytes,
+        &options.enable_features,
+        options.allow_shared_memory,
+    )
+    .map_err(Error::Unsupported)?;
+    // What walrus parses, the fingerprints stay those of the input
+    let rewritten = bindgen::funcref_segments_only(&wasm_bytes).map_err(Error::Unsupported)?;
+    let module_bytes = rewritten.as_deref().unwrap_or(&wasm_bytes);
+    let config = features::module_config(&options.enable_features);
+    let mut module = config
+        .parse(module_bytes)
+        .map_err(|e| Error::Parse(e.to_string()))?;
+
+    // Optimizing the instrumented binary instead of the original would leave the
+    // profiling stubs in, under the optimizer's stubs. The profile's callsite ids
+    // survive stripping, so just strip them.
+    if is_opt && strip::is_instrumented(&module) {
+        strip::strip_instrumentation(&mut module).map_err(|e| {
+            Error::InvalidOptions(format!(
+                "the input is an instrumented binary, optimize the original one instead ({})",
+                e
+            ))
+        })?;
+        println!("The input is an instrumented binary, optimizing it with the instrumentation stripped");
+        let stripped = apply_custom_policy(&wasm_bytes, module.emit_wasm(), &options.keep_custom, &[]);
+        return analyze(Cow::Owned(stripped), map, options);
+    }
+
+    // Instrumenting our own output would wrap the stubs in stubs and renumber the callsites
+    if !is_opt && strip::is_instrumented(&module) {
+        if !options.re_instrument {
+            return Err(Error::InvalidOptions(
+                "the input is already instrumented, pass --re-instrument to strip the old instrumentation first (or instrument the original binary)".to_string(),
+            ));
+        }
+        let original = meta::tool_runs(&module)
+            .last()
+            .map(|run| run.input_fingerprint.clone());
+        strip::strip_instrumentation(&mut module).map_err(Error::Unsupported)?;
+        let stripped = apply_custom_policy(&wasm_bytes, module.emit_wasm(), &options.keep_custom, &[]);
+        let mut analysis = analyze(Cow::Owned(stripped), None, options)?;
+        if original.is_some() {
+            analysis.fingerprint = original;
+        }
+        return Ok(analysis);
+    }
+
+    if !options.emscripten && emscripten::looks_like_emscripten(&module) {
+        println!("The input looks like Emscripten output, consider --emscripten");
+    }
+
+    let table = function_table(&module, options.table_index);
+    let table_base = table.and_then(|_| dylink::table_base(&module));
+    if table_base.is_some() {
+        println!(
+            "Position-independent module: table indices are relative to the imported {}",
+            dylink::TABLE_BASE
+        );
+    }
+    if let Some(table) = table {
+        TableImage::build(&module, table).report_conflicts(&module);
+    }
+    // Before anything numbers the callsites, so the folded ones don't get an id
+    let folded = fold_constant_selectors(&mut module, table);
+    // What the self-check holds the output against
+    let checked_bytes = if options.self_check && !folded.is_empty() {
+        Some(module.emit_wasm())
+    } else {
+        None
+    };
+
+    // The globals --global-counters numbers, before we add any
+    let input_globals = enumerate_globals(&module);
+    // Likewise the exports --entry-counters numbers
+    let input_entries = enumerate_entries(&module);
+    // Everything the cleanup pass must leave alone
+    let input_funcs: HashSet<FunctionId> = module.funcs.iter().map(|func| func.id()).collect();
+    // Likewise the state --init-mode start-chain must leave alone
+    let input_global_ids: HashSet<GlobalId> = module.globals.iter().map(|g| g.id()).collect();
+    let input_memories: HashSet<MemoryId> = module.memories.iter().map(|m| m.id()).collect();
+    // In index order, named before any pass renames them
+    let input_order: Vec<(FunctionId, String)> = module
+        .funcs
+        .iter()
+        .map(|func| (func.id(), report::func_name(&module, func.id())))
+        .collect();
+
+    // Likewise the entry counts the compilation hints go by
+    let hotness = match (&map, options.compilation_hints) {
+        (Some(map), true) => Some(function_hotness(&module, map, options.hot_threshold)),
+        _ => None,
+    };
+
+    // Block ids must be computed on the original functions, before we add any stubs
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+
+    // Trampolines and accessors, sized before any pass adds instructions to them
+    let tiny_funcs: HashSet<FunctionId> = module
+        .funcs
+        .iter_local()
+        .filter(|(_, func)| selfcheck::instrs(func).len() < options.min_func_size)
+        .map(|(id, _)| id)
+        .collect();
+    // --reachable-only: dead code as far as the roots go, so it never records anything
+    let unreachable_funcs: HashSet<FunctionId> = match &options.reachable_only {
+        Some(roots) => {
+            if roots.contains(&Root::Exports) && exports_everything(&module) {
+                println!("Every function is exported (--export-all?), so they are all reachable; name the entry points with --reachable-from instead");
+            }
+            let named = named_functions(&module, &options.reachable_from)
+                .map_err(Error::InvalidOptions)?;
+            let reachable = reachable_functions(&module, roots, &named);
+            let unreachable: HashSet<FunctionId> = original_funcs
+                .iter()
+                .filter(|id| !reachable.contains(id))
+                .cloned()
+                .collect();
+            println!(
+                "Leaving {} functions ({} callsites) unreachable from {} uninstrumented",
+                unreachable.len(),
+                enumerate_callsites(&module, table)
+                    .iter()
+                    .filter(|callsite| unreachable.contains(&callsite.func))
+                    .count(),
+                describe_roots(roots, &options.reachable_from)
+            );
+            unreachable
+        }
+        None => HashSet::new(),
+    };
+    // wasm-bindgen's glue is called from JS, not from the program
+    let shim_funcs = bindgen::shim_functions(&module);
+    let blocks = if options.block_counters || options.split_cold || options.coarse {
+        enumerate_blocks(&module, &original_funcs)
+    } else {
+        vec![]
+    };
+
+    let value_params = enumerate_value_params(&module, &original_funcs);
+    let loops = enumerate_loops(&module, &original_funcs);
+
+    // Branch counters go in first, since they are keyed by instruction position
+    if !is_opt && divergence {
+        let branches = enumerate_branches(&module, &original_funcs);
+        instrument_branches(
+            &mut module,
+            &branches,
+            &options.export_prefix,
+            &options.counters,
+        );
+    }
+    if let (false, Some(min_arms)) = (is_opt, options.br_table_counters) {
+        // Numbered over every br_table, so the ids don't depend on the threshold
+        let tables: Vec<_> = enumerate_br_tables(&module, &original_funcs)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, table)| table.arms >= min_arms)
+            .collect();
+        instrument_br_tables(
+            &mut module,
+            &tables,
+            &options.export_prefix,
+            &options.counters,
+        );
+    }
+    if let (true, Some(max_arms)) = (is_opt, options.peel_br_tables) {
+        let tables = enumerate_br_tables(&module, &original_funcs);
+        peel_br_tables(
+            &mut module,
+            &tables,
+            &map.as_ref().unwrap().br_tables,
+            max_arms,
+        );
+    }
+
+    let original_callsites = enumerate_callsites(&module, table);
+    // Callsite ids are passed to the stubs as i32 constants
+    if original_callsites.len() > i32::MAX as usize {
+        return Err(Error::Unsupported(format!(
+            "{} callsites, callsite ids must fit in an i32",
+            original_callsites.len()
+        )));
+    }
+    let static_targets = static_targets(&module, table, &original_callsites);
+
+    // Identify slowcalls that we need to instrument
+    let slowcalls = if !is_opt {
+        let mut cache = options
+            .analysis_cache
+            .as_deref()
+            .map(|path| AnalysisCache::load(path, module_bytes));
+        let mut slowcalls =
+            compute_slowcalls(
+                &mut module,
+                table,
+                options.emscripten,
+                &options.import_classes,
+                cache.as_mut(),
+                options.max_memory.map(|mb| mb << 20),
+            )
+            .slowcalls;
+        if let (Some(cache), Some(path)) = (cache, &options.analysis_cache) {
+            cache.write(path);
+        }
+        slowcalls.retain(|func| {
+            !tiny_funcs.contains(func)
+                && !shim_funcs.contains(func)
+                && !unreachable_funcs.contains(func)
+                && !options.coarse
+        });
+        slowcalls
+    } else {
+        // No-op since we don't need to instrument anything
+        HashSet::new()
+    };
+
+    // We need to map the profiling data to FunctionId refs in the AST
+    // We parse table 0, get the offset, and then iterate through the functions
+    let mut modified_map: HashMap<usize, MapValue> = HashMap::new();
+    //let tab_id = module.tables.main_function_table().unwrap().unwrap();
+    //let table = module.tables.get(tab_id);
+    let mut map = map;
+    if let (true, Some(name), Some(map)) = (is_opt, &options.entry_point, map.as_mut()) {
+        select_entry_point(&module, name, &original_callsites, map)
+            .map_err(Error::InvalidOptions)?;
+    }
+    let observed: HashMap<usize, Vec<i64>> = map
+        .as_ref()
+        .map(|map| map.map.clone())
+        .unwrap_or_default();
+    if let (Some(map), Some(table)) = (map.as_mut(), table) {
+        if options.devirt_static {
+            // Only for callsites the profile doesn't cover, e.g. skipped with --skip-static-callsites
+            for (idx, (slot, _)) in &static_targets {
+                map.map.entry(*idx).or_insert_with(|| vec![*slot]);
+            }
+        }
+        force_devirt(&module, table, &options.force_devirt, map).map_err(Error::InvalidOptions)?;
+    }
+    if is_opt {
+        // The other modules' table indices aren't relative to our base
+        let linked = match table_base {
+            Some(_) => HashMap::new(),
+            None => linked::linked_functions(&module, &options.linked_targets),
+        };
+        process_map(
+            &module,
+            &mut map,
+            &mut modified_map,
+            table,
+            &original_callsites,
+            &linked,
+        );
+    }
+
+    let fingerprint = Some(manifest::fingerprint(&wasm_bytes));
+    Ok(Analysis {
+        wasm_bytes,
+        rewritten,
+        fingerprint,
+        module,
+        map,
+        profile_fingerprint,
+        table,
+        table_base,
+        folded,
+        checked_bytes,
+        input_globals,
+        input_entries,
+        input_funcs,
+        input_global_ids,
+        input_memories,
+        input_order,
+        hotness,
+        original_funcs,
+        tiny_funcs,
+        unreachable_funcs,
+        shim_funcs,
+        blocks,
+        value_params,
+        loops,
+        original_callsites,
+        static_targets,
+        slowcalls,
+        observed,
+        modified_map,
+    })
+}
+
+/*
+ * The rest of run: decide what happens to each callsite of `analysis`, rewrite
+ * them and emit the binary. This is where the policy options (--conservative,
+ * the explain and retain lists, ...) come in.
+ */
+fn emit(analysis: Analysis, options: &InstrumentOptions) -> Result<Output, Error> {
+    let Analysis {
+        wasm_bytes,
+        rewritten,
+        fingerprint,
+        mut module,
+        map,
+        profile_fingerprint,
+        table,
+        table_base,
+        folded: _,
+        checked_bytes,
+        input_globals,
+        input_entries,
+        input_funcs,
+        input_global_ids,
+        input_memories,
+        input_order,
+        hotness,
+        original_funcs,
+        tiny_funcs,
+        unreachable_funcs,
+        shim_funcs,
+        blocks,
+        value_params,
+        loops,
+        original_callsites,
+        static_targets,
+        slowcalls,
+        observed,
+        mut modified_map,
+    } = analysis;
+    let wasm_bytes: &[u8] = &wasm_bytes;
+    let module_bytes = rewritten.as_deref().unwrap_or(wasm_bytes);
+    let config = features::module_config(&options.enable_features);
+    let indirect_window = options.window;
+    let block_counters = options.block_counters;
+    let split_cold = options.split_cold;
+    let hot_threshold = options.hot_threshold;
+    let trace = options.trace;
+    let trace_entries = options.trace_entries;
+    let is_opt = map.is_some();
+
+    // Emscripten's table is filled in from JS as well, and an exported table
+    // can be written by whoever imports it, so a target the profile never saw
+    // can still show up: devirtualized calls fall back to the call_indirect
+    // instead of trapping, and unexecuted callsites stay indirect
+    let mutable_table = table.filter(|table| {
+        (options.emscripten && table_is_mutable(&module, *table))
+            || table_is_exported(&module, *table)
+    });
+    let fallback = table.filter(|_| options.conservative || mutable_table.is_some());
+    if is_opt {
+        if mutable_table.is_some() {
+            println!("The function table can change at runtime, guarding devirtualized calls with a call_indirect fallback");
+        } else if fallback.is_some() {
+            println!("Conservative build, guarding devirtualized calls with a call_indirect fallback");
+        }
+        if fallback.is_some() {
+            for val in modified_map.values_mut() {
+                val.f_bool = false;
+            }
+        }
+    }
+    // Callsites that saw more targets than their slots kept (--slot-policy lru) can
+    // call one the profile doesn't have, so their guards fall back to the call_indirect
+    let partial: HashMap<usize, TableId> = match (&map, table) {
+        (Some(map), Some(table)) if fallback.is_none() => map
+            .distinct_targets
+            .iter()
+            .filter(|(idx, distinct)| {
+                let kept = map.map.get(idx).map_or(0, |slots| {
+                    slots.iter().filter(|slot| **slot >= 0).count()
+                });
+                **distinct > kept as u64
+            })
+            .map(|(idx, _)| (*idx, table))
+            .collect(),
+        _ => HashMap::new(),
+    };
+    if !partial.is_empty() {
+        println!(
+            "{} callsites saw more targets than their slots kept, guarding their devirtualized calls with a call_indirect fallback",
+            partial.len()
+        );
+    }
+    // Callsites whose guard can't fail, see unguarded_callsites
+    let unguarded = if is_opt {
+        unguarded_callsites(
+            &module,
+            table.filter(|_| fallback.is_none()),
+            &static_targets,
+            &modified_map,
+        )
+    } else {
+        HashSet::new()
+    };
+    let entry_funcs = entry_functions(&module);
+    let decisions = explain::Decisions {
+        module: &module,
+        table,
+        map: &map,
+        modified_map: &modified_map,
+        tiny_funcs: &tiny_funcs,
+        shim_funcs: &shim_funcs,
+        unreachable_funcs: &unreachable_funcs,
+        entry_funcs: &entry_funcs,
+        observed: &observed,
+        static_targets: &static_targets,
+        unguarded: &unguarded,
+        fallback: fallback.is_some(),
+    };
+    if options.explain_all || !options.explain.is_empty() {
+        explain::explain(&decisions, options);
+    }
+    let decision_log = if is_opt {
+        explain::decision_log(&decisions, options)
+    } else {
+        vec![]
+    };
+
+    // Scan for all indirect call types
+    let types: Vec<Vec<(TypeId, TableId)>> = module
+        .funcs
+        .iter_local_mut()
+        .map(|(id, func)| {
+            let entry = func.entry_block();
+            let mut scan = TypeScan { ty: vec![] };
+            walrus::ir::dfs_pre_order_mut(&mut scan, func, entry);
+
+            scan.ty
+        })
+        .collect();
+
+    let mut final_types: HashSet<(TypeId, TableId)> = HashSet::new();
+    for ty in types {
+        final_types.extend(ty);
+    }
+
+    // For each indirect call type generate a new function in the module to serve as a stub
+    let mut stubs: HashMap<(TypeId, TableId), FunctionId> = HashMap::new();
+
+    // Generate stubs to replace indirect calls + add instrumentation
+    generate_stubs(
+        &mut module,
+        &mut final_types,
+        &mut stubs,
+        &mut modified_map,
+        &original_callsites,
+        &map,
+        is_opt,
+        fallback,
+        &unguarded,
+        table_base,
+        &partial,
+    );
+
+    // values
+    let mut skip_funcs: HashSet<FunctionId> = HashSet::new();
+    for id in stubs.values() {
+        skip_funcs.insert(*id);
+    }
+    // The fallback call_indirect of a guarded stub isn't a callsite of the input
+    if is_opt {
+        skip_funcs.extend(
+            module
+                .funcs
+                .iter()
+                .map(|func| func.id())
+                .filter(|id| !input_funcs.contains(id)),
+        );
+    }
+
+    // Track each indirect call we replace
+    // We want to know which calls we can replace with direct calls after profiling
+    let mut global_index = 0;
+    let func_names: HashMap<FunctionId, String> = module
+        .funcs
+        .iter()
+        .map(|f| (f.id(), report::func_name(&module, f.id())))
+        .collect();
+    let mut callsites: Vec<CallsiteEntry> = vec![];
+    // Callsites in these functions keep their ids, they just get no stub and no
+    // globals, so the profile never mentions them
+    let mut uninstrumented = tiny_funcs.clone();
+    uninstrumented.extend(shim_funcs.iter().cloned());
+    uninstrumented.extend(unreachable_funcs.iter().cloned());
+    if options.skip_entry_callsites {
+        uninstrumented.extend(entry_funcs);
+    }
+    if options.coarse {
+        uninstrumented.extend(original_funcs.iter().cloned());
+    }
+    if let Some(hot) = &options.hot_functions {
+        uninstrumented.extend(
+            original_funcs
+                .iter()
+                .filter(|id| !hot.contains(&func_names[*id])),
+        );
+    }
+    let mut skipped: HashSet<usize> = HashSet::new();
+    // --max-callsites: the callsites this round instruments
+    let mut chunk_layout = None;
+    let chunk: Option<HashSet<usize>> = match options.max_callsites {
+        Some(max) if !is_opt => {
+            // Callsites that are left alone anyway don't take up room in a chunk
+            let order: Vec<usize> = prioritize_callsites(&module, &original_callsites)
+                .into_iter()
+                .filter(|idx| {
+                    !uninstrumented.contains(&original_callsites[*idx].func)
+                        && options.callsite_windows.get(idx) != Some(&0)
+                        && !(options.skip_static_callsites && static_targets.contains_key(idx))
+                })
+                .collect();
+            let rounds = std::cmp::max((order.len() + max - 1) / max, 1);
+            if options.callsite_round >= rounds {
+                return Err(Error::InvalidOptions(format!(
+                    "--callsite-round {} is past the last round, {} callsites take {} rounds of {}",
+                    options.callsite_round,
+                    order.len(),
+                    rounds,
+                    max
+                )));
+            }
+            let chunk: HashSet<usize> = order
+                .into_iter()
+                .skip(options.callsite_round * max)
+                .take(max)
+                .collect();
+            println!(
+                "Instrumenting {} callsites, round {} of {}",
+                chunk.len(),
+                options.callsite_round + 1,
+                rounds
+            );
+            chunk_layout = Some(CallsiteChunk {
+                max_callsites: max,
+                round: options.callsite_round,
+                rounds,
+            });
+            Some(chunk)
+        }
+        _ => None,
+    };
+    // Slots for each callsite: --window unless a prior profile says otherwise
+    let callsite_window = |idx: usize| {
+        options
+            .callsite_windows
+            .get(&idx)
+            .cloned()
+            .unwrap_or(indirect_window)
+    };
+
+    // The first callsite the profile mapping left in a state we can't rewrite
+    let mut failed: Option<Error> = None;
+    module.funcs.iter_local_mut().for_each(|(id, func)| {
+        // Skip the stubs we created...
+        if !skip_funcs.contains(&id) {
+            let mut body = func.entry_block();
+            let mut count: usize = 0;
+            let mut insertion_point: Vec<(InstrSeqId, usize, TypeId, TableId)> = vec![];
+            let mut seqs_to_process: Vec<InstrSeqId> = vec![];
+            seqs_to_process.push(body);
+            drop(body);
+
+            while seqs_to_process.len() > 0 {
+                let current_seq = seqs_to_process.pop().unwrap();
+                let bmut = func.block_mut(current_seq);
+                let mut offset = 0;
+                for (instr, loc) in &bmut.instrs {
+                    match instr {
+                        CallIndirect(call) => {
+                            insertion_point.push((
+                                current_seq.clone(),
+                                count + offset,
+                                call.ty,
+                                call.table,
+                            ));
+                            if !is_opt {
+                                offset += 1;
+                            }
+                        }
+                        Block(b) => {
+                            seqs_to_process.push(b.seq);
+                        }
+                        Loop(l) => {
+                            seqs_to_process.push(l.seq);
+                        }
+                        IfElse(if_else) => {
+                            seqs_to_process.push(if_else.consequent);
+                            seqs_to_process.push(if_else.alternative);
+                        }
+                        _ => {}
+                    }
+                    count += 1;
+                }
+                count = 0;
+            }
+            drop(body);
+
+            // Record each callsite (numbered in the same order as global_index below)
+            for (nth, (_, _, ty, _)) in insertion_point.iter().enumerate() {
+                let ty = module.types.get(*ty);
+                callsites.push(CallsiteEntry {
+                    id: global_index as usize + nth,
+                    key: callsite_key(&func_names[&id], nth),
+                    func: func_names[&id].clone(),
+                    func_index: id.index(),
+                    static_target: static_targets
+                        .get(&(global_index as usize + nth))
+                        .map(|(_, func)| func_names[func].clone()),
+                    params: ty.params().iter().map(|p| p.to_string()).collect(),
+                    results: ty.results().iter().map(|r| r.to_string()).collect(),
+                });
+            }
+
+            if uninstrumented.contains(&id) {
+                let first = global_index as usize;
+                skipped.extend(first..first + insertion_point.len());
+                global_index += insertion_point.len() as i32;
+            } else if !is_opt {
+                // Process each sequence, accounting for the callsites we leave alone
+                // when computing where the later ones in the same sequence ended up
+                let mut left_alone: HashMap<InstrSeqId, usize> = HashMap::new();
+                for (seq, point, ty, table) in insertion_point {
+                    let idx = global_index as usize;
+                    if callsite_window(idx) == 0
+                        || (options.skip_static_callsites && static_targets.contains_key(&idx))
+                        || chunk.as_ref().map_or(false, |chunk| !chunk.contains(&idx))
+                    {
+                        *left_alone.entry(seq).or_insert(0) += 1;
+                        skipped.insert(global_index as usize);
+                        global_index += 1;
+                        continue;
+                    }
+                    let point = point - left_alone.get(&seq).cloned().unwrap_or(0);
+                    let mut body = func.builder_mut().instr_seq(seq);
+                    body.instr_at(
+                        point,
+                        walrus::ir::Call {
+                            func: stubs[&(ty, table)],
+                        },
+                    );
+                    body.instr_at(
+                        point,
+                        walrus::ir::Const {
+                            value: Value::I32(global_index),
+                        },
+                    );
+                    body.instrs_mut().remove(point + 2);
+                    global_index += 1;
+                }
+            } else {
+                // If we are optimizing the binary, we replace indirect calls directly here!
+                // We either:
+                // 1) Replace the indirect call with a direct call (if value is defined)
+                // 2) Replace the indirect call with an unreachable statement if it is never called
+                // 3) Keep the indirect call in place as-is
+                //
+                // We must also keep the number of instructions constant (to handle offsets),
+                // except for the drop in front of unguarded direct calls, which we account
+                // for when computing where the later callsites in the same sequence ended up
+                let mut dropped: HashMap<InstrSeqId, usize> = HashMap::new();
+                for (seq, point, _, _) in insertion_point {
+                    if options
+                        .retain_callsites
+                        .contains(&callsites[global_index as usize].key)
+                    {
+                        println!(
+                            "retaining callsite {} (--retain-callsites)",
+                            callsites[global_index as usize].key
+                        );
+                        global_index += 1;
+                        continue;
+                    }
+                    // Callsites the profile doesn't cover are left as-is
+                    let map_val: &MapValue = match modified_map.get(&(global_index as usize)) {
+                        Some(map_val) => map_val,
+                        None => {
+                            global_index += 1;
+                            continue;
+                        }
+                    };
+                    let point = point + dropped.get(&seq).cloned().unwrap_or(0);
+                    let mut body = func.builder_mut().instr_seq(seq);
+                    match map_val {
+                        // process_map gives each devirtualized callsite exactly one target
+                        MapValue { f_id: Some(id), .. } if id.len() != 1 => {
+                            failed.get_or_insert(Error::Internal(format!(
+                                "callsite {} maps to {} functions instead of one",
+                                global_index,
+                                id.len()
+                            )));
+                        }
+                        // Call the only possible target directly, dropping the table index
+                        MapValue { f_id: Some(id), .. }
+                            if unguarded.contains(&(global_index as usize)) =>
+                        {
+                            body.instr_at(point, walrus::ir::Drop {});
+                            body.instr_at(point + 1, walrus::ir::Call { func: id[0] });
+                            body.instrs_mut().remove(point + 2);
+                            *dropped.entry(seq).or_insert(0) += 1;
+                        }
+                        // Replace the call
+                        MapValue {
+                            f_id: Some(id),
+                            f_bool: _b,
+                        } => {
+                            // Remove the indirect call + the idx
+                            body.instr_at(point, walrus::ir::Call { func: id[0] });
+                            // We now have Call --> CallIndirect, with "Call" at point
+                            body.instrs_mut().remove(point + 1);
+                        }
+                        // Replace the call with `unreachable`
+                        MapValue {
+                            f_id: None,
+                            f_bool: true,
+                        } => {
+                            body.instr_at(point, walrus::ir::Unreachable {});
+                            body.instrs_mut().remove(point + 1);
+                        }
+                        // Retain the indirect call (no-op)
+                        MapValue {
+                            f_id: None,
+                            f_bool: false,
+                        } => {}
+                    }
+                    global_index += 1;
+                }
+            }
+        }
+    });
+    if let Some(e) = failed {
+        return Err(e);
+    }
+
+    for key in &options.retain_callsites {
+        if !callsites.iter().any(|c| &c.key == key) {
+            println!("--retain-callsites: no callsite {} in this module", key);
+        }
+    }
+
+    if !is_opt && (block_counters || options.coarse) {
+        instrument_blocks(
+            &mut module,
+            &blocks,
+            &options.export_prefix,
+            !block_counters,
+            &options.counters,
+        );
+    }
+
+    if !is_opt && options.value_profile {
+        instrument_values(&mut module, &value_params, &options.export_prefix);
+    }
+
+    if !is_opt && options.loop_counters {
+        instrument_loops(
+            &mut module,
+            &loops,
+            &options.export_prefix,
+            &options.counters,
+        );
+    }
+
+    if is_opt && split_cold {
+        split_cold_blocks(
+            &mut module,
+            &blocks,
+            &map.as_ref().unwrap().blocks,
+            hot_threshold,
+        );
+    }
+
+    if is_opt && options.specialize {
+        specialize_functions(
+            &mut module,
+            &value_params,
+            &map.as_ref().unwrap().values,
+            hot_threshold,
+        );
+    }
+
+    // Last of the optimize passes, once nothing else adds calls
+    if is_opt {
+        remove_unused_functions(&mut module, &input_funcs);
+    }
+
+    if is_opt && options.reorder_globals {
+        reorder_globals(&mut module, &map.as_ref().unwrap().global_accesses);
+    }
+    if is_opt && options.reorder_data {
+        reorder_data_segments(&mut module, &map.as_ref().unwrap().data_accesses);
+    }
+
+    let mut indirect_id = None;
+    let mut slowcalls_id = None;
+    if !is_opt {
+        indirect_id = Some(options.counters.add_counter(&mut module));
+        slowcalls_id = Some(options.counters.add_counter(&mut module));
+    }
+
+    // Widest slot array, which is what the descriptor table and the stub cost are sized by
+    let max_window = (0..global_index as usize)
+        .filter(|idx| !skipped.contains(idx))
+        .map(callsite_window)
+        .max()
+        .unwrap_or(indirect_window);
+
+    let mut trace_layout = None;
+    let mut descriptor_layout = None;
+    let mut slot_layout = None;
+    let mut host_events = None;
+    if !is_opt && trace {
+        // Record every indirect call into the ring buffer instead of the slot globals
+        let buffer = trace::add_trace_buffer(&mut module, trace_entries, &options.export_prefix);
+        for stub in &skip_funcs {
+            trace::record_trace(&mut module, *stub, &buffer);
+        }
+        trace_layout = Some(trace::trace_layout(&buffer));
+        module.exports.add(
+            &format!("{}indirect", options.export_prefix),
+            indirect_id.unwrap(),
+        );
+        module.exports.add(
+            &format!("{}slowcalls", options.export_prefix),
+            slowcalls_id.unwrap(),
+        );
+    }
+
+    if !is_opt && !trace && options.slot_memory {
+        // One stub body for every callsite, indexing into the slot memory
+        let windows: Vec<usize> = (0..global_index as usize)
+            .map(|idx| {
+                if skipped.contains(&idx) {
+                    0
+                } else {
+                    callsite_window(idx)
+                }
+            })
+            .collect();
+        let (slots, layout) =
+            slotmemory::add_slot_memory(&mut module, &windows, &options.export_prefix);
+        for stub in &skip_funcs {
+            slotmemory::record_slots(
+                &mut module,
+                *stub,
+                &slots,
+                indirect_id.unwrap(),
+                &options.counters,
+            );
+        }
+        slot_layout = Some(layout);
+        module.exports.add(
+            &format!("{}indirect", options.export_prefix),
+            indirect_id.unwrap(),
+        );
+        module.exports.add(
+            &format!("{}slowcalls", options.export_prefix),
+            slowcalls_id.unwrap(),
+        );
+    }
+
+    if !is_opt && options.host_events {
+        // The host gets (callsite, target) of every indirect call instead of slots
+        let (event, abi) = hostevents::add_event_import(&mut module);
+        for stub in &skip_funcs {
+            hostevents::record_events(
+                &mut module,
+                *stub,
+                event,
+                indirect_id.unwrap(),
+                &options.counters,
+            );
+        }
+        host_events = Some(abi);
+        module.exports.add(
+            &format!("{}indirect", options.export_prefix),
+            indirect_id.unwrap(),
+        );
+        module.exports.add(
+            &format!("{}slowcalls", options.export_prefix),
+            slowcalls_id.unwrap(),
+        );
+    }
+
+    if !is_opt && !trace && !options.slot_memory && !options.host_events {
+        // Now insert globals to track each call site
+        let mut global_map: HashMap<usize, Vec<GlobalId>> = HashMap::new();
+        // callsite ==> its distinct target counter, with SlotPolicy::Lru
+        let mut distinct_map: HashMap<usize, GlobalId> = HashMap::new();
+        // Insert X many globals per-call site
+        // We do this to track cases where just a few different targets are possible
+        for idx in 0..(global_index as usize) {
+            if skipped.contains(&idx) {
+                continue;
+            }
+            let mut new_globals = vec![];
+            for inner_idx in 0..callsite_window(idx) {
+                new_globals.push(module.globals.add_local(
+                    walrus::ValType::I32,
+                    true,
+                    walrus::InitExpr::Value(Value::I32(EMPTY_SLOT)),
+                ));
+            }
+            global_map.insert(
+                idx, // e.g., Map 0,1,2,3,4 --> to the same call site to mimic an array
+                new_globals,
+            );
+            if options.slot_policy == SlotPolicy::Lru {
+                distinct_map.insert(
+                    idx,
+                    module.globals.add_local(
+                        walrus::ValType::I32,
+                        true,
+                        walrus::InitExpr::Value(Value::I32(0)),
+                    ),
+                );
+            }
+        }
+
+        // Construct a mapping of function id ==> bools, to identify fastcalls
+        // TODO
+
+        // Now time to go back and modify the indirect call stubs to modify local values
+        for function_idx in skip_funcs {
+            let id = function_idx;
+            let func = module.funcs.get_mut(function_idx).kind.unwrap_local_mut();
+            let args = &func.args.clone();
+            let call_target = args[args.len() - 1];
+            let indirect_call_value = args[args.len() - 2];
+            let mut func_builder = func.builder_mut();
+            let mut func_body = func_builder.func_body();
+            //let local_vals = stub_locals.get(&id).unwrap();
+            //let counter = local_vals[0];
+            //let set_value =  local_vals[1];
+            //let counter = module.locals.add(ValType::I32);
+            let set_value = module.locals.add(ValType::I32);
+            func_body.block_at(0, None, |block| {
+                for instr in options.counters.increment(indirect_id.unwrap()) {
+                    block.instr(instr);
+                }
+                block.i32_const(0).local_set(set_value);
+            });
+            drop(func_body);
+            let mut block_seq = func_builder.dangling_instr_seq(None);
+            let block_seq_id = block_seq.id();
+            for global_idx in 0..global_index as usize {
+                if skipped.contains(&global_idx) {
+                    continue;
+                }
+                /*
+                 * We have an array of values representing each call site
+                 * We "iterate" through the "array" to find an open slot
+                 *
+                 * For each slot:
+                 * if the matching global is -1, set the value ( and set_value <- true)
+                 *  after setting, we break out.
+                 *
+                 * if after falling through all available slots, set_value != true
+                 * set all globals for this call site to -2
+                 *
+                 */
+                if let Some(distinct) = distinct_map.get(&global_idx) {
+                    block_seq.block(None, |block| {
+                        record_lru(
+                            block,
+                            block_seq_id,
+                            call_target,
+                            indirect_call_value,
+                            global_idx as i32,
+                            &global_map[&global_idx],
+                            *distinct,
+                        );
+                    });
+                    continue;
+                }
+                for array_value in global_map.get(&global_idx).unwrap() {
+                    block_seq.block(None, |block| {
+                        // Check which call target we are in
+                        block
+                            .local_get(call_target)
+                            .i32_const(global_idx as i32)
+                            .binop(BinaryOp::I32Eq)
+                            .if_else(
+                                None,
+                                |then| {
+                                    // For each target, we want to check if the previous indirect call
+                                    // matches...
+                                    then.global_get(*array_value)
+                                        .i32_const(EMPTY_SLOT)
+                                        .binop(BinaryOp::I32Eq)
+                                        // OR if the value is already set
+                                        .global_get(*array_value)
+                                        .local_get(indirect_call_value)
+                                        .binop(BinaryOp::I32Eq)
+                                        .binop(BinaryOp::I32Or)
+                                        // if the global == -1, then the function hasn't been called yet!
+                                        // we can set the global value...
+                                        .if_else(
+                                            None,
+                                            |then| {
+                                                then.local_get(indirect_call_value)
+                                                    .global_set(*array_value)
+                                                    .i32_const(1)
+                                                    .local_set(set_value)
+                                                    .br(block_seq_id);
+                                            },
+                                            |_| {},
+                                        );
+                                },
+                                |else_| {},
+                            );
+                    });
+                }
+            }
+            drop(block_seq);
+            let mut func_body = func_builder.func_body();
+            func_body.instr_at(
+                1,
+                walrus::ir::Instr::Block(walrus::ir::Block { seq: block_seq_id }),
+            );
+            // Lru never overflows, see SlotPolicy
+            if options.slot_policy == SlotPolicy::Overflow {
+                let mut block_seq = func_builder.dangling_instr_seq(None);
+                let block_seq_id = block_seq.id();
+                // now check if we failed to set any of the slots for our call target
+                // we have to do this for each call target all over again...
+                for global_idx in 0..global_index as usize {
+                    if skipped.contains(&global_idx) {
+                        continue;
+                    }
+                    let arr = global_map.get(&(global_idx as usize)).unwrap();
+                    block_seq
+                        .local_get(call_target)
+                        .i32_const(global_idx as i32)
+                        .binop(BinaryOp::I32Eq)
+                        .if_else(
+                            None,
+                            |then| {
+                                then.local_get(set_value)
+                                    .i32_const(1)
+                                    .binop(BinaryOp::I32Ne)
+                                    .if_else(
+                                        None,
+                                        |then| {
+                                            for global in arr {
+                                                then.i32_const(OVERFLOW_SLOT).global_set(*global);
+                                            }
+                                        },
+                                        |_| {},
+                                    );
+                            },
+                            |_| {},
+                        );
+                }
+                let mut func_body = func_builder.func_body();
+                func_body.instr_at(
+                    2,
+                    walrus::ir::Instr::Block(walrus::ir::Block { seq: block_seq_id }),
+                );
+            }
+            //end
+        }
+
+        // Now that we have instrumented the indirect calls,
+        // we will instrument the regular slowcalls
+
+        // Don't include these exported globals in the final optimized binary
+        if !is_opt {
+            module.exports.add(
+                &format!("{}indirect", options.export_prefix),
+                indirect_id.unwrap(),
+            );
+            module.exports.add(
+                &format!("{}slowcalls", options.export_prefix),
+                slowcalls_id.unwrap(),
+            );
+        }
+
+        if options.compact_exports {
+            descriptor_layout = Some(add_descriptor_table(
+                &mut module,
+                &global_map,
+                global_index as usize,
+                max_window,
+                &options.export_prefix,
+            ));
+            global_map.clear();
+        }
+
+        // Export all of our globals
+        for (idx, g) in global_map {
+            // We represent each callsite using multuple global values
+            for inner_idx in 0..g.len() {
+                module.exports.add(
+                    &format!(
+                        "{}profiling_global_{}_{}",
+                        options.export_prefix, idx, inner_idx
+                    ),
+                    g[inner_idx],
+                );
+            }
+        }
+        for (idx, g) in distinct_map {
+            module.exports.add(
+                &format!("{}profiling_distinct_{}", options.export_prefix, idx),
+                g,
+            );
+        }
+    }
+
+    if let (false, Some(base)) = (is_opt, table_base) {
+        for stub in stubs.values() {
+            dylink::rebase_stub(&mut module, *stub, base);
+        }
+    }
+
+    if !is_opt {
+        let slowcall_stubs = generate_slowcall_stubs(
+            &mut module,
+            &slowcalls,
+            &slowcalls_id.unwrap(),
+            &options.counters,
+        );
+        if options.count_indirect_slowcalls {
+            let patched = redirect_table_entries(&mut module, &slowcall_stubs);
+            println!(
+                "Routed {} element segment entries through their slowcall stubs",
+                patched
+            );
+        }
+    }
+
+    if !is_opt && options.memory_counters {
+        instrument_memory_growth(&mut module, &options.export_prefix);
+    }
+
+    let mut imports = vec![];
+    if !is_opt && options.import_counters {
+        imports = instrument_imports(&mut module, &options.export_prefix, &options.counters);
+    }
+    let mut entries = vec![];
+    if !is_opt && options.entry_counters {
+        entries = instrument_entries(
+            &mut module,
+            &input_entries,
+            &options.export_prefix,
+            &options.counters,
+        );
+    }
+
+    // Last, since these shift the instructions of the original functions
+    if !is_opt && options.global_counters {
+        instrument_global_accesses(
+            &mut module,
+            &original_funcs,
+            &input_globals,
+            &options.export_prefix,
+            &options.counters,
+        );
+    }
+    if !is_opt && options.data_counters {
+        instrument_data_accesses(
+            &mut module,
+            &original_funcs,
+            &options.export_prefix,
+            &options.counters,
+        );
+    }
+
+    // Moves the original functions' bodies into a block, so after every pass keyed by position
+    let mut context_layout = None;
+    if !is_opt && options.context_tree {
+        context_layout = Some(instrument_context_tree(
+            &mut module,
+            &original_funcs,
+            &tiny_funcs,
+            options.context_nodes,
+            &options.export_prefix,
+        ));
+    }
+
+    // Copies whatever counters the passes above exported
+    let mut snapshot_layout = None;
+    if let (false, Some(every)) = (is_opt, options.snapshot_every) {
+        snapshot_layout = Some(add_snapshots(
+            &mut module,
+            every,
+            options.snapshot_capacity,
+            options
+                .snapshot_tick
+                .as_ref()
+                .map(|(module, name)| (module.as_str(), name.as_str())),
+            &options.export_prefix,
+        ));
+    }
+
+    if !is_opt && options.init_mode == InitMode::StartChain {
+        chain_start_init(&mut module, &input_global_ids, &input_memories);
+    }
+
+    meta::record_run(
+        &mut module,
+        ToolRun {
+            tool: meta::TOOL_NAME.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            mode: if is_opt { "optimize" } else { "instrument" }.to_string(),
+            options: options.clone(),
+            input_fingerprint: manifest::fingerprint(wasm_bytes),
+            profile_fingerprint,
+        },
+    );
+
+    let index_map = function_index_map(&module, &input_order);
+    let hints = hotness
+        .as_ref()
+        .map(|hotness| compilation_hints_section(&module, hotness));
+    // The self-check's parse of the original doesn't need the output, so it
+    // runs while walrus emits
+    let started = std::time::Instant::now();
+    let (wasm, original) = std::thread::scope(|scope| {
+        let original = options.self_check.then(|| {
+            scope.spawn(|| {
+                config
+                    .parse(checked_bytes.as_deref().unwrap_or(module_bytes))
+                    .map_err(|e| Error::SelfCheck(format!("can't parse the input again: {}", e)))
+            })
+        });
+        let wasm = module.emit_wasm();
+        let original = original.map(|parse| {
+            parse.join().unwrap_or_else(|_| {
+                Err(Error::Internal("the self-check's parse of the input panicked".to_string()))
+            })
+        });
+        (wasm, original)
+    });
+    let original = original.transpose()?;
+    if options.verbose {
+        println!(
+            "Emitted {} bytes in {} ms",
+            wasm.len(),
+            started.elapsed().as_millis()
+        );
+    }
+    let mut wasm = apply_custom_policy(wasm_bytes, wasm, &options.keep_custom, &options.drop_custom);
+    if let Some(hints) = hints {
+        insert_before_code(&mut wasm, &hints);
+    }
+
+    if let Some(original) = original {
+        selfcheck::self_check(&original, &wasm, is_opt, &skipped).map_err(Error::SelfCheck)?;
+    }
+
+    if !is_opt {
+        let max_params = callsites
+            .iter()
+            .filter(|c| !skipped.contains(&c.id))
+            .map(|c| c.params.len())
+            .max()
+            .unwrap_or(0);
+        let estimate = overhead::estimate(
+            callsites.len() - skipped.len(),
+            max_window,
+            max_params,
+            if trace {
+                overhead::Recording::Trace
+            } else if options.slot_memory {
+                overhead::Recording::SlotMemory
+            } else if options.host_events {
+                overhead::Recording::HostEvents
+            } else {
+                overhead::Recording::SlotGlobals
+            },
+            wasm_bytes.len(),
+            wasm.len(),
+        );
+        overhead::print_overhead(&estimate);
+        if let Some(budget) = options.max_overhead {
+            if estimate.per_indirect_call > budget {
+                return Err(Error::OverBudget(format!(
+                    "~{} extra instructions per indirect call, --max-overhead is {}",
+                    estimate.per_indirect_call, budget
+                )));
+            }
+        }
+        if let Some(budget) = options.max_size_increase {
+            let growth =
+                (wasm.len() as f64 / std::cmp::max(wasm_bytes.len(), 1) as f64 - 1.0) * 100.0;
+            if growth > budget {
+                let contributors =
+                    overhead::size_contributors(&module, &input_funcs, wasm_bytes, &wasm);
+                let top: Vec<String> = contributors
+                    .iter()
+                    .take(5)
+                    .map(|(what, bytes)| format!("{} (+{} bytes)", what, bytes))
+                    .collect();
+                let message = format!(
+                    "the output is {:+.1}% the size of the input, --max-size-increase is {}%; biggest contributors: {}. Instrument fewer callsites (--max-callsites, --hot-functions-from, --skip-static-callsites) or pass --compact-exports",
+                    growth,
+                    budget,
+                    top.join(", ")
+                );
+                if options.size_warn_only {
+                    println!("warning: {}", message);
+                } else {
+                    return Err(Error::OverBudget(message));
+                }
+            }
+        }
+    }
+
+    Ok(Output {
+        wasm,
+        decisions: decision_log,
+        index_map,
+        manifest: Manifest {
+            callsites,
+            window: indirect_window,
+            callsite_windows: options
+                .callsite_windows
+                .iter()
+                .map(|(idx, window)| (*idx, *window))
+                .collect(),
+            trace: trace_layout,
+            fingerprint,
+            export_prefix: options.export_prefix.clone(),
+            descriptors: descriptor_layout,
+            slot_memory: slot_layout,
+            snapshots: snapshot_layout,
+            host_events,
+            context_tree: context_layout,
+            chunk: chunk_layout,
+            imports,
+            entries,
+            counters: CounterLayout {
+                init: options.counters.init,
+                mode: options.counters.mode,
+                slot_policy: options.slot_policy,
+                ..CounterLayout::default()
+            },
+        },
+    })
+}
+
+/*
+ * Optimize with `map`, then instrument the optimized binary so the fleet can
+ * keep collecting on what it actually runs. Both outputs come from one
+ * invocation, but it is two full passes: only the first resolves `map`, and
+ * the second parses and analyzes the optimized binary from scratch. Nothing
+ * from the first pass's analysis carries over, since it describes the input
+ * rather than the optimized binary. The second pass works on the emitted
+ * optimized bytes rather than the in-memory module: its callsite ids must
+ * match what a later optimize run over the written binary numbers, and
+ * re-parsing drops the dead code left behind `unreachable`.
+ */
+pub fn run_and_reinstrument(
+    wasm_bytes: &[u8],
+    map: Profile,
+    options: &InstrumentOptions,
+) -> Result<(Output, Output), Error> {
+    let optimized = run(wasm_bytes, Some(map), options)?;
+    let instrumented = run(&optimized.wasm, None, options)?;
+    Ok((optimized, instrumented))
+}
+
+/*
+ * --ab-split: optimize with `map` twice, into an aggressive binary (unexecuted
+ * callsites become `unreachable` and a failed guard traps) and a conservative
+ * one (every devirtualized call falls back to the call_indirect and unexecuted
+ * callsites stay indirect), so operators can canary one against the other.
+ * The profile is resolved against the input once and both builds are emitted
+ * from that analysis (see Analysis::fork), so the callsites and their ids
+ * match and one manifest describes both binaries.
+ */
+pub fn run_ab_split(
+    wasm_bytes: &[u8],
+    map: Profile,
+    options: &InstrumentOptions,
+) -> Result<(Output, Output), Error> {
+    let analysis = analyze(Cow::Borrowed(wasm_bytes), Some(map), options)?;
+    let fork = analysis.fork(options)?;
+    let aggressive = InstrumentOptions {
+        conservative: false,
+        ..options.clone()
+    };
+    let conservative = InstrumentOptions {
+        conservative: true,
+        ..options.clone()
+    };
+    let aggressive = emit(analysis, &aggressive)?;
+    let conservative = emit(fork, &conservative)?;
+    Ok((aggressive, conservative))
+}
+
+/*
+ * Move the callsites of an instrumented binary from their position in the
+ * module to `ids[position]`: the slot globals
+ * ({prefix}profiling_global_{id}_{n}) are renamed and the manifest updated.
+ * Only the slot global names carry the callsite id, so traces, slot memories,
+ * descriptor tables, host events and context trees, which record it inside
+ * the module, can't be renumbered.
+ */
+pub fn renumber_callsites(
+    output: &mut Output,
+    ids: &[usize],
+    options: &InstrumentOptions,
+) -> Result<(), Error> {
+    if options.trace
+        || options.slot_memory
+        || options.compact_exports
+        || options.host_events
+        || options.context_tree
+    {
+        return Err(Error::InvalidOptions(
+            "--trace, --slot-memory, --compact-exports, --host-events and --context-tree record callsite ids inside the module, they can't be renumbered".to_string(),
+        ));
+    }
+    let mut module =
+        walrus::Module::from_buffer(&output.wasm).map_err(|e| Error::Internal(e.to_string()))?;
+    let prefix = format!("{}profiling_global_", options.export_prefix);
+    for export in module.exports.iter_mut() {
+        let renamed = export
+            .name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.split_once('_'))
+            .and_then(|(position, slot)| Some((ids.get(position.parse::<usize>().ok()?)?, slot)))
+            .map(|(id, slot)| format!("{}{}_{}", prefix, id, slot));
+        if let Some(name) = renamed {
+            export.name = name;
+        }
+    }
+    for callsite in output.manifest.callsites.iter_mut() {
+        callsite.id = ids[callsite.id];
+    }
+    output.manifest.callsite_windows = output
+        .manifest
+        .callsite_windows
+        .iter()
+        .map(|(position, window)| (ids[*position], *window))
+        .collect();
+    output.wasm = apply_custom_policy(
+        &output.wasm,
+        module.emit_wasm(),
+        &options.keep_custom,
+        &options.drop_custom,
+    );
+    Ok(())
+}
+
+/*
+ * Instrument an in-memory module, for embedders that only want the bytes.
+ * Malformed input, bad options, a failed self-check and the states a pass
+ * can't handle all come back from `run` as errors, so this doesn't rely on
+ * unwinding (a panic=abort build or a wasm host has none).
+ */
+pub fn instrument_bytes(wasm: &[u8], options: &InstrumentOptions) -> Result<Vec<u8>, Error> {
+    run(wasm, None, options).map(|output| output.wasm)
+}
@@ -1,85 +1,384 @@
+use crate::callsites::{callsite_keys, enumerate_callsites, Callsite};
+use crate::compression::{compress, decompress, Compression};
+use crate::dylink::table_base;
+use crate::formats::{decode_globals, decode_profile, encode_profile, ProfileFormat};
+use crate::schema::MapValue;
+use crate::tableimage::{active_segments, TableImage};
+use crate::typecompat::check_target;
 use crate::Profile;
 use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
 use walrus::ir::Value;
 use walrus::InitExpr::*;
 use walrus::*;
 
-// In our modified map, we can perform 3 operations:
-// 1) Replace an indirect call with a func id
-// 2) Replace an indirect call with "unreachable"
-// 3) No-op
-#[derive(Clone, Debug)]
-pub struct MapValue {
-    pub f_id: Option<Vec<FunctionId>>,
-    pub f_bool: bool,
+pub fn read_profile(path: &str) -> Result<Profile, String> {
+    read_profile_as(path, ProfileFormat::Msgpack)
 }
 
-pub fn process_map(
+// Errors name `path`, so callers can report them as they are
+pub fn read_profile_as(path: &str, format: ProfileFormat) -> Result<Profile, String> {
+    let mut file = File::open(path).unwrap();
+    let mut buf = vec![];
+    file.read_to_end(&mut buf).unwrap();
+    let buf = decompress(buf).map_err(|e| format!("{}: {}", path, e))?;
+    decode_profile(&buf, format).map_err(|e| format!("{}: {}", path, e))
+}
+
+// A `globals` dump taken from a binary instrumented with --export-prefix `prefix`
+pub fn read_globals_dump(path: &str, prefix: &str) -> Result<Profile, String> {
+    let mut file = File::open(path).unwrap();
+    let mut buf = vec![];
+    file.read_to_end(&mut buf).unwrap();
+    let buf = decompress(buf).map_err(|e| format!("{}: {}", path, e))?;
+    let text = std::str::from_utf8(&buf).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(decode_globals(text, prefix))
+}
+
+pub fn write_profile(path: &str, profile: &Profile, compression: Compression) {
+    write_profile_as(path, profile, ProfileFormat::Msgpack, compression)
+}
+
+pub fn write_profile_as(
+    path: &str,
+    profile: &Profile,
+    format: ProfileFormat,
+    compression: Compression,
+) {
+    let buf = compress(encode_profile(profile, format), compression);
+    std::fs::write(path, buf).unwrap();
+}
+
+// Human readable version of the decision process_map makes for a callsite's slots
+/*
+ * Slot counts for re-instrumenting with a prior profile: one slot for a
+ * callsite that only ever saw one target, one more than it saw for a
+ * polymorphic one (room for a newcomer), twice the old window for one that
+ * overflowed, and none for a callsite that never executed. Callsites the
+ * profile doesn't mention keep `window`. Capped at 50, like --window.
+ */
+pub fn adaptive_windows(profile: &Profile, window: usize) -> HashMap<usize, usize> {
+    profile
+        .map
+        .iter()
+        .map(|(idx, slots)| {
+            let targets = slots.iter().filter(|val| **val >= 0).count();
+            let slots = if slots.contains(&-2) {
+                std::cmp::max(2 * slots.len(), window)
+            } else if targets == 0 {
+                0
+            } else if targets == 1 {
+                1
+            } else {
+                targets + 1
+            };
+            (*idx, std::cmp::min(slots, 50))
+        })
+        .filter(|(_, slots)| *slots != window)
+        .collect()
+}
+
+pub fn describe_slots(slots: &[i64]) -> String {
+    let calls = slots.iter().filter(|val| **val >= 0).count();
+    if calls > 0 {
+        format!(
+            "devirtualize: guarded direct call to {} observed target(s), trap otherwise",
+            calls
+        )
+    } else if slots.iter().all(|val| *val == -2) {
+        "retain the indirect call (more targets than the window could track)".to_string()
+    } else {
+        "replace with unreachable (never executed while profiling)".to_string()
+    }
+}
+
+/*
+ * The table call_indirect targets are resolved against: the Nth table in the
+ * table index space (imports first) when `--table-index` is given, otherwise
+ * the module's only funcref table, whether it is defined or imported.
+ */
+pub fn function_table(module: &Module, table_index: Option<u32>) -> Option<TableId> {
+    match table_index {
+        Some(idx) => match module.tables.iter().nth(idx as usize) {
+            Some(table) if table.element_ty == ValType::Funcref => Some(table.id()),
+            Some(_) => {
+                println!("table {} is not a funcref table", idx);
+                None
+            }
+            None => {
+                println!("--table-index {} is out of range", idx);
+                None
+            }
+        },
+        None => match module.tables.main_function_table() {
+            Ok(table) => table,
+            Err(_) => {
+                println!("module has several function tables, pick one with --table-index");
+                None
+            }
+        },
+    }
+}
+
+/*
+ * Offset of an active element segment. Besides constants we accept a global
+ * initialized to a constant; an imported global is only known at
+ * instantiation, so segments placed with one can't be resolved statically,
+ * except for `__table_base` in position-independent modules: their table
+ * indices are all relative to it (see dylink), so it resolves to 0. Offsets
+ * are table indices, so unsigned (see counters::slot_value).
+ */
+pub fn segment_offset(module: &Module, kind: &ElementKind) -> Option<i64> {
+    let offset = match kind {
+        ElementKind::Active { offset, .. } => offset,
+        _ => return None,
+    };
+    let value = match offset {
+        InitExpr::Value(value) => value,
+        InitExpr::Global(g) => match &module.globals.get(*g).kind {
+            GlobalKind::Local(InitExpr::Value(value)) => value,
+            GlobalKind::Import(_) if table_base(module) == Some(*g) => return Some(0),
+            _ => return None,
+        },
+        _ => return None,
+    };
+    match value {
+        Value::I32(x) => Some(*x as u32 as i64),
+        Value::I64(x) if *x >= 0 => Some(*x),
+        _ => None,
+    }
+}
+
+/*
+ * Look up which function lives at `idx` in `table`: what the last segment
+ * to write it put there, like TableImage::get without materializing the
+ * whole table for one lookup.
+ */
+pub fn resolve_in_table(module: &Module, table: TableId, idx: i64) -> Option<FunctionId> {
+    let mut found = None;
+    for (_, e) in active_segments(module, table) {
+        let offset = match segment_offset(module, &e.kind) {
+            Some(offset) => offset,
+            None => continue,
+        };
+        if idx >= offset && ((idx - offset) as usize) < e.members.len() {
+            found = e.members[(idx - offset) as usize];
+        }
+    }
+    found
+}
+
+// The (first) index `func` is placed at in `table`
+pub fn table_slot(module: &Module, table: TableId, func: FunctionId) -> Option<i64> {
+    TableImage::build(module, table).slot_of(func)
+}
+
+/*
+ * Apply `--force-devirt key=function` overrides: the callsite's profile
+ * becomes a single observed call to `function`, as if that were all the
+ * profiling run saw. The guard in the devirtualization stub compares against
+ * the table index, so the target has to be in the table, and its type has to
+ * match the call_indirect's.
+ */
+pub fn force_devirt(
     module: &Module,
-    original_map: &Option<Profile>,
-    modified_map: &mut HashMap<usize, MapValue>,
-) -> () {
-    let tab_id = module.tables.main_function_table().unwrap().unwrap();
-    let table = module.tables.get(tab_id);
-    for elem in &table.elem_segments {
-        let e = module.elements.get(*elem);
-        let offset: usize = match e.kind {
-            walrus::ElementKind::Active {
-                table: t,
-                offset: expr,
-            } => match expr {
-                walrus::InitExpr::Value(Value::I32(x)) => x as usize,
-                _ => 0,
-            },
-            _ => 0,
+    table: TableId,
+    overrides: &[(String, String)],
+    profile: &mut Profile,
+) -> Result<(), String> {
+    if overrides.is_empty() {
+        return Ok(());
+    }
+    let callsites = enumerate_callsites(module, Some(table));
+    let keys = callsite_keys(module, &callsites);
+    for (key, target) in overrides {
+        let idx = match keys.iter().position(|k| k == key) {
+            Some(idx) => idx,
+            None => return Err(format!("--force-devirt: no callsite {}", key)),
         };
+        let func = match module.funcs.by_name(target) {
+            Some(func) => func,
+            None => return Err(format!("--force-devirt: no function named {}", target)),
+        };
+        check_target(module, func, callsites[idx].ty)
+            .map_err(|reason| format!("--force-devirt: callsite {}: {}", key, reason))?;
+        if callsites[idx].table != table {
+            return Err(format!(
+                "--force-devirt: callsite {} calls through another table",
+                key
+            ));
+        }
+        let slot = match table_slot(module, table, func) {
+            Some(slot) => slot,
+            None => return Err(format!("--force-devirt: {} is not in the table", target)),
+        };
+        println!(
+            "forcing callsite {} to call {} (table index {})",
+            key, target, slot
+        );
+        profile.map.insert(idx, vec![slot]);
+    }
+    Ok(())
+}
 
-        //dbg!(&e.members);
+// Look up which function lives at `idx` in the main function table
+pub fn resolve_table_index(module: &Module, idx: i64) -> Option<FunctionId> {
+    resolve_in_table(module, function_table(module, None)?, idx)
+}
 
-        // Now that we have the offset, we can remap our profile data
-        // We recorded a mapping of indicies in this table to a value of {-1/-2/integer >= 0}
-        // We need to remap the index in this table to a FunctionId in this element
-        // Later we will replace indirect calls using this mapping of global idx ==> FunctionId
-        for (global_idx, indirect_idx) in &original_map.as_ref().unwrap().map {
-            // Vec contains actual func calls
-            let calls: Vec<&i32> = indirect_idx
-                .iter()
-                .filter(|val| **val != -2 && **val != -1)
-                .collect::<Vec<&i32>>();
-            if calls.len() > 0 {
-                //dbg!(&calls);
-                let mut func_ids = vec![];
-                for id in calls {
-                    func_ids.push(e.members[(*id as usize) - offset].unwrap());
+/*
+ * Resolve the profile's table indices to the functions each callsite
+ * reached. A target whose type the callsite's call_indirect doesn't accept
+ * (the call trapped, or the profile was collected racily or on another
+ * build) would make the direct call invalid, so it is dropped with a
+ * warning, and from the callsite's slots when it has targets left; a
+ * callsite left without any stays indirect, as do the callsites through
+ * another table than `table`.
+ */
+pub fn process_map(
+    module: &Module,
+    original_map: &mut Option<Profile>,
+    modified_map: &mut HashMap<usize, MapValue>,
+    table: Option<TableId>,
+    callsites: &[Callsite],
+    // Table indices filled in by other modules of a linked set, see linked
+    linked: &HashMap<i64, FunctionId>,
+) -> () {
+    let tab_id = match table {
+        Some(tab_id) => tab_id,
+        None => {
+            println!("Unable to find indirect call table --- leaving all indirect calls as-is");
+            return;
+        }
+    };
+    let image = TableImage::build(module, tab_id);
+    let mut mismatched = 0;
+    let mut mismatched_callsites = 0;
+    let mut kept_slots: Vec<(usize, Vec<i64>)> = vec![];
+    let mut other_tables = 0;
+    // Remap our profile data
+    // We recorded a mapping of indicies in this table to a value of {-1/-2/integer >= 0}
+    // We need to remap the index in this table to a FunctionId
+    // Later we will replace indirect calls using this mapping of global idx ==> FunctionId
+    for (global_idx, indirect_idx) in &original_map.as_ref().unwrap().map {
+        // The slots of a call through another table are indices into that one
+        if callsites
+            .get(*global_idx)
+            .map_or(false, |callsite| callsite.table != tab_id)
+        {
+            other_tables += 1;
+            continue;
+        }
+        // Anything below -2 can't have come from the instrumentation (a corrupt or
+        // hand edited profile), don't guess what the callsite called
+        if let Some(bad) = indirect_idx.iter().find(|val| **val < -2) {
+            println!(
+                "invalid slot value {} (callsite {}), retaining the indirect call",
+                bad, global_idx
+            );
+            let val = MapValue {
+                f_id: None,
+                f_bool: false,
+            };
+            modified_map.insert(*global_idx, val);
+            continue;
+        }
+        // Vec contains actual func calls
+        let calls: Vec<&i64> = indirect_idx
+            .iter()
+            .filter(|val| **val >= 0)
+            .collect::<Vec<&i64>>();
+        if calls.len() > 0 {
+            // (slot, the function it resolves to)
+            let mut func_ids = vec![];
+            for id in calls {
+                match image.get(*id).or_else(|| linked.get(id).cloned()) {
+                    Some(f_id) => func_ids.push((*id, f_id)),
+                    None => {
+                        // e.g. the table was grown (and filled) at runtime
+                        println!(
+                            "table index {} (callsite {}) is outside the table's element segments, retaining the indirect call",
+                            id, global_idx
+                        );
+                        func_ids.clear();
+                        break;
+                    }
+                }
+            }
+            if let Some(callsite) = callsites.get(*global_idx) {
+                let before = func_ids.len();
+                func_ids.retain(|(_, func)| match check_target(module, *func, callsite.ty) {
+                    Ok(()) => true,
+                    Err(reason) => {
+                        println!("{} (callsite {}), dropping the target", reason, global_idx);
+                        false
+                    }
+                });
+                if func_ids.len() < before {
+                    mismatched += before - func_ids.len();
+                    mismatched_callsites += 1;
+                    if func_ids.is_empty() {
+                        println!(
+                            "callsite {} has no target of its type left, retaining the indirect call",
+                            global_idx
+                        );
+                    } else {
+                        // The stubs pair the slots up with the targets, see generate_stubs
+                        let kept: Vec<i64> = indirect_idx
+                            .iter()
+                            .filter(|slot| **slot < 0 || func_ids.iter().any(|(s, _)| s == *slot))
+                            .cloned()
+                            .collect();
+                        kept_slots.push((*global_idx, kept));
+                    }
                 }
-                let val = MapValue {
-                    f_id: Some(func_ids),
-                    f_bool: false,
-                };
-                modified_map.insert(*global_idx, val);
-            // if we must retain the indirect call
-            // if the values have been set to -2
-            } else if indirect_idx
-                .iter()
-                .filter(|val| **val == -2)
-                .collect::<Vec<&i32>>()
-                .len()
-                == indirect_idx.len()
-            {
-                //dbg!(&indirect_idx.iter().filter(|val| **val == -2).collect::<Vec<&i32>>());
-                let val = MapValue {
-                    f_id: None,
-                    f_bool: false,
-                };
-                modified_map.insert(*global_idx, val);
-            } else {
-                let val = MapValue {
-                    f_id: None,
-                    f_bool: true,
-                };
-                modified_map.insert(*global_idx, val);
             }
+            let val = MapValue {
+                f_id: if func_ids.is_empty() {
+                    None
+                } else {
+                    Some(func_ids.into_iter().map(|(_, func)| func).collect())
+                },
+                f_bool: false,
+            };
+            modified_map.insert(*global_idx, val);
+        // if we must retain the indirect call
+        // if the values have been set to -2
+        } else if indirect_idx
+            .iter()
+            .filter(|val| **val == -2)
+            .collect::<Vec<&i64>>()
+            .len()
+            == indirect_idx.len()
+        {
+            let val = MapValue {
+                f_id: None,
+                f_bool: false,
+            };
+            modified_map.insert(*global_idx, val);
+        } else {
+            let val = MapValue {
+                f_id: None,
+                f_bool: true,
+            };
+            modified_map.insert(*global_idx, val);
         }
-        break;
+    }
+    let profile = original_map.as_mut().unwrap();
+    for (idx, slots) in kept_slots {
+        profile.map.insert(idx, slots);
+    }
+    if mismatched > 0 {
+        println!(
+            "Dropped {} profiled targets of the wrong type at {} callsites",
+            mismatched, mismatched_callsites
+        );
+    }
+    if other_tables > 0 {
+        println!(
+            "Leaving {} callsites through other tables than the resolved one as-is",
+            other_tables
+        );
     }
 }
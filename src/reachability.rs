@@ -0,0 +1,191 @@
+use crate::callgraph::{BitSet, CallGraph};
+use crate::report::func_name;
+use crate::selfcheck::instrs;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use walrus::ir::*;
+use walrus::*;
+
+// Where the host (or the engine) can enter the module
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Root {
+    // Exported functions
+    Exports,
+    // The start section function
+    Start,
+    // Every function in an element segment, which the host can call through an exported
+    // table or pass around as a funcref
+    Table,
+}
+
+pub const ROOTS: &[&str] = &["exports", "start", "table"];
+pub const ALL_ROOTS: &[Root] = &[Root::Exports, Root::Start, Root::Table];
+
+impl Root {
+    pub fn from_name(name: &str) -> Root {
+        match name {
+            "exports" => Root::Exports,
+            "start" => Root::Start,
+            "table" => Root::Table,
+            _ => panic!("unknown reachability root: {}", name),
+        }
+    }
+}
+
+// The roots named on the command line, all of them when none are
+pub fn roots_from_names<'a>(names: impl Iterator<Item = &'a str>) -> Vec<Root> {
+    let roots: Vec<Root> = names.map(Root::from_name).collect();
+    if roots.is_empty() {
+        ALL_ROOTS.to_vec()
+    } else {
+        roots
+    }
+}
+
+// The functions `roots` names in `module`
+pub fn root_functions(module: &Module, roots: &[Root]) -> HashSet<FunctionId> {
+    let mut funcs = HashSet::new();
+    if roots.contains(&Root::Exports) {
+        funcs.extend(
+            module
+                .exports
+                .iter()
+                .filter_map(|export| match export.item {
+                    ExportItem::Function(f_id) => Some(f_id),
+                    _ => None,
+                }),
+        );
+    }
+    if roots.contains(&Root::Start) {
+        funcs.extend(module.start);
+    }
+    if roots.contains(&Root::Table) {
+        for elem in module.elements.iter() {
+            funcs.extend(elem.members.iter().flatten());
+        }
+    }
+    funcs
+}
+
+/*
+ * The entry points `names` picks out, by export name or else by function
+ * name (report::func_name), for --reachable-from: with `--export-all`
+ * every function is an export, so the exports root keeps everything and
+ * the real entry points have to be named.
+ */
+pub fn named_functions(module: &Module, names: &[String]) -> Result<HashSet<FunctionId>, String> {
+    names
+        .iter()
+        .map(|name| {
+            let export = module.exports.iter().find_map(|export| match export.item {
+                ExportItem::Function(f_id) if export.name == *name => Some(f_id),
+                _ => None,
+            });
+            export
+                .or_else(|| {
+                    module
+                        .funcs
+                        .iter()
+                        .map(|func| func.id())
+                        .find(|f_id| func_name(module, *f_id) == *name)
+                })
+                .ok_or_else(|| {
+                    format!(
+                        "--reachable-from {}: no exported or named function by that name",
+                        name
+                    )
+                })
+        })
+        .collect()
+}
+
+// Whether every local function is exported, as `--export-all` leaves a module
+pub fn exports_everything(module: &Module) -> bool {
+    let exported = root_functions(module, &[Root::Exports]);
+    module
+        .funcs
+        .iter_local()
+        .all(|(id, _)| exported.contains(&id))
+}
+
+/*
+ * Every function of `module` as a node, with an edge to each function it
+ * calls, takes a reference to (ref.func), or may reach with a call_indirect:
+ * all the functions in the elements of the table it calls through. That
+ * last one over-approximates, but it keeps what's reachable sound when
+ * table entries aren't roots.
+ */
+pub fn call_graph(module: &Module) -> CallGraph {
+    let mut table_entries: HashMap<TableId, HashSet<FunctionId>> = HashMap::new();
+    for table in module.tables.iter() {
+        let entries = table_entries.entry(table.id()).or_default();
+        for elem in &table.elem_segments {
+            entries.extend(module.elements.get(*elem).members.iter().flatten());
+        }
+    }
+    let calls: Vec<(FunctionId, HashSet<FunctionId>)> = module
+        .funcs
+        .iter()
+        .map(|func| {
+            let mut callees = HashSet::new();
+            if let FunctionKind::Local(local) = &func.kind {
+                for instr in instrs(local) {
+                    match instr {
+                        Instr::Call(call) => {
+                            callees.insert(call.func);
+                        }
+                        Instr::RefFunc(ref_func) => {
+                            callees.insert(ref_func.func);
+                        }
+                        Instr::CallIndirect(call) => {
+                            callees.extend(table_entries[&call.table].iter().cloned());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            (func.id(), callees)
+        })
+        .collect();
+    let nodes: Vec<(FunctionId, &HashSet<FunctionId>)> = calls
+        .iter()
+        .map(|(func, callees)| (*func, callees))
+        .collect();
+    CallGraph::new(&nodes)
+}
+
+/*
+ * The functions reachable from `roots` and from `extra` (say, the functions
+ * a pass must keep anyway). Everything else can't run short of the host
+ * getting a reference some other way, e.g. through a table that isn't a
+ * root, and is dead code for DCE, --reachable-only and the coverage report.
+ */
+pub fn reachable_functions(
+    module: &Module,
+    roots: &[Root],
+    extra: &HashSet<FunctionId>,
+) -> HashSet<FunctionId> {
+    let graph = call_graph(module);
+    let mut start = BitSet::new(graph.funcs.len());
+    for func in root_functions(module, roots).iter().chain(extra) {
+        if let Some(node) = graph.index.get(func) {
+            start.insert(*node);
+        }
+    }
+    graph
+        .reachable(&start)
+        .iter()
+        .map(|node| graph.funcs[node])
+        .collect()
+}
+
+// The names of `roots` and of the `named` entry points, for messages
+pub fn describe_roots(roots: &[Root], named: &[String]) -> String {
+    roots
+        .iter()
+        .map(|root| ROOTS[ALL_ROOTS.iter().position(|r| r == root).unwrap()])
+        .chain(named.iter().map(|name| name.as_str()))
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
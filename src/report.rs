@@ -0,0 +1,311 @@
+use crate::branches::enumerate_branches;
+use crate::branches::BranchKind;
+use crate::brtables::enumerate_br_tables;
+use crate::callsites::enumerate_callsites;
+use crate::cycles::{cycles_report, CostTable};
+use crate::dataregions::data_regions;
+use crate::dispatch::{dispatch_kinds, dispatch_summary};
+use crate::dwarf::{annotate, SourceMap};
+use crate::entrypoints::enumerate_entries;
+use crate::importcounters::enumerate_imports;
+use crate::megamorphic::megamorphic_report;
+use crate::profilemap::describe_slots;
+use crate::symbolize::display_name;
+use crate::Profile;
+use std::collections::BTreeMap;
+use walrus::*;
+
+pub fn func_name(module: &Module, id: FunctionId) -> String {
+    match &module.funcs.get(id).name {
+        Some(name) => name.clone(),
+        None => format!("func_{}", id.index()),
+    }
+}
+
+// Format a function type as "(i32, i32) -> (i64)"
+pub fn type_signature(ty: &Type) -> String {
+    let params: Vec<String> = ty.params().iter().map(|p| p.to_string()).collect();
+    let results: Vec<String> = ty.results().iter().map(|r| r.to_string()).collect();
+    format!("({}) -> ({})", params.join(", "), results.join(", "))
+}
+
+/*
+ * Rank branches by how likely they are to diverge across lanes.
+ *
+ * We only have the aggregated counts, so we approximate divergence by the
+ * number of times the minority direction was taken: a branch that is always
+ * taken (or never taken) can't diverge, while a 50/50 branch diverges on
+ * almost every lockstep execution.
+ */
+fn divergence_report(module: &Module, profile: &Profile, top: usize, demangle: bool) {
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let branches = enumerate_branches(module, &original_funcs);
+
+    let mut ranked: Vec<(usize, u64, u64)> = profile
+        .branches
+        .iter()
+        .filter(|(idx, _)| **idx < branches.len())
+        .map(|(idx, (taken, not_taken))| (*idx, *taken, *not_taken))
+        .collect();
+    ranked.sort_by(|a, b| {
+        let score_a = std::cmp::min(a.1, a.2);
+        let score_b = std::cmp::min(b.1, b.2);
+        score_b.cmp(&score_a).then(a.0.cmp(&b.0))
+    });
+
+    println!("== Divergence-prone branches ==");
+    println!(
+        "{:>6} {:>8} {:>12} {:>12} {:>8}  {}",
+        "branch", "kind", "taken", "not taken", "ratio", "function"
+    );
+    for (idx, taken, not_taken) in ranked.iter().take(top) {
+        let branch = branches[*idx];
+        let total = taken + not_taken;
+        let ratio = if total > 0 {
+            std::cmp::min(*taken, *not_taken) as f64 / total as f64
+        } else {
+            0.0
+        };
+        let kind = match branch.kind {
+            BranchKind::IfElse => "if",
+            BranchKind::BrIf => "br_if",
+        };
+        println!(
+            "{:>6} {:>8} {:>12} {:>12} {:>8.3}  {}",
+            idx,
+            kind,
+            taken,
+            not_taken,
+            ratio,
+            display_name(module, branch.func, demangle)
+        );
+    }
+    if profile.branches.len() != ranked.len() {
+        println!(
+            "warning: {} branch entries in the profile don't exist in this module",
+            profile.branches.len() - ranked.len()
+        );
+    }
+}
+
+// How many callsites end up with each optimizer decision, plus the most polymorphic ones
+fn callsite_summary(
+    module: &Module,
+    table: Option<TableId>,
+    profile: &Profile,
+    top: usize,
+    demangle: bool,
+    sources: Option<&SourceMap>,
+) {
+    let callsites = enumerate_callsites(module, table);
+    let kinds = dispatch_kinds(module, &callsites);
+    let mut decisions: BTreeMap<String, usize> = BTreeMap::new();
+    let mut polymorphic = vec![];
+    for idx in 0..callsites.len() {
+        if let Some(slots) = profile.map.get(&idx) {
+            let targets = slots.iter().filter(|val| **val >= 0).count();
+            if targets > 1 {
+                polymorphic.push((idx, targets));
+            }
+        }
+        let decision = match profile.map.get(&idx) {
+            Some(slots) => describe_slots(slots),
+            None => "no profile data (left as-is)".to_string(),
+        };
+        *decisions.entry(decision).or_insert(0) += 1;
+    }
+    println!("== Indirect callsites ({}) ==", callsites.len());
+    for (decision, count) in decisions {
+        println!("{:>8}  {}", count, decision);
+    }
+    if !callsites.is_empty() {
+        dispatch_summary(&kinds);
+    }
+
+    if !polymorphic.is_empty() {
+        polymorphic.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        println!("== Polymorphic callsites ==");
+        println!(
+            "{:>8} {:>8}  {:<8} {:<30} {}",
+            "callsite", "targets", "dispatch", "type", "function"
+        );
+        for (idx, targets) in polymorphic.iter().take(top) {
+            let callsite = callsites[*idx];
+            println!(
+                "{:>8} {:>8}  {:<8} {:<30} {}",
+                idx,
+                targets,
+                kinds[*idx].name(),
+                type_signature(module.types.get(callsite.ty)),
+                annotate(
+                    display_name(module, callsite.func, demangle),
+                    sources.and_then(|s| s.locate(callsite.loc))
+                )
+            );
+        }
+    }
+}
+
+// The imported functions called most often (--import-counters)
+fn import_report(module: &Module, profile: &Profile, top: usize) {
+    let imports = enumerate_imports(module);
+    let mut ranked: Vec<(usize, i32)> = profile
+        .imports
+        .iter()
+        .filter(|(idx, _)| **idx < imports.len())
+        .map(|(idx, count)| (*idx, *count))
+        .collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    println!("== Imported function calls ==");
+    println!("{:>12}  {}", "calls", "import");
+    for (idx, count) in ranked.iter().take(top) {
+        let import = &imports[*idx].1;
+        println!("{:>12}  {}::{}", count, import.module, import.name);
+    }
+}
+
+// How often the host invoked each export (--entry-counters)
+fn entry_report(module: &Module, profile: &Profile) {
+    let entries = enumerate_entries(module);
+    let mut ranked: Vec<(usize, i32)> = profile
+        .entries
+        .iter()
+        .filter(|(idx, _)| **idx < entries.len())
+        .map(|(idx, count)| (*idx, *count))
+        .collect();
+    ranked.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    println!("== Entry points ==");
+    println!("{:>12}  {}", "calls", "export");
+    for (idx, count) in ranked {
+        println!("{:>12}  {}", count, entries[idx].1.name);
+    }
+}
+
+// How far each memory grew, for sizing per-instance memory limits (--memory-counters)
+fn memory_report(profile: &Profile) {
+    let memory: BTreeMap<&usize, &(i32, i32)> = profile.memory.iter().collect();
+    println!("== Memory growth ==");
+    println!(
+        "{:>6} {:>12} {:>10} {:>12}",
+        "memory", "pages grown", "max pages", "max bytes"
+    );
+    for (idx, (grown, max_pages)) in memory {
+        println!(
+            "{:>6} {:>12} {:>10} {:>12}",
+            idx,
+            grown,
+            max_pages,
+            *max_pages as u64 * 65536
+        );
+    }
+}
+
+// The data regions the code reads and writes the most (--data-counters)
+fn data_report(module: &Module, profile: &Profile, top: usize) {
+    let regions = data_regions(module);
+    let mut ranked: Vec<(u32, u32, i32)> = profile
+        .data_accesses
+        .iter()
+        .filter_map(|(addr, count)| {
+            let start = *addr as u32;
+            regions.get(&start).map(|end| (start, *end, *count))
+        })
+        .collect();
+    ranked.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+    let total: i64 = ranked.iter().map(|(_, _, count)| *count as i64).sum();
+    println!("== Hot data regions ==");
+    println!("{:>12} {:>8}  {}", "accesses", "share", "addresses");
+    for (start, end, count) in ranked.iter().take(top) {
+        let share = if total > 0 {
+            *count as f64 / total as f64 * 100.0
+        } else {
+            0.0
+        };
+        println!("{:>12} {:>7.1}%  {:#x}..{:#x}", count, share, start, end);
+    }
+    let untouched = regions.len() - ranked.iter().filter(|(_, _, count)| *count > 0).count();
+    println!("{} of {} regions never accessed", untouched, regions.len());
+}
+
+// Where each counted br_table sends its index, hottest arms first (--br-table-counters)
+fn br_table_report(module: &Module, profile: &Profile, top: usize, demangle: bool) {
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let tables = enumerate_br_tables(module, &original_funcs);
+    let mut ranked: Vec<(usize, &Vec<i32>, i64)> = profile
+        .br_tables
+        .iter()
+        .filter(|(idx, _)| **idx < tables.len())
+        .map(|(idx, arms)| (*idx, arms, arms.iter().map(|c| *c as i64).sum()))
+        .collect();
+    ranked.sort_by(|a, b| b.2.cmp(&a.2).then(a.0.cmp(&b.0)));
+    println!("== Hot br_table arms ==");
+    println!(
+        "{:>8} {:>12} {:>10}  {:<32} {}",
+        "br_table", "taken", "arms hit", "hottest arms", "function"
+    );
+    for (idx, arms, total) in ranked.iter().take(top) {
+        let table = tables[*idx];
+        let mut hot: Vec<(usize, i32)> = arms
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, count)| *count > 0)
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        let hottest: Vec<String> = hot
+            .iter()
+            .take(3)
+            .map(|(arm, count)| {
+                let arm = if *arm + 1 == table.arms {
+                    "default".to_string()
+                } else {
+                    arm.to_string()
+                };
+                format!("{} ({:.0}%)", arm, *count as f64 / *total as f64 * 100.0)
+            })
+            .collect();
+        println!(
+            "{:>8} {:>12} {:>10}  {:<32} {}",
+            idx,
+            total,
+            format!("{}/{}", hot.len(), table.arms),
+            hottest.join(", "),
+            display_name(module, table.func, demangle)
+        );
+    }
+}
+
+// `table` is the one the callsites were numbered against, see enumerate_callsites
+pub fn print_report(
+    module: &Module,
+    table: Option<TableId>,
+    profile: &Profile,
+    top: usize,
+    demangle: bool,
+    sources: Option<&SourceMap>,
+    weights: &CostTable,
+) {
+    callsite_summary(module, table, profile, top, demangle, sources);
+    megamorphic_report(module, table, profile, top, demangle, sources);
+    if !profile.blocks.is_empty() {
+        cycles_report(module, profile, weights, top, demangle);
+    }
+    if !profile.imports.is_empty() {
+        import_report(module, profile, top);
+    }
+    if !profile.entries.is_empty() {
+        entry_report(module, profile);
+    }
+    if !profile.memory.is_empty() {
+        memory_report(profile);
+    }
+    if !profile.data_accesses.is_empty() {
+        data_report(module, profile, top);
+    }
+    if !profile.br_tables.is_empty() {
+        br_table_report(module, profile, top, demangle);
+    }
+    if !profile.branches.is_empty() {
+        divergence_report(module, profile, top, demangle);
+    }
+}
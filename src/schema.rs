@@ -0,0 +1,280 @@
+use crate::counters::{CounterMode, SlotPolicy, EMPTY_SLOT, OVERFLOW_SLOT};
+pub use crate::formats::ProfileFormat;
+use crate::formats::{decode_globals, decode_profile, encode_profile};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use walrus::FunctionId;
+
+/*
+ * The data model shared with the host side: the profile collected from an
+ * instrumented binary and the manifest describing that binary's
+ * instrumentation. External collectors and dashboards build and read these
+ * through the library instead of keeping their own copies of the structs.
+ * Profiles convert to and from every on-disk format (ProfileFormat) and the
+ * raw `name=value` dump of the exported globals.
+ */
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Profile {
+    // callsite id ==> slots: table indices, EMPTY_SLOT or OVERFLOW_SLOT (see counters::slot_value)
+    pub map: HashMap<usize, Vec<i64>>,
+    // callsite id ==> (table index, calls) of its targets, when the collector counted them
+    #[serde(default)]
+    pub target_counts: HashMap<usize, Vec<(i64, u64)>>,
+    // block id ==> execution count (only present with --block-counters)
+    #[serde(default)]
+    pub blocks: HashMap<usize, i32>,
+    // branch id ==> (taken, not taken), summed across all lanes by the host
+    #[serde(default)]
+    pub branches: HashMap<usize, (u64, u64)>,
+    // br_table id ==> times each arm was taken, the default last (only present with --br-table-counters)
+    #[serde(default)]
+    pub br_tables: HashMap<usize, Vec<i32>>,
+    // callsite id ==> targets that weren't in its slots when they came in (saturating),
+    // only present with --slot-policy lru
+    #[serde(default)]
+    pub distinct_targets: HashMap<usize, u64>,
+    // Value of the exported slowcalls counter
+    #[serde(default)]
+    pub slowcalls: Option<i32>,
+    // import id ==> call count (only present with --import-counters)
+    #[serde(default)]
+    pub imports: HashMap<usize, i32>,
+    // value id ==> (most common value, majority votes, calls), see valueprofile
+    #[serde(default)]
+    pub values: HashMap<usize, (i32, i32, i32)>,
+    // memory index ==> (pages grown, max pages), only present with --memory-counters
+    #[serde(default)]
+    pub memory: HashMap<usize, (i32, i32)>,
+    // loop id ==> (entries, iterations, max trip count), only present with --loop-counters
+    #[serde(default)]
+    pub loops: HashMap<usize, (i32, i32, i32)>,
+    // global id ==> reads and writes, only present with --global-counters (see globalaccess)
+    #[serde(default)]
+    pub global_accesses: HashMap<usize, i32>,
+    // data region start address ==> loads and stores, only present with --data-counters (see dataregions)
+    #[serde(default)]
+    pub data_accesses: HashMap<usize, i32>,
+    // entry id ==> invocations by the host, only present with --entry-counters (see entrypoints)
+    #[serde(default)]
+    pub entries: HashMap<usize, i32>,
+    // The entry the host invoked last before dumping (profiling_entry), which a
+    // per-invocation dump belongs to; see merge::segment_by_entry
+    #[serde(default)]
+    pub entry: Option<usize>,
+    // entry id ==> callsite id ==> slots, each handler's share of `map` (merge::merge_into
+    // collects it from tagged dumps) for optimize --entry-point
+    #[serde(default)]
+    pub by_entry: HashMap<usize, HashMap<usize, Vec<i64>>>,
+}
+
+impl Profile {
+    // A `globals` dump (see formats::decode_globals) of a binary instrumented with --export-prefix `prefix`
+    pub fn from_globals_dump(text: &str, prefix: &str) -> Profile {
+        decode_globals(text, prefix)
+    }
+
+    // The dump of the unprefixed globals holding this profile
+    pub fn to_globals_dump(&self) -> String {
+        String::from_utf8(encode_profile(self, ProfileFormat::Globals)).unwrap()
+    }
+
+    pub fn decode(buf: &[u8], format: ProfileFormat) -> Result<Profile, String> {
+        decode_profile(buf, format)
+    }
+
+    pub fn encode(&self, format: ProfileFormat) -> Vec<u8> {
+        encode_profile(self, format)
+    }
+}
+
+// In our modified map, we can perform 3 operations:
+// 1) Replace an indirect call with a func id
+// 2) Replace an indirect call with "unreachable"
+// 3) No-op
+// It names functions of the module being optimized, so it has no serialized form.
+#[derive(Clone, Debug)]
+pub struct MapValue {
+    pub f_id: Option<Vec<FunctionId>>,
+    pub f_bool: bool,
+}
+
+// Everything the host (and later runs of this tool) needs to know about the
+// instrumentation that was added to a binary.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Manifest {
+    pub callsites: Vec<CallsiteEntry>,
+    pub window: usize,
+    // Callsites whose slot count differs from `window` (--prior-profile)
+    #[serde(default)]
+    pub callsite_windows: BTreeMap<usize, usize>,
+    #[serde(default)]
+    pub trace: Option<TraceLayout>,
+    // Fingerprint of the original binary, see `fingerprint`
+    #[serde(default)]
+    pub fingerprint: Option<String>,
+    // Prepended to every export name we added (--export-prefix)
+    #[serde(default)]
+    pub export_prefix: String,
+    // Set when the slot globals are described by a descriptor table (--compact-exports)
+    #[serde(default)]
+    pub descriptors: Option<DescriptorLayout>,
+    // Set when the slots live in a dedicated memory (--slot-memory)
+    #[serde(default)]
+    pub slot_memory: Option<SlotMemoryLayout>,
+    // Set when the counters are snapshotted periodically (--snapshot-every)
+    #[serde(default)]
+    pub snapshots: Option<SnapshotLayout>,
+    // Set when the stubs report to the host instead of filling slots (--host-events)
+    #[serde(default)]
+    pub host_events: Option<HostEventAbi>,
+    // Set when a calling-context tree is built (--context-tree)
+    #[serde(default)]
+    pub context_tree: Option<ContextTreeLayout>,
+    // Set when only one chunk of the callsites was instrumented (--max-callsites)
+    #[serde(default)]
+    pub chunk: Option<CallsiteChunk>,
+    // Imported functions with a call counter (--import-counters)
+    #[serde(default)]
+    pub imports: Vec<ImportEntry>,
+    // Exported functions with an entry counter (--entry-counters)
+    #[serde(default)]
+    pub entries: Vec<EntryPoint>,
+    #[serde(default)]
+    pub counters: CounterLayout,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CallsiteEntry {
+    // The callsite index (profiling_global_{id}_*, and the key in Profile::map)
+    pub id: usize,
+    // Stable key for this callsite: "{function name}#{nth call_indirect in the function}"
+    pub key: String,
+    pub func: String,
+    pub func_index: usize,
+    // The only function this callsite can reach, when that's statically known
+    // (callsites::static_targets). No slot globals with --skip-static-callsites.
+    #[serde(default)]
+    pub static_target: Option<String>,
+    pub params: Vec<String>,
+    pub results: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TraceLayout {
+    pub memory_export: String,
+    pub cursor_export: String,
+    pub entries: u32,
+    pub record_size: u32,
+}
+
+/*
+ * A --max-callsites run: the callsites in prioritize_callsites order are cut
+ * into `rounds` chunks of `max_callsites`, and this binary instruments chunk
+ * `round`. Callsite ids don't depend on the chunk, so merging the profiles of
+ * every round gives the profile of the whole module.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CallsiteChunk {
+    pub max_callsites: usize,
+    pub round: usize,
+    pub rounds: usize,
+}
+
+// Where to find the slots of a --slot-memory binary, see slotmemory::decode_slot_memory
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SlotMemoryLayout {
+    pub memory_export: String,
+    pub version: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotLayout {
+    pub memory_export: String,
+    pub version: u32,
+    // Slowcalls between snapshots (or between asking the host's tick function)
+    pub every: u32,
+    // Snapshots the ring buffer holds
+    pub capacity: u32,
+    // Export names (without the prefix) of the counters each snapshot holds, in order
+    pub counters: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContextTreeLayout {
+    pub memory_export: String,
+    pub version: u32,
+    // Nodes the hash table holds
+    pub capacity: u32,
+    // Names of the functions the nodes refer to, by their number
+    pub functions: Vec<String>,
+}
+
+/*
+ * The function a --host-events binary imports and calls on every indirect
+ * call: module.name(i32 per param, in order) -> (), where `callsite` is the
+ * callsite id and `target` the table index being called.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostEventAbi {
+    pub module: String,
+    pub name: String,
+    pub version: u32,
+    pub params: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ImportEntry {
+    // The counter index (profiling_import_{id}, and the key in Profile::imports)
+    pub id: usize,
+    pub module: String,
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryPoint {
+    // The entry id (profiling_entry_{id}, the profiling_entry tag and the key in Profile::entries)
+    pub id: usize,
+    // The export's name
+    pub name: String,
+}
+
+/*
+ * How to read the profiling globals. Event counters (indirect, slowcalls,
+ * profiling_block_*, profiling_import_*) start at `init`, so the number of
+ * events is the dumped value minus `init`, and either wrap around or stick
+ * at i32::MAX (`mode`). Callsite slots hold a table index, `empty_slot` if
+ * the slot was never filled, or `overflow_slot` in every slot of a callsite
+ * that saw more targets than it had slots. With the lru `slot_policy` they
+ * never overflow: they're in most-recently-seen order, and
+ * profiling_distinct_{id} counts the targets that came in (see SlotPolicy).
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CounterLayout {
+    pub init: i32,
+    pub mode: CounterMode,
+    pub empty_slot: i32,
+    pub overflow_slot: i32,
+    #[serde(default)]
+    pub slot_policy: SlotPolicy,
+}
+
+impl Default for CounterLayout {
+    fn default() -> CounterLayout {
+        CounterLayout {
+            init: 0,
+            mode: CounterMode::Wrap,
+            empty_slot: EMPTY_SLOT,
+            overflow_slot: OVERFLOW_SLOT,
+            slot_policy: SlotPolicy::Overflow,
+        }
+    }
+}
+
+// Where to find the descriptor table, see descriptors::add_descriptor_table
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct DescriptorLayout {
+    pub count_export: String,
+    pub memory_export: String,
+    pub version: u32,
+}
@@ -0,0 +1,212 @@
+use crate::callsites::enumerate_callsites;
+use std::collections::{HashMap, HashSet};
+use walrus::ir::*;
+use walrus::*;
+
+// Names generate_stubs gives the stubs when instrumenting / optimizing
+pub const INSTRUMENT_STUB_PREFIX: &str = "indirect_stub_";
+pub const OPTIMIZE_STUB_PREFIX: &str = "indirect_call_stub_";
+
+fn is_stub(module: &Module, id: FunctionId, prefix: &str) -> bool {
+    match &module.funcs.get(id).name {
+        Some(name) => name.starts_with(prefix),
+        None => false,
+    }
+}
+
+fn signature(module: &Module, ty: TypeId) -> (Vec<ValType>, Vec<ValType>) {
+    let ty = module.types.get(ty);
+    (ty.params().to_vec(), ty.results().to_vec())
+}
+
+// Every instruction of a function, visiting sequences in the same order as the instrumentation pass
+pub fn instrs(func: &LocalFunction) -> Vec<&Instr> {
+    let mut instrs = vec![];
+    let mut seqs_to_process: Vec<InstrSeqId> = vec![func.entry_block()];
+    while let Some(current_seq) = seqs_to_process.pop() {
+        for (instr, _) in &func.block(current_seq).instrs {
+            match instr {
+                Instr::Block(b) => seqs_to_process.push(b.seq),
+                Instr::Loop(l) => seqs_to_process.push(l.seq),
+                Instr::IfElse(if_else) => {
+                    seqs_to_process.push(if_else.consequent);
+                    seqs_to_process.push(if_else.alternative);
+                }
+                _ => (),
+            }
+            instrs.push(instr);
+        }
+    }
+    instrs
+}
+
+/*
+ * Every call_indirect must have become `i32.const {callsite}; call
+ * indirect_stub_*`, each callsite id must appear exactly once, and the stub
+ * must take the original call_indirect's params plus (callsite, target).
+ * A call_indirect left behind outside the stubs means an insertion landed on
+ * the wrong instruction and the removal that followed deleted something else,
+ * unless it is one of the `skipped` callsites (entry and tiny functions).
+ */
+fn check_instrumented(
+    original: &Module,
+    output: &Module,
+    skipped: &HashSet<usize>,
+    errors: &mut Vec<String>,
+) {
+    // `original` is the input with the constant selectors already folded, see
+    // pipeline::run, so there's nothing left to fold
+    let callsites = enumerate_callsites(original, None);
+    let mut seen: HashMap<i32, usize> = HashMap::new();
+    // How many call_indirects each function is allowed to keep
+    let mut retained: HashMap<String, usize> = HashMap::new();
+    for idx in skipped {
+        let name = crate::report::func_name(original, callsites[*idx].func);
+        *retained.entry(name).or_insert(0) += 1;
+    }
+    for (id, func) in output.funcs.iter_local() {
+        if is_stub(output, id, INSTRUMENT_STUB_PREFIX) {
+            continue;
+        }
+        let name = crate::report::func_name(output, id);
+        let instrs = instrs(func);
+        for (pos, instr) in instrs.iter().enumerate() {
+            match instr {
+                Instr::CallIndirect(_) => match retained.get_mut(&name) {
+                    Some(n) if *n > 0 => *n -= 1,
+                    _ => errors.push(format!(
+                        "{}: call_indirect was not replaced by a stub call",
+                        name
+                    )),
+                },
+                Instr::Call(call) if is_stub(output, call.func, INSTRUMENT_STUB_PREFIX) => {
+                    let callsite = match pos.checked_sub(1).map(|prev| instrs[prev]) {
+                        Some(Instr::Const(Const {
+                            value: Value::I32(callsite),
+                        })) => *callsite,
+                        _ => {
+                            errors.push(format!("{}: stub call without a callsite id", name));
+                            continue;
+                        }
+                    };
+                    *seen.entry(callsite).or_insert(0) += 1;
+                    let original_callsite = match callsites.get(callsite as usize) {
+                        Some(c) if callsite >= 0 => c,
+                        _ => {
+                            errors.push(format!("{}: callsite id {} out of range", name, callsite));
+                            continue;
+                        }
+                    };
+                    let (mut params, results) = signature(original, original_callsite.ty);
+                    params.push(ValType::I32);
+                    params.push(ValType::I32);
+                    let stub_ty = output.funcs.get(call.func).ty();
+                    if signature(output, stub_ty) != (params, results) {
+                        errors.push(format!(
+                            "{}: callsite {} calls a stub whose type doesn't match the original call_indirect",
+                            name, callsite
+                        ));
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    for callsite in 0..callsites.len() as i32 {
+        if skipped.contains(&(callsite as usize)) {
+            if seen.contains_key(&callsite) {
+                errors.push(format!(
+                    "callsite {} was skipped but is instrumented",
+                    callsite
+                ));
+            }
+            continue;
+        }
+        match seen.get(&callsite) {
+            Some(1) => (),
+            Some(n) => errors.push(format!("callsite {} is instrumented {} times", callsite, n)),
+            None => errors.push(format!("callsite {} was not instrumented", callsite)),
+        }
+    }
+}
+
+/*
+ * Each devirtualization stub must take some callsite's call_indirect params
+ * plus the table index, and only call functions of that type. Together with
+ * the number of call_indirects left behind, this has to add up to at most the
+ * number of callsites we started with.
+ */
+fn check_optimized(original: &Module, output: &Module, errors: &mut Vec<String>) {
+    let callsites = enumerate_callsites(original, None);
+    let mut rewritten = 0;
+    for (id, func) in output.funcs.iter_local() {
+        let name = crate::report::func_name(output, id);
+        if is_stub(output, id, OPTIMIZE_STUB_PREFIX) {
+            let (mut params, results) = signature(output, func.ty());
+            if params.pop() != Some(ValType::I32) {
+                errors.push(format!("{}: missing the trailing table index param", name));
+            }
+            for instr in instrs(func) {
+                if let Instr::Call(call) = instr {
+                    let target_ty = output.funcs.get(call.func).ty();
+                    if signature(output, target_ty) != (params.clone(), results.clone()) {
+                        errors.push(format!(
+                            "{}: calls {} which doesn't match the original call_indirect type",
+                            name,
+                            crate::report::func_name(output, call.func)
+                        ));
+                    }
+                }
+            }
+            continue;
+        }
+        for instr in instrs(func) {
+            match instr {
+                Instr::CallIndirect(_) => rewritten += 1,
+                Instr::Call(call) if is_stub(output, call.func, OPTIMIZE_STUB_PREFIX) => {
+                    rewritten += 1
+                }
+                _ => (),
+            }
+        }
+    }
+    if rewritten > callsites.len() {
+        errors.push(format!(
+            "found {} indirect/devirtualized calls but the original only had {} callsites",
+            rewritten,
+            callsites.len()
+        ));
+    }
+}
+
+/*
+ * Re-parse what we emitted (which also runs the validator) and check the
+ * callsite rewriting invariants against the original module. Fails with
+ * every violation found, so a broken binary never silently makes it out.
+ */
+pub fn self_check(
+    original: &Module,
+    output: &[u8],
+    is_opt: bool,
+    skipped: &HashSet<usize>,
+) -> Result<(), String> {
+    let output = match Module::from_buffer(output) {
+        Ok(module) => module,
+        Err(e) => return Err(format!("emitted module doesn't parse: {:?}", e)),
+    };
+    let mut errors = vec![];
+    if is_opt {
+        check_optimized(original, &output, &mut errors);
+    } else {
+        check_instrumented(original, &output, skipped, &mut errors);
+    }
+    if !errors.is_empty() {
+        return Err(format!(
+            "{} error(s):\n{}",
+            errors.len(),
+            errors.join("\n")
+        ));
+    }
+    println!("self-check passed");
+    Ok(())
+}
@@ -0,0 +1,177 @@
+use crate::blockcounters::enumerate_blocks;
+use crate::branches::enumerate_branches;
+use crate::callsites::enumerate_callsites;
+use crate::compression::{decompress, Compression};
+use crate::merge::{merge_into, DecayingProfile};
+use crate::profilemap::write_profile;
+use crate::Profile;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tiny_http::{Method, Request, Response, Server};
+use walrus::*;
+
+// What an instance POSTs to the aggregator: a msgpack encoded envelope,
+// optionally gzip/zstd compressed.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope {
+    // manifest::fingerprint of the original binary the profile was collected on
+    pub fingerprint: String,
+    // Unix time (seconds) the profile was collected at; defaults to when we receive it
+    #[serde(default)]
+    pub timestamp: Option<u64>,
+    pub profile: Profile,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// How many of each kind of counter the original binary has
+struct Shape {
+    callsites: usize,
+    blocks: usize,
+    branches: usize,
+}
+
+fn validate(envelope: &Envelope, fingerprint: &str, shape: &Shape) -> Result<(), String> {
+    if envelope.fingerprint != fingerprint {
+        return Err(format!(
+            "fingerprint mismatch: expected {}, got {}",
+            fingerprint, envelope.fingerprint
+        ));
+    }
+    let profile = &envelope.profile;
+    if let Some(idx) = profile.map.keys().find(|idx| **idx >= shape.callsites) {
+        return Err(format!(
+            "callsite {} out of range ({} callsites)",
+            idx, shape.callsites
+        ));
+    }
+    if let Some(idx) = profile.blocks.keys().find(|idx| **idx >= shape.blocks) {
+        return Err(format!(
+            "block {} out of range ({} blocks)",
+            idx, shape.blocks
+        ));
+    }
+    if let Some(idx) = profile.branches.keys().find(|idx| **idx >= shape.branches) {
+        return Err(format!(
+            "branch {} out of range ({} branches)",
+            idx, shape.branches
+        ));
+    }
+    Ok(())
+}
+
+// The envelope in a POSTed body, or why it couldn't be read
+fn read_envelope(request: &mut Request) -> Result<Envelope, String> {
+    let mut body = vec![];
+    request
+        .as_reader()
+        .read_to_end(&mut body)
+        .map_err(|e| format!("can't read the body: {}", e))?;
+    let body = decompress(body)?;
+    rmp_serde::decode::from_read(&body[..]).map_err(|e| format!("malformed envelope: {}", e))
+}
+
+/*
+ * Collection backend for fleet PGO: accept POSTed envelopes, check they were
+ * collected on the binary we were started with, merge them into a running
+ * aggregate, and write the aggregate to `output` every `interval` (only if
+ * something new arrived). Runs until killed; a request that can't be read
+ * gets a 400 and the server keeps going. Fails if it can't listen.
+ *
+ * With a `half_life` (seconds) each profile's weight halves every half_life
+ * seconds after its timestamp, and targets whose weight decays to nothing are
+ * dropped from the aggregate. `table` numbers the callsites, see
+ * enumerate_callsites.
+ */
+pub fn serve(
+    module: &Module,
+    table: Option<TableId>,
+    fingerprint: &str,
+    listen: &str,
+    output: &str,
+    interval: Duration,
+    half_life: Option<f64>,
+) -> Result<(), String> {
+    let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let shape = Shape {
+        callsites: enumerate_callsites(module, table).len(),
+        blocks: enumerate_blocks(module, &original_funcs).len(),
+        branches: enumerate_branches(module, &original_funcs).len(),
+    };
+
+    let server = Server::http(listen).map_err(|e| format!("can't listen on {}: {}", listen, e))?;
+    println!("Listening on {} (fingerprint {})", listen, fingerprint);
+
+    let mut aggregate = Profile::default();
+    let mut decaying = half_life.map(|half_life| DecayingProfile::new(half_life, unix_now()));
+    let mut received = 0;
+    let mut dirty = false;
+    let mut last_write = Instant::now();
+    loop {
+        let timeout = interval.saturating_sub(last_write.elapsed());
+        let request = match server.recv_timeout(timeout) {
+            Ok(request) => request,
+            Err(e) => {
+                println!("failed to accept a request: {}", e);
+                None
+            }
+        };
+        if let Some(mut request) = request {
+            let response = if *request.method() != Method::Post {
+                Response::from_string("POST a profile envelope\n").with_status_code(405)
+            } else {
+                match read_envelope(&mut request) {
+                    Err(e) => {
+                        println!("rejected profile from {:?}: {}", request.remote_addr(), e);
+                        Response::from_string(format!("{}\n", e)).with_status_code(400)
+                    }
+                    Ok(envelope) => match validate(&envelope, fingerprint, &shape) {
+                        Err(e) => {
+                            println!("rejected profile from {:?}: {}", request.remote_addr(), e);
+                            Response::from_string(format!("{}\n", e)).with_status_code(409)
+                        }
+                        Ok(()) => {
+                            let now = unix_now();
+                            match decaying.as_mut() {
+                                Some(decaying) => decaying.add(
+                                    &envelope.profile,
+                                    envelope.timestamp.unwrap_or(now),
+                                    now,
+                                ),
+                                None => merge_into(&mut aggregate, &envelope.profile),
+                            }
+                            received += 1;
+                            dirty = true;
+                            Response::from_string(format!("merged ({} profiles)\n", received))
+                        }
+                    },
+                }
+            };
+            let _ = request.respond(response);
+        }
+
+        if last_write.elapsed() >= interval {
+            if let Some(decaying) = decaying.as_mut() {
+                // Decay changes the aggregate even when nothing new arrives
+                aggregate = decaying.snapshot(unix_now());
+                dirty |= received > 0;
+            }
+            if dirty {
+                write_profile(output, &aggregate, Compression::None);
+                println!(
+                    "Wrote aggregate of {} profiles ({} callsites) to {}",
+                    received,
+                    aggregate.map.len(),
+                    output
+                );
+                dirty = false;
+            }
+            last_write = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use crate::counters::{index_const, slot_value, CounterPolicy};
+use crate::manifest::SlotMemoryLayout;
+use std::collections::HashMap;
+use walrus::ir::*;
+use walrus::*;
+
+pub const SLOT_MEMORY_VERSION: u32 = 1;
+// version, callsite count, stride
+const HEADER_WORDS: usize = 3;
+
+pub struct SlotMemory {
+    pub memory: MemoryId,
+    pub callsites: usize,
+    // Slots reserved per callsite (the widest window)
+    pub stride: usize,
+}
+
+impl SlotMemory {
+    fn windows_offset(&self) -> u32 {
+        (HEADER_WORDS * 4) as u32
+    }
+
+    fn slots_offset(&self) -> u32 {
+        ((HEADER_WORDS + self.callsites) * 4) as u32
+    }
+}
+
+/*
+ * `--slot-memory`: keep the callsite slots in a dedicated memory rather than
+ * in globals, so a stub can index straight to its callsite's slots and its
+ * size no longer grows with the number of callsites. All little-endian
+ * 32-bit words:
+ *
+ *   version, callsite count, stride,
+ *   the window of each callsite (0 if it isn't instrumented),
+ *   then `stride` slots per callsite.
+ *
+ * A slot holds the profile value + 1, so the zero-initialized memory starts
+ * out empty (0 is -1) and an overflowed callsite's slots read -1 (-2).
+ */
+pub fn add_slot_memory(
+    module: &mut Module,
+    windows: &[usize],
+    export_prefix: &str,
+) -> (SlotMemory, SlotMemoryLayout) {
+    let stride = windows.iter().cloned().max().unwrap_or(0);
+    let slots = SlotMemory {
+        memory: module.memories.add_local(false, 0, None),
+        callsites: windows.len(),
+        stride,
+    };
+    let bytes = (HEADER_WORDS + windows.len() * (1 + stride)) as u64 * 4;
+    let pages = std::cmp::max(1, (bytes + 65535) / 65536) as u32;
+    let memory = module.memories.get_mut(slots.memory);
+    memory.initial = pages;
+    memory.maximum = Some(pages);
+
+    let mut header = vec![SLOT_MEMORY_VERSION, windows.len() as u32, stride as u32];
+    header.extend(windows.iter().map(|w| *w as u32));
+    module.data.add(
+        DataKind::Active(ActiveData {
+            memory: slots.memory,
+            location: ActiveDataLocation::Absolute(0),
+        }),
+        header.iter().flat_map(|word| word.to_le_bytes()).collect(),
+    );
+    let layout = SlotMemoryLayout {
+        memory_export: format!("{}profiling_slots", export_prefix),
+        version: SLOT_MEMORY_VERSION,
+    };
+    module.exports.add(&layout.memory_export, slots.memory);
+    (slots, layout)
+}
+
+/*
+ * Prepend the slot update to an indirect call stub. The same code serves
+ * every callsite:
+ *
+ *   indirect += 1
+ *   base = slots + callsite * stride * 4; n = windows[callsite]
+ *   for i in 0..n: if mem[base + 4i] is empty or target: store target, done
+ *   no free slot: mark all n slots overflowed
+ */
+pub fn record_slots(
+    module: &mut Module,
+    stub: FunctionId,
+    slots: &SlotMemory,
+    indirect: GlobalId,
+    policy: &CounterPolicy,
+) {
+    let base = module.locals.add(ValType::I32);
+    let window = module.locals.add(ValType::I32);
+    let i = module.locals.add(ValType::I32);
+    let addr = module.locals.add(ValType::I32);
+    let slot = module.locals.add(ValType::I32);
+    let func = module.funcs.get_mut(stub).kind.unwrap_local_mut();
+    let args = func.args.clone();
+    let callsite = args[args.len() - 1];
+    let target = args[args.len() - 2];
+    let memory = slots.memory;
+    let load = LoadKind::I32 { atomic: false };
+    let store = StoreKind::I32 { atomic: false };
+    let arg = |offset| MemArg { align: 4, offset };
+    let mut func_body = func.builder_mut().func_body();
+    func_body.block_at(0, None, |block| {
+        for instr in policy.increment(indirect) {
+            block.instr(instr);
+        }
+        block
+            .local_get(callsite)
+            .i32_const((slots.stride * 4) as i32)
+            .binop(BinaryOp::I32Mul)
+            .i32_const(slots.slots_offset() as i32)
+            .binop(BinaryOp::I32Add)
+            .local_set(base)
+            .local_get(callsite)
+            .i32_const(2)
+            .binop(BinaryOp::I32Shl)
+            .load(memory, load, arg(slots.windows_offset()))
+            .local_set(window)
+            .i32_const(0)
+            .local_set(i);
+        block.block(None, |done| {
+            let done_id = done.id();
+            done.block(None, |full| {
+                let full_id = full.id();
+                full.loop_(None, |scan| {
+                    let scan_id = scan.id();
+                    scan.local_get(i)
+                        .local_get(window)
+                        .binop(BinaryOp::I32GeU)
+                        .br_if(full_id)
+                        .local_get(base)
+                        .local_get(i)
+                        .i32_const(2)
+                        .binop(BinaryOp::I32Shl)
+                        .binop(BinaryOp::I32Add)
+                        .local_tee(addr)
+                        .load(memory, load, arg(0))
+                        .local_tee(slot)
+                        .unop(UnaryOp::I32Eqz)
+                        .local_get(slot)
+                        .local_get(target)
+                        .i32_const(1)
+                        .binop(BinaryOp::I32Add)
+                        .binop(BinaryOp::I32Eq)
+                        .binop(BinaryOp::I32Or)
+                        .if_else(
+                            None,
+                            |then| {
+                                then.local_get(addr)
+                                    .local_get(target)
+                                    .i32_const(1)
+                                    .binop(BinaryOp::I32Add)
+                                    .store(memory, store, arg(0))
+                                    .br(done_id);
+                            },
+                            |_| {},
+                        )
+                        .local_get(i)
+                        .i32_const(1)
+                        .binop(BinaryOp::I32Add)
+                        .local_set(i)
+                        .br(scan_id);
+                });
+            });
+            // More targets than slots
+            done.i32_const(0).local_set(i).loop_(None, |mark| {
+                let mark_id = mark.id();
+                mark.local_get(i)
+                    .local_get(window)
+                    .binop(BinaryOp::I32LtU)
+                    .if_else(
+                        None,
+                        |then| {
+                            then.local_get(base)
+                                .local_get(i)
+                                .i32_const(2)
+                                .binop(BinaryOp::I32Shl)
+                                .binop(BinaryOp::I32Add)
+                                .i32_const(-1)
+                                .store(memory, store, arg(0))
+                                .local_get(i)
+                                .i32_const(1)
+                                .binop(BinaryOp::I32Add)
+                                .local_set(i)
+                                .br(mark_id);
+                        },
+                        |_| {},
+                    );
+            });
+        });
+    });
+}
+
+fn word(buf: &[u8], idx: usize) -> u32 {
+    let bytes = &buf[idx * 4..idx * 4 + 4];
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// The callsite slots in a dump of the profiling_slots memory
+pub fn decode_slot_memory(buf: &[u8]) -> HashMap<usize, Vec<i64>> {
+    assert_eq!(
+        word(buf, 0),
+        SLOT_MEMORY_VERSION,
+        "unsupported slot memory version"
+    );
+    let callsites = word(buf, 1) as usize;
+    let stride = word(buf, 2) as usize;
+    let mut map = HashMap::new();
+    for idx in 0..callsites {
+        let window = word(buf, HEADER_WORDS + idx) as usize;
+        if window == 0 {
+            continue;
+        }
+        let first = HEADER_WORDS + callsites + idx * stride;
+        let slots = (0..window)
+            .map(|slot| slot_value((word(buf, first + slot) as i32).wrapping_sub(1)))
+            .collect();
+        map.insert(idx, slots);
+    }
+    map
+}
+
+// The memory image decode_slot_memory reads `map` back from
+pub fn encode_slot_memory(map: &HashMap<usize, Vec<i64>>) -> Vec<u8> {
+    let callsites = map.keys().max().map(|idx| idx + 1).unwrap_or(0);
+    let stride = map.values().map(|slots| slots.len()).max().unwrap_or(0);
+    let mut words = vec![0u32; HEADER_WORDS + callsites * (1 + stride)];
+    words[0] = SLOT_MEMORY_VERSION;
+    words[1] = callsites as u32;
+    words[2] = stride as u32;
+    for (idx, slots) in map {
+        words[HEADER_WORDS + idx] = slots.len() as u32;
+        for (slot, value) in slots.iter().enumerate() {
+            // The i32 the stub would have stored
+            let raw = index_const(*value).unwrap_or(*value as i32);
+            words[HEADER_WORDS + callsites + idx * stride + slot] = raw.wrapping_add(1) as u32;
+        }
+    }
+    words.iter().flat_map(|word| word.to_le_bytes()).collect()
+}
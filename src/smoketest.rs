@@ -0,0 +1,54 @@
+use std::process::Command;
+
+// Lines of the failed command's output shown in the error
+const OUTPUT_LINES: usize = 20;
+
+// `path` as one shell word
+fn shell_quote(path: &str) -> String {
+    if cfg!(windows) {
+        format!("\"{}\"", path)
+    } else {
+        format!("'{}'", path.replace('\'', "'\\''"))
+    }
+}
+
+// The last OUTPUT_LINES lines of `output`
+fn tail(output: &[u8]) -> String {
+    let text = String::from_utf8_lossy(output);
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(OUTPUT_LINES)..].join("\n")
+}
+
+/*
+ * `--smoke-test CMD`: run CMD through the shell with every `{}` replaced by
+ * the emitted binary's path (appended when there is none), e.g.
+ * `wasmtime run {}`, and fail unless it exits successfully. An
+ * instrumentation bug that makes the module trap on its first call or fail
+ * validation shows up here instead of on a fleet.
+ */
+pub fn run_smoke_test(command: &str, path: &str) -> Result<(), String> {
+    let command = if command.contains("{}") {
+        command.replace("{}", &shell_quote(path))
+    } else {
+        format!("{} {}", command, shell_quote(path))
+    };
+    let output = if cfg!(windows) {
+        Command::new("cmd").arg("/C").arg(&command).output()
+    } else {
+        Command::new("sh").arg("-c").arg(&command).output()
+    }
+    .map_err(|e| format!("--smoke-test: failed to run `{}`: {}", command, e))?;
+    if !output.status.success() {
+        let mut message = format!("--smoke-test: `{}` exited with {}", command, output.status);
+        for stream in [&output.stdout, &output.stderr] {
+            let text = tail(stream);
+            if !text.is_empty() {
+                message.push('\n');
+                message.push_str(&text);
+            }
+        }
+        return Err(message);
+    }
+    println!("Smoke test passed: {}", command);
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use crate::formats::decode_globals;
+use crate::manifest::SnapshotLayout;
+use crate::Profile;
+use walrus::ir::*;
+use walrus::*;
+
+pub const SNAPSHOT_VERSION: u32 = 1;
+// version, counters per snapshot, capacity, snapshots taken
+const HEADER_WORDS: u32 = 4;
+const TAKEN_OFFSET: u32 = 12;
+pub const SNAPSHOT_STUB_NAME: &str = "snapshot_stub";
+
+// The exported i32 counters to copy, as (name without the export prefix, global)
+fn snapshot_globals(module: &Module, export_prefix: &str) -> Vec<(String, GlobalId)> {
+    module
+        .exports
+        .iter()
+        .filter_map(|export| {
+            let name = export.name.strip_prefix(export_prefix)?;
+            match export.item {
+                ExportItem::Global(global)
+                    if name.starts_with("profiling_")
+                        || name == "indirect"
+                        || name == "slowcalls" =>
+                {
+                    Some((name.to_string(), global))
+                }
+                _ => None,
+            }
+        })
+        .filter(|(_, global)| {
+            let global = module.globals.get(*global);
+            global.ty == ValType::I32 && global.mutable
+        })
+        .collect()
+}
+
+/*
+ * `--snapshot-every N`: every N slowcalls, copy the exported counters into
+ * a ring buffer of snapshots in a dedicated memory ({prefix}profiling_snapshots),
+ * so the host can tell warm-up from steady state without a full --trace.
+ * All little-endian 32-bit words:
+ *
+ *   version, counters per snapshot, capacity, snapshots taken,
+ *   then `capacity` snapshots, each holding the counters in layout order.
+ *
+ * Snapshot n goes to slot n % capacity, so once more than `capacity` were
+ * taken only the latest ones survive. With a `tick` import (module, name)
+ * the host decides instead: every N slowcalls its () -> i32 function is
+ * called and a snapshot is taken when it returns non-zero. The import is
+ * added last, so it shifts the indices of the module's own functions.
+ *
+ * Must run after every pass that adds counters, and only the slot and
+ * counter globals are copied (not the --slot-memory or --trace memories).
+ */
+pub fn add_snapshots(
+    module: &mut Module,
+    every: u32,
+    capacity: u32,
+    tick: Option<(&str, &str)>,
+    export_prefix: &str,
+) -> SnapshotLayout {
+    let globals = snapshot_globals(module, export_prefix);
+    let bytes = (HEADER_WORDS as u64 + capacity as u64 * globals.len() as u64) * 4;
+    let pages = std::cmp::max(1, (bytes + 65535) / 65536) as u32;
+    let memory = module.memories.add_local(false, pages, Some(pages));
+    let header = [SNAPSHOT_VERSION, globals.len() as u32, capacity, 0];
+    module.data.add(
+        DataKind::Active(ActiveData {
+            memory,
+            location: ActiveDataLocation::Absolute(0),
+        }),
+        header.iter().flat_map(|word| word.to_le_bytes()).collect(),
+    );
+    let layout = SnapshotLayout {
+        memory_export: format!("{}profiling_snapshots", export_prefix),
+        version: SNAPSHOT_VERSION,
+        every,
+        capacity,
+        counters: globals.iter().map(|(name, _)| name.clone()).collect(),
+    };
+    module.exports.add(&layout.memory_export, memory);
+
+    let countdown = module.globals.add_local(
+        ValType::I32,
+        true,
+        InitExpr::Value(Value::I32(every as i32)),
+    );
+    let tick = tick.map(|(module_name, name)| {
+        let ty = module.types.add(&[], &[ValType::I32]);
+        module.add_import_func(module_name, name, ty).0
+    });
+    let base = module.locals.add(ValType::I32);
+    let stride = globals.len() as u32 * 4;
+    let load = LoadKind::I32 { atomic: false };
+    let store = StoreKind::I32 { atomic: false };
+    let arg = |offset| MemArg { align: 4, offset };
+
+    let mut stub = FunctionBuilder::new(&mut module.types, &[], &[]);
+    stub.name(SNAPSHOT_STUB_NAME.to_string());
+    let mut body = stub.func_body();
+    body.global_get(countdown)
+        .i32_const(1)
+        .binop(BinaryOp::I32Sub)
+        .global_set(countdown);
+    body.block(None, |done| {
+        let done_id = done.id();
+        done.global_get(countdown).br_if(done_id);
+        done.i32_const(every as i32).global_set(countdown);
+        if let Some(tick) = tick {
+            done.call(tick).unop(UnaryOp::I32Eqz).br_if(done_id);
+        }
+        // base = header + (taken % capacity) * stride
+        done.i32_const(0)
+            .load(memory, load, arg(TAKEN_OFFSET))
+            .i32_const(capacity as i32)
+            .binop(BinaryOp::I32RemU)
+            .i32_const(stride as i32)
+            .binop(BinaryOp::I32Mul)
+            .i32_const((HEADER_WORDS * 4) as i32)
+            .binop(BinaryOp::I32Add)
+            .local_set(base);
+        for (i, (_, global)) in globals.iter().enumerate() {
+            done.local_get(base)
+                .global_get(*global)
+                .store(memory, store, arg(i as u32 * 4));
+        }
+        done.i32_const(0)
+            .i32_const(0)
+            .load(memory, load, arg(TAKEN_OFFSET))
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .store(memory, store, arg(TAKEN_OFFSET));
+    });
+    let stub = stub.finish(vec![], &mut module.funcs);
+
+    // Every slowcall stub counts down to the next snapshot
+    let slowcall_stubs: Vec<FunctionId> = module
+        .funcs
+        .iter()
+        .filter(|func| {
+            func.name
+                .as_deref()
+                .map_or(false, |name| name.starts_with("slowcall_stub_"))
+        })
+        .map(|func| func.id())
+        .collect();
+    for id in &slowcall_stubs {
+        let func = module.funcs.get_mut(*id).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        func.block_mut(entry)
+            .instrs
+            .insert(0, (Instr::Call(Call { func: stub }), InstrLocId::default()));
+    }
+    if slowcall_stubs.is_empty() {
+        println!("warning: no slowcalls are instrumented, so no snapshot will ever be taken");
+    }
+    println!(
+        "Snapshotting {} counters every {} slowcalls ({} snapshots kept)",
+        layout.counters.len(),
+        every,
+        capacity
+    );
+    layout
+}
+
+fn word(buf: &[u8], idx: usize) -> u32 {
+    u32::from_le_bytes(buf[idx * 4..idx * 4 + 4].try_into().unwrap())
+}
+
+/*
+ * The snapshots in a dump of the profiling_snapshots memory, oldest first,
+ * as (snapshot number, profile). Each profile holds the counters as they
+ * were when it was taken, i.e. everything counted since the start.
+ */
+pub fn decode_snapshots(buf: &[u8], layout: &SnapshotLayout) -> Vec<(u32, Profile)> {
+    assert_eq!(word(buf, 0), SNAPSHOT_VERSION, "unknown snapshot version");
+    let counters = word(buf, 1) as usize;
+    let capacity = word(buf, 2);
+    let taken = word(buf, 3);
+    let first = taken.saturating_sub(capacity);
+    (first..taken)
+        .map(|n| {
+            let base = HEADER_WORDS as usize + (n % capacity) as usize * counters;
+            let dump: String = layout
+                .counters
+                .iter()
+                .enumerate()
+                .map(|(i, name)| format!("{}={}\n", name, word(buf, base + i) as i32))
+                .collect();
+            (n, decode_globals(&dump, ""))
+        })
+        .collect()
+}
+
+/*
+ * How the profile evolved across snapshots: slowcalls since the previous
+ * one and how many callsite targets were known by then. Once the targets
+ * stop growing the program has warmed up, and the steady-state profile is
+ * the last snapshot minus the one where that happened.
+ */
+pub fn snapshot_report(snapshots: &[(u32, Profile)]) {
+    println!("== Snapshots ==");
+    println!(
+        "{:>8} {:>12} {:>10} {:>12}",
+        "snapshot", "slowcalls", "targets", "new targets"
+    );
+    let mut previous: Option<&Profile> = None;
+    for (n, profile) in snapshots {
+        let targets = |profile: &Profile| -> usize {
+            profile
+                .map
+                .values()
+                .map(|slots| slots.iter().filter(|slot| **slot >= 0).count())
+                .sum()
+        };
+        let slowcalls = profile.slowcalls.unwrap_or(0) as i64;
+        let (calls, new) = match previous {
+            Some(previous) => (
+                slowcalls - previous.slowcalls.unwrap_or(0) as i64,
+                targets(profile) as i64 - targets(previous) as i64,
+            ),
+            None => (slowcalls, targets(profile) as i64),
+        };
+        println!(
+            "{:>8} {:>12} {:>10} {:>12}",
+            n,
+            calls,
+            targets(profile),
+            new
+        );
+        previous = Some(profile);
+    }
+}
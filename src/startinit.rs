@@ -0,0 +1,159 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use walrus::ir::*;
+use walrus::*;
+
+pub const INIT_FUNCTION_NAME: &str = "profiling_init";
+
+// How the profiling state we add gets its initial values
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum InitMode {
+    // Global initializers and active data segments, set up at instantiation
+    InitExpr,
+    // An init function run as the start function, before the module's own
+    StartChain,
+}
+
+pub const INIT_MODES: &[&str] = &["init-expr", "start-chain"];
+
+impl InitMode {
+    pub fn from_name(name: &str) -> InitMode {
+        match name {
+            "init-expr" => InitMode::InitExpr,
+            "start-chain" => InitMode::StartChain,
+            _ => panic!("unknown init mode: {}", name),
+        }
+    }
+}
+
+// Store `bytes` at `offset` of `memory`, a word at a time, skipping the zero words
+fn store_bytes(body: &mut InstrSeqBuilder, memory: MemoryId, offset: u32, bytes: &[u8]) {
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let (width, store) = match bytes.len() - pos {
+            n if n >= 8 => (8, StoreKind::I64 { atomic: false }),
+            n if n >= 4 => (4, StoreKind::I32 { atomic: false }),
+            _ => (1, StoreKind::I32_8 { atomic: false }),
+        };
+        let chunk = &bytes[pos..pos + width];
+        if chunk.iter().any(|b| *b != 0) {
+            let mut word = [0u8; 8];
+            word[..width].copy_from_slice(chunk);
+            let value = i64::from_le_bytes(word);
+            body.i32_const(0);
+            match width {
+                8 => body.i64_const(value),
+                _ => body.i32_const(value as i32),
+            };
+            body.store(
+                memory,
+                store,
+                MemArg {
+                    align: width as u32,
+                    offset: offset + pos as u32,
+                },
+            );
+        }
+        pos += width;
+    }
+}
+
+/*
+ * `--init-mode start-chain`: give the profiling state the passes added its
+ * initial values from code instead of from initializers, for engines that
+ * reset or share globals and memories between runs without instantiating
+ * the module again, and for whatever runtime initialization the state needs
+ * next. The added mutable globals start at 0 and the data segments written
+ * into the added memories are dropped; an init function (profiling_init)
+ * sets both, then calls the module's own start function, and becomes the
+ * start function in its place. `input_globals` and `input_memories` are the
+ * module's own, which are left as they are. Runs after every other pass.
+ */
+pub fn chain_start_init(
+    module: &mut Module,
+    input_globals: &HashSet<GlobalId>,
+    input_memories: &HashSet<MemoryId>,
+) -> FunctionId {
+    let added: Vec<GlobalId> = module
+        .globals
+        .iter()
+        .filter(|global| global.mutable && !input_globals.contains(&global.id()))
+        .map(|global| global.id())
+        .collect();
+    let mut values: Vec<(GlobalId, Value)> = vec![];
+    for id in added {
+        if let GlobalKind::Local(InitExpr::Value(value)) = &mut module.globals.get_mut(id).kind {
+            let zero = match *value {
+                Value::I32(v) if v != 0 => Value::I32(0),
+                Value::I64(v) if v != 0 => Value::I64(0),
+                Value::F32(v) if v.to_bits() != 0 => Value::F32(0.0),
+                Value::F64(v) if v.to_bits() != 0 => Value::F64(0.0),
+                Value::V128(v) if v != 0 => Value::V128(0),
+                // Already 0, as it would be set to
+                _ => continue,
+            };
+            values.push((id, std::mem::replace(value, zero)));
+        }
+    }
+    let segments: Vec<(DataId, MemoryId, u32, Vec<u8>)> = module
+        .data
+        .iter()
+        .filter_map(|data| match data.kind {
+            DataKind::Active(ActiveData {
+                memory,
+                location: ActiveDataLocation::Absolute(offset),
+            }) if !input_memories.contains(&memory) => {
+                Some((data.id(), memory, offset, data.value.clone()))
+            }
+            _ => None,
+        })
+        .collect();
+
+    let mut init = FunctionBuilder::new(&mut module.types, &[], &[]);
+    init.name(INIT_FUNCTION_NAME.to_string());
+    let mut body = init.func_body();
+    for (global, value) in &values {
+        body.const_(*value).global_set(*global);
+    }
+    for (data, memory, offset, bytes) in &segments {
+        store_bytes(&mut body, *memory, *offset, bytes);
+        module.memories.get_mut(*memory).data_segments.remove(data);
+    }
+    if let Some(start) = module.start {
+        body.call(start);
+    }
+    let init = init.finish(vec![], &mut module.funcs);
+    for (data, _, _, _) in &segments {
+        module.data.delete(*data);
+    }
+    module.start = Some(init);
+    println!(
+        "Initializing {} globals and {} data segments in {}",
+        values.len(),
+        segments.len(),
+        INIT_FUNCTION_NAME
+    );
+    init
+}
+
+/*
+ * Undo chain_start_init: the module's own start function (the one the init
+ * function ends by calling, if any) is the start function again. The state
+ * it initialized goes with the rest of the instrumentation.
+ */
+pub fn unchain_start_init(module: &mut Module) {
+    let init = match module.funcs.by_name(INIT_FUNCTION_NAME) {
+        Some(init) => init,
+        None => return,
+    };
+    let func = module.funcs.get(init).kind.unwrap_local();
+    let original = match func.block(func.entry_block()).instrs.last() {
+        Some((Instr::Call(call), _)) => Some(call.func),
+        _ => None,
+    };
+    if module.start == Some(init) {
+        module.start = original;
+    }
+    module.funcs.delete(init);
+}
@@ -0,0 +1,282 @@
+use crate::manifest::MANIFEST_SECTION;
+use crate::meta::{self, tool_runs};
+use crate::selfcheck::{instrs, INSTRUMENT_STUB_PREFIX};
+use crate::snapshots::SNAPSHOT_STUB_NAME;
+use crate::startinit::unchain_start_init;
+use std::collections::{HashMap, HashSet};
+use walrus::ir::*;
+use walrus::*;
+
+// Names of the forwarding stubs the other instrumentation passes add
+const FORWARDING_STUB_PREFIXES: &[&str] = &["slowcall_stub_", "import_stub_", "entry_stub_"];
+const MEMORY_GROW_STUB_PREFIX: &str = "memory_grow_stub_";
+// Counters inserted into the original functions' own code, which we can't take back out
+const INLINE_COUNTER_EXPORTS: &[&str] = &[
+    "profiling_block_",
+    "profiling_branch_",
+    "profiling_br_table_",
+    "profiling_value_",
+    "profiling_loop_",
+    "profiling_access_",
+    "profiling_data_",
+    "profiling_context_tree",
+];
+
+fn has_prefix(module: &Module, id: FunctionId, prefix: &str) -> bool {
+    match &module.funcs.get(id).name {
+        Some(name) => name.starts_with(prefix),
+        None => false,
+    }
+}
+
+// The export prefix of the instrumenting run that produced `module` (if recorded)
+pub fn export_prefix(module: &Module) -> String {
+    match tool_runs(module).last() {
+        Some(run) if run.mode == "instrument" => run.options.export_prefix.clone(),
+        _ => String::new(),
+    }
+}
+
+/*
+ * Whether `module` is the output of instrument mode: the last run recorded
+ * in its meta section instrumented it, or (for binaries whose custom
+ * sections were stripped) it still has the callsite stubs.
+ */
+pub fn is_instrumented(module: &Module) -> bool {
+    if let Some(run) = tool_runs(module).last() {
+        return run.mode == "instrument";
+    }
+    module
+        .funcs
+        .iter()
+        .any(|f| has_prefix(module, f.id(), INSTRUMENT_STUB_PREFIX))
+}
+
+// What each kind of stub stands in for
+#[derive(Default)]
+struct Stubs {
+    // callsite stub ==> the call_indirect it forwards to
+    indirect: HashMap<FunctionId, (TypeId, TableId)>,
+    // slowcall / import / entry stub ==> the function it calls
+    calls: HashMap<FunctionId, FunctionId>,
+    // memory growth stub ==> its memory
+    grows: HashMap<FunctionId, MemoryId>,
+    // The snapshot stub
+    snapshot: Option<FunctionId>,
+    // Host functions only the stubs call (the snapshot tick, the host event callback)
+    host: HashSet<FunctionId>,
+}
+
+impl Stubs {
+    fn all(&self) -> HashSet<FunctionId> {
+        let mut all: HashSet<FunctionId> = self.indirect.keys().cloned().collect();
+        all.extend(self.calls.keys());
+        all.extend(self.grows.keys());
+        all.extend(self.snapshot);
+        all
+    }
+}
+
+fn find_stubs(module: &Module) -> Stubs {
+    let mut stubs = Stubs::default();
+    for (id, func) in module.funcs.iter_local() {
+        if has_prefix(module, id, SNAPSHOT_STUB_NAME) {
+            stubs.snapshot = Some(id);
+        }
+        for instr in instrs(func) {
+            match instr {
+                Instr::CallIndirect(call) if has_prefix(module, id, INSTRUMENT_STUB_PREFIX) => {
+                    stubs.indirect.insert(id, (call.ty, call.table));
+                }
+                Instr::Call(call)
+                    if FORWARDING_STUB_PREFIXES
+                        .iter()
+                        .any(|prefix| has_prefix(module, id, prefix)) =>
+                {
+                    stubs.calls.insert(id, call.func);
+                }
+                Instr::MemoryGrow(grow) if has_prefix(module, id, MEMORY_GROW_STUB_PREFIX) => {
+                    stubs.grows.insert(id, grow.memory);
+                }
+                Instr::Call(call)
+                    if (has_prefix(module, id, SNAPSHOT_STUB_NAME)
+                        || has_prefix(module, id, INSTRUMENT_STUB_PREFIX))
+                        && matches!(module.funcs.get(call.func).kind, FunctionKind::Import(_)) =>
+                {
+                    stubs.host.insert(call.func);
+                }
+                _ => (),
+            }
+        }
+    }
+    // Slowcall stubs of imports call the import's stub, go straight to the import
+    let calls = stubs.calls.clone();
+    for target in stubs.calls.values_mut() {
+        while let Some(next) = calls.get(target) {
+            *target = *next;
+        }
+    }
+    stubs
+}
+
+struct Unstub<'a> {
+    stubs: &'a Stubs,
+}
+
+// Put back what each call to a stub replaced
+impl VisitorMut for Unstub<'_> {
+    fn start_instr_seq_mut(&mut self, seq: &mut InstrSeq) {
+        let mut idx = 0;
+        while idx < seq.instrs.len() {
+            let stub = match &seq.instrs[idx].0 {
+                Instr::Call(call) => call.func,
+                _ => {
+                    idx += 1;
+                    continue;
+                }
+            };
+            if let Some((ty, table)) = self.stubs.indirect.get(&stub) {
+                // `i32.const {callsite}; call stub` was a single call_indirect
+                seq.instrs[idx].0 = Instr::CallIndirect(CallIndirect {
+                    ty: *ty,
+                    table: *table,
+                });
+                if idx > 0 && matches!(seq.instrs[idx - 1].0, Instr::Const(_)) {
+                    seq.instrs.remove(idx - 1);
+                    continue;
+                }
+            } else if let Some(func) = self.stubs.calls.get(&stub) {
+                seq.instrs[idx].0 = Instr::Call(Call { func: *func });
+            } else if let Some(memory) = self.stubs.grows.get(&stub) {
+                seq.instrs[idx].0 = Instr::MemoryGrow(MemoryGrow { memory: *memory });
+            }
+            idx += 1;
+        }
+    }
+}
+
+/*
+ * Undo an earlier instrument run, so the module can be instrumented again
+ * (--re-instrument) or optimized as if it were the original. Calls to the
+ * stubs (and exports of the entry stubs, and the table entries pointed at
+ * the slowcall stubs) go back to what they replaced, and
+ * the stubs, the profiling exports and the globals and memories behind them
+ * are deleted. Everything we added was appended, so the original functions,
+ * globals and memories keep their indices and the callsites their ids.
+ *
+ * Block, branch, br_table, value, loop, global access and data region
+ * counters live in the original functions' code, so a binary instrumented
+ * with those can't be stripped; instrument the original binary instead.
+ */
+pub fn strip_instrumentation(module: &mut Module) -> Result<(), String> {
+    let prefix = export_prefix(module);
+    let exports: Vec<(ExportId, String, ExportItem)> = module
+        .exports
+        .iter()
+        .filter_map(|export| {
+            let name = export.name.strip_prefix(prefix.as_str())?;
+            Some((export.id(), name.to_string(), export.item))
+        })
+        .filter(|(_, name, _)| {
+            name.starts_with("profiling_")
+                || name.starts_with("trace_")
+                || name == "indirect"
+                || name == "slowcalls"
+        })
+        .collect();
+    if let Some((_, name, _)) = exports.iter().find(|(_, name, _)| {
+        INLINE_COUNTER_EXPORTS
+            .iter()
+            .any(|counter| name.starts_with(counter))
+    }) {
+        return Err(format!(
+            "{}{} is counted inside the original code and can't be stripped, instrument the original binary instead",
+            prefix, name
+        ));
+    }
+
+    // Before the state it sets goes
+    unchain_start_init(module);
+    let stubs = find_stubs(module);
+    let all_stubs = stubs.all();
+    // Globals only the stubs touch (e.g. unexported slots with --compact-exports)
+    let mut globals: HashSet<GlobalId> = HashSet::new();
+    for stub in stubs.indirect.keys() {
+        for instr in instrs(module.funcs.get(*stub).kind.unwrap_local()) {
+            match instr {
+                Instr::GlobalGet(get) => globals.insert(get.global),
+                Instr::GlobalSet(set) => globals.insert(set.global),
+                _ => false,
+            };
+        }
+    }
+    let mut memories: HashSet<MemoryId> = HashSet::new();
+    for (id, _, item) in &exports {
+        match item {
+            ExportItem::Global(global) => {
+                globals.insert(*global);
+            }
+            ExportItem::Memory(memory) => {
+                memories.insert(*memory);
+            }
+            _ => (),
+        }
+        module.exports.delete(*id);
+    }
+    // The growth counters start at the initial size, they aren't plain counters,
+    // and the snapshot countdown isn't exported
+    for stub in stubs.grows.keys().chain(stubs.snapshot.iter()) {
+        for instr in instrs(module.funcs.get(*stub).kind.unwrap_local()) {
+            if let Instr::GlobalSet(set) = instr {
+                globals.insert(set.global);
+            }
+        }
+    }
+
+    let funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    for id in funcs {
+        if all_stubs.contains(&id) {
+            continue;
+        }
+        let func = module.funcs.get_mut(id).kind.unwrap_local_mut();
+        let entry = func.entry_block();
+        dfs_pre_order_mut(&mut Unstub { stubs: &stubs }, func, entry);
+    }
+    for export in module.exports.iter_mut() {
+        if let ExportItem::Function(func) = export.item {
+            if let Some(target) = stubs.calls.get(&func) {
+                export.item = ExportItem::Function(*target);
+            }
+        }
+    }
+    // --count-indirect-slowcalls
+    for elem in module.elements.iter_mut() {
+        for member in elem.members.iter_mut().flatten() {
+            if let Some(target) = stubs.calls.get(member) {
+                *member = *target;
+            }
+        }
+    }
+    for stub in all_stubs {
+        module.funcs.delete(stub);
+    }
+    for host in &stubs.host {
+        if let FunctionKind::Import(import) = &module.funcs.get(*host).kind {
+            module.imports.delete(import.import);
+        }
+        module.funcs.delete(*host);
+    }
+    for global in globals {
+        module.globals.delete(global);
+    }
+    for memory in memories {
+        for data in module.memories.get(memory).data_segments.clone() {
+            module.data.delete(data);
+        }
+        module.memories.delete(memory);
+    }
+    module.customs.remove_raw(MANIFEST_SECTION);
+    meta::forget_last_run(module);
+    println!("Stripped the instrumentation of an earlier run");
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use crate::report::func_name;
+use walrus::*;
+
+/*
+ * Human readable function names for reports. Names come from the name
+ * section (func_name), and Rust (legacy and v0) and Itanium C++ mangled
+ * names are demangled. This is for display only: callsite keys, manifests
+ * and everything matched against user input keep the raw names.
+ */
+pub fn demangle(name: &str) -> Option<String> {
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        // `{:#}` leaves off the trailing hash
+        return Some(format!("{:#}", demangled));
+    }
+    if name.starts_with("_Z") {
+        let symbol = cpp_demangle::Symbol::new(name).ok()?;
+        return symbol
+            .demangle(&cpp_demangle::DemangleOptions::default())
+            .ok();
+    }
+    None
+}
+
+// `name` demangled when `demangle` is set and it is mangled, as-is otherwise
+pub fn display(name: &str, demangle: bool) -> String {
+    if demangle {
+        self::demangle(name).unwrap_or_else(|| name.to_string())
+    } else {
+        name.to_string()
+    }
+}
+
+pub fn display_name(module: &Module, id: FunctionId, demangle: bool) -> String {
+    display(&func_name(module, id), demangle)
+}
+
+// A callsite key ("{function name}#{n}") with the function part demangled
+pub fn display_key(key: &str, demangle: bool) -> String {
+    match key.rsplit_once('#') {
+        Some((func, nth)) => format!("{}#{}", display(func, demangle), nth),
+        None => display(key, demangle),
+    }
+}
@@ -0,0 +1,118 @@
+use crate::profilemap::segment_offset;
+use crate::report::func_name;
+use std::collections::BTreeMap;
+use walrus::*;
+
+// Conflicts listed before the report just counts the rest
+const CONFLICTS_SHOWN: usize = 10;
+
+// A table index written by more than one active segment, with different functions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Conflict {
+    pub index: i64,
+    // (segment, what it wrote) for the earlier and the later segment; the later one
+    // is what the table holds. Segments are numbered in the element section.
+    pub earlier: (usize, Option<FunctionId>),
+    pub later: (usize, Option<FunctionId>),
+}
+
+/*
+ * What a table holds once instantiation has applied its active element
+ * segments, in the order the element section lists them: a later segment
+ * writing an index an earlier one wrote replaces the entry, which is what
+ * the engine does and what the table index a profile recorded refers to.
+ * Segments at offsets only known at instantiation (see segment_offset)
+ * aren't applied and are counted in `unplaced`.
+ */
+#[derive(Debug, Clone, Default)]
+pub struct TableImage {
+    // index ==> function, null entries left out
+    pub entries: BTreeMap<i64, FunctionId>,
+    pub conflicts: Vec<Conflict>,
+    pub unplaced: usize,
+}
+
+// The active segments of `table` as (position in the element section, segment)
+pub fn active_segments(module: &Module, table: TableId) -> Vec<(usize, &Element)> {
+    module
+        .elements
+        .iter()
+        .enumerate()
+        .filter(|(_, elem)| matches!(elem.kind, ElementKind::Active { table: t, .. } if t == table))
+        .collect()
+}
+
+impl TableImage {
+    pub fn build(module: &Module, table: TableId) -> TableImage {
+        let mut image = TableImage::default();
+        // index ==> (segment, member) that last wrote it
+        let mut written: BTreeMap<i64, (usize, Option<FunctionId>)> = BTreeMap::new();
+        for (segment, elem) in active_segments(module, table) {
+            let offset = match segment_offset(module, &elem.kind) {
+                Some(offset) => offset,
+                None => {
+                    image.unplaced += 1;
+                    continue;
+                }
+            };
+            for (pos, member) in elem.members.iter().enumerate() {
+                let index = offset + pos as i64;
+                if let Some(earlier) = written.insert(index, (segment, *member)) {
+                    if earlier.1 != *member {
+                        image.conflicts.push(Conflict {
+                            index,
+                            earlier,
+                            later: (segment, *member),
+                        });
+                    }
+                }
+                match member {
+                    Some(func) => image.entries.insert(index, *func),
+                    None => image.entries.remove(&index),
+                };
+            }
+        }
+        image
+    }
+
+    // The function at `idx`, if any
+    pub fn get(&self, idx: i64) -> Option<FunctionId> {
+        self.entries.get(&idx).cloned()
+    }
+
+    // The lowest index holding `func`
+    pub fn slot_of(&self, func: FunctionId) -> Option<i64> {
+        self.entries
+            .iter()
+            .find(|(_, f)| **f == func)
+            .map(|(idx, _)| *idx)
+    }
+
+    // Print the entries later segments overwrote, if any
+    pub fn report_conflicts(&self, module: &Module) {
+        if self.conflicts.is_empty() {
+            return;
+        }
+        println!(
+            "{} table entries are written by several element segments, resolving against the last write:",
+            self.conflicts.len()
+        );
+        let name = |func: Option<FunctionId>| match func {
+            Some(func) => func_name(module, func),
+            None => "null".to_string(),
+        };
+        for conflict in self.conflicts.iter().take(CONFLICTS_SHOWN) {
+            println!(
+                "  index {}: {} (segment {}) overwritten with {} (segment {})",
+                conflict.index,
+                name(conflict.earlier.1),
+                conflict.earlier.0,
+                name(conflict.later.1),
+                conflict.later.0
+            );
+        }
+        if self.conflicts.len() > CONFLICTS_SHOWN {
+            println!("  and {} more", self.conflicts.len() - CONFLICTS_SHOWN);
+        }
+    }
+}
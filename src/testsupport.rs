@@ -0,0 +1,164 @@
+use crate::selfcheck::{instrs, INSTRUMENT_STUB_PREFIX, OPTIMIZE_STUB_PREFIX};
+use walrus::ir::*;
+use walrus::*;
+
+/*
+ * Helpers for exercising the pipeline on small, hand built modules.
+ *
+ * ModuleBuilder generates the WAT for a module with a single funcref table:
+ * `target` adds a function to the table (returning its table index), and
+ * `caller` adds an exported function `(param $idx i32)` that performs a
+ * number of call_indirects of a given signature through that index. Turning
+ * the text into a binary is left to the caller (e.g. `wat::parse_str`).
+ * The pipeline expects a WASI command, so every module gets an empty
+ * exported `_start`.
+ */
+#[derive(Default)]
+pub struct ModuleBuilder {
+    types: Vec<(Vec<String>, Vec<String>)>,
+    funcs: Vec<String>,
+    table: Vec<String>,
+}
+
+fn strings(types: &[&str]) -> Vec<String> {
+    types.iter().map(|t| t.to_string()).collect()
+}
+
+// A constant of the given type, used for arguments and return values
+fn zero(ty: &str) -> String {
+    format!("({}.const 0)", ty)
+}
+
+impl ModuleBuilder {
+    pub fn new() -> ModuleBuilder {
+        ModuleBuilder::default()
+    }
+
+    fn type_name(&mut self, params: &[&str], results: &[&str]) -> String {
+        let sig = (strings(params), strings(results));
+        let idx = match self.types.iter().position(|t| *t == sig) {
+            Some(idx) => idx,
+            None => {
+                self.types.push(sig);
+                self.types.len() - 1
+            }
+        };
+        format!("$t{}", idx)
+    }
+
+    // Add a function to the table, returns its table index
+    pub fn target(&mut self, name: &str, params: &[&str], results: &[&str]) -> i32 {
+        let ty = self.type_name(params, results);
+        let body: Vec<String> = results.iter().map(|r| zero(r)).collect();
+        self.funcs
+            .push(format!("(func ${} (type {}) {})", name, ty, body.join(" ")));
+        self.table.push(format!("${}", name));
+        (self.table.len() - 1) as i32
+    }
+
+    // Add an exported function containing `callsites` call_indirects of this signature
+    pub fn caller(
+        &mut self,
+        name: &str,
+        params: &[&str],
+        results: &[&str],
+        callsites: usize,
+    ) -> &mut ModuleBuilder {
+        let ty = self.type_name(params, results);
+        let mut body = vec![];
+        for _ in 0..callsites {
+            body.extend(params.iter().map(|p| zero(p)));
+            body.push(format!("(call_indirect (type {}) (local.get $idx))", ty));
+            body.extend(results.iter().map(|_| "(drop)".to_string()));
+        }
+        self.funcs.push(format!(
+            "(func ${} (export \"{}\") (param $idx i32)\n    {})",
+            name,
+            name,
+            body.join("\n    ")
+        ));
+        self
+    }
+
+    // Add a function written by hand
+    pub fn func(&mut self, wat: &str) -> &mut ModuleBuilder {
+        self.funcs.push(wat.to_string());
+        self
+    }
+
+    pub fn to_wat(&self) -> String {
+        let mut wat = String::from("(module\n");
+        for (idx, (params, results)) in self.types.iter().enumerate() {
+            wat.push_str(&format!(
+                "  (type $t{} (func (param {}) (result {})))\n",
+                idx,
+                params.join(" "),
+                results.join(" ")
+            ));
+        }
+        wat.push_str(&format!("  (table {} funcref)\n", self.table.len()));
+        wat.push_str(&format!(
+            "  (elem (i32.const 0) {})\n",
+            self.table.join(" ")
+        ));
+        for func in &self.funcs {
+            wat.push_str(&format!("  {}\n", func));
+        }
+        wat.push_str("  (func $_start (export \"_start\"))\n");
+        wat.push_str(")\n");
+        wat
+    }
+}
+
+fn named(module: &Module, prefix: &str) -> Vec<FunctionId> {
+    module
+        .funcs
+        .iter()
+        .filter(|f| match &f.name {
+            Some(name) => name.starts_with(prefix),
+            None => false,
+        })
+        .map(|f| f.id())
+        .collect()
+}
+
+// The profiling stubs added when instrumenting
+pub fn instrument_stubs(module: &Module) -> Vec<FunctionId> {
+    named(module, INSTRUMENT_STUB_PREFIX)
+}
+
+// The guarded direct call stubs added when optimizing
+pub fn optimize_stubs(module: &Module) -> Vec<FunctionId> {
+    named(module, OPTIMIZE_STUB_PREFIX)
+}
+
+pub fn export_names(module: &Module) -> Vec<String> {
+    let mut names: Vec<String> = module.exports.iter().map(|e| e.name.clone()).collect();
+    names.sort();
+    names
+}
+
+pub fn function(module: &Module, name: &str) -> FunctionId {
+    match module.funcs.by_name(name) {
+        Some(id) => id,
+        None => panic!("no function named {}", name),
+    }
+}
+
+// Names of the functions `name` calls directly, in instrumentation order
+pub fn direct_calls(module: &Module, name: &str) -> Vec<String> {
+    let func = module.funcs.get(function(module, name)).kind.unwrap_local();
+    instrs(func)
+        .iter()
+        .filter_map(|instr| match instr {
+            Instr::Call(call) => Some(crate::report::func_name(module, call.func)),
+            _ => None,
+        })
+        .collect()
+}
+
+// How many instructions in `name` match `pred`
+pub fn count_instrs(module: &Module, name: &str, pred: impl Fn(&Instr) -> bool) -> usize {
+    let func = module.funcs.get(function(module, name)).kind.unwrap_local();
+    instrs(func).iter().filter(|instr| pred(instr)).count()
+}
@@ -0,0 +1,103 @@
+use crate::manifest::TraceLayout;
+use walrus::ir::*;
+use walrus::*;
+
+// Each record is (i32 callsite id, i32 target table index)
+pub const TRACE_RECORD_SIZE: u32 = 8;
+
+pub struct TraceBuffer {
+    pub memory: MemoryId,
+    pub cursor: GlobalId,
+    pub entries: u32,
+    pub memory_export: String,
+    pub cursor_export: String,
+}
+
+/*
+ * Add a dedicated memory for the ring buffer plus an exported cursor global.
+ *
+ * The cursor counts every record ever written, so the host can tell how many
+ * records wrapped around: the oldest valid record is at (cursor % entries)
+ * once cursor >= entries.
+ */
+pub fn add_trace_buffer(module: &mut Module, entries: u32, export_prefix: &str) -> TraceBuffer {
+    assert!(
+        entries.is_power_of_two(),
+        "trace buffer entries must be a power of two"
+    );
+    let bytes = entries as u64 * TRACE_RECORD_SIZE as u64;
+    let pages = ((bytes + 65535) / 65536) as u32;
+    let memory = module.memories.add_local(false, pages, Some(pages));
+    let cursor = module
+        .globals
+        .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+    let memory_export = format!("{}trace_buffer", export_prefix);
+    let cursor_export = format!("{}trace_cursor", export_prefix);
+    module.exports.add(&memory_export, memory);
+    module.exports.add(&cursor_export, cursor);
+    TraceBuffer {
+        memory,
+        cursor,
+        entries,
+        memory_export,
+        cursor_export,
+    }
+}
+
+pub fn trace_layout(buffer: &TraceBuffer) -> TraceLayout {
+    TraceLayout {
+        memory_export: buffer.memory_export.clone(),
+        cursor_export: buffer.cursor_export.clone(),
+        entries: buffer.entries,
+        record_size: TRACE_RECORD_SIZE,
+    }
+}
+
+/*
+ * Prepend the ring buffer append to an indirect call stub:
+ *
+ * addr = (cursor & (entries - 1)) * 8
+ * mem[addr] = callsite; mem[addr + 4] = target
+ * cursor += 1
+ */
+pub fn record_trace(module: &mut Module, stub: FunctionId, buffer: &TraceBuffer) {
+    let addr = module.locals.add(ValType::I32);
+    let func = module.funcs.get_mut(stub).kind.unwrap_local_mut();
+    let args = func.args.clone();
+    let call_target = args[args.len() - 1];
+    let indirect_call_value = args[args.len() - 2];
+    let store = StoreKind::I32 { atomic: false };
+    let mut func_body = func.builder_mut().func_body();
+    func_body.block_at(0, None, |block| {
+        block
+            .global_get(buffer.cursor)
+            .i32_const((buffer.entries - 1) as i32)
+            .binop(BinaryOp::I32And)
+            .i32_const(3)
+            .binop(BinaryOp::I32Shl)
+            .local_tee(addr)
+            .local_get(call_target)
+            .store(
+                buffer.memory,
+                store,
+                MemArg {
+                    align: 4,
+                    offset: 0,
+                },
+            )
+            .local_get(addr)
+            .local_get(indirect_call_value)
+            .store(
+                buffer.memory,
+                store,
+                MemArg {
+                    align: 4,
+                    offset: 4,
+                },
+            )
+            .global_get(buffer.cursor)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .global_set(buffer.cursor);
+    });
+}
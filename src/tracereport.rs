@@ -0,0 +1,138 @@
+use crate::manifest::Manifest;
+use crate::symbolize::display_key;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::collections::HashMap;
+
+// Only trust a phase's dominant target if the callsite was hit this many times
+const MIN_PHASE_SAMPLES: usize = 4;
+
+/*
+ * Decode the raw ring buffer dump into (callsite, target) records in the order
+ * they were written. `cursor` is the final value of the exported cursor.
+ */
+pub fn decode_trace(buf: &[u8], cursor: u64, entries: u32, record_size: u32) -> Vec<(i32, i32)> {
+    let entries = entries as u64;
+    let record_size = record_size as usize;
+    let read = |idx: u64| {
+        let start = idx as usize * record_size;
+        let callsite = i32::from_le_bytes(buf[start..start + 4].try_into().unwrap());
+        let target = i32::from_le_bytes(buf[start + 4..start + 8].try_into().unwrap());
+        (callsite, target)
+    };
+    if cursor <= entries {
+        (0..cursor).map(read).collect()
+    } else {
+        // The buffer wrapped: the oldest surviving record is at cursor % entries
+        let start = cursor % entries;
+        (0..entries)
+            .map(|idx| read((start + idx) % entries))
+            .collect()
+    }
+}
+
+fn dominant(targets: &HashMap<i32, usize>) -> Option<(i32, usize)> {
+    targets
+        .iter()
+        .map(|(t, c)| (*t, *c))
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+}
+
+/*
+ * Split the trace into equally sized phases and compare the per-callsite
+ * target distributions across phases.
+ *
+ * - A callsite whose dominant target changes between phases is "shifting".
+ * - If new targets still show up in the final phase the target set hasn't
+ *   converged, so we recommend the conservative policy (keep an indirect call
+ *   fallback). Otherwise the aggressive policy (trap on unseen targets) is safe.
+ */
+pub fn trace_report(
+    records: &[(i32, i32)],
+    manifest: &Manifest,
+    phases: usize,
+    wrapped: bool,
+    demangle: bool,
+) {
+    let phases = std::cmp::max(1, std::cmp::min(phases, records.len()));
+    let phase_len = (records.len() + phases - 1) / std::cmp::max(phases, 1);
+
+    // callsite ==> per-phase target counts
+    let mut dist: BTreeMap<i32, Vec<HashMap<i32, usize>>> = BTreeMap::new();
+    for (idx, (callsite, target)) in records.iter().enumerate() {
+        let phase = if phase_len > 0 { idx / phase_len } else { 0 };
+        let per_phase = dist
+            .entry(*callsite)
+            .or_insert_with(|| vec![HashMap::new(); phases]);
+        *per_phase[phase].entry(*target).or_insert(0) += 1;
+    }
+
+    let names: HashMap<usize, String> = manifest
+        .callsites
+        .iter()
+        .map(|c| (c.id, display_key(&c.key, demangle)))
+        .collect();
+
+    println!(
+        "== Trace: {} records, {} phases of ~{} records{} ==",
+        records.len(),
+        phases,
+        phase_len,
+        if wrapped {
+            " (buffer wrapped, warm-up was overwritten)"
+        } else {
+            ""
+        }
+    );
+
+    let mut shifting = 0;
+    for (callsite, per_phase) in &dist {
+        let name = names
+            .get(&(*callsite as usize))
+            .map(|name| name.as_str())
+            .unwrap_or("<unknown>");
+        let mut dominants = vec![];
+        let mut seen: BTreeSet<i32> = BTreeSet::new();
+        let mut late_new_targets = false;
+        for (phase, targets) in per_phase.iter().enumerate() {
+            let total: usize = targets.values().sum();
+            if total >= MIN_PHASE_SAMPLES {
+                if let Some((target, _)) = dominant(targets) {
+                    dominants.push(target);
+                }
+            }
+            if phase == phases - 1 && phase > 0 {
+                late_new_targets = targets.keys().any(|t| !seen.contains(t));
+            }
+            seen.extend(targets.keys());
+        }
+        let mut distinct = dominants.clone();
+        distinct.dedup();
+        let is_shifting = distinct.len() > 1;
+        if is_shifting {
+            shifting += 1;
+        }
+        let policy = if is_shifting || late_new_targets {
+            "conservative"
+        } else {
+            "aggressive"
+        };
+
+        println!("callsite {} ({}):", callsite, name);
+        for (phase, targets) in per_phase.iter().enumerate() {
+            let mut sorted: Vec<(&i32, &usize)> = targets.iter().collect();
+            sorted.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+            let summary: Vec<String> = sorted.iter().map(|(t, c)| format!("{}x{}", t, c)).collect();
+            println!("    phase {}: [{}]", phase, summary.join(", "));
+        }
+        println!(
+            "    targets: {:?}, dominant shifts: {}, new targets late: {} => recommend {}",
+            seen, is_shifting, late_new_targets, policy
+        );
+    }
+    println!(
+        "{} of {} traced callsites shift their dominant target over time",
+        shifting,
+        dist.len()
+    );
+}
@@ -0,0 +1,308 @@
+use crate::blockcounters::enumerate_blocks;
+use crate::callsites::enumerate_callsites;
+use crate::profilemap::{describe_slots, resolve_table_index};
+use crate::report::type_signature;
+use crate::symbolize::display_name;
+use crate::Profile;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashMap;
+use std::collections::HashSet;
+use walrus::{FunctionId, Module, TableId};
+
+struct FuncRow {
+    name: String,
+    calls: Option<i32>,
+    // (callsite id, call_indirect type, observed slots)
+    callsites: Vec<(usize, String, Vec<i64>)>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Sort {
+    Calls,
+    Callsites,
+    Name,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Focus {
+    Functions,
+    Callsites,
+}
+
+struct App {
+    rows: Vec<FuncRow>,
+    funcs: ListState,
+    callsites: ListState,
+    focus: Focus,
+    sort: Sort,
+    // table index ==> function name
+    targets: HashMap<i64, String>,
+}
+
+impl App {
+    fn new(module: &Module, table: Option<TableId>, profile: &Profile, demangle: bool) -> App {
+        let original_funcs: Vec<FunctionId> = module.funcs.iter_local().map(|(id, _)| id).collect();
+        let mut entry_counts: HashMap<FunctionId, i32> = HashMap::new();
+        let mut seen = HashSet::new();
+        for (idx, (f_id, _)) in enumerate_blocks(module, &original_funcs).iter().enumerate() {
+            if seen.insert(*f_id) {
+                if let Some(count) = profile.blocks.get(&idx) {
+                    entry_counts.insert(*f_id, *count);
+                }
+            }
+        }
+
+        let mut per_func: HashMap<FunctionId, Vec<(usize, String, Vec<i64>)>> = HashMap::new();
+        let mut targets = HashMap::new();
+        for (idx, callsite) in enumerate_callsites(module, table).iter().enumerate() {
+            let slots = profile.map.get(&idx).cloned().unwrap_or_default();
+            for target in slots.iter().filter(|t| **t >= 0) {
+                let name = match resolve_table_index(module, *target) {
+                    Some(f) => display_name(module, f, demangle),
+                    None => format!("<table[{}] out of range>", target),
+                };
+                targets.insert(*target, name);
+            }
+            per_func.entry(callsite.func).or_default().push((
+                idx,
+                type_signature(module.types.get(callsite.ty)),
+                slots,
+            ));
+        }
+
+        let mut rows = vec![];
+        for f in &original_funcs {
+            if entry_counts.contains_key(f) || per_func.contains_key(f) {
+                rows.push(FuncRow {
+                    name: display_name(module, *f, demangle),
+                    calls: entry_counts.get(f).cloned(),
+                    callsites: per_func.remove(f).unwrap_or_default(),
+                });
+            }
+        }
+
+        let mut app = App {
+            rows,
+            funcs: ListState::default(),
+            callsites: ListState::default(),
+            focus: Focus::Functions,
+            sort: Sort::Calls,
+            targets,
+        };
+        app.resort();
+        app
+    }
+
+    fn resort(&mut self) {
+        match self.sort {
+            Sort::Calls => self.rows.sort_by(|a, b| {
+                b.calls
+                    .unwrap_or(0)
+                    .cmp(&a.calls.unwrap_or(0))
+                    .then(b.callsites.len().cmp(&a.callsites.len()))
+            }),
+            Sort::Callsites => self
+                .rows
+                .sort_by(|a, b| b.callsites.len().cmp(&a.callsites.len())),
+            Sort::Name => self.rows.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+        self.funcs
+            .select(if self.rows.is_empty() { None } else { Some(0) });
+        self.reset_callsites();
+    }
+
+    fn reset_callsites(&mut self) {
+        let empty = match self.selected_row() {
+            Some(row) => row.callsites.is_empty(),
+            None => true,
+        };
+        self.callsites.select(if empty { None } else { Some(0) });
+    }
+
+    fn selected_row(&self) -> Option<&FuncRow> {
+        self.funcs.selected().and_then(|idx| self.rows.get(idx))
+    }
+
+    fn move_selection(&mut self, delta: i64) {
+        let (state, len) = match self.focus {
+            Focus::Functions => (&mut self.funcs, self.rows.len()),
+            Focus::Callsites => {
+                let len = self
+                    .funcs
+                    .selected()
+                    .and_then(|idx| self.rows.get(idx))
+                    .map(|row| row.callsites.len())
+                    .unwrap_or(0);
+                (&mut self.callsites, len)
+            }
+        };
+        if len == 0 {
+            return;
+        }
+        let current = state.selected().unwrap_or(0) as i64;
+        let next = std::cmp::min(std::cmp::max(current + delta, 0), len as i64 - 1);
+        state.select(Some(next as usize));
+        if self.focus == Focus::Functions {
+            self.reset_callsites();
+        }
+    }
+
+    fn target_names(&self, slots: &[i64]) -> Vec<String> {
+        slots
+            .iter()
+            .filter(|t| **t >= 0)
+            .map(|t| self.targets.get(t).cloned().unwrap_or(t.to_string()))
+            .collect()
+    }
+}
+
+fn highlight(active: bool) -> Style {
+    if active {
+        Style::default().add_modifier(Modifier::REVERSED)
+    } else {
+        Style::default().add_modifier(Modifier::BOLD)
+    }
+}
+
+fn draw(frame: &mut Frame, app: &mut App) {
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+        .split(frame.area());
+    let right = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(65), Constraint::Percentage(35)])
+        .split(columns[1]);
+
+    let sort = match app.sort {
+        Sort::Calls => "calls",
+        Sort::Callsites => "callsites",
+        Sort::Name => "name",
+    };
+    let funcs: Vec<ListItem> = app
+        .rows
+        .iter()
+        .map(|row| {
+            let calls = match row.calls {
+                Some(calls) => calls.to_string(),
+                None => "-".to_string(),
+            };
+            ListItem::new(format!(
+                "{:>10} {:>4}  {}",
+                calls,
+                row.callsites.len(),
+                row.name
+            ))
+        })
+        .collect();
+    let funcs = List::new(funcs)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("Functions (calls, callsites) sorted by {}", sort)),
+        )
+        .highlight_style(highlight(app.focus == Focus::Functions));
+    frame.render_stateful_widget(funcs, columns[0], &mut app.funcs);
+
+    let (callsites, decision) = match app.selected_row() {
+        Some(row) => {
+            let items: Vec<ListItem> = row
+                .callsites
+                .iter()
+                .map(|(idx, ty, slots)| {
+                    ListItem::new(format!(
+                        "#{:<6} {}  [{}]",
+                        idx,
+                        ty,
+                        app.target_names(slots).join(", ")
+                    ))
+                })
+                .collect();
+            let decision = match app.callsites.selected().and_then(|i| row.callsites.get(i)) {
+                Some((idx, _, slots)) if slots.is_empty() => {
+                    format!("callsite {}: no profile data, the call is left as-is", idx)
+                }
+                Some((idx, _, slots)) => format!(
+                    "callsite {}\nslots: {:?}\ntargets: {}\n\n{}",
+                    idx,
+                    slots,
+                    app.target_names(slots).join(", "),
+                    describe_slots(slots)
+                ),
+                None => String::new(),
+            };
+            (items, decision)
+        }
+        None => (vec![], String::new()),
+    };
+    let callsites = List::new(callsites)
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("Callsites (observed targets)"),
+        )
+        .highlight_style(highlight(app.focus == Focus::Callsites));
+    frame.render_stateful_widget(callsites, right[0], &mut app.callsites);
+
+    let decision = Paragraph::new(decision).wrap(Wrap { trim: false }).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title("Optimizer decision  [tab] switch pane  [s] sort  [q] quit"),
+    );
+    frame.render_widget(decision, right[1]);
+}
+
+fn event_loop(terminal: &mut DefaultTerminal, app: &mut App) -> std::io::Result<()> {
+    loop {
+        terminal.draw(|frame| draw(frame, app))?;
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+                KeyCode::Down | KeyCode::Char('j') => app.move_selection(1),
+                KeyCode::Up | KeyCode::Char('k') => app.move_selection(-1),
+                KeyCode::PageDown => app.move_selection(20),
+                KeyCode::PageUp => app.move_selection(-20),
+                KeyCode::Tab | KeyCode::Enter | KeyCode::Right | KeyCode::Left => {
+                    app.focus = match app.focus {
+                        Focus::Functions => Focus::Callsites,
+                        Focus::Callsites => Focus::Functions,
+                    };
+                }
+                KeyCode::Char('s') => {
+                    app.sort = match app.sort {
+                        Sort::Calls => Sort::Callsites,
+                        Sort::Callsites => Sort::Name,
+                        Sort::Name => Sort::Calls,
+                    };
+                    app.resort();
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+/*
+ * Interactive profile browser: functions sorted by hotness on the left,
+ * the selected function's callsites + observed targets on the right, and the
+ * decision the optimizer would make for the selected callsite below that.
+ */
+pub fn run(
+    module: &Module,
+    table: Option<TableId>,
+    profile: &Profile,
+    demangle: bool,
+) -> std::io::Result<()> {
+    let mut app = App::new(module, table, profile, demangle);
+    let mut terminal = ratatui::init();
+    let result = event_loop(&mut terminal, &mut app);
+    ratatui::restore();
+    result
+}
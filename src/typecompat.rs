@@ -0,0 +1,55 @@
+use crate::report::{func_name, type_signature};
+use walrus::*;
+
+// How a function's type relates to the type a call_indirect expects
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TypeMatch {
+    // The very type the callsite names
+    Declared,
+    // A different type entry with the same signature, which call_indirect accepts
+    Structural,
+    // call_indirect would trap
+    Incompatible,
+}
+
+/*
+ * Whether a function of type `actual` passes a call_indirect's check
+ * against `expected`, which is what devirtualizing the call assumes. With
+ * the function-references and GC proposals the check follows the declared
+ * subtypes (a type passes for its supertypes, and only structurally equal
+ * types in equal recursion groups are the same type), so a signature that
+ * merely fits, parameters contravariant and results covariant, isn't
+ * enough. The types walrus parses are all final with no supertypes (the
+ * modules that declare any are refused up front, see features::check),
+ * where the two coincide: a type matches when its signature is the same.
+ */
+pub fn type_match(module: &Module, actual: TypeId, expected: TypeId) -> TypeMatch {
+    if actual == expected {
+        return TypeMatch::Declared;
+    }
+    let actual = module.types.get(actual);
+    let expected = module.types.get(expected);
+    if actual.params() == expected.params() && actual.results() == expected.results() {
+        TypeMatch::Structural
+    } else {
+        TypeMatch::Incompatible
+    }
+}
+
+// Whether `func` can be called where a call_indirect of type `expected` is
+pub fn func_matches(module: &Module, func: FunctionId, expected: TypeId) -> bool {
+    type_match(module, module.funcs.get(func).ty(), expected) != TypeMatch::Incompatible
+}
+
+// Why `func` can't be called for `expected`, naming both signatures
+pub fn check_target(module: &Module, func: FunctionId, expected: TypeId) -> Result<(), String> {
+    if func_matches(module, func, expected) {
+        return Ok(());
+    }
+    Err(format!(
+        "{} has type {} but the callsite calls {}",
+        func_name(module, func),
+        type_signature(module.types.get(module.funcs.get(func).ty())),
+        type_signature(module.types.get(expected))
+    ))
+}
@@ -0,0 +1,232 @@
+use crate::coldsplit::{copy_seq, region_locals};
+use crate::fastcalls::redirect_calls;
+use crate::report::func_name;
+use std::collections::HashMap;
+use walrus::ir::*;
+use walrus::*;
+
+/*
+ * Number every i32 parameter of the given functions, in function then
+ * parameter order. The index of each entry is the value id used in the
+ * profile (profiling_value_{id}_*), so like the block ids this must be
+ * computed on the *original* module in both runs.
+ */
+pub fn enumerate_value_params(module: &Module, funcs: &[FunctionId]) -> Vec<(FunctionId, usize)> {
+    let mut params = vec![];
+    for f_id in funcs {
+        let func = module.funcs.get(*f_id);
+        if let FunctionKind::Local(_) = func.kind {
+            let ty = module.types.get(func.ty());
+            for (idx, param) in ty.params().iter().enumerate() {
+                if *param == ValType::I32 {
+                    params.push((*f_id, idx));
+                }
+            }
+        }
+    }
+    params
+}
+
+/*
+ * Track the most common value of each parameter with a Boyer-Moore majority
+ * vote, so every parameter costs three globals no matter how many distinct
+ * values it sees:
+ *
+ *   calls += 1
+ *   if votes == 0 { value = arg; votes = 1 }
+ *   else if value == arg { votes += 1 } else { votes -= 1 }
+ *
+ * written branch-free with `select` in a block prepended to the entry
+ * block. If some value was passed in more than half the calls it is the one
+ * left in `value`, and `votes` is a lower bound on how often it was passed:
+ * every other call cancelled at most one vote.
+ */
+pub fn instrument_values(module: &mut Module, params: &[(FunctionId, usize)], export_prefix: &str) {
+    for (idx, (f_id, param)) in params.iter().enumerate() {
+        let mut add_counter = |name: &str| {
+            let global =
+                module
+                    .globals
+                    .add_local(ValType::I32, true, InitExpr::Value(Value::I32(0)));
+            module.exports.add(
+                &format!("{}profiling_value_{}_{}", export_prefix, idx, name),
+                global,
+            );
+            global
+        };
+        let value = add_counter("value");
+        let votes = add_counter("votes");
+        let calls = add_counter("calls");
+
+        let func = module.funcs.get_mut(*f_id).kind.unwrap_local_mut();
+        let arg = func.args[*param];
+        let entry = func.entry_block();
+        let builder = func.builder_mut();
+        let mut seq = builder.dangling_instr_seq(None);
+        seq.global_get(calls)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .global_set(calls)
+            // New vote count: 1 if there was no candidate, else votes +/- 1
+            .i32_const(1)
+            .global_get(votes)
+            .i32_const(1)
+            .binop(BinaryOp::I32Add)
+            .global_get(votes)
+            .i32_const(1)
+            .binop(BinaryOp::I32Sub)
+            .global_get(value)
+            .local_get(arg)
+            .binop(BinaryOp::I32Eq)
+            .select(None)
+            .global_get(votes)
+            .unop(UnaryOp::I32Eqz)
+            .select(None)
+            // New candidate: the argument if there was none
+            .local_get(arg)
+            .global_get(value)
+            .global_get(votes)
+            .unop(UnaryOp::I32Eqz)
+            .select(None)
+            .global_set(value)
+            .global_set(votes);
+        let seq = seq.id();
+        builder.instr_seq(entry).instr_at(0, Block { seq });
+    }
+    println!(
+        "Instrumented {} function parameters with value profiles",
+        params.len()
+    );
+}
+
+// Combine two (value, votes, calls) majority votes, as if they were one run
+pub fn merge_votes(a: (i32, i32, i32), b: (i32, i32, i32)) -> (i32, i32, i32) {
+    let calls = a.2.saturating_add(b.2);
+    if a.0 == b.0 {
+        (a.0, a.1.saturating_add(b.1), calls)
+    } else if a.1 >= b.1 {
+        (a.0, a.1 - b.1, calls)
+    } else {
+        (b.0, b.1 - a.1, calls)
+    }
+}
+
+// Copy of `f_id` with `value` stored into parameter `param` before the body runs
+fn clone_with_constant(
+    module: &mut Module,
+    f_id: FunctionId,
+    param: usize,
+    value: i32,
+) -> FunctionId {
+    let func = module.funcs.get(f_id).kind.unwrap_local();
+    let ty = module.types.get(func.ty()).clone();
+    let mut locals = func.args.clone();
+    region_locals(func, func.entry_block(), &mut locals);
+    let mut local_map = HashMap::new();
+    for l in &locals {
+        local_map.insert(*l, module.locals.add(module.locals.get(*l).ty()));
+    }
+    let args: Vec<LocalId> = func.args.iter().map(|a| local_map[a]).collect();
+
+    let mut builder = FunctionBuilder::new(&mut module.types, ty.params(), ty.results());
+    builder.name(format!("{}_specialized", func_name(module, f_id)));
+    builder.func_body().i32_const(value).local_set(args[param]);
+    let body = builder.func_body_id();
+    let mut seq_map = HashMap::new();
+    copy_seq(
+        func,
+        func.entry_block(),
+        &mut builder,
+        body,
+        &mut seq_map,
+        &local_map,
+    );
+    builder.finish(args, &mut module.funcs)
+}
+
+// `if (arg == value) specialized(args...) else original(args...)`
+fn guard(
+    module: &mut Module,
+    f_id: FunctionId,
+    specialized: FunctionId,
+    param: usize,
+    value: i32,
+) -> FunctionId {
+    let ty = module.types.get(module.funcs.get(f_id).ty()).clone();
+    let seq_ty = InstrSeqType::new(&mut module.types, &[], ty.results());
+    let args: Vec<LocalId> = ty.params().iter().map(|p| module.locals.add(*p)).collect();
+    let mut builder = FunctionBuilder::new(&mut module.types, ty.params(), ty.results());
+    builder.name(format!("spec_guard_{}", func_name(module, f_id)));
+    builder
+        .func_body()
+        .local_get(args[param])
+        .i32_const(value)
+        .binop(BinaryOp::I32Eq)
+        .if_else(
+            seq_ty,
+            |then| {
+                for arg in &args {
+                    then.local_get(*arg);
+                }
+                then.call(specialized);
+            },
+            |otherwise| {
+                for arg in &args {
+                    otherwise.local_get(*arg);
+                }
+                otherwise.call(f_id);
+            },
+        );
+    builder.finish(args, &mut module.funcs)
+}
+
+/*
+ * Specialize functions on their profiled argument values. A parameter
+ * qualifies if its function was called at least `hot_threshold` times and
+ * the majority vote kept at least half the calls, so the value was passed in
+ * at least half of them. Each such function (at most once, for its
+ * strongest parameter) is cloned with the value baked in, and direct calls
+ * go through a guard that checks the argument and picks the clone or the
+ * original. The clone starts with `i32.const value; local.set param`, which
+ * is what constant propagation downstream needs to get going.
+ *
+ * `params` must come from enumerate_value_params on the original module.
+ */
+pub fn specialize_functions(
+    module: &mut Module,
+    params: &[(FunctionId, usize)],
+    values: &HashMap<usize, (i32, i32, i32)>,
+    hot_threshold: i32,
+) -> usize {
+    // function ==> (param, value, votes) of the strongest candidate
+    let mut best: HashMap<FunctionId, (usize, i32, i32)> = HashMap::new();
+    for (idx, (f_id, param)) in params.iter().enumerate() {
+        let (value, votes, calls) = match values.get(&idx) {
+            Some(profile) => *profile,
+            None => continue,
+        };
+        if calls < hot_threshold || votes < calls / 2 {
+            continue;
+        }
+        match best.get(f_id) {
+            Some((_, _, best_votes)) if *best_votes >= votes => (),
+            _ => {
+                best.insert(*f_id, (*param, value, votes));
+            }
+        }
+    }
+
+    let mut candidates: Vec<_> = best.into_iter().collect();
+    candidates.sort_by_key(|(f_id, _)| f_id.index());
+    let mut mapping = HashMap::new();
+    for (f_id, (param, value, _)) in &candidates {
+        let specialized = clone_with_constant(module, *f_id, *param, *value);
+        mapping.insert(*f_id, guard(module, *f_id, specialized, *param, *value));
+    }
+    redirect_calls(module, &mapping);
+    println!(
+        "Specialized {} functions on profiled argument values",
+        candidates.len()
+    );
+    candidates.len()
+}
@@ -0,0 +1,137 @@
+use wasmtime::*;
+use wasmtime_wasi::pipe::{MemoryInputPipe, MemoryOutputPipe};
+use wasmtime_wasi::preview1::{self, WasiP1Ctx};
+use wasmtime_wasi::{Deterministic, I32Exit, WasiCtxBuilder};
+
+// Captured stdout is capped so a runaway module can't eat all our memory
+const MAX_STDOUT: usize = 64 << 20;
+
+// How one run of a WASI command ended
+#[derive(Debug, PartialEq)]
+enum Exit {
+    Code(i32),
+    Trap(String),
+}
+
+struct Outcome {
+    exit: Exit,
+    stdout: Vec<u8>,
+    memory: Option<Vec<u8>>,
+}
+
+/*
+ * Run a WASI command module to completion. Randomness is seeded identically
+ * for every run so the two modules see the same inputs; clocks are still the
+ * host's, so programs that print timings will show up as mismatches.
+ */
+fn run(engine: &Engine, path: &str, args: &[String], stdin: &[u8]) -> Outcome {
+    let module = Module::from_file(engine, path).unwrap();
+    let stdout = MemoryOutputPipe::new(MAX_STDOUT);
+    let wasi = WasiCtxBuilder::new()
+        .arg(path)
+        .args(args)
+        .stdin(MemoryInputPipe::new(stdin.to_vec()))
+        .stdout(stdout.clone())
+        .inherit_stderr()
+        .secure_random(Deterministic::new(vec![0x5a; 32]))
+        .insecure_random_seed(0)
+        .build_p1();
+
+    let mut linker: Linker<WasiP1Ctx> = Linker::new(engine);
+    preview1::add_to_linker_sync(&mut linker, |ctx| ctx).unwrap();
+    let mut store = Store::new(engine, wasi);
+
+    let instance = match linker.instantiate(&mut store, &module) {
+        Ok(instance) => instance,
+        Err(e) => {
+            return Outcome {
+                exit: Exit::Trap(format!("instantiation failed: {}", e.root_cause())),
+                stdout: vec![],
+                memory: None,
+            }
+        }
+    };
+    let start = instance
+        .get_typed_func::<(), ()>(&mut store, "_start")
+        .expect("verify needs WASI command modules (no _start export)");
+    // Only compare the trap kind: backtraces differ since the optimized
+    // binary has extra functions
+    let exit = match start.call(&mut store, ()) {
+        Ok(()) => Exit::Code(0),
+        Err(e) => match (e.downcast_ref::<I32Exit>(), e.downcast_ref::<Trap>()) {
+            (Some(I32Exit(code)), _) => Exit::Code(*code),
+            (None, Some(trap)) => Exit::Trap(trap.to_string()),
+            (None, None) => Exit::Trap(e.root_cause().to_string()),
+        },
+    };
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .map(|m| m.data(&store).to_vec());
+    Outcome {
+        exit,
+        stdout: stdout.contents().to_vec(),
+        memory,
+    }
+}
+
+// Print the first difference between two byte buffers, returns whether they match
+fn compare_bytes(what: &str, a: &[u8], b: &[u8]) -> bool {
+    if a == b {
+        return true;
+    }
+    match a.iter().zip(b.iter()).position(|(x, y)| x != y) {
+        Some(offset) => println!(
+            "  {} differs at offset {:#x}: {:#04x} vs {:#04x} ({} vs {} bytes)",
+            what,
+            offset,
+            a[offset],
+            b[offset],
+            a.len(),
+            b.len()
+        ),
+        None => println!(
+            "  {} length differs: {} vs {} bytes",
+            what,
+            a.len(),
+            b.len()
+        ),
+    }
+    false
+}
+
+/*
+ * Run the original (`a`) and optimized (`b`) module on each argument list and
+ * compare exit status, stdout and final linear memory. The optimized binary
+ * traps on any target the profile didn't see, so this is mostly a check that
+ * the profiling workload covered the inputs we care about.
+ */
+pub fn verify(a: &str, b: &str, runs: &[Vec<String>], stdin: &[u8]) -> bool {
+    let engine = Engine::default();
+    let mut ok = true;
+    for (idx, args) in runs.iter().enumerate() {
+        let outcome_a = run(&engine, a, args, stdin);
+        let outcome_b = run(&engine, b, args, stdin);
+
+        println!("run {}: {:?}", idx, args);
+        let mut matches = true;
+        if outcome_a.exit != outcome_b.exit {
+            println!(
+                "  exit differs: {:?} vs {:?}",
+                outcome_a.exit, outcome_b.exit
+            );
+            matches = false;
+        }
+        matches &= compare_bytes("stdout", &outcome_a.stdout, &outcome_b.stdout);
+        match (&outcome_a.memory, &outcome_b.memory) {
+            (Some(mem_a), Some(mem_b)) => matches &= compare_bytes("memory", mem_a, mem_b),
+            (None, None) => (),
+            _ => {
+                println!("  only one of the modules exports its memory");
+                matches = false;
+            }
+        }
+        println!("  {}", if matches { "ok" } else { "MISMATCH" });
+        ok &= matches;
+    }
+    ok
+}
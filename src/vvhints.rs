@@ -0,0 +1,87 @@
+use crate::callsites::enumerate_callsites;
+use crate::costs::{compute_costs, entry_counts};
+use crate::report::func_name;
+use crate::Profile;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use walrus::*;
+
+pub const HINTS_VERSION: u32 = 1;
+
+/*
+ * Partitioning hints for VectorVisor: the functions hot enough to be worth
+ * keeping resident on the GPU, hottest first. Call counts come from the
+ * entry block counters, so the profile must have been collected with
+ * --block-counters.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct VvHints {
+    pub version: u32,
+    pub hot_threshold: i32,
+    pub functions: Vec<FunctionHint>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FunctionHint {
+    pub func: String,
+    pub func_index: usize,
+    pub calls: i32,
+    pub instrs: usize,
+    // call_indirects the profile turns into guarded direct calls
+    pub devirtualized_calls: usize,
+    // devirtualized_calls per 1000 instructions
+    pub devirtualized_density: f64,
+}
+
+pub fn compute_hints(
+    module: &Module,
+    table: Option<TableId>,
+    profile: &Profile,
+    hot_threshold: i32,
+) -> VvHints {
+    let mut devirtualized: HashMap<FunctionId, usize> = HashMap::new();
+    for (idx, callsite) in enumerate_callsites(module, table).iter().enumerate() {
+        if let Some(slots) = profile.map.get(&idx) {
+            if slots.iter().any(|val| *val >= 0) {
+                *devirtualized.entry(callsite.func).or_insert(0) += 1;
+            }
+        }
+    }
+    if profile.blocks.is_empty() {
+        println!("no block counts in the profile (collect it with --block-counters), emitting no VV hints");
+    }
+
+    let calls = entry_counts(module, profile);
+    let mut functions: Vec<FunctionHint> = compute_costs(module, &None)
+        .into_iter()
+        .filter_map(|cost| {
+            let calls = *calls.get(&cost.func)?;
+            if calls < hot_threshold {
+                return None;
+            }
+            let devirtualized_calls = devirtualized.get(&cost.func).cloned().unwrap_or(0);
+            Some(FunctionHint {
+                func: func_name(module, cost.func),
+                func_index: cost.func.index(),
+                calls,
+                instrs: cost.instrs,
+                devirtualized_calls,
+                devirtualized_density: devirtualized_calls as f64 * 1000.0
+                    / std::cmp::max(cost.instrs, 1) as f64,
+            })
+        })
+        .collect();
+    functions.sort_by(|a, b| b.calls.cmp(&a.calls).then(a.func_index.cmp(&b.func_index)));
+    VvHints {
+        version: HINTS_VERSION,
+        hot_threshold,
+        functions,
+    }
+}
+
+impl VvHints {
+    pub fn write(&self, path: &str) {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        std::fs::write(path, json).unwrap();
+    }
+}
@@ -0,0 +1,34 @@
+use std::process::Command;
+
+// Binaryen's optimizer, overridable for non-standard installs
+fn wasm_opt_binary() -> String {
+    std::env::var("WASM_OPT").unwrap_or_else(|_| "wasm-opt".to_string())
+}
+
+/*
+ * Run Binaryen's wasm-opt over `path` in place. Devirtualized calls are
+ * direct calls wasm-opt can inline, and callsites turned into `unreachable`
+ * leave dead code behind, so a pass over the optimized binary usually pays
+ * for itself. Returns the size before and after.
+ */
+pub fn run_wasm_opt(path: &str, args: &[String]) -> Result<(usize, usize), String> {
+    let before = std::fs::metadata(path).map_err(|e| e.to_string())?.len() as usize;
+    let binary = wasm_opt_binary();
+    let output = Command::new(&binary)
+        .arg(path)
+        .args(args)
+        .arg("-o")
+        .arg(path)
+        .output()
+        .map_err(|e| format!("failed to run {}: {}", binary, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} exited with {}:\n{}",
+            binary,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    let after = std::fs::metadata(path).map_err(|e| e.to_string())?.len() as usize;
+    Ok((before, after))
+}
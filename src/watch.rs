@@ -0,0 +1,78 @@
+use crate::manifest::fingerprint;
+use std::time::Duration;
+
+/*
+ * --watch: keep the process around and redo the run whenever one of the
+ * watched files (the input modules, and the profile when optimizing)
+ * changes. Files are polled by content, so a build that rewrites a module
+ * without changing it doesn't trigger a run. A build writes its output in
+ * several steps, so a change only counts once the files have stayed the
+ * same for a whole poll interval. Every run is a fresh process sharing one
+ * --analysis-cache (see run_args), so it only analyzes the functions the
+ * change touched.
+ */
+pub fn watch(paths: &[String], interval: Duration, mut run: impl FnMut()) {
+    let mut last = snapshot(paths);
+    run();
+    println!("Watching {} for changes", paths.join(", "));
+    loop {
+        std::thread::sleep(interval);
+        let current = snapshot(paths);
+        if current == last {
+            continue;
+        }
+        std::thread::sleep(interval);
+        if snapshot(paths) != current || current.iter().any(Option::is_none) {
+            continue;
+        }
+        for (path, (before, after)) in paths.iter().zip(last.iter().zip(&current)) {
+            if before != after {
+                println!("{} changed", path);
+            }
+        }
+        last = current;
+        run();
+    }
+}
+
+// The fingerprint of each file, None while it's missing (some builds delete the output first)
+fn snapshot(paths: &[String]) -> Vec<Option<String>> {
+    paths
+        .iter()
+        .map(|path| std::fs::read(path).ok().map(|bytes| fingerprint(&bytes)))
+        .collect()
+}
+
+// Where the runs of a watch cache the fastcall analysis unless --analysis-cache says otherwise
+pub fn analysis_cache_path(output: &str) -> String {
+    format!("{}.analysis-cache.json", output)
+}
+
+/*
+ * The command line of one run: the arguments of the watching process
+ * without --watch, and with `--analysis-cache {analysis_cache}` unless
+ * they already pick a cache.
+ */
+pub fn run_args(args: &[String], analysis_cache: &str) -> Vec<String> {
+    let mut rest = vec![];
+    let mut cached = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--watch" => (),
+            "--watch-interval" => {
+                args.next();
+            }
+            _ if arg.starts_with("--watch-interval=") => (),
+            _ => {
+                cached |= arg == "--analysis-cache" || arg.starts_with("--analysis-cache=");
+                rest.push(arg.clone());
+            }
+        }
+    }
+    if !cached {
+        rest.push("--analysis-cache".to_string());
+        rest.push(analysis_cache.to_string());
+    }
+    rest
+}
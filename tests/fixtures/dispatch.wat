@@ -0,0 +1,28 @@
+;; A small interpreter-style dispatcher: two handler signatures, callsites in
+;; nested blocks/loops so the callsite numbering order is exercised.
+(module
+  (type $unary (func (param i32) (result i32)))
+  (type $binary (func (param i32 i32) (result i32)))
+  (table 4 funcref)
+  (elem (i32.const 0) $neg $not $add $sub)
+  (func $neg (type $unary) (i32.sub (i32.const 0) (local.get 0)))
+  (func $not (type $unary) (i32.eqz (local.get 0)))
+  (func $add (type $binary) (i32.add (local.get 0) (local.get 1)))
+  (func $sub (type $binary) (i32.sub (local.get 0) (local.get 1)))
+  (func $dispatch (export "dispatch") (param $op i32) (param $n i32) (result i32)
+    (local $acc i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (local.get $n)))
+        (if (i32.lt_u (local.get $op) (i32.const 2))
+          (then
+            (local.set $acc
+              (call_indirect (type $unary) (local.get $acc) (local.get $op))))
+          (else
+            (local.set $acc
+              (call_indirect (type $binary) (local.get $acc) (local.get $n) (local.get $op)))))
+        (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+        (br $next)))
+    (call_indirect (type $unary) (local.get $acc) (i32.const 0)))
+  (func $_start (export "_start")
+    (drop (call $dispatch (i32.const 2) (i32.const 10)))))
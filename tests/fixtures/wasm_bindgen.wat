@@ -0,0 +1,22 @@
+;; The shape of wasm-bindgen output: an externref table next to the function
+;; table (initialized with a null segment), JS imports, and exported glue
+;; whose callsites are left alone.
+(module
+  (type $closure (func (param i32 i32) (result i32)))
+  (import "wbg" "__wbg_log_0a1b2c" (func $__wbg_log (param externref)))
+  (import "__wbindgen_placeholder__" "__wbindgen_describe" (func $__wbindgen_describe (param i32)))
+  (table $funcs 3 funcref)
+  (table $heap (export "__wbindgen_export_0") 128 externref)
+  (memory (export "memory") 1)
+  (elem (table $funcs) (i32.const 1) func $double $triple)
+  (elem (table $heap) (i32.const 0) externref (ref.null extern) (ref.null extern))
+  (func $double (type $closure) (i32.mul (local.get 0) (i32.const 2)))
+  (func $triple (type $closure) (i32.mul (local.get 0) (i32.const 3)))
+  (func $apply (export "apply") (param $f i32) (param $x i32) (result i32)
+    (call_indirect $funcs (type $closure) (local.get $x) (i32.const 0) (local.get $f)))
+  (func $__externref_table_alloc (export "__externref_table_alloc") (result i32)
+    (table.grow $heap (ref.null extern) (i32.const 1)))
+  (func $__wbindgen_closure_call (export "__wbindgen_closure_call") (param $f i32) (param $x i32) (result i32)
+    (call_indirect $funcs (type $closure) (local.get $x) (i32.const 0) (local.get $f)))
+  (func $_start (export "_start")
+    (drop (call $apply (i32.const 1) (i32.const 21)))))
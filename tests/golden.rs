@@ -0,0 +1,39 @@
+use std::path::Path;
+use vv_profiler::pipeline::{self, InstrumentOptions};
+
+/*
+ * Instrument every .wat file in tests/fixtures and compare the manifest against the
+ * checked in `*.manifest.json` next to it. Run with VV_UPDATE_GOLDEN=1 to
+ * rewrite the golden files after an intended change.
+ */
+#[test]
+fn manifests_match_golden_files() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let update = std::env::var("VV_UPDATE_GOLDEN").is_ok();
+    let mut checked = 0;
+    for entry in std::fs::read_dir(&fixtures).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|e| e.to_str()) != Some("wat") {
+            continue;
+        }
+        let wasm = wat::parse_file(&path).unwrap();
+        let options = InstrumentOptions {
+            window: 2,
+            self_check: true,
+            ..InstrumentOptions::default()
+        };
+        let output = pipeline::run(&wasm, None, &options).unwrap();
+        let actual = serde_json::to_string_pretty(&output.manifest).unwrap() + "\n";
+
+        let golden = path.with_extension("manifest.json");
+        if update {
+            std::fs::write(&golden, &actual).unwrap();
+        } else {
+            let expected = std::fs::read_to_string(&golden)
+                .unwrap_or_else(|_| panic!("missing {:?} (run with VV_UPDATE_GOLDEN=1)", golden));
+            assert_eq!(actual, expected, "manifest for {:?} changed", path);
+        }
+        checked += 1;
+    }
+    assert!(checked > 0, "no fixtures found in {:?}", fixtures);
+}
@@ -0,0 +1,4282 @@
+use std::collections::{HashMap, HashSet};
+use vv_profiler::analysiscache::AnalysisCache;
+use vv_profiler::callgraph::{BitSet, CallGraph};
+use vv_profiler::callsitelock::{lock_keys, CallsiteLock};
+use vv_profiler::fastcalls::compute_slowcalls;
+use vv_profiler::importclasses::{CostClass, ImportClasses};
+use vv_profiler::linked;
+use vv_profiler::meta::tool_runs;
+use vv_profiler::pipeline::{self, InstrumentOptions};
+use vv_profiler::profilemap::function_table;
+use vv_profiler::reachability::{reachable_functions, Root, ALL_ROOTS};
+use vv_profiler::schema::ProfileFormat;
+use vv_profiler::tableimage::TableImage;
+use vv_profiler::testsupport::*;
+use vv_profiler::Profile;
+use walrus::ir::Instr;
+use walrus::Module;
+
+fn instrument(builder: &ModuleBuilder, options: &InstrumentOptions) -> (Module, pipeline::Output) {
+    let wasm = wat::parse_str(builder.to_wat()).unwrap();
+    let output = pipeline::run(&wasm, None, options).unwrap();
+    (Module::from_buffer(&output.wasm).unwrap(), output)
+}
+
+fn optimize(builder: &ModuleBuilder, map: &[(usize, Vec<i64>)]) -> Module {
+    let wasm = wat::parse_str(builder.to_wat()).unwrap();
+    let profile = Profile {
+        map: map.iter().cloned().collect::<HashMap<_, _>>(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    Module::from_buffer(&output.wasm).unwrap()
+}
+
+fn is_call_indirect(instr: &Instr) -> bool {
+    matches!(instr, Instr::CallIndirect(_))
+}
+
+// Two targets of type i32 -> i32, and `callsites` call_indirects of that type in `run`
+fn single_type(callsites: usize) -> ModuleBuilder {
+    let mut builder = ModuleBuilder::new();
+    builder.target("a", &["i32"], &["i32"]);
+    builder.target("b", &["i32"], &["i32"]);
+    builder.caller("run", &["i32"], &["i32"], callsites);
+    builder
+}
+
+#[test]
+fn instrument_replaces_every_callsite_with_a_stub_call() {
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (module, output) = instrument(&single_type(3), &options);
+
+    let stubs = instrument_stubs(&module);
+    assert_eq!(stubs.len(), 1);
+    let stub = vv_profiler::report::func_name(&module, stubs[0]);
+    assert_eq!(
+        direct_calls(&module, "run"),
+        vec![stub.clone(), stub.clone(), stub]
+    );
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 0);
+    assert_eq!(output.manifest.callsites.len(), 3);
+}
+
+#[test]
+fn instrument_exports_a_global_per_slot() {
+    let options = InstrumentOptions {
+        window: 2,
+        ..InstrumentOptions::default()
+    };
+    let (module, _) = instrument(&single_type(2), &options);
+
+    let exports = export_names(&module);
+    for name in &[
+        "indirect",
+        "slowcalls",
+        "profiling_global_0_0",
+        "profiling_global_0_1",
+        "profiling_global_1_0",
+        "profiling_global_1_1",
+    ] {
+        assert!(
+            exports.contains(&name.to_string()),
+            "missing export {}",
+            name
+        );
+    }
+    assert!(!exports.contains(&"profiling_global_2_0".to_string()));
+}
+
+#[test]
+fn instrument_creates_one_stub_per_call_indirect_type() {
+    let mut builder = single_type(1);
+    builder.target("c", &["i64"], &[]);
+    builder.caller("run64", &["i64"], &[], 2);
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (module, output) = instrument(&builder, &options);
+
+    assert_eq!(instrument_stubs(&module).len(), 2);
+    let params: Vec<Vec<String>> = output
+        .manifest
+        .callsites
+        .iter()
+        .map(|c| c.params.clone())
+        .collect();
+    assert_eq!(params.iter().filter(|p| *p == &["i64"]).count(), 2);
+}
+
+#[test]
+fn optimize_devirtualizes_monomorphic_callsites() {
+    let module = optimize(&single_type(1), &[(0, vec![1])]);
+
+    let stubs = optimize_stubs(&module);
+    assert_eq!(stubs.len(), 1);
+    let stub = vv_profiler::report::func_name(&module, stubs[0]);
+    assert_eq!(direct_calls(&module, "run"), vec![stub.clone()]);
+    assert_eq!(direct_calls(&module, &stub), vec!["b".to_string()]);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 0);
+}
+
+#[test]
+fn optimize_handles_unexecuted_and_megamorphic_callsites() {
+    // callsite 0 overflowed its window, callsite 1 never ran (it goes last,
+    // walrus drops the dead code after an `unreachable` when re-parsing)
+    let module = optimize(&single_type(2), &[(0, vec![-2]), (1, vec![-1])]);
+
+    assert!(optimize_stubs(&module).is_empty());
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 1);
+    assert_eq!(
+        count_instrs(&module, "run", |i| matches!(i, Instr::Unreachable(_))),
+        1
+    );
+}
+
+#[test]
+fn block_counters_are_exported() {
+    let options = InstrumentOptions {
+        block_counters: true,
+        ..InstrumentOptions::default()
+    };
+    let (module, _) = instrument(&single_type(1), &options);
+
+    let exports = export_names(&module);
+    assert!(exports.iter().any(|e| e.starts_with("profiling_block_")));
+}
+
+fn instrument_wat(wat: &str) -> Result<Vec<u8>, pipeline::Error> {
+    let wasm = wat::parse_str(wat).unwrap();
+    pipeline::instrument_bytes(&wasm, &InstrumentOptions::default())
+}
+
+#[test]
+fn instrument_bytes_accepts_modules_without_start_or_table() {
+    instrument_wat("(module (func (export \"f\") (result i32) (i32.const 1)))").unwrap();
+    instrument_wat("(module (func $_start (export \"_start\")))").unwrap();
+    instrument_wat(
+        "(module
+           (table 2 funcref)
+           (func $_start (export \"_start\")))",
+    )
+    .unwrap();
+}
+
+#[test]
+fn instrument_bytes_reports_errors_instead_of_panicking() {
+    assert!(matches!(
+        pipeline::instrument_bytes(b"not wasm", &InstrumentOptions::default()),
+        Err(pipeline::Error::Parse(_))
+    ));
+    let wasm = wat::parse_str("(module)").unwrap();
+    let options = InstrumentOptions {
+        window: 51,
+        ..InstrumentOptions::default()
+    };
+    assert!(matches!(
+        pipeline::instrument_bytes(&wasm, &options),
+        Err(pipeline::Error::InvalidOptions(_))
+    ));
+
+    // A self-check failure is an error too, not a panic
+    let original = Module::from_buffer(&wat::parse_str(single_type(1).to_wat()).unwrap()).unwrap();
+    let skipped = HashSet::new();
+    let uninstrumented = wat::parse_str(single_type(1).to_wat()).unwrap();
+    let failure = vv_profiler::selfcheck::self_check(&original, &uninstrumented, false, &skipped);
+    assert!(failure.unwrap_err().contains("callsite 0 was not instrumented"));
+    assert!(vv_profiler::selfcheck::self_check(&original, b"junk", false, &skipped).is_err());
+}
+
+#[test]
+fn self_check_failures_come_back_from_the_threaded_emit() {
+    // The self-check takes anything named like a stub for one, and this
+    // one has no table index param
+    let wat = "(module
+        (type $t (func (param i32) (result i32)))
+        (table 1 funcref)
+        (elem (i32.const 0) $a)
+        (func $a (type $t) local.get 0)
+        (func $indirect_call_stub_user (export \"user\") (param f32))
+        (func $run (export \"run\") (param i32) (result i32)
+            local.get 0
+            local.get 0
+            call_indirect (type $t)))";
+    let wasm = wat::parse_str(wat).unwrap();
+    let options = InstrumentOptions {
+        self_check: true,
+        verbose: true,
+        ..InstrumentOptions::default()
+    };
+    match pipeline::run(&wasm, Some(Profile::default()), &options) {
+        Err(pipeline::Error::SelfCheck(e)) => {
+            assert!(e.contains("missing the trailing table index param"), "{}", e)
+        }
+        other => panic!("expected a self-check failure, got {:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn background_writes_replace_the_output_whole() {
+    let dir = std::env::temp_dir().join(format!("vv-outputs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("out.wasm");
+    std::fs::write(&path, b"previous run").unwrap();
+    let path = path.to_str().unwrap();
+
+    let wasm = vec![7u8; 9 << 20];
+    let write = vv_profiler::outputs::write_in_background(path, wasm.clone());
+    // Until the rename, readers see the previous output or the new one, never a prefix
+    let seen = std::fs::read(path).unwrap();
+    assert!(seen == b"previous run" || seen == wasm);
+    write.finish(false);
+    assert_eq!(std::fs::read(path).unwrap(), wasm);
+    let leftovers: Vec<_> = std::fs::read_dir(&dir).unwrap().collect();
+    assert_eq!(leftovers.len(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn optimize_resolves_targets_in_imported_tables() {
+    let wat = "(module
+       (type $t (func (param i32) (result i32)))
+       (import \"env\" \"table\" (table 4 funcref))
+       (elem (i32.const 2) $a $b)
+       (func $a (type $t) (i32.const 0))
+       (func $b (type $t) (i32.const 1))
+       (func $run (export \"run\") (param $idx i32)
+         (drop (call_indirect (type $t) (i32.const 0) (local.get $idx))))
+       (func $_start (export \"_start\")))";
+    let wasm = wat::parse_str(wat).unwrap();
+    let profile = Profile {
+        map: vec![(0, vec![3])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &InstrumentOptions::default()).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    let stubs = optimize_stubs(&module);
+    assert_eq!(stubs.len(), 1);
+    let stub = vv_profiler::report::func_name(&module, stubs[0]);
+    assert_eq!(direct_calls(&module, &stub), vec!["b".to_string()]);
+}
+
+// Two structurally equal types, the call_indirect uses the second one
+const DUPLICATE_TYPES: &str = "(module
+   (type $t0 (func (param i32) (result i32)))
+   (type $t1 (func (param i32) (result i32)))
+   (table 2 funcref)
+   (elem (i32.const 0) $a $b)
+   (func $a (type $t0) (i32.const 0))
+   (func $b (type $t1) (i32.const 1))
+   (func $run (export \"run\") (param $idx i32)
+     (drop (call_indirect (type $t1) (i32.const 0) (local.get $idx))))
+   (func $_start (export \"_start\")))";
+
+#[test]
+fn duplicate_types_are_handled() {
+    let wasm = wat::parse_str(DUPLICATE_TYPES).unwrap();
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(instrument_stubs(&module).len(), 1);
+
+    let profile = Profile {
+        map: vec![(0, vec![0])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 0);
+}
+
+// call_indirects in both `_start` and the start section function
+const ENTRY_CALLSITES: &str = "(module
+   (type $t (func (param i32) (result i32)))
+   (table 1 funcref)
+   (elem (i32.const 0) $a)
+   (global $sel (mut i32) (i32.const 0))
+   (func $a (type $t) (i32.const 0))
+   (func $init
+     (drop (call_indirect (type $t) (i32.const 0) (global.get $sel))))
+   (start $init)
+   (func $_start (export \"_start\")
+     (drop (call_indirect (type $t) (i32.const 0) (global.get $sel))))
+   (func $run (export \"run\") (param $idx i32)
+     (drop (call_indirect (type $t) (i32.const 0) (local.get $idx)))))";
+
+#[test]
+fn entry_function_callsites_are_instrumented() {
+    let wasm = wat::parse_str(ENTRY_CALLSITES).unwrap();
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    assert_eq!(output.manifest.callsites.len(), 3);
+    for name in &["init", "_start", "run"] {
+        assert_eq!(count_instrs(&module, name, is_call_indirect), 0);
+    }
+    assert!(export_names(&module).contains(&"profiling_global_2_0".to_string()));
+}
+
+#[test]
+fn entry_function_callsites_can_be_skipped() {
+    let wasm = wat::parse_str(ENTRY_CALLSITES).unwrap();
+    let options = InstrumentOptions {
+        self_check: true,
+        skip_entry_callsites: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    // Ids stay the same, the skipped callsites just aren't profiled
+    assert_eq!(output.manifest.callsites.len(), 3);
+    assert_eq!(count_instrs(&module, "init", is_call_indirect), 1);
+    assert_eq!(count_instrs(&module, "_start", is_call_indirect), 1);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 0);
+    let exports = export_names(&module);
+    let profiled: Vec<&String> = exports
+        .iter()
+        .filter(|e| e.starts_with("profiling_global_"))
+        .collect();
+    assert_eq!(profiled, vec!["profiling_global_2_0"]);
+
+    // An optimize run leaves them alone even if the profile mentions them
+    let profile = Profile {
+        map: vec![(0, vec![-1]), (1, vec![-1]), (2, vec![0])]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(count_instrs(&module, "init", is_call_indirect), 1);
+    assert_eq!(count_instrs(&module, "_start", is_call_indirect), 1);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 0);
+}
+
+#[test]
+fn tiny_functions_are_left_uninstrumented() {
+    let mut builder = single_type(1);
+    // `run` has 4 instructions, `big` has 12
+    builder.caller("big", &["i32"], &["i32"], 3);
+    let options = InstrumentOptions {
+        self_check: true,
+        min_func_size: 5,
+        ..InstrumentOptions::default()
+    };
+    let (module, output) = instrument(&builder, &options);
+
+    assert_eq!(output.manifest.callsites.len(), 4);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 1);
+    assert_eq!(count_instrs(&module, "big", is_call_indirect), 0);
+    let exports = export_names(&module);
+    assert!(!exports.contains(&"profiling_global_0_0".to_string()));
+    assert!(exports.contains(&"profiling_global_1_0".to_string()));
+}
+
+#[test]
+fn reinstrument_profiles_the_optimized_binary() {
+    let wasm = wat::parse_str(single_type(2).to_wat()).unwrap();
+    let profile = Profile {
+        map: vec![(0, vec![1]), (1, vec![-2])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (optimized, instrumented) =
+        pipeline::run_and_reinstrument(&wasm, profile, &options).unwrap();
+
+    // Only the megamorphic callsite is still indirect, so it's all that's left to profile
+    assert_eq!(
+        instrumented.manifest.fingerprint,
+        Some(vv_profiler::manifest::fingerprint(&optimized.wasm))
+    );
+    assert_eq!(instrumented.manifest.callsites.len(), 1);
+    let module = Module::from_buffer(&instrumented.wasm).unwrap();
+    assert_eq!(instrument_stubs(&module).len(), 1);
+    assert_eq!(optimize_stubs(&module).len(), 1);
+}
+
+#[test]
+fn retained_callsites_stay_indirect() {
+    let wasm = wat::parse_str(single_type(2).to_wat()).unwrap();
+    let profile = Profile {
+        map: vec![(0, vec![1]), (1, vec![1])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        retain_callsites: vec!["run#1".to_string()].into_iter().collect(),
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 1);
+    assert_eq!(direct_calls(&module, "run").len(), 1);
+}
+
+fn force(builder: &ModuleBuilder, key: &str, target: &str) -> Result<Module, pipeline::Error> {
+    let wasm = wat::parse_str(builder.to_wat()).unwrap();
+    let profile = Profile {
+        map: vec![(0, vec![-1])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        force_devirt: vec![(key.to_string(), target.to_string())],
+        ..InstrumentOptions::default()
+    };
+    pipeline::run(&wasm, Some(profile), &options).map(|o| Module::from_buffer(&o.wasm).unwrap())
+}
+
+#[test]
+fn force_devirt_overrides_the_profile() {
+    let module = force(&single_type(1), "run#0", "a").unwrap();
+    let stubs = optimize_stubs(&module);
+    assert_eq!(stubs.len(), 1);
+    let stub = vv_profiler::report::func_name(&module, stubs[0]);
+    assert_eq!(direct_calls(&module, &stub), vec!["a".to_string()]);
+}
+
+#[test]
+fn force_devirt_validates_its_target() {
+    let mut builder = single_type(1);
+    builder.target("c", &["i64"], &[]);
+    for (key, target) in &[("run#1", "a"), ("run#0", "nope"), ("run#0", "c")] {
+        assert!(matches!(
+            force(&builder, key, target),
+            Err(pipeline::Error::InvalidOptions(_))
+        ));
+    }
+}
+
+#[test]
+fn optimize_retains_callsites_with_targets_of_another_type() {
+    use vv_profiler::callsites::enumerate_callsites;
+    use vv_profiler::typecompat::{type_match, TypeMatch};
+
+    let wasm = wat::parse_str(DUPLICATE_TYPES).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let expected = enumerate_callsites(&module, function_table(&module, None))[0].ty;
+    let ty = |name: &str| module.funcs.get(module.funcs.by_name(name).unwrap()).ty();
+    assert_eq!(type_match(&module, ty("b"), expected), TypeMatch::Declared);
+    // walrus may merge the duplicate into the callsite's type
+    assert_ne!(
+        type_match(&module, ty("a"), expected),
+        TypeMatch::Incompatible
+    );
+    assert_eq!(
+        type_match(&module, ty("run"), expected),
+        TypeMatch::Incompatible
+    );
+
+    // A stale profile has the callsite reach `c`, which would trap
+    let mut builder = single_type(1);
+    let c = builder.target("c", &["i64"], &[]);
+    let module = optimize(&builder, &[(0, vec![c as i64])]);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 1);
+    assert!(optimize_stubs(&module).is_empty());
+
+    let wasm = wat::parse_str(builder.to_wat()).unwrap();
+    let run = |slots: Vec<i64>| {
+        let profile = Profile {
+            map: vec![(0, slots)].into_iter().collect(),
+            ..Profile::default()
+        };
+        pipeline::run(&wasm, Some(profile), &InstrumentOptions::default()).unwrap()
+    };
+    let retained = run(vec![c as i64]);
+    assert_eq!(retained.decisions[0].disposition, "indirect");
+    assert_eq!(
+        retained.decisions[0].reason,
+        "no observed target has the callsite's type"
+    );
+    // Only the mismatched target is dropped
+    let output = run(vec![1, c as i64]);
+    assert_eq!(output.decisions[0].disposition, "devirtualized");
+    assert_eq!(output.decisions[0].targets, ["b"]);
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let stub = vv_profiler::report::func_name(&module, optimize_stubs(&module)[0]);
+    assert_eq!(direct_calls(&module, &stub), ["b"]);
+}
+
+#[test]
+fn overlapping_element_segments_resolve_to_the_last_write() {
+    let wat = r#"
+        (module
+          (type $t (func))
+          (table 4 funcref)
+          (elem (i32.const 0) $a $b $c)
+          (elem (i32.const 1) $d)
+          (elem (i32.const 2) $c)
+          (func $a)
+          (func $b)
+          (func $c)
+          (func $d)
+          (func $run (export "run") (param i32) (call_indirect (type $t) (local.get 0))))
+    "#;
+    let wasm = wat::parse_str(wat).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let table = module.tables.main_function_table().unwrap().unwrap();
+    let image = TableImage::build(&module, table);
+    assert_eq!(image.get(1), Some(function(&module, "d")));
+    assert_eq!(
+        vv_profiler::profilemap::resolve_in_table(&module, table, 1),
+        Some(function(&module, "d"))
+    );
+    assert_eq!(image.slot_of(function(&module, "c")), Some(2));
+    // Writing the same function again isn't a conflict
+    assert_eq!(image.conflicts.len(), 1);
+    assert_eq!(image.conflicts[0].index, 1);
+    assert_eq!(
+        image.conflicts[0].earlier,
+        (0, Some(function(&module, "b")))
+    );
+    assert_eq!(image.conflicts[0].later, (1, Some(function(&module, "d"))));
+
+    let profile = Profile {
+        map: vec![(0, vec![1])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let stubs = optimize_stubs(&module);
+    assert_eq!(stubs.len(), 1);
+    let stub = vv_profiler::report::func_name(&module, stubs[0]);
+    assert_eq!(direct_calls(&module, &stub), ["d"]);
+}
+
+#[test]
+fn optimize_retains_callsites_with_out_of_range_targets() {
+    // A target past the end of the table, one that is negative, and a valid
+    // target recorded after an empty slot
+    let module = optimize(
+        &single_type(3),
+        &[(0, vec![99]), (1, vec![-7]), (2, vec![-1, 1])],
+    );
+
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 2);
+    let stubs = optimize_stubs(&module);
+    assert_eq!(stubs.len(), 1);
+    let stub = vv_profiler::report::func_name(&module, stubs[0]);
+    assert_eq!(direct_calls(&module, &stub), vec!["b".to_string()]);
+    assert_eq!(
+        count_instrs(&module, &stub, |i| matches!(
+            i,
+            Instr::Const(walrus::ir::Const {
+                value: walrus::ir::Value::I32(1)
+            })
+        )),
+        1
+    );
+}
+
+#[test]
+fn emitted_binaries_record_the_runs_that_made_them() {
+    let wasm = wat::parse_str(single_type(2).to_wat()).unwrap();
+    let profile = Profile {
+        map: vec![(0, vec![1]), (1, vec![-2])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let fingerprint = vv_profiler::meta::profile_fingerprint(&profile);
+    let (optimized, instrumented) =
+        pipeline::run_and_reinstrument(&wasm, profile, &InstrumentOptions::default()).unwrap();
+
+    let runs = tool_runs(&Module::from_buffer(&instrumented.wasm).unwrap());
+    let modes: Vec<&str> = runs.iter().map(|r| r.mode.as_str()).collect();
+    assert_eq!(modes, vec!["optimize", "instrument"]);
+    assert_eq!(runs[0].profile_fingerprint, Some(fingerprint));
+    assert_eq!(
+        runs[0].input_fingerprint,
+        vv_profiler::manifest::fingerprint(&wasm)
+    );
+    assert_eq!(
+        runs[1].input_fingerprint,
+        vv_profiler::manifest::fingerprint(&optimized.wasm)
+    );
+    assert_eq!(runs[1].profile_fingerprint, None);
+}
+
+#[test]
+fn export_prefix_applies_to_every_added_export() {
+    let options = InstrumentOptions {
+        block_counters: true,
+        export_prefix: "vv_".to_string(),
+        ..InstrumentOptions::default()
+    };
+    let (module, output) = instrument(&single_type(1), &options);
+
+    assert_eq!(output.manifest.export_prefix, "vv_");
+    let added: Vec<String> = export_names(&module)
+        .into_iter()
+        .filter(|name| !["run", "_start"].contains(&name.as_str()))
+        .collect();
+    assert!(added.contains(&"vv_profiling_global_0_0".to_string()));
+    assert!(
+        added.iter().all(|name| name.starts_with("vv_")),
+        "{:?}",
+        added
+    );
+}
+
+// Position of each exported global in the module's global index space
+fn exported_global_indices(module: &Module) -> HashMap<String, u32> {
+    let globals: Vec<walrus::GlobalId> = module.globals.iter().map(|g| g.id()).collect();
+    module
+        .exports
+        .iter()
+        .filter_map(|e| match e.item {
+            walrus::ExportItem::Global(g) => Some((
+                e.name.clone(),
+                globals.iter().position(|id| *id == g).unwrap() as u32,
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn compact_exports_describe_the_slot_globals() {
+    let options = InstrumentOptions {
+        window: 2,
+        ..InstrumentOptions::default()
+    };
+    let (module, _) = instrument(&single_type(2), &options);
+    let expected = exported_global_indices(&module);
+
+    let options = InstrumentOptions {
+        window: 2,
+        compact_exports: true,
+        ..InstrumentOptions::default()
+    };
+    let (module, output) = instrument(&single_type(2), &options);
+    let exports = export_names(&module);
+    assert!(!exports.iter().any(|e| e.starts_with("profiling_global_")));
+    assert!(exports.contains(&"profiling_callsites".to_string()));
+    assert!(exports.contains(&"profiling_descriptors".to_string()));
+    assert!(output.manifest.descriptors.is_some());
+
+    let data = module.data.iter().last().unwrap();
+    let words: Vec<u32> = data
+        .value
+        .chunks(4)
+        .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+        .collect();
+    assert_eq!(&words[..3], &[1, 2, 2]);
+    for callsite in 0..2 {
+        for slot in 0..2 {
+            assert_eq!(
+                words[3 + callsite * 2 + slot],
+                expected[&format!("profiling_global_{}_{}", callsite, slot)]
+            );
+        }
+    }
+}
+
+#[test]
+fn max_overhead_rejects_expensive_instrumentation() {
+    use vv_profiler::overhead::Recording;
+
+    let wasm = wat::parse_str(single_type(4).to_wat()).unwrap();
+    let estimate =
+        |window| vv_profiler::overhead::estimate(4, window, 1, Recording::SlotGlobals, 0, 0);
+    assert!(estimate(4).per_indirect_call > estimate(1).per_indirect_call);
+
+    let options = InstrumentOptions {
+        max_overhead: Some(estimate(1).per_indirect_call),
+        ..InstrumentOptions::default()
+    };
+    pipeline::run(&wasm, None, &options).unwrap();
+    let options = InstrumentOptions {
+        window: 4,
+        ..options
+    };
+    assert!(matches!(
+        pipeline::run(&wasm, None, &options),
+        Err(pipeline::Error::OverBudget(_))
+    ));
+}
+
+#[test]
+fn max_size_increase_names_the_biggest_contributors() {
+    let wasm = wat::parse_str(single_type(4).to_wat()).unwrap();
+    let options = InstrumentOptions {
+        max_size_increase: Some(50.0),
+        ..InstrumentOptions::default()
+    };
+    match pipeline::run(&wasm, None, &options) {
+        Err(pipeline::Error::OverBudget(msg)) => {
+            assert!(msg.contains("added functions indirect_stub_*"), "{}", msg);
+            assert!(msg.contains("exports"), "{}", msg);
+        }
+        other => panic!("expected OverBudget, got {:?}", other.err()),
+    }
+    pipeline::run(
+        &wasm,
+        None,
+        &InstrumentOptions {
+            size_warn_only: true,
+            ..options.clone()
+        },
+    )
+    .unwrap();
+    pipeline::run(
+        &wasm,
+        None,
+        &InstrumentOptions {
+            max_size_increase: Some(100000.0),
+            ..options
+        },
+    )
+    .unwrap();
+}
+
+#[test]
+fn unsupported_proposals_fail_before_parsing() {
+    let wasm = wat::parse_str(
+        r#"(module
+            (tag $e (param i32))
+            (func (export "run") (param i32)
+                local.get 0
+                throw $e))"#,
+    )
+    .unwrap();
+    match pipeline::run(&wasm, None, &InstrumentOptions::default()) {
+        Err(pipeline::Error::Unsupported(msg)) => assert!(msg.contains("exceptions")),
+        other => panic!("expected Unsupported, got {:?}", other.err()),
+    }
+
+    let shared = wat::parse_str(single_type(1).to_wat().replacen(
+        "(module",
+        "(module (memory 1 1 shared)",
+        1,
+    ))
+    .unwrap();
+    assert!(matches!(
+        pipeline::run(&shared, None, &InstrumentOptions::default()),
+        Err(pipeline::Error::Unsupported(_))
+    ));
+    let options = InstrumentOptions {
+        allow_shared_memory: true,
+        ..InstrumentOptions::default()
+    };
+    pipeline::run(&shared, None, &options).unwrap();
+}
+
+#[test]
+fn enable_feature_restricts_accepted_proposals() {
+    let simd = wat::parse_str(
+        r#"(module
+            (func (export "run") (param i32) (result i32)
+                v128.const i32x4 0 0 0 0
+                drop
+                local.get 0))"#,
+    )
+    .unwrap();
+    pipeline::run(&simd, None, &InstrumentOptions::default()).unwrap();
+
+    let options = InstrumentOptions {
+        enable_features: vec!["mvp".to_string()],
+        ..InstrumentOptions::default()
+    };
+    match pipeline::run(&simd, None, &options) {
+        Err(pipeline::Error::Unsupported(msg)) => {
+            assert!(msg.contains("--enable-feature simd"))
+        }
+        other => panic!("expected Unsupported, got {:?}", other.err()),
+    }
+    let options = InstrumentOptions {
+        enable_features: vec!["simd".to_string()],
+        ..InstrumentOptions::default()
+    };
+    pipeline::run(&simd, None, &options).unwrap();
+}
+
+#[test]
+fn vv_hints_list_hot_functions_with_devirtualized_calls() {
+    let wasm = wat::parse_str(single_type(2).to_wat()).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let funcs: Vec<_> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let blocks = vv_profiler::blockcounters::enumerate_blocks(&module, &funcs);
+    let entry = |name: &str| {
+        blocks
+            .iter()
+            .position(|(f, _)| module.funcs.get(*f).name.as_deref() == Some(name))
+            .unwrap()
+    };
+    let profile = Profile {
+        map: vec![(0, vec![0]), (1, vec![-2])].into_iter().collect(),
+        blocks: vec![(entry("run"), 5000), (entry("a"), 5000), (entry("b"), 10)]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+
+    let hints = vv_profiler::vvhints::compute_hints(&module, function_table(&module, None), &profile, 1000);
+    let names: Vec<&str> = hints.functions.iter().map(|f| f.func.as_str()).collect();
+    assert_eq!(names.len(), 2);
+    assert!(names.contains(&"run") && names.contains(&"a"));
+    let run = hints.functions.iter().find(|f| f.func == "run").unwrap();
+    assert_eq!(run.calls, 5000);
+    assert_eq!(run.devirtualized_calls, 1);
+    assert!(run.devirtualized_density > 0.0);
+}
+
+#[test]
+fn compilation_hints_precede_the_code_section() {
+    use vv_profiler::compilationhints::COMPILATION_PRIORITY_SECTION;
+    use wasmparser::{BinaryReader, Parser, Payload};
+
+    let wasm = wat::parse_str(single_type(2).to_wat()).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let funcs: Vec<_> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let blocks = vv_profiler::blockcounters::enumerate_blocks(&module, &funcs);
+    let entry = |name: &str| {
+        blocks
+            .iter()
+            .position(|(f, _)| module.funcs.get(*f).name.as_deref() == Some(name))
+            .unwrap()
+    };
+    let profile = Profile {
+        map: vec![(0, vec![0]), (1, vec![-2])].into_iter().collect(),
+        blocks: vec![(entry("run"), 5000), (entry("a"), 10), (entry("b"), 0)]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        compilation_hints: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    let mut hints: Vec<(String, Vec<u8>)> = vec![];
+    let mut seen_code = false;
+    for payload in Parser::new(0).parse_all(&output.wasm) {
+        match payload.unwrap() {
+            Payload::CodeSectionStart { .. } => seen_code = true,
+            Payload::CustomSection(reader) if reader.name() == COMPILATION_PRIORITY_SECTION => {
+                assert!(!seen_code);
+                let mut section = BinaryReader::new(reader.data(), 0);
+                for _ in 0..section.read_var_u32().unwrap() {
+                    let func = section.read_var_u32().unwrap();
+                    assert_eq!(section.read_var_u32().unwrap(), 1);
+                    assert_eq!(section.read_var_u32().unwrap(), 0);
+                    let len = section.read_var_u32().unwrap();
+                    let payload = section.read_bytes(len as usize).unwrap().to_vec();
+                    let name = module.funcs.iter().nth(func as usize).unwrap().name.clone();
+                    hints.push((name.unwrap(), payload));
+                }
+            }
+            _ => (),
+        }
+    }
+    hints.sort();
+    assert_eq!(
+        hints,
+        vec![
+            ("a".to_string(), vec![1]),
+            ("b".to_string(), vec![127, 127]),
+            ("run".to_string(), vec![0, 0]),
+        ]
+    );
+}
+
+#[test]
+fn import_counters_wrap_every_imported_function() {
+    let wasm = wat::parse_str(
+        r#"(module
+            (import "wasi" "fd_write" (func $fd_write (param i32) (result i32)))
+            (import "wasi" "clock" (func $clock (result i64)))
+            (func $run (export "run") (param i32) (result i32)
+                call $clock
+                drop
+                local.get 0
+                call $fd_write))"#,
+    )
+    .unwrap();
+    let options = InstrumentOptions {
+        import_counters: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    let imports: Vec<(usize, &str)> = output
+        .manifest
+        .imports
+        .iter()
+        .map(|i| (i.id, i.name.as_str()))
+        .collect();
+    assert_eq!(imports, vec![(0, "fd_write"), (1, "clock")]);
+    for id in 0..2 {
+        let name = format!("profiling_import_{}", id);
+        assert!(module.exports.iter().any(|e| e.name == name));
+    }
+
+    // `run` only calls the wrappers now
+    let run = module.funcs.by_name("run").unwrap();
+    let run = module.funcs.get(run).kind.unwrap_local();
+    for instr in vv_profiler::selfcheck::instrs(run) {
+        if let Instr::Call(call) = instr {
+            let callee = module.funcs.get(call.func).name.as_deref().unwrap();
+            assert!(callee.starts_with("import_stub_"), "{}", callee);
+        }
+    }
+}
+
+#[test]
+fn value_profiles_drive_specialization() {
+    let wasm = wat::parse_str(
+        r#"(module
+            (func $add (param i32 i32) (result i32)
+                local.get 0
+                local.get 1
+                i32.add)
+            (func $run (export "run") (param i32) (result i32)
+                local.get 0
+                i32.const 7
+                call $add))"#,
+    )
+    .unwrap();
+    let options = InstrumentOptions {
+        value_profile: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let values = module
+        .exports
+        .iter()
+        .filter(|e| e.name.starts_with("profiling_value_"))
+        .count();
+    assert_eq!(values, 3 * 3);
+
+    // Two instances: 7 won the vote in both
+    let dump = "profiling_value_1_value=7\nprofiling_value_1_votes=3000\nprofiling_value_1_calls=3000\n\
+                profiling_value_1_value=7\nprofiling_value_1_votes=2000\nprofiling_value_1_calls=2000\n";
+    let profile = vv_profiler::formats::decode_globals(dump, "");
+    assert_eq!(profile.values[&1], (7, 5000, 5000));
+
+    let options = InstrumentOptions {
+        specialize: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    assert!(module.funcs.by_name("add_specialized").is_some());
+    let guard = module.funcs.by_name("spec_guard_add").unwrap();
+    let run = module.funcs.by_name("run").unwrap();
+    let run = module.funcs.get(run).kind.unwrap_local();
+    assert!(vv_profiler::selfcheck::instrs(run)
+        .iter()
+        .any(|instr| matches!(instr, Instr::Call(call) if call.func == guard)));
+}
+
+#[test]
+fn memory_counters_route_growth_through_a_stub() {
+    let wasm = wat::parse_str(
+        r#"(module
+            (memory 2)
+            (func $run (export "run") (param i32) (result i32)
+                local.get 0
+                memory.grow))"#,
+    )
+    .unwrap();
+    let options = InstrumentOptions {
+        memory_counters: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    for name in ["profiling_memory_0_grown", "profiling_memory_0_max_pages"] {
+        assert!(module.exports.iter().any(|e| e.name == name));
+    }
+    let run = module.funcs.by_name("run").unwrap();
+    let run = module.funcs.get(run).kind.unwrap_local();
+    let instrs = vv_profiler::selfcheck::instrs(run);
+    assert!(!instrs.iter().any(|i| matches!(i, Instr::MemoryGrow(_))));
+
+    let dump = "profiling_memory_0_grown=3\nprofiling_memory_0_max_pages=5\n\
+                profiling_memory_0_grown=1\nprofiling_memory_0_max_pages=3\n";
+    let profile = vv_profiler::formats::decode_globals(dump, "");
+    assert_eq!(profile.memory[&0], (4, 5));
+}
+
+#[test]
+fn user_exports_sharing_a_profiling_prefix_are_skipped() {
+    let dump = "profiling_global_0_0=3\nprofiling_global_a_b=3\n\
+                profiling_block_x=1\nprofiling_block_2=4\n\
+                profiling_branch_y_taken=1\nprofiling_br_table_1_z=2\n\
+                profiling_value_v_value=1\nprofiling_loop_l_entries=1\n\
+                profiling_import_i=1\nprofiling_data_d=1\nprofiling_entry_e=1\n";
+    let profile = vv_profiler::formats::decode_globals(dump, "");
+    assert_eq!(profile.map.len(), 1);
+    assert_eq!(profile.blocks.len(), 1);
+    assert_eq!(profile.blocks[&2], 4);
+    assert!(profile.branches.is_empty());
+    assert!(profile.br_tables.is_empty());
+    assert!(profile.values.is_empty());
+    assert!(profile.loops.is_empty());
+    assert!(profile.imports.is_empty());
+    assert!(profile.data_accesses.is_empty());
+    assert!(profile.entries.is_empty());
+}
+
+#[test]
+fn coarse_then_fine_instrumentation() {
+    let mut builder = single_type(1);
+    builder.caller("cold", &["i32"], &["i32"], 1);
+    let wasm = wat::parse_str(builder.to_wat()).unwrap();
+
+    let options = InstrumentOptions {
+        coarse: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let exports: Vec<&str> = module.exports.iter().map(|e| e.name.as_str()).collect();
+    assert!(!exports.iter().any(|e| e.starts_with("profiling_global_")));
+    let blocks = exports
+        .iter()
+        .filter(|e| e.starts_with("profiling_block_"))
+        .count();
+    let original = Module::from_buffer(&wasm).unwrap();
+    // One per function entry
+    assert_eq!(blocks, original.funcs.iter_local().count());
+
+    let funcs: Vec<_> = original.funcs.iter_local().map(|(id, _)| id).collect();
+    let all_blocks = vv_profiler::blockcounters::enumerate_blocks(&original, &funcs);
+    let run = original.funcs.by_name("run").unwrap();
+    let entry = all_blocks.iter().position(|(f, _)| *f == run).unwrap();
+    let coarse = Profile {
+        blocks: vec![(entry, 5000)].into_iter().collect(),
+        ..Profile::default()
+    };
+    let hot = vv_profiler::costs::hot_functions(&original, &coarse, 1000);
+    assert_eq!(hot, vec!["run".to_string()].into_iter().collect());
+
+    let options = InstrumentOptions {
+        hot_functions: Some(hot),
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    for callsite in &output.manifest.callsites {
+        let name = format!("profiling_global_{}_0", callsite.id);
+        let exported = module.exports.iter().any(|e| e.name == name);
+        assert_eq!(exported, callsite.func == "run", "{}", callsite.key);
+    }
+}
+
+#[test]
+fn reachable_only_skips_functions_no_root_reaches() {
+    let wat = r#"
+        (module
+          (type $t (func))
+          (table 1 funcref)
+          (elem (i32.const 0) $target)
+          (func $target)
+          (func $used (param i32) (call_indirect (type $t) (local.get 0)))
+          (func $dead (param i32) (call_indirect (type $t) (local.get 0)))
+          (func $run (call $used (i32.const 0)))
+          (export "run" (func $run)))
+    "#;
+    let wasm = wat::parse_str(wat).unwrap();
+    let original = Module::from_buffer(&wasm).unwrap();
+    let reachable = |roots: &[Root]| {
+        let mut names: Vec<String> = reachable_functions(&original, roots, &HashSet::new())
+            .into_iter()
+            .map(|func| vv_profiler::report::func_name(&original, func))
+            .collect();
+        names.sort();
+        names
+    };
+    // call_indirect reaches the table entries even when they aren't roots
+    assert_eq!(reachable(&[Root::Exports]), ["run", "target", "used"]);
+    assert_eq!(reachable(&[Root::Table]), ["target"]);
+    assert!(reachable(&[Root::Start]).is_empty());
+
+    let options = InstrumentOptions {
+        reachable_only: Some(ALL_ROOTS.to_vec()),
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(output.manifest.callsites.len(), 2);
+    for callsite in &output.manifest.callsites {
+        let name = format!("profiling_global_{}_0", callsite.id);
+        let exported = module.exports.iter().any(|e| e.name == name);
+        assert_eq!(exported, callsite.func == "used", "{}", callsite.key);
+    }
+}
+
+#[test]
+fn reachable_from_names_the_entry_points_of_export_all_builds() {
+    let wat = r#"
+        (module
+          (type $t (func))
+          (table 1 funcref)
+          (elem (i32.const 0) $target)
+          (func $target (export "target"))
+          (func $used (export "used") (param i32) (call_indirect (type $t) (local.get 0)))
+          (func $dead (export "dead") (param i32) (call_indirect (type $t) (local.get 0)))
+          (func $run (export "main") (call $used (i32.const 0))))
+    "#;
+    let wasm = wat::parse_str(wat).unwrap();
+    let instrumented = |reachable_from: &[&str]| {
+        let options = InstrumentOptions {
+            reachable_only: Some(vec![Root::Start]),
+            reachable_from: reachable_from.iter().map(|name| name.to_string()).collect(),
+            self_check: true,
+            ..InstrumentOptions::default()
+        };
+        let output = pipeline::run(&wasm, None, &options)?;
+        let module = Module::from_buffer(&output.wasm).unwrap();
+        let mut funcs: Vec<String> = output
+            .manifest
+            .callsites
+            .iter()
+            .filter(|callsite| {
+                let name = format!("profiling_global_{}_0", callsite.id);
+                module.exports.iter().any(|e| e.name == name)
+            })
+            .map(|callsite| callsite.func.clone())
+            .collect();
+        funcs.sort();
+        Ok(funcs)
+    };
+    // By export name or by function name
+    assert_eq!(instrumented(&["main"]).unwrap(), ["used"]);
+    assert_eq!(instrumented(&["run", "dead"]).unwrap(), ["dead", "used"]);
+    assert!(instrumented(&[]).unwrap().is_empty());
+    assert!(matches!(
+        instrumented(&["missing"]),
+        Err(pipeline::Error::InvalidOptions(_))
+    ));
+}
+
+#[test]
+fn prior_profile_sizes_each_callsite() {
+    let wasm = wat::parse_str(single_type(3).to_wat()).unwrap();
+    let prior = Profile {
+        map: vec![(0, vec![5, -1]), (1, vec![-1, -1]), (2, vec![-2, -2])]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+    let windows = vv_profiler::profilemap::adaptive_windows(&prior, 2);
+    assert_eq!(windows, vec![(0, 1), (1, 0), (2, 4)].into_iter().collect());
+
+    let options = InstrumentOptions {
+        window: 2,
+        callsite_windows: windows,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let slots = |callsite: usize| {
+        let prefix = format!("profiling_global_{}_", callsite);
+        module
+            .exports
+            .iter()
+            .filter(|e| e.name.starts_with(&prefix))
+            .count()
+    };
+    assert_eq!((slots(0), slots(1), slots(2)), (1, 0, 4));
+    // The never-executed callsite keeps its call_indirect
+    let run = module.funcs.by_name("run").unwrap();
+    let run = module.funcs.get(run).kind.unwrap_local();
+    let left = vv_profiler::selfcheck::instrs(run)
+        .into_iter()
+        .filter(|i| is_call_indirect(i))
+        .count();
+    assert_eq!(left, 1);
+    assert_eq!(output.manifest.callsite_windows.len(), 3);
+}
+
+#[test]
+fn statically_monomorphic_callsites_are_skipped_or_devirtualized() {
+    // `b` is the only i64 -> i64 entry, the i32 -> i32 callsite has two candidates
+    let mut builder = ModuleBuilder::new();
+    builder.target("a", &["i32"], &["i32"]);
+    builder.target("b", &["i64"], &["i64"]);
+    builder.target("c", &["i32"], &["i32"]);
+    builder.caller("run", &["i64"], &["i64"], 1);
+    builder.caller("poly", &["i32"], &["i32"], 1);
+
+    let options = InstrumentOptions {
+        skip_static_callsites: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (module, output) = instrument(&builder, &options);
+    let targets: Vec<Option<String>> = output
+        .manifest
+        .callsites
+        .iter()
+        .map(|c| c.static_target.clone())
+        .collect();
+    assert_eq!(targets, vec![Some("b".to_string()), None]);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 1);
+    assert_eq!(count_instrs(&module, "poly", is_call_indirect), 0);
+    assert!(!export_names(&module).contains(&"profiling_global_0_0".to_string()));
+
+    // The profile says nothing about callsite 0, --devirt-static fills it in
+    let wasm = wat::parse_str(builder.to_wat()).unwrap();
+    let options = InstrumentOptions {
+        devirt_static: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(Profile::default()), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 0);
+    assert_eq!(count_instrs(&module, "poly", is_call_indirect), 1);
+}
+
+#[test]
+fn counter_policy_sets_initial_values_and_saturation() {
+    use vv_profiler::counters::{CounterMode, CounterPolicy};
+    let options = InstrumentOptions {
+        block_counters: true,
+        counters: CounterPolicy {
+            init: 7,
+            mode: CounterMode::Saturate,
+        },
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (module, output) = instrument(&single_type(1), &options);
+
+    for export in module.exports.iter() {
+        let counter = export.name.starts_with("profiling_block_")
+            || export.name == "indirect"
+            || export.name == "slowcalls";
+        if let walrus::ExportItem::Global(global) = export.item {
+            if counter {
+                match module.globals.get(global).kind {
+                    walrus::GlobalKind::Local(walrus::InitExpr::Value(value)) => {
+                        assert_eq!(value.to_string(), "7", "{}", export.name)
+                    }
+                    _ => panic!("{} isn't a local constant", export.name),
+                }
+            }
+        }
+    }
+    // Every block counter compares against i32::MAX
+    assert!(
+        count_instrs(&module, "run", |i| matches!(
+            i,
+            Instr::Const(c) if c.value.to_string() == i32::MAX.to_string()
+        )) > 0
+    );
+    assert_eq!(output.manifest.counters.init, 7);
+    assert_eq!(output.manifest.counters.mode, CounterMode::Saturate);
+    assert_eq!(output.manifest.counters.overflow_slot, -2);
+}
+
+#[test]
+fn branch_counters_follow_the_counter_policy() {
+    use vv_profiler::counters::{CounterMode, CounterPolicy};
+    let wasm = wat::parse_str(
+        r#"(module
+            (func $run (export "run") (param i32) (result i32)
+                (block
+                    (br_if 0 (local.get 0)))
+                (if (result i32) (local.get 0)
+                    (then (i32.const 1))
+                    (else (i32.const 2)))))"#,
+    )
+    .unwrap();
+    let options = InstrumentOptions {
+        divergence: true,
+        counters: CounterPolicy {
+            init: 7,
+            mode: CounterMode::Saturate,
+        },
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    let mut branch_counters = 0;
+    for export in module.exports.iter() {
+        if let walrus::ExportItem::Global(global) = export.item {
+            if export.name.starts_with("profiling_branch_") {
+                branch_counters += 1;
+                match module.globals.get(global).kind {
+                    walrus::GlobalKind::Local(walrus::InitExpr::Value(value)) => {
+                        assert_eq!(value.to_string(), "7", "{}", export.name)
+                    }
+                    _ => panic!("{} isn't a local constant", export.name),
+                }
+            }
+        }
+    }
+    assert_eq!(branch_counters, 4);
+    // Both arms of both branches saturate
+    let saturating = count_instrs(&module, "run", |i| {
+        matches!(i, Instr::Const(c) if c.value.to_string() == i32::MAX.to_string())
+    });
+    assert_eq!(saturating, 4);
+}
+
+#[test]
+fn symbolization_demangles_rust_and_cpp_names() {
+    use vv_profiler::symbolize::{demangle, display, display_key};
+    assert_eq!(
+        demangle("_ZN4core3fmt5write17h0123456789abcdefE").as_deref(),
+        Some("core::fmt::write")
+    );
+    assert_eq!(demangle("_Z3fooi").as_deref(), Some("foo(int)"));
+    assert_eq!(demangle("dlmalloc"), None);
+    assert_eq!(
+        display_key("_ZN4core3fmt5write17h0123456789abcdefE#2", true),
+        "core::fmt::write#2"
+    );
+    assert_eq!(display("_Z3fooi", false), "_Z3fooi");
+}
+
+// Append a custom section, which leaves every code offset where it was
+fn append_custom_section(wasm: &mut Vec<u8>, name: &str, data: &[u8]) {
+    fn leb(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+    let mut payload = vec![];
+    leb(&mut payload, name.len());
+    payload.extend_from_slice(name.as_bytes());
+    payload.extend_from_slice(data);
+    wasm.push(0);
+    leb(wasm, payload.len());
+    wasm.extend(payload);
+}
+
+#[test]
+fn source_locations_come_from_dwarf_line_tables() {
+    use gimli::write::{
+        Address, AttributeValue, DwarfUnit, EndianVec, LineProgram, LineString, Sections,
+    };
+    use vv_profiler::dwarf::SourceMap;
+
+    let mut wasm = wat::parse_str(single_type(1).to_wat()).unwrap();
+    assert!(SourceMap::load(&wasm).is_none());
+
+    let module = Module::from_buffer(&wasm).unwrap();
+    let callsite = vv_profiler::callsites::enumerate_callsites(&module, None)[0];
+    let code_start = wasmparser::Parser::new(0)
+        .parse_all(&wasm)
+        .find_map(|payload| match payload.unwrap() {
+            wasmparser::Payload::CodeSectionStart { range, .. } => Some(range.start),
+            _ => None,
+        })
+        .unwrap();
+    let addr = callsite.loc.data() as u64 - code_start as u64;
+
+    // Everything before the call_indirect is line 1, the call_indirect is line 42
+    let encoding = gimli::Encoding {
+        format: gimli::Format::Dwarf32,
+        version: 4,
+        address_size: 4,
+    };
+    let mut dwarf = DwarfUnit::new(encoding);
+    let root = dwarf.unit.root();
+    dwarf.unit.get_mut(root).set(
+        gimli::DW_AT_comp_dir,
+        AttributeValue::String(b"/src".to_vec()),
+    );
+    let mut program = LineProgram::new(
+        encoding,
+        gimli::LineEncoding::default(),
+        LineString::String(b"/src".to_vec()),
+        LineString::String(b"dispatch.c".to_vec()),
+        None,
+    );
+    let dir = program.default_directory();
+    let file = program.add_file(LineString::String(b"dispatch.c".to_vec()), dir, None);
+    program.begin_sequence(Some(Address::Constant(0)));
+    program.row().file = file;
+    program.row().line = 1;
+    program.generate_row();
+    program.row().address_offset = addr;
+    program.row().line = 42;
+    program.generate_row();
+    program.end_sequence(addr + 1);
+    dwarf.unit.line_program = program;
+    let mut sections = Sections::new(EndianVec::new(gimli::LittleEndian));
+    dwarf.write(&mut sections).unwrap();
+    sections
+        .for_each(|id, data| {
+            if !data.slice().is_empty() {
+                append_custom_section(&mut wasm, id.name(), data.slice());
+            }
+            Ok::<(), ()>(())
+        })
+        .unwrap();
+
+    let sources = SourceMap::load(&wasm).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let callsite = vv_profiler::callsites::enumerate_callsites(&module, None)[0];
+    assert_eq!(
+        sources.locate(callsite.loc).as_deref(),
+        Some("/src/dispatch.c:42")
+    );
+    let run = module
+        .funcs
+        .get(function(&module, "run"))
+        .kind
+        .unwrap_local();
+    assert_eq!(
+        sources.locate_function(run).as_deref(),
+        Some("/src/dispatch.c:1")
+    );
+}
+
+#[test]
+fn lcov_export_without_line_tables_numbers_blocks() {
+    let wasm = wat::parse_str(single_type(1).to_wat()).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let profile = Profile {
+        blocks: vec![(0, 3)].into_iter().collect(),
+        ..Profile::default()
+    };
+    let tracefile = vv_profiler::lcov::export_lcov(&module, &profile, None, "app.wasm", true, None);
+    let lines: Vec<&str> = tracefile.lines().collect();
+    assert_eq!(lines[..3], ["TN:", "SF:app.wasm", "FN:1,a"]);
+    assert!(lines.contains(&"FNDA:3,a"));
+    assert!(lines.contains(&"FNH:1"));
+    assert!(lines.contains(&"DA:1,3"));
+    assert!(lines.contains(&"LH:1"));
+    assert_eq!(lines.last(), Some(&"end_of_record"));
+}
+
+#[test]
+fn loop_counters_feed_unroll_candidates() {
+    let mut builder = single_type(1);
+    builder.func(
+        "(func $sum (param $n i32) (result i32) (local $i i32)
+    (loop $l
+      (local.set $i (i32.add (local.get $i) (i32.const 1)))
+      (br_if $l (i32.lt_u (local.get $i) (local.get $n))))
+    (local.get $i))",
+    );
+    let options = InstrumentOptions {
+        loop_counters: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (module, _) = instrument(&builder, &options);
+    let exports = export_names(&module);
+    for field in ["entries", "iterations", "max_trip"] {
+        assert!(exports.contains(&format!("profiling_loop_0_{}", field)));
+    }
+
+    let module = Module::from_buffer(&wat::parse_str(builder.to_wat()).unwrap()).unwrap();
+    let candidates = |counts: (i32, i32, i32)| {
+        let profile = Profile {
+            loops: vec![(0, counts)].into_iter().collect(),
+            ..Profile::default()
+        };
+        vv_profiler::loops::unroll_candidates(&module, &profile, 500, 64)
+    };
+    // 10 iterations every time it's entered
+    let stable = candidates((100, 1000, 10));
+    assert_eq!(stable.len(), 1);
+    assert_eq!(stable[0].info.depth, 1);
+    assert_eq!(stable[0].avg_trip, 10.0);
+    // Same average, but one entry ran 40 times
+    assert!(candidates((100, 1000, 40)).is_empty());
+    // Not hot enough
+    assert!(candidates((10, 100, 10)).is_empty());
+}
+
+#[test]
+fn slot_memory_stubs_do_not_grow_with_the_callsites() {
+    let stub_size = |callsites, slot_memory| {
+        let options = InstrumentOptions {
+            window: 2,
+            slot_memory,
+            self_check: true,
+            ..InstrumentOptions::default()
+        };
+        let (module, output) = instrument(&single_type(callsites), &options);
+        let stub = vv_profiler::report::func_name(&module, instrument_stubs(&module)[0]);
+        (count_instrs(&module, &stub, |_| true), module, output)
+    };
+    assert!(stub_size(20, false).0 > stub_size(2, false).0);
+    let (small, _, _) = stub_size(2, true);
+    let (large, module, output) = stub_size(20, true);
+    assert_eq!(small, large);
+
+    let exports = export_names(&module);
+    assert!(exports.contains(&"profiling_slots".to_string()));
+    assert!(!exports.iter().any(|e| e.starts_with("profiling_global_")));
+    assert_eq!(
+        output.manifest.slot_memory.unwrap().memory_export,
+        "profiling_slots"
+    );
+
+    let profile = Profile {
+        map: vec![(0, vec![1, -1]), (3, vec![-2, -2])]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+    let format = vv_profiler::formats::ProfileFormat::SlotMemory;
+    let dump = vv_profiler::formats::encode_profile(&profile, format);
+    let decoded = vv_profiler::formats::decode_profile(&dump, format).unwrap();
+    assert_eq!(decoded.map, profile.map);
+}
+
+#[test]
+fn max_callsites_instruments_one_chunk_per_round() {
+    let mut builder = ModuleBuilder::new();
+    builder.target("a", &["i32"], &["i32"]);
+    builder.caller("small", &["i32"], &["i32"], 2);
+    builder.caller("big", &["i32"], &["i32"], 3);
+    let round = |round| {
+        let options = InstrumentOptions {
+            max_callsites: Some(3),
+            callsite_round: round,
+            self_check: true,
+            ..InstrumentOptions::default()
+        };
+        let wasm = wat::parse_str(builder.to_wat()).unwrap();
+        pipeline::run(&wasm, None, &options)
+    };
+    let instrumented = |output: &pipeline::Output| -> Vec<String> {
+        let module = Module::from_buffer(&output.wasm).unwrap();
+        let exports = export_names(&module);
+        output
+            .manifest
+            .callsites
+            .iter()
+            .filter(|c| exports.contains(&format!("profiling_global_{}_0", c.id)))
+            .map(|c| c.func.clone())
+            .collect()
+    };
+
+    // The larger function goes first
+    let first = round(0).unwrap();
+    assert_eq!(instrumented(&first), vec!["big", "big", "big"]);
+    let chunk = first.manifest.chunk.unwrap();
+    assert_eq!((chunk.round, chunk.rounds), (0, 2));
+    assert_eq!(instrumented(&round(1).unwrap()), vec!["small", "small"]);
+    assert!(matches!(round(2), Err(pipeline::Error::InvalidOptions(_))));
+}
+
+#[test]
+fn decisions_record_the_rule_behind_each_callsite() {
+    let wasm = wat::parse_str(single_type(4).to_wat()).unwrap();
+    let profile = Profile {
+        map: vec![(0, vec![0]), (1, vec![-1]), (2, vec![0]), (3, vec![-2, -2])]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        force_devirt: vec![("run#2".to_string(), "b".to_string())],
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let summary: Vec<(&str, &str, bool)> = output
+        .decisions
+        .iter()
+        .map(|d| (d.disposition.as_str(), d.rule.as_str(), d.may_trap))
+        .collect();
+    assert_eq!(
+        summary,
+        vec![
+            ("devirtualized", "profile", true),
+            ("unreachable", "profile", true),
+            ("devirtualized", "override", true),
+            ("indirect", "threshold", false),
+        ]
+    );
+    let forced = &output.decisions[2];
+    assert_eq!(forced.key, "run#2");
+    assert_eq!(forced.observed_targets, vec!["a"]);
+    assert_eq!(forced.targets, vec!["b"]);
+    assert!(output
+        .decisions
+        .windows(2)
+        .all(|d| d[0].offset < d[1].offset));
+
+    let (_, instrumented) = instrument(&single_type(1), &InstrumentOptions::default());
+    assert!(instrumented.decisions.is_empty());
+}
+
+#[test]
+fn instrumented_binaries_are_refused_or_stripped() {
+    let mut builder = single_type(3);
+    builder.caller("other", &["i32"], &["i32"], 1);
+    let options = InstrumentOptions {
+        window: 2,
+        export_prefix: "vv_".to_string(),
+        compact_exports: true,
+        ..InstrumentOptions::default()
+    };
+    let (first, output) = instrument(&builder, &options);
+    let instrumented = output.wasm;
+    assert!(matches!(
+        pipeline::run(&instrumented, None, &options),
+        Err(pipeline::Error::InvalidOptions(_))
+    ));
+
+    let options = InstrumentOptions {
+        re_instrument: true,
+        self_check: true,
+        ..options
+    };
+    let again = pipeline::run(&instrumented, None, &options).unwrap();
+    let module = Module::from_buffer(&again.wasm).unwrap();
+    assert_eq!(export_names(&module), export_names(&first));
+    assert_eq!(instrument_stubs(&module).len(), 1);
+    assert_eq!(module.globals.iter().count(), first.globals.iter().count());
+    assert_eq!(direct_calls(&module, "run"), direct_calls(&first, "run"));
+    assert_eq!(again.manifest.fingerprint, output.manifest.fingerprint);
+    let modes: Vec<String> = tool_runs(&module).into_iter().map(|r| r.mode).collect();
+    assert_eq!(modes, vec!["instrument"]);
+
+    // Block counters are part of the original functions' code now
+    let options = InstrumentOptions {
+        block_counters: true,
+        ..InstrumentOptions::default()
+    };
+    let (_, output) = instrument(&builder, &options);
+    let options = InstrumentOptions {
+        re_instrument: true,
+        ..options
+    };
+    assert!(matches!(
+        pipeline::run(&output.wasm, None, &options),
+        Err(pipeline::Error::Unsupported(_))
+    ));
+}
+
+#[test]
+fn optimizing_an_instrumented_binary_strips_it_first() {
+    let builder = single_type(2);
+    let map = vec![(0, vec![1]), (1, vec![-1])];
+    let expected = optimize(&builder, &map);
+
+    let (_, output) = instrument(&builder, &InstrumentOptions::default());
+    let profile = Profile {
+        map: map.into_iter().collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let optimized = pipeline::run(&output.wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&optimized.wasm).unwrap();
+    assert!(instrument_stubs(&module).is_empty());
+    assert_eq!(
+        optimize_stubs(&module).len(),
+        optimize_stubs(&expected).len()
+    );
+    assert_eq!(direct_calls(&module, "run"), direct_calls(&expected, "run"));
+    assert_eq!(export_names(&module), export_names(&expected));
+    let modes: Vec<String> = tool_runs(&module).into_iter().map(|r| r.mode).collect();
+    assert_eq!(modes, vec!["optimize"]);
+
+    let options = InstrumentOptions {
+        block_counters: true,
+        ..InstrumentOptions::default()
+    };
+    let (_, output) = instrument(&builder, &options);
+    assert!(matches!(
+        pipeline::run(&output.wasm, Some(Profile::default()), &options),
+        Err(pipeline::Error::InvalidOptions(_))
+    ));
+}
+
+#[test]
+fn table_indices_past_i32_max_are_unsigned() {
+    // Segment bounds are only checked at instantiation, so a small table will do here
+    let wat = r#"(module
+  (type $t (func (param i32) (result i32)))
+  (table 2 funcref)
+  (elem (i32.const -2147483648) $a $b)
+  (func $a (type $t) (i32.const 0))
+  (func $b (type $t) (i32.const 0))
+  (func $run (export "run") (param $idx i32) (result i32)
+    (call_indirect (type $t) (i32.const 0) (local.get $idx)))
+  (func $_start (export "_start")))"#;
+    let wasm = wat::parse_str(wat).unwrap();
+
+    // What the slot global holds after a call to $b
+    let dump = "profiling_global_0_0=-2147483647\n";
+    let profile = vv_profiler::formats::decode_globals(dump, "");
+    assert_eq!(profile.map[&0], vec![2147483649]);
+
+    let output = pipeline::run(&wasm, Some(profile), &InstrumentOptions::default()).unwrap();
+    assert_eq!(output.decisions[0].disposition, "devirtualized");
+    assert_eq!(output.decisions[0].targets, vec!["b"]);
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let stub = vv_profiler::report::func_name(&module, optimize_stubs(&module)[0]);
+    let guard = |instr: &Instr| matches!(instr, Instr::Const(c) if matches!(c.value, walrus::ir::Value::I32(-2147483647)));
+    assert_eq!(count_instrs(&module, &stub, guard), 1);
+}
+
+const EMSCRIPTEN: &str = r#"(module
+  (type $t (func (param i32) (result i32)))
+  (import "env" "invoke_ii" (func $invoke_ii (param i32 i32) (result i32)))
+  (table (export "__indirect_function_table") 2 funcref)
+  (elem (i32.const 0) $a $b)
+  (func $a (type $t) (i32.const 0))
+  (func $b (type $t) (i32.const 1))
+  (func $run (export "run") (param $idx i32) (result i32)
+    (i32.add
+      (call_indirect (type $t) (i32.const 0) (local.get $idx))
+      (call_indirect (type $t) (i32.const 0) (local.get $idx))))
+  (func $catching (export "catching") (param $idx i32) (result i32)
+    (call $invoke_ii (local.get $idx) (i32.const 0)))
+  (func $_start (export "_start")))"#;
+
+// The same module without the table export, which changes what can reach the table
+fn private_table(wat: &str) -> String {
+    wat.replace(" (export \"__indirect_function_table\")", "")
+}
+
+#[test]
+fn emscripten_invokes_are_calls_into_the_table() {
+    let wasm = wat::parse_str(private_table(EMSCRIPTEN)).unwrap();
+    let slowcalls = |emscripten: bool| {
+        let mut module = Module::from_buffer(&wasm).unwrap();
+        let table = module.tables.main_function_table().unwrap();
+        let catching = function(&module, "catching");
+        compute_slowcalls(&mut module, table, emscripten, &ImportClasses::default(), None, None)
+            .slowcalls
+            .contains(&catching)
+    };
+    assert!(slowcalls(false));
+    // $a and $b don't call anything
+    assert!(!slowcalls(true));
+}
+
+#[test]
+fn the_slowcall_analysis_degrades_past_its_memory_budget() {
+    let wasm = wat::parse_str(private_table(EMSCRIPTEN)).unwrap();
+    let slowcalls = |max_memory: Option<usize>| {
+        let mut module = Module::from_buffer(&wasm).unwrap();
+        let table = module.tables.main_function_table().unwrap();
+        let classes = ImportClasses::default();
+        let names: Vec<String> =
+            compute_slowcalls(&mut module, table, true, &classes, None, max_memory)
+                .slowcalls
+                .into_iter()
+                .map(|func| vv_profiler::report::func_name(&module, func))
+                .collect();
+        names
+    };
+    let full = slowcalls(None);
+    let degraded = slowcalls(Some(0));
+    assert!(!full.contains(&"catching".to_string()));
+    assert!(degraded.contains(&"catching".to_string()));
+    // Only ever more slowcalls than the full analysis finds
+    assert!(full.iter().all(|func| degraded.contains(func)));
+}
+
+#[test]
+fn slowcalls_follow_call_chains_and_cycles() {
+    let wat = r#"
+        (module
+          (func $leaf)
+          (func $calls_leaf call $leaf)
+          (func $calls_calls_leaf call $calls_leaf)
+          (func $ping call $pong)
+          (func $pong call $ping)
+          (func $calls_ping call $ping)
+          (func $self call $self)
+          (func $_start call $calls_calls_leaf call $calls_ping call $self)
+          (export "_start" (func $_start)))
+    "#;
+    let wasm = wat::parse_str(wat).unwrap();
+    let mut module = Module::from_buffer(&wasm).unwrap();
+    let mut slowcalls: Vec<String> =
+        compute_slowcalls(&mut module, None, false, &ImportClasses::default(), None, None)
+            .slowcalls
+            .into_iter()
+            .map(|func| vv_profiler::report::func_name(&module, func))
+            .collect();
+    slowcalls.sort();
+    assert_eq!(slowcalls, ["_start", "calls_ping", "ping", "pong", "self"]);
+
+    let calls: Vec<(walrus::FunctionId, HashSet<walrus::FunctionId>)> =
+        ["_start", "calls_ping", "ping", "pong"]
+            .iter()
+            .map(|name| {
+                let callees = match *name {
+                    "_start" => vec!["calls_ping"],
+                    "calls_ping" => vec!["ping"],
+                    "ping" => vec!["pong"],
+                    _ => vec!["ping"],
+                };
+                (
+                    function(&module, name),
+                    callees
+                        .iter()
+                        .map(|callee| function(&module, callee))
+                        .collect(),
+                )
+            })
+            .collect();
+    let nodes: Vec<_> = calls
+        .iter()
+        .map(|(func, callees)| (*func, callees))
+        .collect();
+    let graph = CallGraph::new(&nodes);
+    let mut roots = BitSet::new(graph.funcs.len());
+    roots.insert(graph.index[&function(&module, "calls_ping")]);
+    let reachable = graph.reachable(&roots);
+    assert_eq!(reachable.count(), 3);
+    assert!(!reachable.contains(graph.index[&function(&module, "_start")]));
+}
+
+#[test]
+fn emscripten_devirtualized_calls_fall_back_to_call_indirect() {
+    let run = |wat: &str, emscripten: bool| {
+        let wasm = wat::parse_str(wat).unwrap();
+        let profile = Profile {
+            map: vec![(0, vec![1]), (1, vec![-1])].into_iter().collect(),
+            ..Profile::default()
+        };
+        let options = InstrumentOptions {
+            self_check: true,
+            emscripten,
+            ..InstrumentOptions::default()
+        };
+        pipeline::run(&wasm, Some(profile), &options).unwrap()
+    };
+
+    let output = run(&private_table(EMSCRIPTEN), false);
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let stub = vv_profiler::report::func_name(&module, optimize_stubs(&module)[0]);
+    assert_eq!(count_instrs(&module, &stub, is_call_indirect), 0);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 0);
+    assert!(output.decisions[0].may_trap);
+
+    let output = run(EMSCRIPTEN, true);
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let stub = vv_profiler::report::func_name(&module, optimize_stubs(&module)[0]);
+    assert_eq!(direct_calls(&module, &stub), vec!["b".to_string()]);
+    assert_eq!(count_instrs(&module, &stub, is_call_indirect), 1);
+    // Never executed, but something else may be in the table by then
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 1);
+    assert_eq!(output.decisions[0].disposition, "devirtualized");
+    assert!(!output.decisions[0].may_trap);
+    assert_eq!(output.decisions[1].disposition, "indirect");
+}
+
+#[test]
+fn ab_split_emits_an_aggressive_and_a_conservative_build() {
+    let wasm = wat::parse_str(private_table(EMSCRIPTEN)).unwrap();
+    let profile = Profile {
+        map: vec![(0, vec![1]), (1, vec![-1])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (aggressive, conservative) = pipeline::run_ab_split(&wasm, profile, &options).unwrap();
+    let keys = |output: &pipeline::Output| -> Vec<String> {
+        output
+            .manifest
+            .callsites
+            .iter()
+            .map(|c| c.key.clone())
+            .collect()
+    };
+    assert_eq!(keys(&aggressive), keys(&conservative));
+
+    let module = Module::from_buffer(&aggressive.wasm).unwrap();
+    let stub = vv_profiler::report::func_name(&module, optimize_stubs(&module)[0]);
+    assert_eq!(count_instrs(&module, &stub, is_call_indirect), 0);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 0);
+    assert!(aggressive.decisions[0].may_trap);
+
+    let module = Module::from_buffer(&conservative.wasm).unwrap();
+    let stub = vv_profiler::report::func_name(&module, optimize_stubs(&module)[0]);
+    assert_eq!(direct_calls(&module, &stub), vec!["b".to_string()]);
+    assert_eq!(count_instrs(&module, &stub, is_call_indirect), 1);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 1);
+    assert!(!conservative.decisions[0].may_trap);
+    assert_eq!(conservative.decisions[1].disposition, "indirect");
+    let conservative_runs: Vec<bool> = tool_runs(&module)
+        .into_iter()
+        .map(|run| run.options.conservative)
+        .collect();
+    assert_eq!(conservative_runs, vec![true]);
+}
+
+#[test]
+fn ab_split_builds_match_separate_runs() {
+    // A folded selector, a peeled br_table and cold blocks, so the fork has
+    // rewrites to replay as well as resolved targets to carry over
+    let wat = "(module
+        (type $t (func (param i32) (result i32)))
+        (memory 1)
+        (table 2 2 funcref)
+        (elem (i32.const 0) $a $b)
+        (func $a (type $t) local.get 0)
+        (func $b (type $t) local.get 0 i32.const 1 i32.add)
+        (func $run (export \"run\") (param i32) (result i32) (local i32)
+            (if (i32.gt_u (local.get 0) (i32.const 1000))
+                (then
+                    (i32.store (local.get 1) (local.get 0))
+                    (i32.store offset=4 (local.get 0) (local.get 1))
+                    (i32.store offset=8 (local.get 1) (local.get 1))
+                    unreachable))
+            (block $out
+                (block $one
+                    (block $zero
+                        local.get 0
+                        br_table $zero $one $out)
+                    i32.const 10
+                    local.set 1
+                    br $out)
+                i32.const 20
+                local.set 1)
+            local.get 0
+            i32.const 1
+            call_indirect (type $t)
+            drop
+            local.get 1
+            local.get 0
+            call_indirect (type $t)
+            local.get 1
+            call_indirect (type $t)))";
+    let wasm = wat::parse_str(wat).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let funcs: Vec<_> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let blocks = vv_profiler::blockcounters::enumerate_blocks(&module, &funcs);
+    let run = function(&module, "run");
+    let cold = vv_profiler::selfcheck::instrs(module.funcs.get(run).kind.unwrap_local())
+        .iter()
+        .find_map(|instr| match instr {
+            Instr::IfElse(if_else) => Some(if_else.consequent),
+            _ => None,
+        })
+        .unwrap();
+    let profile = Profile {
+        map: vec![(0, vec![1]), (1, vec![-1])].into_iter().collect(),
+        br_tables: vec![(0, vec![90, 5, 5])].into_iter().collect(),
+        blocks: blocks
+            .iter()
+            .enumerate()
+            .map(|(idx, block)| (idx, if *block == (run, cold) { 0 } else { 5000 }))
+            .collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        peel_br_tables: Some(1),
+        split_cold: true,
+        ..InstrumentOptions::default()
+    };
+    let (aggressive, conservative) =
+        pipeline::run_ab_split(&wasm, profile.clone(), &options).unwrap();
+    for (output, conservative) in [(aggressive, false), (conservative, true)] {
+        let options = InstrumentOptions {
+            conservative,
+            ..options.clone()
+        };
+        let alone = pipeline::run(&wasm, Some(profile.clone()), &options).unwrap();
+        assert_eq!(output.wasm, alone.wasm);
+        let dispositions = |output: &pipeline::Output| -> Vec<String> {
+            output
+                .decisions
+                .iter()
+                .map(|d| d.disposition.clone())
+                .collect()
+        };
+        assert_eq!(dispositions(&output), dispositions(&alone));
+    }
+}
+
+#[test]
+fn wasm_bindgen_glue_and_externref_tables_are_tolerated() {
+    let path = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/tests/fixtures/wasm_bindgen.wat"
+    );
+    let wasm = wat::parse_file(path).unwrap();
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let stub = vv_profiler::report::func_name(&module, instrument_stubs(&module)[0]);
+    assert_eq!(direct_calls(&module, "apply"), vec![stub]);
+    assert_eq!(
+        count_instrs(&module, "__wbindgen_closure_call", is_call_indirect),
+        1
+    );
+    assert!(export_names(&module).contains(&"profiling_global_0_0".to_string()));
+    assert!(!export_names(&module).contains(&"profiling_global_1_0".to_string()));
+
+    let profile = Profile {
+        map: vec![(0, vec![2])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let optimized = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    assert_eq!(optimized.decisions[0].targets, vec!["triple"]);
+    assert_eq!(optimized.decisions[1].disposition, "indirect");
+
+    // Anything but nulls would be lost, so that is still refused
+    let wat = r#"(module
+  (import "env" "undefined" (global $undefined externref))
+  (table $heap 4 externref)
+  (elem (table $heap) (i32.const 0) externref (global.get $undefined)))"#;
+    assert!(matches!(
+        pipeline::run(&wat::parse_str(wat).unwrap(), None, &options),
+        Err(pipeline::Error::Unsupported(_))
+    ));
+}
+
+// A main module and a side module sharing the main module's table
+const LINKED_MAIN: &str = r#"(module
+  (type $t (func (param i32) (result i32)))
+  (import "side" "side_f" (func $side_f (type $t)))
+  (table (export "table") 4 funcref)
+  (elem (i32.const 0) $main_f)
+  (func $main_f (export "main_f") (type $t) (local.get 0))
+  (func $run (export "run") (param $idx i32) (result i32)
+    (call_indirect (type $t) (i32.const 0) (local.get $idx)))
+  (func $_start (export "_start")))"#;
+
+const LINKED_SIDE: &str = r#"(module
+  (type $t (func (param i32) (result i32)))
+  (import "env" "table" (table 4 funcref))
+  (elem (i32.const 1) $side_f)
+  (func $side_f (export "side_f") (type $t) (i32.const 7))
+  (func $side_run (export "side_run") (param $idx i32) (result i32)
+    (call_indirect (type $t) (i32.const 0) (local.get $idx))))"#;
+
+#[test]
+fn linked_modules_share_one_callsite_numbering() {
+    let modules = vec![
+        (
+            "main.wasm".to_string(),
+            wat::parse_str(LINKED_MAIN).unwrap(),
+        ),
+        (
+            "side.wasm".to_string(),
+            wat::parse_str(LINKED_SIDE).unwrap(),
+        ),
+    ];
+    let options = InstrumentOptions {
+        window: 1,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (instrumented, link) = linked::instrument_set(&modules, &options, None).unwrap();
+    let offsets: Vec<usize> = link.modules.iter().map(|m| m.offset).collect();
+    assert_eq!(offsets, vec![0, 1]);
+    assert_eq!(link.modules[1].manifest.callsites[0].id, 1);
+    let side = Module::from_buffer(&instrumented[1]).unwrap();
+    assert!(export_names(&side).contains(&"profiling_global_1_0".to_string()));
+    assert!(!export_names(&side).contains(&"profiling_global_0_0".to_string()));
+
+    // Both callsites called the other module's function
+    let dumps = "profiling_global_0_0=1\nprofiling_global_1_0=0\n";
+    let profile = vv_profiler::formats::decode_globals(dumps, "");
+    let outputs = linked::optimize_set(&modules, &profile, &link, &options, None).unwrap();
+    // main imports side_f, so it can call it directly
+    assert_eq!(outputs[0].decisions[0].targets, vec!["side_f"]);
+    let main = Module::from_buffer(&outputs[0].wasm).unwrap();
+    let stub = vv_profiler::report::func_name(&main, optimize_stubs(&main)[0]);
+    assert_eq!(direct_calls(&main, &stub), vec!["side_f".to_string()]);
+    // side doesn't import main_f
+    assert_eq!(outputs[1].decisions[0].disposition, "indirect");
+
+    let stale = vec![modules[0].clone(), modules[0].clone()];
+    assert!(matches!(
+        linked::optimize_set(&stale, &profile, &link, &options, None),
+        Err(pipeline::Error::InvalidOptions(_))
+    ));
+}
+
+fn locked_build(extra: &str) -> Vec<u8> {
+    let wat = format!(
+        r#"(module
+  (type $t (func (param i32) (result i32)))
+  (table 2 funcref)
+  (elem (i32.const 0) $f $g)
+  {}
+  (func $f (type $t) (local.get 0))
+  (func $g (type $t) (i32.const 1))
+  (func $a (export "a") (param $idx i32) (result i32)
+    (call_indirect (type $t) (i32.const 0) (local.get $idx)))
+  (func $b (export "b") (param $idx i32) (result i32)
+    (call_indirect (type $t) (i32.const 1) (local.get $idx)))
+  (func $_start (export "_start")))"#,
+        extra
+    );
+    wat::parse_str(wat).unwrap()
+}
+
+#[test]
+fn callsite_lock_keeps_ids_across_rebuilds() {
+    let mut lock = CallsiteLock::default();
+    let instrument = |wasm: &[u8], lock: &mut CallsiteLock| {
+        let options = InstrumentOptions::default();
+        let mut output = pipeline::run(wasm, None, &options).unwrap();
+        let keys: Vec<String> = output
+            .manifest
+            .callsites
+            .iter()
+            .map(|c| c.key.clone())
+            .collect();
+        let ids = lock.allocate(&keys);
+        pipeline::renumber_callsites(&mut output, &ids, &options).unwrap();
+        output
+    };
+    let v1 = instrument(&locked_build(""), &mut lock);
+    let ids: Vec<usize> = v1.manifest.callsites.iter().map(|c| c.id).collect();
+    assert_eq!(ids, vec![0, 1]);
+
+    // The rebuild adds a callsite in front of the others
+    let rebuilt = locked_build(
+        r#"(func $c (export "c") (param $idx i32) (result i32)
+    (call_indirect (type $t) (i32.const 2) (local.get $idx)))"#,
+    );
+    let v2 = instrument(&rebuilt, &mut lock);
+    let ids: Vec<(String, usize)> = v2
+        .manifest
+        .callsites
+        .iter()
+        .map(|c| (c.key.clone(), c.id))
+        .collect();
+    assert_eq!(
+        ids,
+        vec![
+            ("c#0".to_string(), 2),
+            ("a#0".to_string(), 0),
+            ("b#0".to_string(), 1)
+        ]
+    );
+    let module = Module::from_buffer(&v2.wasm).unwrap();
+    assert!(export_names(&module).contains(&"profiling_global_2_0".to_string()));
+
+    // A profile of the first build (a called $g) still applies to the rebuild
+    let profile = vv_profiler::formats::decode_globals("profiling_global_0_0=1\n", "");
+    let module = Module::from_buffer(&rebuilt).unwrap();
+    let profile = lock.localize(&lock_keys(&module, function_table(&module, None), None), profile);
+    assert_eq!(profile.map.keys().collect::<Vec<_>>(), vec![&1]);
+    let output = pipeline::run(&rebuilt, Some(profile), &InstrumentOptions::default()).unwrap();
+    assert_eq!(output.decisions[1].targets, vec!["g"]);
+}
+
+#[test]
+fn watch_runs_without_the_watch_flags() {
+    let args: Vec<String> = [
+        "-i",
+        "in.wasm",
+        "--watch",
+        "--watch-interval",
+        "200",
+        "--output",
+        "out.wasm",
+        "--watch-interval=100",
+    ]
+    .iter()
+    .map(|arg| arg.to_string())
+    .collect();
+    assert_eq!(
+        vv_profiler::watch::run_args(&args, "out.wasm.analysis-cache.json"),
+        vec![
+            "-i",
+            "in.wasm",
+            "--output",
+            "out.wasm",
+            "--analysis-cache",
+            "out.wasm.analysis-cache.json"
+        ]
+    );
+    // A cache of the user's own is kept
+    let mut args = args;
+    args.push("--analysis-cache=mine.json".to_string());
+    let run = vv_profiler::watch::run_args(&args, "out.wasm.analysis-cache.json");
+    assert_eq!(run.last().unwrap(), "--analysis-cache=mine.json");
+    assert_eq!(run.iter().filter(|arg| arg.starts_with("--analysis-cache")).count(), 1);
+}
+
+fn cached_build(b_body: &str) -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"(module
+  (import "wasi_snapshot_preview1" "fd_close" (func $close (param i32) (result i32)))
+  (type $t (func (param i32) (result i32)))
+  (table 2 funcref)
+  (elem (i32.const 0) $f $g)
+  (func $f (type $t) (local.get 0))
+  (func $g (type $t) (call $close (local.get 0)))
+  (func $a (export "a") (param $idx i32) (result i32)
+    (call_indirect (type $t) (i32.const 0) (local.get $idx)))
+  (func $b (export "b") (param $idx i32) (result i32)
+    {})
+  (func $_start (export "_start")))"#,
+        b_body
+    ))
+    .unwrap()
+}
+
+#[test]
+fn analysis_cache_only_rescans_changed_functions() {
+    let path = std::env::temp_dir().join(format!("vv-analysis-cache-{}.json", std::process::id()));
+    let path = path.to_string_lossy().to_string();
+    let _ = std::fs::remove_file(&path);
+    // The names of the slowcalls, and how many entries the cache has afterwards
+    let slowcalls = |wasm: &[u8], cached: bool| {
+        let mut module = Module::from_buffer(wasm).unwrap();
+        let table = module.tables.main_function_table().unwrap();
+        let mut cache = AnalysisCache::load(&path, wasm);
+        let slowcalls = compute_slowcalls(
+            &mut module,
+            table,
+            false,
+            &ImportClasses::default(),
+            cached.then_some(&mut cache),
+            None,
+        )
+        .slowcalls;
+        cache.write(&path);
+        let mut names: Vec<String> = slowcalls
+            .iter()
+            .map(|f_id| module.funcs.get(*f_id).name.clone().unwrap())
+            .collect();
+        names.sort();
+        (names, cache.entries.len())
+    };
+
+    let v1 = cached_build("(call $f (local.get $idx))");
+    let (uncached, _) = slowcalls(&v1, false);
+    assert_eq!(uncached, vec!["a", "g"]);
+    assert_eq!(slowcalls(&v1, true), (uncached.clone(), 5));
+    assert_eq!(slowcalls(&v1, true), (uncached, 5));
+
+    // Only $b changed, and is now a slowcall through $g
+    let v2 = cached_build("(call $g (local.get $idx))");
+    assert_eq!(
+        slowcalls(&v2, true),
+        (vec!["a".to_string(), "b".to_string(), "g".to_string()], 6)
+    );
+    assert_eq!(slowcalls(&v2, false).0, vec!["a", "b", "g"]);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn schema_profiles_round_trip_through_global_dumps() {
+    let dump = "vv_profiling_global_0_0=3\nvv_profiling_global_0_1=-1\nvv_profiling_block_2=9\nvv_slowcalls=4\n";
+    let profile = Profile::from_globals_dump(dump, "vv_");
+    assert_eq!(profile.map[&0], vec![3, -1]);
+    let text = profile.to_globals_dump();
+    for format in [
+        ProfileFormat::Msgpack,
+        ProfileFormat::Json,
+        ProfileFormat::Cbor,
+    ] {
+        let decoded = Profile::decode(&profile.encode(format), format).unwrap();
+        assert_eq!(decoded.to_globals_dump(), text);
+    }
+    let reread = Profile::from_globals_dump(&text, "");
+    assert_eq!(reread.map, profile.map);
+    assert_eq!(reread.blocks, profile.blocks);
+    assert_eq!(reread.slowcalls, Some(4));
+}
+
+#[test]
+fn malformed_profiles_are_errors() {
+    let rows = [
+        ("callsite,target,count\n0,1,3\n0\n", 3),
+        ("0,1\nx,1\n", 2),
+        ("0,1,many\n", 1),
+    ];
+    for (csv, row) in rows {
+        let err = Profile::decode(csv.as_bytes(), ProfileFormat::Csv).unwrap_err();
+        assert!(err.starts_with(&format!("malformed csv row {}:", row)), "{}", err);
+    }
+    assert!(Profile::decode(b"not json", ProfileFormat::Json).is_err());
+    assert!(ProfileFormat::from_name("yaml").is_err());
+    assert_eq!(ProfileFormat::from_name("csv"), Ok(ProfileFormat::Csv));
+}
+
+#[test]
+fn c_header_describes_the_instrumented_binary() {
+    let options = InstrumentOptions {
+        window: 2,
+        export_prefix: "vv_".to_string(),
+        slot_memory: true,
+        ..InstrumentOptions::default()
+    };
+    let (module, output) = instrument(&single_type(2), &options);
+    let header = vv_profiler::glue::c_header(&output.manifest);
+    for line in [
+        "#define VV_CALLSITE_COUNT 2u",
+        "#define VV_WINDOW 2u",
+        "#define VV_SLOT_GLOBAL_FORMAT \"vv_profiling_global_%u_%u\"",
+        "#define VV_SLOWCALLS_GLOBAL \"vv_slowcalls\"",
+        "#define VV_SLOT_MEMORY \"vv_profiling_slots\"",
+    ] {
+        assert!(
+            header.lines().any(|l| l == line),
+            "{} missing from\n{}",
+            line,
+            header
+        );
+    }
+    assert!(header.contains("\"run#0\",\n    \"run#1\",\n};"));
+    assert!(export_names(&module).contains(&"vv_profiling_slots".to_string()));
+    assert!(export_names(&module).contains(&"vv_slowcalls".to_string()));
+    assert!(header.ends_with("#endif /* VV_PROFILING_H */\n"));
+}
+
+// The table index each block of the dispatch stub compares against, in order
+fn dispatch_order(module: &Module) -> Vec<i32> {
+    let stub = module
+        .funcs
+        .get(optimize_stubs(module)[0])
+        .kind
+        .unwrap_local();
+    stub.block(stub.entry_block())
+        .instrs
+        .iter()
+        .filter_map(|(instr, _)| match instr {
+            Instr::Block(block) => match &stub.block(block.seq).instrs[0].0 {
+                Instr::Const(c) => match c.value {
+                    walrus::ir::Value::I32(idx) => Some(idx),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn dispatch_stubs_check_the_hottest_target_first() {
+    let wasm = wat::parse_str(single_type(1).to_wat()).unwrap();
+    let optimize = |target_counts: HashMap<usize, Vec<(i64, u64)>>| {
+        let profile = Profile {
+            map: [(0, vec![0, 1])].into_iter().collect(),
+            target_counts,
+            ..Profile::default()
+        };
+        let options = InstrumentOptions {
+            window: 2,
+            ..InstrumentOptions::default()
+        };
+        let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+        dispatch_order(&Module::from_buffer(&output.wasm).unwrap())
+    };
+    let hot = |first: i64, second: i64| {
+        [(0, vec![(first, 1000), (second, 3)])]
+            .into_iter()
+            .collect()
+    };
+    assert_eq!(optimize(hot(0, 1)), vec![0, 1]);
+    assert_eq!(optimize(hot(1, 0)), vec![1, 0]);
+
+    // csv profiles carry their counts along
+    let csv = "callsite,target,count\n0,0,3\n0,1,1000\n";
+    let profile =
+        vv_profiler::formats::decode_profile(csv.as_bytes(), ProfileFormat::Csv).unwrap();
+    assert_eq!(profile.target_counts[&0], vec![(1, 1000), (0, 3)]);
+    let encoded = vv_profiler::formats::encode_profile(&profile, ProfileFormat::Csv);
+    assert_eq!(
+        String::from_utf8(encoded).unwrap(),
+        "callsite,target,count\n0,1,1000\n0,0,3\n"
+    );
+}
+
+#[test]
+fn provably_unique_targets_are_called_without_a_guard() {
+    let mut builder = ModuleBuilder::new();
+    builder.target("a", &["i32"], &["i32"]);
+    builder.target("b", &["i64"], &["i64"]);
+    builder.target("c", &["i32"], &["i32"]);
+    // `b` is the only i64 -> i64 entry, `a` and `c` share a type
+    builder.caller("run", &["i64"], &["i64"], 1);
+    builder.caller("poly", &["i32"], &["i32"], 1);
+    builder.func(
+        r#"(func $konst (export "konst") (param $idx i32) (local $sel i32) (local $mixed i32)
+    (local.set $sel (i32.const 0))
+    (local.set $mixed (i32.const 0))
+    (block (local.set $sel (i32.const 0)) (local.set $mixed (i32.const 2)))
+    (drop (call_indirect (type $t0) (i32.const 0) (i32.const 2)))
+    (drop (call_indirect (type $t0) (i32.const 0) (local.get $sel)))
+    (drop (call_indirect (type $t0) (i32.const 0) (local.get $mixed))))"#,
+    );
+    // The constant table indices in `konst` are folded before numbering
+    let profile = [(0, vec![1]), (1, vec![0]), (2, vec![0])];
+    let module = optimize(&builder, &profile);
+
+    // `poly` and the `$mixed` callsite keep their guard
+    let stubs = optimize_stubs(&module);
+    assert_eq!(stubs.len(), 2);
+    let stub = |name: &str| {
+        direct_calls(&module, name)
+            .into_iter()
+            .find(|f| f.starts_with("indirect_call_stub_"))
+            .unwrap()
+    };
+    assert_eq!(direct_calls(&module, "run"), vec!["b"]);
+    assert_eq!(direct_calls(&module, "poly"), vec![stub("poly")]);
+    assert_eq!(
+        direct_calls(&module, "konst"),
+        vec!["c".to_string(), "a".to_string(), stub("konst")]
+    );
+    let drops = |name: &str| count_instrs(&module, name, |i| matches!(i, Instr::Drop(_)));
+    // The table index of each unguarded call is dropped
+    assert_eq!(drops("run"), 2);
+    assert_eq!(drops("konst"), 5);
+}
+
+const CONSTANT_SELECTORS: &str = "(module
+   (type $t (func (param i32) (result i32)))
+   (type $l (func (param i64) (result i64)))
+   (table 2 funcref)
+   (elem (i32.const 0) $a $b)
+   (global $fixed i32 (i32.const 0))
+   (global $sel (mut i32) (i32.const 0))
+   (func $a (type $t) (i32.const 0))
+   (func $b (type $l) (i64.const 0))
+   (func $run (export \"run\")
+     (drop (call_indirect (type $t) (i32.const 0) (i32.const 0)))
+     (drop (call_indirect (type $t) (i32.const 0) (global.get $fixed)))
+     (drop (call_indirect (type $t) (i32.const 0) (global.get $sel)))
+     (drop (call_indirect (type $t) (i32.const 0) (i32.const 1)))
+     (drop (call_indirect (type $t) (i32.const 0) (i32.const 7)))))";
+
+#[test]
+fn constant_selectors_are_folded_before_numbering() {
+    let wasm = wat::parse_str(CONSTANT_SELECTORS).unwrap();
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    // Index 1 holds another type and 7 is out of range, those still trap
+    let keys: Vec<&str> = output
+        .manifest
+        .callsites
+        .iter()
+        .map(|c| c.key.as_str())
+        .collect();
+    assert_eq!(keys, vec!["run#0", "run#1", "run#2"]);
+    // The tools that look at the original module agree
+    let original = Module::from_buffer(&wasm).unwrap();
+    assert_eq!(
+        vv_profiler::callsites::enumerate_callsites(&original, function_table(&original, None)).len(),
+        3
+    );
+    let calls = direct_calls(&module, "run");
+    assert_eq!(&calls[..2], &["a", "a"]);
+    assert_eq!(calls.len(), 5);
+
+    // Optimizing numbers the callsites the same way
+    let profile = Profile {
+        map: vec![(0, vec![0]), (1, vec![-2]), (2, vec![-1])]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    // Callsite 0 is devirtualized too (`a` is the only entry of its type)
+    assert_eq!(direct_calls(&module, "run"), vec!["a", "a", "a"]);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 1);
+    assert_eq!(
+        count_instrs(&module, "run", |i| matches!(i, Instr::Unreachable(_))),
+        1
+    );
+
+    // Other modules can write to an exported table
+    let exported = CONSTANT_SELECTORS.replace("(table 2", "(table (export \"t\") 2");
+    let wasm = wat::parse_str(exported).unwrap();
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    assert_eq!(output.manifest.callsites.len(), 5);
+}
+
+#[test]
+fn unused_optimize_stubs_are_removed() {
+    let wasm = wat::parse_str(single_type(2).to_wat()).unwrap();
+    // The module has no callsite 7, and run#1 keeps its call_indirect
+    let profile = Profile {
+        map: vec![(0, vec![1]), (1, vec![1]), (7, vec![0])]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        retain_callsites: vec!["run#1".to_string()].into_iter().collect(),
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    let stubs = optimize_stubs(&module);
+    assert_eq!(stubs.len(), 1);
+    let stub = vv_profiler::report::func_name(&module, stubs[0]);
+    assert_eq!(direct_calls(&module, "run"), vec![stub]);
+    // The targets are in the table, so they stay
+    assert_eq!(module.funcs.iter().count(), 5);
+}
+
+const GLOBAL_ACCESSES: &str = "(module
+   (import \"env\" \"base\" (global $base i32))
+   (global $cold (export \"cold\") (mut i32) (i32.const 1))
+   (global $hot (export \"hot\") (mut i32) (i32.const 2))
+   (memory 1)
+   (data (global.get $base) \"x\")
+   (func $run (export \"run\") (result i32)
+     (global.set $hot (i32.add (global.get $hot) (i32.const 1)))
+     (global.get $cold))
+   (func $_start (export \"_start\")))";
+
+// Index of the exported global `name` in the module's global index space
+fn global_index(module: &Module, name: &str) -> usize {
+    let global = match module.exports.iter().find(|e| e.name == name).unwrap().item {
+        walrus::ExportItem::Global(global) => global,
+        _ => panic!("{} is not a global", name),
+    };
+    module
+        .globals
+        .iter()
+        .position(|g| g.id() == global)
+        .unwrap()
+}
+
+#[test]
+fn global_access_counts_reorder_the_globals() {
+    let wasm = wat::parse_str(GLOBAL_ACCESSES).unwrap();
+    let options = InstrumentOptions {
+        global_counters: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    // Only globals the code touches get a counter
+    let mut counters: Vec<String> = export_names(&module)
+        .into_iter()
+        .filter(|e| e.starts_with("profiling_access_"))
+        .collect();
+    counters.sort();
+    assert_eq!(counters, vec!["profiling_access_1", "profiling_access_2"]);
+    // One increment (get, const, add, set) in front of each of the three accesses
+    let sets = |i: &Instr| matches!(i, Instr::GlobalSet(_));
+    assert_eq!(count_instrs(&module, "run", sets), 4);
+
+    let profile = Profile::from_globals_dump("profiling_access_1=1\nprofiling_access_2=40\n", "");
+    assert_eq!(profile.global_accesses[&2], 40);
+    let options = InstrumentOptions {
+        reorder_globals: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    // The imported global keeps index 0
+    assert_eq!(global_index(&module, "hot"), 1);
+    assert_eq!(global_index(&module, "cold"), 2);
+}
+
+const DATA_REGIONS: &str = r#"(module
+   (memory 1)
+   (data (i32.const 0) "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef")
+   (data (i32.const 256) "hot")
+   (func $run (export "run") (param i32) (result i32)
+     (i64.store offset=256 (i32.const 0) (i64.const 7))
+     (i32.store (i32.const 1024) (local.get 0))
+     (i32.load offset=64 (local.get 0)))
+   (func $_start (export "_start")))"#;
+
+// (offset, length) of each active data segment, in section order
+fn data_segments(module: &Module) -> Vec<(u32, usize)> {
+    module
+        .data
+        .iter()
+        .map(|data| match data.kind {
+            walrus::DataKind::Active(walrus::ActiveData {
+                location: walrus::ActiveDataLocation::Absolute(offset),
+                ..
+            }) => (offset, data.value.len()),
+            _ => panic!("expected an active segment at a constant offset"),
+        })
+        .collect()
+}
+
+#[test]
+fn data_region_counts_split_the_segments() {
+    let wasm = wat::parse_str(DATA_REGIONS).unwrap();
+    let options = InstrumentOptions {
+        data_counters: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    // A counter per 64-byte region of each segment
+    let mut counters: Vec<String> = export_names(&module)
+        .into_iter()
+        .filter(|e| e.starts_with("profiling_data_"))
+        .collect();
+    counters.sort();
+    assert_eq!(
+        counters,
+        vec![
+            "profiling_data_0",
+            "profiling_data_256",
+            "profiling_data_64"
+        ]
+    );
+    // Every load and store reports its address, even outside the segments
+    let calls = |i: &Instr| matches!(i, Instr::Call(_));
+    assert_eq!(count_instrs(&module, "run", calls), 3);
+
+    let profile = Profile::from_globals_dump("profiling_data_64=9\nprofiling_data_256=30\n", "");
+    assert_eq!(profile.data_accesses[&256], 30);
+    let options = InstrumentOptions {
+        reorder_data: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    // The first segment is cut where it turns hot, then everything goes hottest first
+    assert_eq!(data_segments(&module), vec![(256, 3), (64, 64), (0, 64)]);
+    let bytes: Vec<u8> = module.data.iter().flat_map(|d| d.value.clone()).collect();
+    assert_eq!(&bytes[..3], b"hot");
+    assert_eq!(&bytes[3..19], b"0123456789abcdef");
+}
+
+const ENTRY_POINTS: &str = r#"(module
+   (type $t (func (result i32)))
+   (table 2 funcref)
+   (elem (i32.const 0) $one $two)
+   (global $sel (mut i32) (i32.const 0))
+   (func $one (type $t) (i32.const 1))
+   (func $two (type $t) (i32.const 2))
+   (func $a (export "a") (result i32) (call_indirect (type $t) (global.get $sel)))
+   (func $b (export "b") (result i32) (call_indirect (type $t) (global.get $sel)))
+   (func $_start (export "_start")))"#;
+
+// Name of the function behind the export `name`
+fn exported_func_name(module: &Module, name: &str) -> String {
+    match module.exports.iter().find(|e| e.name == name).unwrap().item {
+        walrus::ExportItem::Function(f) => module.funcs.get(f).name.clone().unwrap(),
+        _ => panic!("{} is not a function", name),
+    }
+}
+
+#[test]
+fn entry_points_are_tagged_and_segmented() {
+    use vv_profiler::merge::segment_by_entry;
+    use vv_profiler::strip::strip_instrumentation;
+
+    let wasm = wat::parse_str(ENTRY_POINTS).unwrap();
+    let options = InstrumentOptions {
+        entry_counters: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let names: Vec<&str> = output
+        .manifest
+        .entries
+        .iter()
+        .map(|e| e.name.as_str())
+        .collect();
+    assert_eq!(names, vec!["a", "b", "_start"]);
+    let mut module = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(exported_func_name(&module, "a"), "entry_stub_0");
+    assert_eq!(exported_func_name(&module, "_start"), "entry_stub_2");
+    let exports = export_names(&module);
+    assert!(exports.contains(&"profiling_entry".to_string()));
+    assert!(exports.contains(&"profiling_entry_1".to_string()));
+    // Stripping points the exports back at the handlers
+    strip_instrumentation(&mut module).unwrap();
+    assert_eq!(exported_func_name(&module, "a"), "a");
+    assert!(!export_names(&module).contains(&"profiling_entry".to_string()));
+
+    // One dump per invocation, tagged with the handler that ran
+    let dump = |entry: i32| {
+        Profile::from_globals_dump(
+            &format!(
+                "profiling_entry={}\nprofiling_global_0_0=0\nprofiling_global_1_0=1\n",
+                entry
+            ),
+            "",
+        )
+    };
+    assert_eq!(dump(-1).entry, None);
+    let segments = segment_by_entry(&[dump(0), dump(1), dump(0), dump(-1)]);
+    assert_eq!(segments.keys().cloned().collect::<Vec<_>>(), vec![0, 1]);
+    assert_eq!(segments[&0].entry, Some(0));
+
+    // Only what `a` can reach is optimized with a's profile
+    let mut segments = segments;
+    let options = InstrumentOptions {
+        entry_point: Some("a".to_string()),
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let profile = segments.remove(&0).unwrap();
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(count_instrs(&module, "a", is_call_indirect), 0);
+    assert_eq!(count_instrs(&module, "b", is_call_indirect), 1);
+
+    // b's profile can't optimize for a
+    let profile = segments.remove(&1).unwrap();
+    match pipeline::run(&wasm, Some(profile), &options) {
+        Err(pipeline::Error::InvalidOptions(e)) => assert!(e.contains("collected on b"), "{}", e),
+        other => panic!("expected InvalidOptions, got {:?}", other.map(|_| ())),
+    }
+}
+
+const SHARED_DISPATCH: &str = r#"(module
+   (type $t (func (result i32)))
+   (table 2 funcref)
+   (elem (i32.const 0) $one $two)
+   (global $sel (mut i32) (i32.const 0))
+   (func $one (type $t) (i32.const 1))
+   (func $two (type $t) (i32.const 2))
+   (func $dispatch (result i32) (call_indirect (type $t) (global.get $sel)))
+   (func $a (export "a") (result i32) (call $dispatch))
+   (func $b (export "b") (result i32) (call $dispatch))
+   (func $_start (export "_start")))"#;
+
+#[test]
+fn entry_points_devirtualize_with_their_own_targets() {
+    use vv_profiler::merge::merge_into;
+
+    // `a` only ever dispatches to $one and `b` to $two
+    let mut merged = Profile::default();
+    for (entry, target) in [(0, 0), (1, 1), (0, 0)] {
+        let dump = format!(
+            "profiling_entry={}\nprofiling_global_0_0={}\n",
+            entry, target
+        );
+        merge_into(&mut merged, &Profile::from_globals_dump(&dump, ""));
+    }
+    assert_eq!(merged.map[&0], vec![-2]);
+    assert_eq!(merged.by_entry[&0][&0], vec![0]);
+    let merged = Profile::decode(&merged.encode(ProfileFormat::Json), ProfileFormat::Json).unwrap();
+    assert_eq!(merged.by_entry[&1][&0], vec![1]);
+
+    let wasm = wat::parse_str(SHARED_DISPATCH).unwrap();
+    let optimize_for = |entry_point: Option<&str>| {
+        let options = InstrumentOptions {
+            entry_point: entry_point.map(|name| name.to_string()),
+            self_check: true,
+            ..InstrumentOptions::default()
+        };
+        let profile = Profile::decode(&merged.encode(ProfileFormat::Json), ProfileFormat::Json).unwrap();
+        let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+        Module::from_buffer(&output.wasm).unwrap()
+    };
+    // The merged slots overflowed, so the call stays indirect
+    let module = optimize_for(None);
+    assert_eq!(count_instrs(&module, "dispatch", is_call_indirect), 1);
+    for (entry_point, target) in [("a", "one"), ("b", "two")] {
+        let module = optimize_for(Some(entry_point));
+        let stubs = optimize_stubs(&module);
+        assert_eq!(stubs.len(), 1);
+        let stub = vv_profiler::report::func_name(&module, stubs[0]);
+        assert_eq!(direct_calls(&module, "dispatch"), vec![stub.clone()]);
+        assert_eq!(direct_calls(&module, &stub), vec![target.to_string()]);
+    }
+}
+
+// $dispatch calls into the host, so calling it is a slowcall
+const SLOWCALLS: &str = r#"(module
+  (type $t (func (result i32)))
+  (import "env" "log" (func $log (param i32)))
+  (table 2 funcref)
+  (elem (i32.const 0) $one $two)
+  (func $one (type $t) (i32.const 1))
+  (func $two (type $t) (i32.const 2))
+  (func $dispatch (param $idx i32) (result i32)
+    (call $log (local.get $idx))
+    (call_indirect (type $t) (local.get $idx)))
+  (func $run (export "run") (param $idx i32) (result i32)
+    (call $dispatch (local.get $idx)))
+  (func $_start (export "_start")))"#;
+
+#[test]
+fn counters_are_snapshotted_every_n_slowcalls() {
+    use vv_profiler::snapshots::decode_snapshots;
+    use vv_profiler::strip::strip_instrumentation;
+
+    let wasm = wat::parse_str(SLOWCALLS).unwrap();
+    let options = InstrumentOptions {
+        snapshot_every: Some(2),
+        snapshot_capacity: 2,
+        snapshot_tick: Some(("env".to_string(), "tick".to_string())),
+        min_func_size: 0,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let layout = output.manifest.snapshots.clone().unwrap();
+    assert_eq!(layout.memory_export, "profiling_snapshots");
+    assert!(layout.counters.contains(&"slowcalls".to_string()));
+    assert!(layout
+        .counters
+        .contains(&"profiling_global_0_0".to_string()));
+    let mut module = Module::from_buffer(&output.wasm).unwrap();
+    assert!(module
+        .imports
+        .iter()
+        .any(|import| import.module == "env" && import.name == "tick"));
+    let stub = module.funcs.by_name("slowcall_stub_0").unwrap();
+    let stub = vv_profiler::report::func_name(&module, stub);
+    assert!(direct_calls(&module, &stub).contains(&"snapshot_stub".to_string()));
+    strip_instrumentation(&mut module).unwrap();
+    assert_eq!(module.imports.iter().count(), 1);
+    assert!(module.funcs.by_name("snapshot_stub").is_none());
+    assert_eq!(module.memories.iter().count(), 0);
+
+    // Three snapshots taken, the ring kept the last two
+    let word = |w: u32| w.to_le_bytes().to_vec();
+    let slowcalls = layout
+        .counters
+        .iter()
+        .position(|c| c == "slowcalls")
+        .unwrap();
+    let mut buf: Vec<u8> = [1, layout.counters.len() as u32, 2, 3]
+        .iter()
+        .flat_map(|w| word(*w))
+        .collect();
+    for snapshot in [6, 4] {
+        for i in 0..layout.counters.len() {
+            buf.extend(word(if i == slowcalls { snapshot } else { 0 }));
+        }
+    }
+    let snapshots = decode_snapshots(&buf, &layout);
+    let taken: Vec<(u32, Option<i32>)> = snapshots
+        .iter()
+        .map(|(n, profile)| (*n, profile.slowcalls))
+        .collect();
+    assert_eq!(taken, vec![(1, Some(4)), (2, Some(6))]);
+
+    assert!(matches!(
+        pipeline::run(
+            &wasm,
+            None,
+            &InstrumentOptions {
+                snapshot_every: Some(0),
+                ..InstrumentOptions::default()
+            }
+        ),
+        Err(pipeline::Error::InvalidOptions(_))
+    ));
+}
+
+#[test]
+fn host_events_call_the_imported_callback() {
+    use vv_profiler::strip::strip_instrumentation;
+
+    let options = InstrumentOptions {
+        window: 2,
+        host_events: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (mut module, output) = instrument(&single_type(2), &options);
+    let abi = output.manifest.host_events.clone().unwrap();
+    assert_eq!(
+        (abi.module.as_str(), abi.name.as_str()),
+        ("env", "vv_profile_event")
+    );
+    assert_eq!(abi.params, vec!["callsite", "target"]);
+    assert!(module
+        .imports
+        .iter()
+        .any(|import| import.module == "env" && import.name == "vv_profile_event"));
+    // No slot globals, only the event counters
+    let exports = export_names(&module);
+    assert!(!exports
+        .iter()
+        .any(|name| name.starts_with("profiling_global_")));
+    assert!(exports.contains(&"indirect".to_string()));
+    let stubs = instrument_stubs(&module);
+    assert_eq!(stubs.len(), 1);
+    let stub = vv_profiler::report::func_name(&module, stubs[0]);
+    assert_eq!(
+        direct_calls(&module, &stub),
+        vec!["vv_profile_event".to_string()]
+    );
+
+    let header = vv_profiler::glue::c_header(&output.manifest);
+    assert!(header
+        .lines()
+        .any(|l| l == "void vv_profile_event(int32_t callsite, int32_t target);"));
+
+    strip_instrumentation(&mut module).unwrap();
+    assert_eq!(module.imports.iter().count(), 0);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 2);
+
+    let wasm = wat::parse_str(single_type(2).to_wat()).unwrap();
+    let options = InstrumentOptions {
+        slot_memory: true,
+        ..options
+    };
+    assert!(matches!(
+        pipeline::run(&wasm, None, &options),
+        Err(pipeline::Error::InvalidOptions(_))
+    ));
+}
+
+const BR_TABLES: &str = r#"(module
+   (func $dispatch (export "dispatch") (param i32) (result i32)
+     (block
+       (block
+         (block
+           (block
+             (br_table 0 1 2 3 (local.get 0)))
+           (return (i32.const 10)))
+         (return (i32.const 11)))
+       (return (i32.const 12)))
+     (block
+       (br_table 0 0 (local.get 0)))
+     (i32.const 13))
+   (func $_start (export "_start")))"#;
+
+#[test]
+fn br_table_arms_are_counted() {
+    use vv_profiler::strip::strip_instrumentation;
+
+    let wasm = wat::parse_str(BR_TABLES).unwrap();
+    let options = InstrumentOptions {
+        br_table_counters: Some(3),
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let mut module = Module::from_buffer(&output.wasm).unwrap();
+    // One counter per arm, the default last; the two-arm br_table is under the threshold
+    let mut counters: Vec<String> = export_names(&module)
+        .into_iter()
+        .filter(|e| e.starts_with("profiling_br_table_"))
+        .collect();
+    counters.sort();
+    assert_eq!(
+        counters,
+        vec![
+            "profiling_br_table_0_0",
+            "profiling_br_table_0_1",
+            "profiling_br_table_0_2",
+            "profiling_br_table_0_3"
+        ]
+    );
+    let calls = |i: &Instr| matches!(i, Instr::Call(_));
+    assert_eq!(count_instrs(&module, "dispatch", calls), 1);
+
+    let profile = Profile::from_globals_dump(
+        "profiling_br_table_0_0=5\nprofiling_br_table_0_3=2\nprofiling_br_table_0_0=1\n",
+        "",
+    );
+    assert_eq!(profile.br_tables[&0], vec![6, 0, 0, 2]);
+    assert!(strip_instrumentation(&mut module)
+        .unwrap_err()
+        .contains("profiling_br_table_0_"));
+}
+
+#[test]
+fn hot_br_table_arms_are_peeled() {
+    let wasm = wat::parse_str(BR_TABLES).unwrap();
+    let options = InstrumentOptions {
+        peel_br_tables: Some(2),
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let profile = Profile::from_globals_dump(
+        "profiling_br_table_0_0=1\nprofiling_br_table_0_2=30\nprofiling_br_table_0_3=12\n",
+        "",
+    );
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    // Arm 2, then the default; arm 0 is too rare to test on every dispatch
+    let br_ifs = |i: &Instr| matches!(i, Instr::BrIf(_));
+    assert_eq!(count_instrs(&module, "dispatch", br_ifs), 2);
+    let br_tables = |i: &Instr| matches!(i, Instr::BrTable(_));
+    assert_eq!(count_instrs(&module, "dispatch", br_tables), 2);
+
+    // A profile from another build of the module is ignored
+    let profile = Profile::from_globals_dump("profiling_br_table_0_5=30\n", "");
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(count_instrs(&module, "dispatch", br_ifs), 0);
+}
+
+const CONTEXTS: &str = r#"(module
+   (type $t (func (param i32) (result i32)))
+   (table 2 funcref)
+   (elem (i32.const 0) $a $b)
+   (func $a (param i32) (result i32)
+     (i32.add (local.get 0) (i32.const 1)))
+   (func $b (param i32) (result i32)
+     (if (local.get 0)
+       (then (return (i32.const 2))))
+     (br 0 (i32.const 3)))
+   (func $run (export "run") (param i32) (result i32)
+     (call_indirect (type $t) (local.get 0) (local.get 0)))
+   (func $_start (export "_start")))"#;
+
+#[test]
+fn context_tree_records_calling_contexts() {
+    use vv_profiler::contexttree::decode_context_tree;
+    use vv_profiler::export::context_to_folded;
+    use vv_profiler::strip::strip_instrumentation;
+
+    let wasm = wat::parse_str(CONTEXTS).unwrap();
+    let options = InstrumentOptions {
+        context_tree: true,
+        context_nodes: 4,
+        min_func_size: 0,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let layout = output.manifest.context_tree.clone().unwrap();
+    assert_eq!(layout.memory_export, "profiling_context_tree");
+    assert_eq!(layout.functions, vec!["a", "b", "run", "_start"]);
+    let mut module = Module::from_buffer(&output.wasm).unwrap();
+    assert!(export_names(&module).contains(&"profiling_context_tree".to_string()));
+    for func in ["a", "b", "run"] {
+        assert!(direct_calls(&module, func).contains(&"context_enter_stub".to_string()));
+    }
+    // Every way out of the function goes past the restore
+    let returns = |i: &Instr| matches!(i, Instr::Return(_));
+    assert_eq!(count_instrs(&module, "b", returns), 0);
+    assert!(strip_instrumentation(&mut module)
+        .unwrap_err()
+        .contains("profiling_context_tree"));
+
+    // run (node 2) called a from its only callsite three times, b once
+    let words: [i32; 20] = [
+        1, 4, 3, 0, //
+        0, 0, 0, 0, //
+        0, 2, -1, 1, //
+        2, 0, 0, 3, //
+        2, 1, 0, 1, //
+    ];
+    let buf: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    let (nodes, overflowed) = decode_context_tree(&buf, &layout);
+    assert_eq!(overflowed, 0);
+    assert_eq!(nodes.len(), 3);
+    assert_eq!(nodes[1].callsite, Some(0));
+    assert_eq!(nodes[0].callsite, None);
+    assert_eq!(
+        context_to_folded(&nodes, &layout, None, false),
+        "run 1\nrun;a 3\nrun;b 1\n"
+    );
+
+    let options = InstrumentOptions {
+        context_nodes: 3,
+        ..options
+    };
+    assert!(matches!(
+        pipeline::run(&wasm, None, &options),
+        Err(pipeline::Error::InvalidOptions(_))
+    ));
+}
+
+#[test]
+fn context_trees_export_as_folded_stacks() {
+    use vv_profiler::contexttree::ContextNode;
+    use vv_profiler::export::context_to_folded;
+    use vv_profiler::manifest::ContextTreeLayout;
+
+    let wasm = wat::parse_str(
+        r#"(module
+             (func $_ZN3app4main17h0123456789abcdefE (export "main") (call $_Z4stepi (i32.const 1)))
+             (func $_Z4stepi (param i32)))"#,
+    )
+    .unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let layout = ContextTreeLayout {
+        memory_export: "profiling_context_tree".to_string(),
+        version: 1,
+        capacity: 4,
+        functions: vec!["old_main".to_string(), "old_step".to_string()],
+    };
+    let node = |id, parent, func, calls| ContextNode {
+        id,
+        parent,
+        func,
+        callsite: None,
+        calls,
+    };
+    let nodes = vec![node(3, 0, 0, 1), node(1, 3, 1, 4)];
+    // The name section wins over the manifest's names, and is demangled
+    assert_eq!(
+        context_to_folded(&nodes, &layout, Some(&module), true),
+        "app::main 1\napp::main;step(int) 4\n"
+    );
+    assert_eq!(
+        context_to_folded(&nodes, &layout, None, true),
+        "old_main 1\nold_main;old_step 4\n"
+    );
+}
+
+#[test]
+fn block_counts_and_instruction_weights_estimate_cycles() {
+    use vv_profiler::cycles::{estimate_cycles, CostTable};
+
+    let wasm = wat::parse_str(
+        r#"(module
+             (func $work (export "work") (param i32) (result i32)
+               (local i32)
+               (loop $again
+                 (local.set 1 (i32.div_s (local.get 0) (i32.const 3)))
+                 (br_if $again (i32.eqz (local.get 1))))
+               (local.get 1))
+             (func $_start (export "_start")))"#,
+    )
+    .unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    // Entered twice, looping ten times in all; _start never ran
+    let profile = Profile::from_globals_dump("profiling_block_0=2\nprofiling_block_1=10\n", "");
+
+    // loop + local.get, then 6 instructions at 1 and i32.div_s at 20
+    let cycles = estimate_cycles(&module, &profile, &CostTable::default());
+    assert_eq!(cycles.len(), 1);
+    assert_eq!((cycles[0].cycles, cycles[0].calls), (2 + 10 * 26, 2));
+
+    let table = CostTable::from_toml("default = 2\n[weights]\nI32DivS = 50\n").unwrap();
+    let cycles = estimate_cycles(&module, &profile, &table);
+    assert_eq!(cycles[0].cycles, 2 * 2 + 10 * 62);
+    assert!(CostTable::from_toml("[wieghts]\nCall = 3\n").is_err());
+}
+
+#[test]
+fn index_map_translates_output_indices_to_the_input() {
+    let options = InstrumentOptions {
+        window: 2,
+        host_events: true,
+        ..InstrumentOptions::default()
+    };
+    let builder = single_type(2);
+    let input = Module::from_buffer(&wat::parse_str(builder.to_wat()).unwrap()).unwrap();
+    let (module, output) = instrument(&builder, &options);
+    let map = &output.index_map;
+    assert_eq!(map.version, 1);
+    assert_eq!(map.functions.len(), module.funcs.iter().count());
+
+    let name_at = |module: &Module, index: u32| {
+        let func = module
+            .funcs
+            .iter()
+            .find(|f| f.id().index() == index as usize);
+        func.and_then(|f| f.name.clone())
+    };
+    let mut originals = 0;
+    for entry in &map.functions {
+        assert_eq!(name_at(&module, entry.output), Some(entry.name.clone()));
+        if let Some(original) = entry.original {
+            assert_eq!(name_at(&input, original), Some(entry.name.clone()));
+            originals += 1;
+        }
+    }
+    assert_eq!(originals, input.funcs.iter().count());
+
+    // The event import goes before every local function, shifting them all
+    let event = map
+        .functions
+        .iter()
+        .find(|f| f.name == "vv_profile_event")
+        .unwrap();
+    assert_eq!(event.original, None);
+    assert_eq!(map.by_output(event.output), Some(event));
+    assert!(map
+        .functions
+        .iter()
+        .any(|f| f.original.is_some() && f.original != Some(f.output)));
+}
+
+// Newer LLVM returns small structs as multiple values
+fn multi_value() -> ModuleBuilder {
+    let mut builder = ModuleBuilder::new();
+    builder.target("a", &["i32"], &["i32", "i64"]);
+    builder.target("b", &["i32"], &["i32", "i64"]);
+    builder.target("c", &["i32"], &["i32", "i64"]);
+    builder.caller("run", &["i32"], &["i32", "i64"], 2);
+    builder.func(
+        "(func $pair (export \"pair\") (param $idx i32) (result i32 i64)
+           (block (result i32 i64)
+             (call_indirect (type $t0) (i32.const 5) (local.get $idx))))",
+    );
+    builder
+}
+
+fn stub_results(module: &Module, stubs: &[walrus::FunctionId]) -> Vec<Vec<walrus::ValType>> {
+    stubs
+        .iter()
+        .map(|stub| {
+            let ty = module.funcs.get(*stub).ty();
+            module.types.get(ty).results().to_vec()
+        })
+        .collect()
+}
+
+#[test]
+fn multi_value_callsites_are_instrumented() {
+    use walrus::ValType::{I32, I64};
+
+    let base = InstrumentOptions {
+        window: 2,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let modes = vec![
+        base.clone(),
+        InstrumentOptions {
+            trace: true,
+            ..base.clone()
+        },
+        InstrumentOptions {
+            slot_memory: true,
+            ..base.clone()
+        },
+        InstrumentOptions {
+            host_events: true,
+            ..base.clone()
+        },
+        InstrumentOptions {
+            context_tree: true,
+            block_counters: true,
+            ..base.clone()
+        },
+    ];
+    for options in modes {
+        let (module, output) = instrument(&multi_value(), &options);
+        assert_eq!(output.manifest.callsites.len(), 3);
+        let stubs = instrument_stubs(&module);
+        assert_eq!(stub_results(&module, &stubs), vec![vec![I32, I64]]);
+        assert_eq!(count_instrs(&module, "pair", is_call_indirect), 0);
+    }
+}
+
+#[test]
+fn multi_value_callsites_are_devirtualized() {
+    use walrus::ValType::{I32, I64};
+
+    // Monomorphic and polymorphic (a guard chain); the block's callsite never ran
+    let map = vec![(0, vec![1, -1]), (1, vec![0, 2])];
+    let module = optimize(&multi_value(), &map);
+    let stubs = optimize_stubs(&module);
+    assert_eq!(stubs.len(), 2);
+    assert_eq!(
+        stub_results(&module, &stubs),
+        vec![vec![I32, I64], vec![I32, I64]]
+    );
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 0);
+    assert_eq!(count_instrs(&module, "pair", is_call_indirect), 1);
+
+    // The conservative build keeps a call_indirect fallback behind every guard
+    let wasm = wat::parse_str(multi_value().to_wat()).unwrap();
+    let profile = Profile {
+        map: map.into_iter().collect::<HashMap<_, _>>(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let (_, conservative) = pipeline::run_ab_split(&wasm, profile, &options).unwrap();
+    let module = Module::from_buffer(&conservative.wasm).unwrap();
+    for stub in optimize_stubs(&module) {
+        let name = module.funcs.get(stub).name.clone().unwrap();
+        assert_eq!(count_instrs(&module, &name, is_call_indirect), 1);
+    }
+}
+
+#[test]
+fn exported_tables_are_reachable_from_outside() {
+    let wasm = wat::parse_str(EMSCRIPTEN).unwrap();
+    let slowcalls = |wasm: &[u8]| {
+        let mut module = Module::from_buffer(wasm).unwrap();
+        let table = module.tables.main_function_table().unwrap();
+        let slowcalls =
+            compute_slowcalls(&mut module, table, true, &ImportClasses::default(), None, None)
+                .slowcalls;
+        let mut names: Vec<String> = slowcalls
+            .iter()
+            .map(|id| vv_profiler::report::func_name(&module, *id))
+            .collect();
+        names.sort();
+        names
+    };
+    // Whoever imports the table can call $a and $b directly, which taints their callers
+    assert_eq!(slowcalls(&wasm), vec!["a", "b", "catching", "run"]);
+    let private = wat::parse_str(private_table(EMSCRIPTEN)).unwrap();
+    assert!(slowcalls(&private).is_empty());
+
+    // ... and put other functions in it, so devirtualized calls keep a fallback
+    let profile = Profile {
+        map: vec![(0, vec![1]), (1, vec![-1])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let stub = vv_profiler::report::func_name(&module, optimize_stubs(&module)[0]);
+    assert_eq!(direct_calls(&module, &stub), vec!["b".to_string()]);
+    assert_eq!(count_instrs(&module, &stub, is_call_indirect), 1);
+    assert_eq!(count_instrs(&module, "run", is_call_indirect), 1);
+    assert!(!output.decisions[0].may_trap);
+}
+
+#[test]
+fn manifests_embed_in_the_instrumented_binary() {
+    use vv_profiler::manifest::{embed_manifest, embedded_manifest, MANIFEST_SECTION};
+
+    let wasm = wat::parse_str(single_type(2).to_wat()).unwrap();
+    let options = InstrumentOptions {
+        window: 2,
+        ..InstrumentOptions::default()
+    };
+    let mut output = pipeline::run(&wasm, None, &options).unwrap();
+    assert!(embedded_manifest(&output.wasm).is_none());
+    let plain = Module::from_buffer(&output.wasm).unwrap();
+    embed_manifest(&mut output.wasm, &output.manifest);
+
+    let embedded = embedded_manifest(&output.wasm).unwrap();
+    assert_eq!(embedded.manifest.callsites.len(), 2);
+    assert_eq!(
+        embedded.manifest.fingerprint.as_deref(),
+        Some(vv_profiler::manifest::fingerprint(&wasm).as_str())
+    );
+    let mut ids: Vec<usize> = embedded.profile.map.keys().cloned().collect();
+    ids.sort();
+    assert_eq!(ids, vec![0, 1]);
+    assert!(embedded.profile.map.values().all(|slots| slots.is_empty()));
+
+    // Only a section was added, the functions keep their indices
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let names = |module: &Module| -> Vec<Option<String>> {
+        module.funcs.iter().map(|f| f.name.clone()).collect()
+    };
+    assert_eq!(names(&module), names(&plain));
+    assert!(module
+        .customs
+        .iter()
+        .any(|(_, section)| section.name() == MANIFEST_SECTION));
+
+    // Optimizing the instrumented binary itself drops the section with the rest
+    let profile = Profile {
+        map: vec![(0, vec![0, -1]), (1, vec![1, -1])]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+    let optimized = pipeline::run(&output.wasm, Some(profile), &options).unwrap();
+    assert!(embedded_manifest(&optimized.wasm).is_none());
+}
+
+#[test]
+fn profiles_are_recovered_from_core_dumps() {
+    use vv_profiler::coredump::profile_from_core_dump;
+    use walrus::ir::Value;
+    use walrus::{ExportItem, InitExpr, RawCustomSection, ValType};
+
+    let options = InstrumentOptions {
+        window: 2,
+        export_prefix: "vv_".to_string(),
+        ..InstrumentOptions::default()
+    };
+    let (module, _) = instrument(&single_type(1), &options);
+    let exported: HashMap<usize, String> = module
+        .exports
+        .iter()
+        .filter_map(|export| match export.item {
+            ExportItem::Global(global) => Some((global.index(), export.name.clone())),
+            _ => None,
+        })
+        .collect();
+    let globals = module.globals.iter().count();
+
+    // Two lanes of the instrumented binary, which called $a and $b respectively
+    let mut dump = Module::default();
+    for target in [0, 1] {
+        for idx in 0..globals {
+            let value = match exported.get(&idx).map(|name| name.as_str()) {
+                Some("vv_profiling_global_0_0") => target,
+                Some("vv_slowcalls") => 3,
+                _ => -1,
+            };
+            dump.globals
+                .add_local(ValType::I32, true, InitExpr::Value(Value::I32(value)));
+        }
+    }
+    let mut instances = vec![2];
+    for lane in 0..2 {
+        instances.extend([0, 0, 0, globals as u8]);
+        instances.extend((0..globals).map(|idx| (lane * globals + idx) as u8));
+    }
+    dump.customs.add(RawCustomSection {
+        name: "coreinstances".to_string(),
+        data: instances,
+    });
+
+    let profile = profile_from_core_dump(&module, &dump.emit_wasm()).unwrap();
+    assert_eq!(profile.map[&0], vec![0, 1]);
+    assert_eq!(profile.slowcalls, Some(6));
+
+    // A dump of some other module has no instance to read
+    let other = wat::parse_str("(module (global i32 (i32.const 0)))").unwrap();
+    assert!(profile_from_core_dump(&module, &other).is_err());
+}
+
+#[test]
+fn warm_up_profiles_are_read_from_wizened_modules() {
+    use vv_profiler::coredump::profile_from_snapshot;
+    use walrus::ir::Value;
+    use walrus::{ExportItem, GlobalKind, InitExpr};
+
+    // The imported global shifts the index of every global the module defines
+    let wasm = wat::parse_str(
+        r#"(module
+  (import "env" "base" (global $base i32))
+  (type $t (func (param i32) (result i32)))
+  (table 2 funcref)
+  (elem (i32.const 0) $a $b)
+  (func $a (type $t) (global.get $base))
+  (func $b (type $t) (i32.const 1))
+  (func $run (export "run") (param $idx i32) (result i32)
+    (call_indirect (type $t) (i32.const 0) (local.get $idx)))
+  (func $_start (export "_start")))"#,
+    )
+    .unwrap();
+    let options = InstrumentOptions {
+        window: 2,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    // What Wizer leaves behind after a warm-up run that called $b
+    let mut wizened = Module::from_buffer(&output.wasm).unwrap();
+    let baked: Vec<(walrus::GlobalId, i32)> = wizened
+        .exports
+        .iter()
+        .filter_map(|export| match (export.item, export.name.as_str()) {
+            (ExportItem::Global(global), "profiling_global_0_0") => Some((global, 1)),
+            _ => None,
+        })
+        .collect();
+    for (global, value) in baked {
+        wizened.globals.get_mut(global).kind =
+            GlobalKind::Local(InitExpr::Value(Value::I32(value)));
+    }
+
+    let profile = profile_from_snapshot(&module, &wizened.emit_wasm()).unwrap();
+    assert_eq!(profile.map[&0], vec![1, -1]);
+}
+
+#[test]
+fn init_functions_chain_the_start_function() {
+    use vv_profiler::startinit::{InitMode, INIT_FUNCTION_NAME};
+    use vv_profiler::strip::strip_instrumentation;
+    use walrus::{ExportItem, GlobalKind, InitExpr};
+
+    let wasm = wat::parse_str(ENTRY_CALLSITES).unwrap();
+    let options = InstrumentOptions {
+        snapshot_every: Some(2),
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(module.start, module.funcs.by_name("init"));
+    assert_eq!(module.data.iter().count(), 1);
+
+    let output = pipeline::run(
+        &wasm,
+        None,
+        &InstrumentOptions {
+            init_mode: InitMode::StartChain,
+            ..options
+        },
+    )
+    .unwrap();
+    let mut module = Module::from_buffer(&output.wasm).unwrap();
+    let init = module.funcs.by_name(INIT_FUNCTION_NAME).unwrap();
+    assert_eq!(module.start, Some(init));
+    assert_eq!(direct_calls(&module, INIT_FUNCTION_NAME), vec!["init"]);
+    // The snapshot header is written by the init function instead
+    assert_eq!(module.data.iter().count(), 0);
+    let slot = module
+        .exports
+        .iter()
+        .find_map(|export| match export.item {
+            ExportItem::Global(global) if export.name == "profiling_global_0_0" => Some(global),
+            _ => None,
+        })
+        .unwrap();
+    assert!(matches!(
+        module.globals.get(slot).kind,
+        GlobalKind::Local(InitExpr::Value(walrus::ir::Value::I32(0)))
+    ));
+    // The three slots and the snapshot countdown; the input's $sel keeps its initializer
+    assert_eq!(
+        count_instrs(&module, INIT_FUNCTION_NAME, |instr| matches!(
+            instr,
+            Instr::GlobalSet(_)
+        )),
+        4
+    );
+
+    strip_instrumentation(&mut module).unwrap();
+    assert_eq!(module.start, module.funcs.by_name("init"));
+    assert!(module.funcs.by_name(INIT_FUNCTION_NAME).is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn smoke_tests_fail_with_the_command() {
+    use vv_profiler::smoketest::run_smoke_test;
+
+    let dir = std::env::temp_dir().join(format!("vv-smoke-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("it's here.wasm");
+    std::fs::write(&path, wat::parse_str("(module)").unwrap()).unwrap();
+    let path = path.to_str().unwrap();
+
+    assert!(run_smoke_test("test -s {}", path).is_ok());
+    // Appended when the command doesn't say where
+    assert!(run_smoke_test("test -s", path).is_ok());
+    let err = run_smoke_test("echo trapped >&2; exit 3; true {}", path).unwrap_err();
+    assert!(err.contains("exit status: 3"), "{}", err);
+    assert!(err.ends_with("trapped"), "{}", err);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+// A custom section called `name` holding `data`
+fn custom_section(name: &str, data: &[u8]) -> Vec<u8> {
+    let mut contents = vec![name.len() as u8];
+    contents.extend_from_slice(name.as_bytes());
+    contents.extend_from_slice(data);
+    let mut section = vec![0, contents.len() as u8];
+    section.extend(contents);
+    section
+}
+
+fn contains_bytes(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack
+        .windows(needle.len())
+        .any(|window| window == needle)
+}
+
+#[test]
+fn custom_sections_pass_through_byte_for_byte() {
+    let dylink = custom_section("dylink.0", &[1, 2, 0x10, 0x20]);
+    let features = custom_section("target_features", b"\x01+\x08mutable-globals");
+    let hints = custom_section("metadata.code.branch_hint", &[0]);
+    let vendor = custom_section("vendor.blob", b"opaque");
+
+    let module = wat::parse_str(single_type(2).to_wat()).unwrap();
+    let mut wasm = module[..8].to_vec();
+    wasm.extend(&dylink);
+    wasm.extend(&module[8..]);
+    for section in [&features, &hints, &vendor] {
+        wasm.extend(section);
+    }
+
+    let run = |keep: &[&str], drop: &[&str]| {
+        let options = InstrumentOptions {
+            keep_custom: keep.iter().map(|s| s.to_string()).collect(),
+            drop_custom: drop.iter().map(|s| s.to_string()).collect(),
+            ..InstrumentOptions::default()
+        };
+        let output = pipeline::run(&wasm, None, &options).unwrap().wasm;
+        Module::from_buffer(&output).unwrap();
+        output
+    };
+
+    let output = run(&[], &[]);
+    // dylink.0 has to stay the first section
+    assert_eq!(&output[8..8 + dylink.len()], &dylink[..]);
+    assert!(contains_bytes(&output, &features));
+    assert!(contains_bytes(&output, &vendor));
+    // Its offsets point into code the instrumentation changed
+    assert!(!contains_bytes(&output, &hints));
+
+    let output = run(&["metadata.code.*"], &["vendor.blob"]);
+    assert!(contains_bytes(&output, &hints));
+    assert!(!contains_bytes(&output, &vendor));
+    assert!(contains_bytes(&output, &features));
+
+    let options = InstrumentOptions {
+        keep_custom: vec!["vendor.blob".to_string()],
+        drop_custom: vec!["vendor.blob".to_string()],
+        ..InstrumentOptions::default()
+    };
+    assert!(pipeline::run(&wasm, None, &options).is_err());
+}
+
+#[test]
+fn position_independent_modules_guard_against_base_relative_indices() {
+    use walrus::ir::{BinaryOp, Binop};
+
+    let module = wat::parse_str(
+        r#"(module
+    (type $t (func (param i32) (result i32)))
+    (import "env" "__indirect_function_table" (table $table 2 funcref))
+    (import "env" "__table_base" (global $base i32))
+    (elem (global.get $base) func $a $b)
+    (func $a (type $t) (local.get 0))
+    (func $b (type $t) (i32.add (local.get 0) (i32.const 1)))
+    (func $run (export "run") (param $idx i32) (result i32)
+        (call_indirect (type $t) (i32.const 7) (local.get $idx))))"#,
+    )
+    .unwrap();
+    let mut wasm = module[..8].to_vec();
+    wasm.extend(custom_section("dylink.0", &[]));
+    wasm.extend(&module[8..]);
+    let parsed = Module::from_buffer(&wasm).unwrap();
+    let table = parsed.tables.main_function_table().unwrap().unwrap();
+    let image = TableImage::build(&parsed, table);
+    assert_eq!(image.unplaced, 0);
+    assert_eq!(image.get(1), parsed.funcs.by_name("b"));
+
+    // (global.get, i32.sub, i32.add) in `name`
+    let base_ops = |module: &Module, name: &str| {
+        let binops = |op: fn(&BinaryOp) -> bool| {
+            count_instrs(
+                module,
+                name,
+                |instr| matches!(instr, Instr::Binop(Binop { op: o }) if op(o)),
+            )
+        };
+        (
+            count_instrs(module, name, |instr| matches!(instr, Instr::GlobalGet(_))),
+            binops(|op| matches!(op, BinaryOp::I32Sub)),
+            binops(|op| matches!(op, BinaryOp::I32Add)),
+        )
+    };
+
+    // The profiling stub records the index minus the base, and adds it back for the call
+    let output = pipeline::run(&wasm, None, &InstrumentOptions::default()).unwrap();
+    let instrumented = Module::from_buffer(&output.wasm).unwrap();
+    let (_, subs, adds) = base_ops(&instrumented, "indirect_stub_0");
+    assert_eq!(subs, 1);
+    assert!(adds >= 1);
+
+    // Slot 1 is `b` wherever the loader puts the module
+    let profile = Profile {
+        map: [(0, vec![1])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &InstrumentOptions::default()).unwrap();
+    let optimized = Module::from_buffer(&output.wasm).unwrap();
+    assert_eq!(optimize_stubs(&optimized).len(), 1);
+    assert_eq!(direct_calls(&optimized, "indirect_call_stub_0"), vec!["b"]);
+    assert_eq!(base_ops(&optimized, "indirect_call_stub_0"), (1, 0, 1));
+}
+
+#[test]
+fn lru_slots_keep_the_recent_targets_of_megamorphic_callsites() {
+    use vv_profiler::counters::SlotPolicy;
+    use vv_profiler::merge::{merge_into, merge_lru_slots};
+
+    let mut builder = single_type(1);
+    builder.target("c", &["i32"], &["i32"]);
+    let options = InstrumentOptions {
+        window: 2,
+        slot_policy: SlotPolicy::Lru,
+        ..InstrumentOptions::default()
+    };
+    let (module, output) = instrument(&builder, &options);
+    assert!(export_names(&module).contains(&"profiling_distinct_0".to_string()));
+    assert_eq!(output.manifest.counters.slot_policy, SlotPolicy::Lru);
+    // Nothing ever overflows
+    let overflow = |instr: &Instr| matches!(instr, Instr::Const(c) if matches!(c.value, walrus::ir::Value::I32(-2)));
+    assert_eq!(count_instrs(&module, "indirect_stub_0", overflow), 0);
+    let trace = InstrumentOptions {
+        trace: true,
+        ..options.clone()
+    };
+    let wasm = wat::parse_str(builder.to_wat()).unwrap();
+    assert!(pipeline::run(&wasm, None, &trace).is_err());
+
+    // Lanes and runs keep the most recent targets and the largest count
+    let dump = "profiling_global_0_0=2\nprofiling_global_0_1=1\nprofiling_distinct_0=3\n\
+                profiling_global_0_0=1\nprofiling_global_0_1=-1\nprofiling_distinct_0=1\n";
+    let mut profile = Profile::from_globals_dump(dump, "");
+    assert_eq!(profile.map[&0], vec![2, 1]);
+    assert_eq!(profile.distinct_targets[&0], 3);
+    assert_eq!(merge_lru_slots(&[3, 1], &[2, 3]), vec![3, 2]);
+    let other = Profile {
+        map: [(0, vec![0, 2])].into_iter().collect(),
+        distinct_targets: [(0, 2)].into_iter().collect(),
+        ..Profile::default()
+    };
+    merge_into(&mut profile, &other);
+    assert_eq!(profile.map[&0], vec![2, 0]);
+    assert_eq!(profile.distinct_targets[&0], 3);
+
+    // `c` is the only target kept, but the callsite saw others
+    let optimize = |distinct_targets: HashMap<usize, u64>| {
+        let profile = Profile {
+            map: [(0, vec![2, -1])].into_iter().collect(),
+            distinct_targets,
+            ..Profile::default()
+        };
+        let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+        let module = Module::from_buffer(&output.wasm).unwrap();
+        assert_eq!(direct_calls(&module, "indirect_call_stub_0"), vec!["c"]);
+        count_instrs(&module, "indirect_call_stub_0", is_call_indirect)
+    };
+    assert_eq!(optimize(HashMap::new()), 0);
+    assert_eq!(optimize([(0, 1)].into_iter().collect()), 0);
+    assert_eq!(optimize([(0, 3)].into_iter().collect()), 1);
+}
+
+#[test]
+fn megamorphic_callsites_come_with_a_recommendation() {
+    use vv_profiler::megamorphic::{megamorphic_callsites, Recommendation};
+
+    let mut builder = single_type(3);
+    builder.target("c", &["i32"], &["i32"]);
+    builder.target("wide", &["i64"], &["i64"]);
+    let module = Module::from_buffer(&wat::parse_str(builder.to_wat()).unwrap()).unwrap();
+    let profile = Profile {
+        map: [(0, vec![-2, -2]), (1, vec![0, 1]), (2, vec![-2, -2])]
+            .into_iter()
+            .collect(),
+        target_counts: [(2, vec![(1, 900), (0, 50), (2, 50)])]
+            .into_iter()
+            .collect(),
+        ..Profile::default()
+    };
+    let megamorphic = megamorphic_callsites(&module, function_table(&module, None), &profile);
+    let found: Vec<(usize, Option<usize>, Recommendation)> = megamorphic
+        .iter()
+        .map(|site| (site.callsite, site.fan_out, site.recommendation.clone()))
+        .collect();
+    // `wide` isn't type-compatible, so three entries fit in a window of three
+    assert_eq!(
+        found,
+        vec![
+            (0, Some(3), Recommendation::IncreaseWindow(3)),
+            (2, Some(3), Recommendation::ForceDevirt("b".to_string())),
+        ]
+    );
+    assert_eq!(megamorphic[1].key, "run#2");
+}
+
+#[test]
+fn indirect_callsites_are_classified_by_dispatch_pattern() {
+    use vv_profiler::dispatch::{dispatch_kinds, DispatchKind};
+
+    let wat = r#"
+        (module
+          (type $t (func (param i32) (result i32)))
+          (memory 1)
+          (table 4 funcref)
+          ;; this->vptr[2](this)
+          (func $virtual (param $this i32) (result i32)
+            (call_indirect (type $t)
+              (local.get $this)
+              (i32.load offset=8 (i32.load (local.get $this)))))
+          ;; a Rust `dyn` method, the vtable pointer in a local
+          (func $dyn (param $data i32) (param $vtable i32) (result i32)
+            (local $method i32)
+            (local.set $method (i32.load offset=12 (local.get $vtable)))
+            (call_indirect (type $t) (local.get $data) (local.get $method)))
+          ;; handlers[op](x)
+          (func $table (param $op i32) (param $x i32) (result i32)
+            (call_indirect (type $t)
+              (local.get $x)
+              (i32.load
+                (i32.add (i32.const 1024) (i32.shl (local.get $op) (i32.const 2))))))
+          (func $_ZN4demo4main28_$u7b$$u7b$closure$u7d$$u7d$17h0123456789abcdefE
+            (param $f i32) (result i32)
+            (call_indirect (type $t) (i32.const 0) (local.get $f)))
+          ;; a callback passed in
+          (func $callback (param $f i32) (result i32)
+            (call_indirect (type $t) (i32.const 0) (local.get $f))))
+    "#;
+    let module = Module::from_buffer(&wat::parse_str(wat).unwrap()).unwrap();
+    let callsites = vv_profiler::callsites::enumerate_callsites(&module, function_table(&module, None));
+    let kinds = dispatch_kinds(&module, &callsites);
+    let found: Vec<(String, DispatchKind)> = callsites
+        .iter()
+        .zip(kinds)
+        .map(|(callsite, kind)| (vv_profiler::report::func_name(&module, callsite.func), kind))
+        .collect();
+    assert_eq!(
+        found,
+        vec![
+            ("virtual".to_string(), DispatchKind::Vtable),
+            ("dyn".to_string(), DispatchKind::Vtable),
+            ("table".to_string(), DispatchKind::FunctionPointerTable),
+            (
+                "_ZN4demo4main28_$u7b$$u7b$closure$u7d$$u7d$17h0123456789abcdefE".to_string(),
+                DispatchKind::Closure
+            ),
+            ("callback".to_string(), DispatchKind::Unknown),
+        ]
+    );
+}
+
+#[test]
+fn import_cost_classes_propagate_along_the_call_graph() {
+    let wat = r#"
+        (module
+          (import "env" "now" (func $now (result i32)))
+          (import "env" "log_str" (func $log_str))
+          (import "env" "abort" (func $abort))
+          (func $clock (result i32) (call $now))
+          (func $logs (call $log_str))
+          (func $fails (call $abort))
+          (func $calls_clock (result i32) (call $clock))
+          (func $calls_both (result i32) (call $fails) (call $clock))
+          (func $leaf (result i32) (i32.const 1)))
+    "#;
+    let classes = ImportClasses::from_toml(
+        r#"
+        [imports]
+        "env.now" = "cheap"
+        "env.log_*" = "free"
+        "abort" = "forbidden"
+        "#,
+    )
+    .unwrap();
+    let wasm = wat::parse_str(wat).unwrap();
+    let mut module = Module::from_buffer(&wasm).unwrap();
+    let analysis = compute_slowcalls(&mut module, None, false, &classes, None, None);
+    let class = |name: &str| analysis.classes[&function(&module, name)];
+    assert_eq!(class("leaf"), CostClass::Free);
+    assert_eq!(class("logs"), CostClass::Free);
+    assert_eq!(class("clock"), CostClass::Cheap);
+    assert_eq!(class("calls_clock"), CostClass::Cheap);
+    assert_eq!(class("calls_both"), CostClass::Forbidden);
+
+    // Cheap imports leave their callers fastcalls, forbidden ones don't
+    let mut slowcalls: Vec<String> = analysis
+        .slowcalls
+        .iter()
+        .map(|func| vv_profiler::report::func_name(&module, *func))
+        .collect();
+    slowcalls.sort();
+    assert_eq!(slowcalls, ["calls_both", "fails"]);
+
+    // Without the map, every import but proc_exit and fd_write is slow
+    let mut module = Module::from_buffer(&wasm).unwrap();
+    let classes = ImportClasses::default();
+    let analysis = compute_slowcalls(&mut module, None, false, &classes, None, None);
+    assert_eq!(analysis.slowcalls.len(), 5);
+    assert!(ImportClasses::from_toml("default = \"pricey\"").is_err());
+}
+
+#[test]
+fn indirect_slowcalls_go_through_the_counting_stubs() {
+    use vv_profiler::strip::strip_instrumentation;
+
+    let wat = r#"(module
+      (type $t (func (param i32)))
+      (import "env" "log" (func $log (param i32)))
+      (table 2 funcref)
+      (elem (i32.const 0) $quiet $noisy)
+      (func $quiet (type $t))
+      (func $noisy (type $t) (call $log (local.get 0)))
+      (func $run (export "run") (param $idx i32)
+        (call_indirect (type $t) (i32.const 7) (local.get $idx)))
+      (func $_start (export "_start")))"#;
+    let wasm = wat::parse_str(wat).unwrap();
+    let entries = |wasm: &[u8]| {
+        let module = Module::from_buffer(wasm).unwrap();
+        let elem = module.elements.iter().next().unwrap();
+        let names: Vec<String> = elem
+            .members
+            .iter()
+            .map(|member| vv_profiler::report::func_name(&module, member.unwrap()))
+            .collect();
+        names
+    };
+    let instrument = |count_indirect_slowcalls: bool| {
+        let options = InstrumentOptions {
+            count_indirect_slowcalls,
+            min_func_size: 0,
+            self_check: true,
+            ..InstrumentOptions::default()
+        };
+        pipeline::run(&wasm, None, &options).unwrap().wasm
+    };
+    assert_eq!(entries(&instrument(false)), ["quiet", "noisy"]);
+
+    let output = instrument(true);
+    let patched = entries(&output);
+    assert_eq!(patched[0], "quiet");
+    assert!(patched[1].starts_with("slowcall_stub_"));
+    let module = Module::from_buffer(&output).unwrap();
+    assert_eq!(direct_calls(&module, &patched[1]), ["noisy"]);
+
+    let mut module = Module::from_buffer(&output).unwrap();
+    strip_instrumentation(&mut module).unwrap();
+    assert_eq!(entries(&module.emit_wasm()), ["quiet", "noisy"]);
+}
+
+#[test]
+fn corrupt_compressed_profiles_are_an_error() {
+    use vv_profiler::compression::{compress, decompress, Compression};
+
+    let body = b"not really a profile".to_vec();
+    for compression in [Compression::Gzip(6), Compression::Zstd(3)] {
+        let compressed = compress(body.clone(), compression);
+        assert_eq!(decompress(compressed.clone()).unwrap(), body);
+        let truncated = compressed[..compressed.len() / 2].to_vec();
+        assert!(decompress(truncated).is_err());
+    }
+    assert_eq!(decompress(body.clone()).unwrap(), body);
+}
+
+#[test]
+fn reordering_globals_remaps_constant_expressions() {
+    use vv_profiler::globalaccess::reorder_globals;
+    use walrus::{ActiveData, ActiveDataLocation, DataKind, ElementKind, InitExpr, ValType};
+
+    // walrus only parses initializers reading local globals with the GC
+    // proposal, which it doesn't support yet, so the globals are added here
+    let wat = r#"(module
+      (memory 1)
+      (table 32 funcref)
+      (func $run (export "run")))"#;
+    let mut module = Module::from_buffer(&wat::parse_str(wat).unwrap()).unwrap();
+    let i32_const = |value| InitExpr::Value(walrus::ir::Value::I32(value));
+    let base = module.globals.add_local(ValType::I32, false, i32_const(16));
+    let alias = module
+        .globals
+        .add_local(ValType::I32, false, InitExpr::Global(base));
+    let hot = module.globals.add_local(ValType::I32, true, i32_const(2));
+    for (name, global) in [("base", base), ("alias", alias), ("hot", hot)] {
+        module.exports.add(name, global);
+    }
+    let memory = module.memories.iter().next().unwrap().id();
+    let location = ActiveDataLocation::Relative(base);
+    let data = DataKind::Active(ActiveData { memory, location });
+    module.data.add(data, b"x".to_vec());
+    let table = module.tables.iter().next().unwrap().id();
+    let run = function(&module, "run");
+    let offset = InitExpr::Global(base);
+    let elem = ElementKind::Active { table, offset };
+    module.elements.add(elem, ValType::Funcref, vec![Some(run)]);
+
+    let counts: HashMap<usize, i32> = [(1, 50), (2, 40)].into_iter().collect();
+    reorder_globals(&mut module, &counts);
+    // $alias reads $base, so $base goes in front of it despite having no count
+    assert_eq!(global_index(&module, "base"), 0);
+    assert_eq!(global_index(&module, "alias"), 1);
+    assert_eq!(global_index(&module, "hot"), 2);
+    let base = module.globals.iter().next().unwrap().id();
+    let alias = module.globals.iter().nth(1).unwrap();
+    assert!(matches!(alias.kind, walrus::GlobalKind::Local(InitExpr::Global(g)) if g == base));
+    let data = module.data.iter().next().unwrap();
+    assert!(matches!(
+        data.kind,
+        DataKind::Active(ActiveData {
+            location: ActiveDataLocation::Relative(g),
+            ..
+        }) if g == base
+    ));
+    let elem = module.elements.iter().next().unwrap();
+    assert!(matches!(
+        elem.kind,
+        ElementKind::Active { offset: InitExpr::Global(g), .. } if g == base
+    ));
+}
+
+#[test]
+fn cold_blocks_are_outlined_with_their_live_in_locals() {
+    let wat = r#"(module
+        (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+        (memory (export "memory") 1)
+        (func $compute (param $x i32) (param $y i32) (result i32)
+            (local $t i32)
+            (local.set $t (i32.mul (local.get $x) (local.get $y)))
+            (if (i32.gt_u (local.get $x) (i32.const 1000))
+                (then
+                    (i32.store (local.get $t) (local.get $y))
+                    (i32.store offset=4 (local.get $x) (local.get $t))
+                    (i32.store offset=8 (local.get $y) (local.get $x))
+                    unreachable))
+            (i32.add (local.get $t) (local.get $x)))
+        (func $_start (export "_start")
+            (call $proc_exit (call $compute (i32.const 3) (i32.const 4)))))"#;
+    let wasm = wat::parse_str(wat).unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let funcs: Vec<_> = module.funcs.iter_local().map(|(id, _)| id).collect();
+    let blocks = vv_profiler::blockcounters::enumerate_blocks(&module, &funcs);
+    let compute = function(&module, "compute");
+    let body = module.funcs.get(compute).kind.unwrap_local();
+    let cold = vv_profiler::selfcheck::instrs(body)
+        .iter()
+        .find_map(|instr| match instr {
+            Instr::IfElse(if_else) => Some(if_else.consequent),
+            _ => None,
+        })
+        .unwrap();
+    // Everything ran on every call except the then-branch
+    let profile = Profile {
+        blocks: blocks
+            .iter()
+            .enumerate()
+            .map(|(idx, block)| (idx, if *block == (compute, cold) { 0 } else { 5000 }))
+            .collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        split_cold: true,
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let outlined = function(&module, "cold_outline_0");
+    let ty = module.types.get(module.funcs.get(outlined).ty());
+    // $x, $y and $t are all read before the region writes them
+    assert_eq!(ty.params(), &[walrus::ValType::I32; 3]);
+    assert!(ty.results().is_empty());
+    assert_eq!(direct_calls(&module, "compute"), vec!["cold_outline_0"]);
+    let stores = |name| count_instrs(&module, name, |i| matches!(i, Instr::Store(_)));
+    assert_eq!(stores("compute"), 0);
+    assert_eq!(stores("cold_outline_0"), 3);
+
+    // The hot path still exits with 3 * 4 + 3
+    #[cfg(feature = "verify")]
+    {
+        let dir = std::env::temp_dir();
+        let original = dir.join(format!("vv-coldsplit-{}.wasm", std::process::id()));
+        let split = dir.join(format!("vv-coldsplit-{}.opt.wasm", std::process::id()));
+        std::fs::write(&original, &wasm).unwrap();
+        std::fs::write(&split, &output.wasm).unwrap();
+        let same = vv_profiler::verify::verify(
+            original.to_str().unwrap(),
+            split.to_str().unwrap(),
+            &[vec![]],
+            b"",
+        );
+        std::fs::remove_file(&original).unwrap();
+        std::fs::remove_file(&split).unwrap();
+        assert!(same);
+    }
+}
+
+// Entry counts import_llvm finds for `names`, by function name
+fn imported_entry_counts(names: &[&str]) -> HashMap<String, i32> {
+    let wasm = wat::parse_str(
+        r#"(module
+            (func $helper)
+            (func $ns::method))"#,
+    )
+    .unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let records: Vec<_> = names
+        .iter()
+        .map(|name| vv_profiler::llvmprof::ProfRecord {
+            name: name.to_string(),
+            hash: 0,
+            counters: vec![42],
+        })
+        .collect();
+    let profile = vv_profiler::llvmprof::import_llvm(&module, &records);
+    // One block per function, in function order
+    ["helper", "ns::method"]
+        .iter()
+        .enumerate()
+        .filter_map(|(idx, name)| Some((name.to_string(), *profile.blocks.get(&idx)?)))
+        .collect()
+}
+
+#[test]
+fn llvm_static_function_records_split_on_semicolons() {
+    let counts = imported_entry_counts(&["a.c;helper", "b.cpp;ns::method"]);
+    assert_eq!(counts["helper"], 42);
+    assert_eq!(counts["ns::method"], 42);
+}
+
+#[test]
+fn llvm_static_function_records_split_on_lone_colons() {
+    let counts = imported_entry_counts(&["a.c:helper", "b.cpp:ns::method"]);
+    assert_eq!(counts["helper"], 42);
+    assert_eq!(counts["ns::method"], 42);
+    // Without a file in front, "::" is left alone
+    let counts = imported_entry_counts(&["ns::method"]);
+    assert_eq!(counts.len(), 1);
+    assert_eq!(counts["ns::method"], 42);
+}
+
+// Two funcref tables with an entry of the same type each, and a callsite into each
+const TWO_TABLES: &str = r#"(module
+    (type $t (func (result i32)))
+    (table $a 1 funcref)
+    (table $b 1 funcref)
+    (elem (table $a) (i32.const 0) func $in_a)
+    (elem (table $b) (i32.const 0) func $in_b)
+    (func $in_a (type $t) i32.const 1)
+    (func $in_b (type $t) i32.const 2)
+    (func $run (export "run") (param i32) (result i32)
+        (call_indirect $a (type $t) (local.get 0))
+        (call_indirect $b (type $t) (local.get 0))
+        i32.add))"#;
+
+#[test]
+fn table_index_keeps_a_stub_per_table() {
+    let wasm = wat::parse_str(TWO_TABLES).unwrap();
+    let options = InstrumentOptions {
+        table_index: Some(1),
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+
+    let tables: Vec<_> = module.tables.iter().map(|table| table.id()).collect();
+    // The table each stub `run` calls goes through, in callsite order
+    let called: Vec<_> = direct_calls(&module, "run")
+        .iter()
+        .map(|stub| {
+            let stub = module.funcs.get(function(&module, stub)).kind.unwrap_local();
+            vv_profiler::selfcheck::instrs(stub)
+                .iter()
+                .find_map(|instr| match instr {
+                    Instr::CallIndirect(call) => Some(call.table),
+                    _ => None,
+                })
+                .unwrap()
+        })
+        .collect();
+    assert_eq!(called, tables);
+}
+
+#[test]
+fn constant_selectors_only_fold_through_the_resolved_table() {
+    let wasm = wat::parse_str(
+        r#"(module
+            (type $t (func (result i32)))
+            (table $a 1 funcref)
+            (table $b 1 funcref)
+            (elem (table $a) (i32.const 0) func $in_a)
+            (elem (table $b) (i32.const 0) func $in_b)
+            (func $in_a (type $t) i32.const 1)
+            (func $in_b (type $t) i32.const 2)
+            (func $run (export "run") (result i32)
+                (call_indirect $a (type $t) (i32.const 0))
+                (call_indirect $b (type $t) (i32.const 0))
+                i32.add))"#,
+    )
+    .unwrap();
+    let module = Module::from_buffer(&wasm).unwrap();
+    let b = function_table(&module, Some(1));
+    // The call through $b folds, so only the one through $a gets an id
+    let callsites = vv_profiler::callsites::enumerate_callsites(&module, b);
+    assert_eq!(callsites.len(), 1);
+    assert_eq!(Some(callsites[0].table), function_table(&module, Some(0)));
+
+    let options = InstrumentOptions {
+        table_index: Some(1),
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, None, &options).unwrap();
+    assert_eq!(output.manifest.callsites.len(), 1);
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let calls = direct_calls(&module, "run");
+    assert_eq!(calls.len(), 2);
+    assert!(calls[0].starts_with("indirect_stub_"));
+    assert_eq!(calls[1], "in_b");
+}
+
+#[test]
+fn modules_without_a_name_section_can_be_optimized() {
+    // No name section: callsite 0 has a single possible target, callsite 1 two
+    let wasm = wat::parse_str(
+        r#"(module
+            (type (func (result i32)))
+            (type (func (param i32) (result i32)))
+            (table 3 funcref)
+            (elem (i32.const 0) func 0 1 2)
+            (func (type 0) i32.const 1)
+            (func (type 1) local.get 0)
+            (func (type 1) i32.const 3)
+            (func (export "run") (param i32) (result i32)
+                (call_indirect (type 0) (local.get 0))
+                (call_indirect (type 1) (local.get 0) (local.get 0))
+                i32.add))"#,
+    )
+    .unwrap();
+    let profile = Profile {
+        map: vec![(0, vec![0]), (1, vec![1])].into_iter().collect(),
+        ..Profile::default()
+    };
+    let options = InstrumentOptions {
+        self_check: true,
+        ..InstrumentOptions::default()
+    };
+    let output = pipeline::run(&wasm, Some(profile), &options).unwrap();
+    let module = Module::from_buffer(&output.wasm).unwrap();
+    let run = module
+        .exports
+        .iter()
+        .find_map(|export| match export.item {
+            walrus::ExportItem::Function(func) if export.name == "run" => Some(func),
+            _ => None,
+        })
+        .unwrap();
+    let run = module.funcs.get(run).kind.unwrap_local();
+    let instrs = vv_profiler::selfcheck::instrs(run);
+    assert_eq!(instrs.iter().filter(|i| is_call_indirect(i)).count(), 0);
+    assert_eq!(instrs.iter().filter(|i| matches!(i, Instr::Call(_))).count(), 2);
+}